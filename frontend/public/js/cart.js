@@ -5,13 +5,20 @@ document.addEventListener('DOMContentLoaded', function() {
     async function initCart() {
         await loadCartItems();
         await updateCartCounter();
+        await loadDeliveryZones();
         setupEventListeners();
     }
 
+    // Подпись Telegram WebApp, по которой сервер находит корзину пользователя
+    function telegramAuthHeaders() {
+        const initData = window.Telegram && window.Telegram.WebApp && window.Telegram.WebApp.initData;
+        return initData ? { 'X-Telegram-Init-Data': initData } : {};
+    }
+
     // Загрузка товаров в корзине
     async function loadCartItems() {
         try {
-            const response = await fetch('/api/cart');
+            const response = await fetch('/api/cart', { headers: telegramAuthHeaders() });
             if (!response.ok) throw new Error('Ошибка загрузки корзины');
 
             const items = await response.json();
@@ -23,10 +30,98 @@ document.addEventListener('DOMContentLoaded', function() {
         }
     }
 
+    // Зоны доставки; если способы доставки не настроены, выбор не показывается
+    async function loadDeliveryZones() {
+        try {
+            const [zonesResponse, methodsResponse] = await Promise.all([
+                fetch('/api/delivery/zones'),
+                fetch('/api/delivery/methods')
+            ]);
+            if (!zonesResponse.ok || !methodsResponse.ok) throw new Error('Ошибка загрузки доставки');
+
+            const zones = await zonesResponse.json();
+            const methods = await methodsResponse.json();
+            if (methods.length === 0) return;
+
+            const zoneSelect = document.getElementById('deliveryZone');
+            zoneSelect.innerHTML = '<option value="">Выберите зону</option>' + zones.map(zone =>
+                `<option value="${zone.id}">${zone.name}</option>`
+            ).join('');
+            document.getElementById('deliveryOptions').style.display = '';
+        } catch (error) {
+            console.error('Ошибка:', error);
+            showError('Не удалось загрузить способы доставки');
+        }
+    }
+
+    // Способы доставки выбранной зоны со стоимостью для текущей корзины
+    async function loadDeliveryQuotes() {
+        const zoneId = document.getElementById('deliveryZone').value;
+        const methodSelect = document.getElementById('deliveryMethod');
+        const selectedMethod = methodSelect.value;
+        document.getElementById('deliveryCost').textContent = '';
+
+        if (!zoneId) {
+            methodSelect.innerHTML = '<option value="">Сначала выберите зону</option>';
+            methodSelect.disabled = true;
+            return;
+        }
+
+        try {
+            const response = await fetch('/api/delivery/quote', {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json',
+                    ...telegramAuthHeaders()
+                },
+                body: JSON.stringify({ zone_id: parseInt(zoneId) })
+            });
+            const result = await response.json();
+            if (!response.ok) throw new Error(result.error || 'Ошибка расчета доставки');
+
+            methodSelect.innerHTML = '<option value="">Выберите способ</option>' + result.quotes.map(quote =>
+                `<option value="${quote.method_id}" data-cost="${quote.cost}" data-free="${quote.free}">` +
+                `${quote.name} — ${quote.free ? 'бесплатно' : quote.cost.toFixed(2) + ' TON'}</option>`
+            ).join('');
+            methodSelect.disabled = result.quotes.length === 0;
+            // После пересчета сохраняем выбранный способ, если он есть в зоне
+            if (result.quotes.some(quote => String(quote.method_id) === selectedMethod)) {
+                methodSelect.value = selectedMethod;
+                showDeliveryCost();
+            }
+        } catch (error) {
+            console.error('Ошибка:', error);
+            showError(error.message || 'Ошибка расчета доставки');
+        }
+    }
+
+    function showDeliveryCost() {
+        const option = document.getElementById('deliveryMethod').selectedOptions[0];
+        const cost = document.getElementById('deliveryCost');
+        if (!option || !option.value) {
+            cost.textContent = '';
+            return;
+        }
+        cost.textContent = option.dataset.free === 'true'
+            ? 'Доставка: бесплатно'
+            : `Доставка: ${parseFloat(option.dataset.cost).toFixed(2)} TON`;
+    }
+
+    // Выбранные способ и зона доставки; null - доставка не настроена
+    function selectedDelivery() {
+        if (document.getElementById('deliveryOptions').style.display === 'none') {
+            return null;
+        }
+        return {
+            zoneId: parseInt(document.getElementById('deliveryZone').value) || null,
+            methodId: parseInt(document.getElementById('deliveryMethod').value) || null
+        };
+    }
+
     // Обновление счетчика товаров
     async function updateCartCounter() {
         try {
-            const response = await fetch('/api/cart/count');
+            const response = await fetch('/api/cart/count', { headers: telegramAuthHeaders() });
             if (!response.ok) return;
 
             const count = await response.json();
@@ -98,6 +193,8 @@ document.addEventListener('DOMContentLoaded', function() {
         });
 
         document.getElementById('checkoutBtn')?.addEventListener('click', checkout);
+        document.getElementById('deliveryZone')?.addEventListener('change', loadDeliveryQuotes);
+        document.getElementById('deliveryMethod')?.addEventListener('change', showDeliveryCost);
     }
 
     // Изменение количества товара
@@ -109,14 +206,15 @@ document.addEventListener('DOMContentLoaded', function() {
                 method: 'PUT',
                 headers: {
                     'Content-Type': 'application/json',
-                    'Accept': 'application/json'
+                    'Accept': 'application/json',
+                    ...telegramAuthHeaders()
                 },
                 body: JSON.stringify({ quantity: newQuantity })  // Теперь точно правильный формат
             });
 
             if (!response.ok) {
                 const errorData = await response.json();
-                throw new Error(errorData.message || 'Ошибка сервера');
+                throw new Error(errorData.error || errorData.message || 'Ошибка сервера');
             }
 
             // Обновляем интерфейс
@@ -125,9 +223,10 @@ document.addEventListener('DOMContentLoaded', function() {
                 input.value = newQuantity;
             }
 
-            // Перезагружаем данные корзины
+            // Перезагружаем данные корзины; стоимость доставки зависит от суммы и веса
             await loadCartItems();
             await updateCartCounter();
+            await loadDeliveryQuotes();
 
         } catch (error) {
             console.error('Ошибка при изменении количества:', error);
@@ -145,11 +244,13 @@ document.addEventListener('DOMContentLoaded', function() {
     async function removeItem(itemId) {
         try {
             const response = await fetch(`/api/cart/${itemId}`, {
-                method: 'DELETE'
+                method: 'DELETE',
+                headers: telegramAuthHeaders()
             });
 
             if (response.ok) {
                 await Promise.all([loadCartItems(), updateCartCounter()]);
+                await loadDeliveryQuotes();
             }
         } catch (error) {
             console.error('Ошибка:', error);
@@ -219,8 +320,15 @@ document.addEventListener('DOMContentLoaded', function() {
             return;
         }
 
-        // Получаем адрес доставки от пользователя
-        const deliveryAddress = prompt('Введите адрес доставки:');
+        // Способ и зона доставки обязательны, если доставка настроена
+        const delivery = selectedDelivery();
+        if (delivery && (!delivery.zoneId || !delivery.methodId)) {
+            alert('Выберите зону и способ доставки!');
+            return;
+        }
+
+        // Адрес доставки из формы корзины
+        const deliveryAddress = document.getElementById('deliveryAddress').value;
         if (!deliveryAddress || deliveryAddress.trim() === '') {
             alert('Адрес доставки обязателен!');
             return;
@@ -355,6 +463,10 @@ document.addEventListener('DOMContentLoaded', function() {
             delivery_address: deliveryAddress.trim(),
             telegram_username: telegramUsername
         };
+        if (delivery) {
+            orderData.delivery_method_id = delivery.methodId;
+            orderData.delivery_zone_id = delivery.zoneId;
+        }
 
         showDiagnostic('=== ОТПРАВКА ЗАКАЗА ===');
         showDiagnostic(`Order data: ${JSON.stringify(orderData, null, 2)}`);
@@ -364,6 +476,7 @@ document.addEventListener('DOMContentLoaded', function() {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
+                    ...telegramAuthHeaders()
                 },
                 body: JSON.stringify(orderData)
             });
@@ -384,6 +497,11 @@ document.addEventListener('DOMContentLoaded', function() {
             } else {
                 const error = await response.json();
                 showDiagnostic(`Ошибка сервера: ${JSON.stringify(error)}`, true);
+                if (response.status === 409) {
+                    // Цены или остатки изменились - приводим корзину в порядок и показываем заново
+                    await fetch('/api/cart/validate', { method: 'POST', headers: telegramAuthHeaders() });
+                    await Promise.all([loadCartItems(), updateCartCounter()]);
+                }
                 showError(error.error || 'Ошибка оформления заказа');
             }
         } catch (error) {
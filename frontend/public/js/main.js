@@ -1,8 +1,14 @@
 document.addEventListener('DOMContentLoaded', function() {
+    // Подпись Telegram WebApp, по которой сервер находит корзину пользователя
+    function telegramAuthHeaders() {
+        const initData = window.Telegram && window.Telegram.WebApp && window.Telegram.WebApp.initData;
+        return initData ? { 'X-Telegram-Init-Data': initData } : {};
+    }
+
     // Обновление счетчика корзины
     async function updateCartCounter() {
         try {
-            const response = await fetch('/api/cart/count');
+            const response = await fetch('/api/cart/count', { headers: telegramAuthHeaders() });
             if (response.ok) {
                 const count = await response.json();
                 const counter = document.getElementById('cart-counter');
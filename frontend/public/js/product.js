@@ -2,10 +2,16 @@ document.addEventListener('DOMContentLoaded', function() {
     const urlParams = new URLSearchParams(window.location.search);
     const productId = urlParams.get('id');
 
+    // Подпись Telegram WebApp, по которой сервер находит корзину пользователя
+    function telegramAuthHeaders() {
+        const initData = window.Telegram && window.Telegram.WebApp && window.Telegram.WebApp.initData;
+        return initData ? { 'X-Telegram-Init-Data': initData } : {};
+    }
+
     // Функция обновления счетчика корзины
     async function updateCartCounter() {
         try {
-            const response = await fetch('/api/cart/count');
+            const response = await fetch('/api/cart/count', { headers: telegramAuthHeaders() });
             if (response.ok) {
                 const count = await response.json();
                 const counter = document.getElementById('cart-counter');
@@ -23,7 +29,7 @@ document.addEventListener('DOMContentLoaded', function() {
             try {
                 const response = await fetch('/api/cart', {
                     method: 'POST',
-                    headers: { 'Content-Type': 'application/json' },
+                    headers: { 'Content-Type': 'application/json', ...telegramAuthHeaders() },
                     body: JSON.stringify({
                         product_id: parseInt(productId),
                         quantity: 1
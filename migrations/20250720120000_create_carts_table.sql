@@ -0,0 +1,30 @@
+-- Корзина принадлежит пользователю Telegram, а анонимная - сессии браузера
+CREATE TABLE carts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    telegram_user_id BIGINT UNIQUE,
+    session_id TEXT UNIQUE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO carts (session_id) SELECT DISTINCT session_id FROM cart;
+
+-- Позиции корзины теперь ссылаются на carts вместо session_id
+CREATE TABLE cart_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    cart_id INTEGER NOT NULL,
+    product_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    FOREIGN KEY (cart_id) REFERENCES carts(id) ON DELETE CASCADE,
+    FOREIGN KEY (product_id) REFERENCES products(id) ON DELETE CASCADE,
+    UNIQUE (cart_id, product_id)
+);
+
+INSERT INTO cart_new (id, cart_id, product_id, quantity)
+SELECT MIN(c.id), k.id, c.product_id, SUM(c.quantity)
+FROM cart c
+JOIN carts k ON k.session_id = c.session_id
+GROUP BY k.id, c.product_id;
+
+DROP TABLE cart;
+ALTER TABLE cart_new RENAME TO cart;
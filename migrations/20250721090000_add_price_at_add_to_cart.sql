@@ -0,0 +1,4 @@
+-- Цена товара на момент добавления в корзину, нужна для предупреждения об изменении цены
+ALTER TABLE cart ADD COLUMN price_at_add REAL;
+
+UPDATE cart SET price_at_add = (SELECT price FROM products WHERE products.id = cart.product_id);
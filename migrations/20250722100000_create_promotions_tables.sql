@@ -0,0 +1,50 @@
+-- Промокоды и правила скидок
+CREATE TABLE promotions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    description TEXT,
+    -- percentage | fixed | free_delivery | buy_x_get_y
+    discount_type TEXT NOT NULL,
+    discount_value REAL NOT NULL DEFAULT 0,
+    buy_quantity INTEGER,
+    get_quantity INTEGER,
+    -- order | category | product
+    scope TEXT NOT NULL DEFAULT 'order',
+    scope_id INTEGER,
+    min_order_total REAL,
+    starts_at TIMESTAMP,
+    ends_at TIMESTAMP,
+    usage_limit INTEGER,
+    usage_limit_per_user INTEGER,
+    stackable BOOLEAN NOT NULL DEFAULT FALSE,
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Промокоды, примененные к корзине
+CREATE TABLE cart_promotions (
+    cart_id INTEGER NOT NULL,
+    promotion_id INTEGER NOT NULL,
+    applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (cart_id, promotion_id),
+    FOREIGN KEY (cart_id) REFERENCES carts(id) ON DELETE CASCADE,
+    FOREIGN KEY (promotion_id) REFERENCES promotions(id) ON DELETE CASCADE
+);
+
+-- Скидки, вошедшие в заказ (по ним же считается использование промокодов)
+CREATE TABLE order_discounts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id INTEGER NOT NULL,
+    promotion_id INTEGER NOT NULL,
+    code TEXT NOT NULL,
+    description TEXT,
+    amount REAL NOT NULL,
+    free_delivery BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(id) ON DELETE CASCADE,
+    FOREIGN KEY (promotion_id) REFERENCES promotions(id)
+);
+
+CREATE INDEX idx_order_discounts_promotion ON order_discounts(promotion_id);
+
+ALTER TABLE orders ADD COLUMN discount_amount REAL NOT NULL DEFAULT 0;
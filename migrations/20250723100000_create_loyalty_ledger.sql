@@ -0,0 +1,21 @@
+-- Журнал бонусных баллов пользователей Telegram.
+-- Начисления хранят остаток (remaining), который списывается по FIFO и сгорает в expires_at.
+CREATE TABLE loyalty_ledger (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id BIGINT NOT NULL,
+    order_id INTEGER,
+    -- accrual | redemption | expiration | reversal | refund
+    kind TEXT NOT NULL,
+    points INTEGER NOT NULL,
+    remaining INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_loyalty_ledger_user ON loyalty_ledger(user_id);
+
+-- Баллы за заказ начисляются не более одного раза
+CREATE UNIQUE INDEX idx_loyalty_ledger_accrual_order ON loyalty_ledger(order_id) WHERE kind = 'accrual';
+
+ALTER TABLE orders ADD COLUMN points_redeemed INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN points_discount REAL NOT NULL DEFAULT 0;
@@ -0,0 +1,29 @@
+-- Наборы (бандлы): товар, собранный из других товаров
+ALTER TABLE products ADD COLUMN is_bundle BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE bundle_components (
+    bundle_id INTEGER NOT NULL,
+    component_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (bundle_id, component_id),
+    FOREIGN KEY (bundle_id) REFERENCES products(id) ON DELETE CASCADE,
+    FOREIGN KEY (component_id) REFERENCES products(id)
+);
+
+-- Состав набора в заказе хранится строками со ссылкой на строку набора
+ALTER TABLE order_items ADD COLUMN parent_item_id INTEGER REFERENCES order_items(id) ON DELETE CASCADE;
+
+-- Доступный остаток: для набора считается по остаткам компонентов
+CREATE VIEW product_stock AS
+SELECT
+    p.id AS product_id,
+    CASE
+        WHEN p.is_bundle THEN COALESCE((
+            SELECT MAX(0, MIN(c.stock / bc.quantity))
+            FROM bundle_components bc
+            JOIN products c ON c.id = bc.component_id
+            WHERE bc.bundle_id = p.id
+        ), 0)
+        ELSE p.stock
+    END AS available_stock
+FROM products p;
@@ -0,0 +1,28 @@
+-- Регулярные заказы расходников по подписке
+CREATE TABLE subscriptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id BIGINT NOT NULL,
+    interval_days INTEGER NOT NULL CHECK (interval_days > 0),
+    next_run_at TIMESTAMP NOT NULL,
+    -- active | paused | cancelled
+    status TEXT NOT NULL DEFAULT 'active',
+    delivery_address TEXT NOT NULL,
+    telegram_username TEXT,
+    last_order_id INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_subscriptions_due ON subscriptions(status, next_run_at);
+CREATE INDEX idx_subscriptions_user ON subscriptions(user_id);
+
+CREATE TABLE subscription_items (
+    subscription_id INTEGER NOT NULL,
+    product_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (subscription_id, product_id),
+    FOREIGN KEY (subscription_id) REFERENCES subscriptions(id) ON DELETE CASCADE,
+    FOREIGN KEY (product_id) REFERENCES products(id) ON DELETE CASCADE
+);
+
+ALTER TABLE orders ADD COLUMN subscription_id INTEGER REFERENCES subscriptions(id);
@@ -0,0 +1,54 @@
+-- Способы доставки, зоны и тарифы
+ALTER TABLE products ADD COLUMN weight_grams INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE delivery_zones (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE delivery_methods (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    -- courier | pickup | post
+    kind TEXT NOT NULL,
+    -- zone: стоимость из delivery_rates; weight: base_cost + cost_per_kg за каждый начатый килограмм
+    rate_type TEXT NOT NULL DEFAULT 'zone',
+    base_cost REAL NOT NULL DEFAULT 0,
+    cost_per_kg REAL NOT NULL DEFAULT 0,
+    -- Сумма заказа, начиная с которой доставка бесплатна
+    free_threshold REAL,
+    active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE delivery_rates (
+    method_id INTEGER NOT NULL,
+    zone_id INTEGER NOT NULL,
+    cost REAL NOT NULL CHECK (cost >= 0),
+    PRIMARY KEY (method_id, zone_id),
+    FOREIGN KEY (method_id) REFERENCES delivery_methods(id) ON DELETE CASCADE,
+    FOREIGN KEY (zone_id) REFERENCES delivery_zones(id) ON DELETE CASCADE
+);
+
+ALTER TABLE orders ADD COLUMN delivery_method_id INTEGER REFERENCES delivery_methods(id);
+ALTER TABLE orders ADD COLUMN delivery_zone_id INTEGER REFERENCES delivery_zones(id);
+ALTER TABLE orders ADD COLUMN delivery_cost REAL NOT NULL DEFAULT 0;
+
+INSERT INTO delivery_zones (name) VALUES ('Москва'), ('Санкт-Петербург'), ('Россия');
+
+INSERT INTO delivery_methods (code, name, kind, rate_type, base_cost, cost_per_kg, free_threshold) VALUES
+    ('courier', 'Курьер', 'courier', 'zone', 0, 0, 50),
+    ('pickup', 'Пункт выдачи', 'pickup', 'zone', 0, 0, 30),
+    ('post', 'Почта', 'post', 'weight', 1.5, 0.5, NULL);
+
+INSERT INTO delivery_rates (method_id, zone_id, cost)
+SELECT m.id, z.id, CASE m.code || '/' || z.name
+    WHEN 'courier/Москва' THEN 3
+    WHEN 'courier/Санкт-Петербург' THEN 3.5
+    WHEN 'pickup/Москва' THEN 1
+    WHEN 'pickup/Санкт-Петербург' THEN 1
+    ELSE 2
+END
+FROM delivery_methods m, delivery_zones z
+WHERE m.rate_type = 'zone' AND NOT (m.code = 'courier' AND z.name = 'Россия');
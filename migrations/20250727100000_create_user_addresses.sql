@@ -0,0 +1,33 @@
+-- Адресная книга пользователей Telegram
+CREATE TABLE user_addresses (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id BIGINT NOT NULL,
+    label TEXT,
+    recipient_name TEXT NOT NULL,
+    phone TEXT NOT NULL,
+    city TEXT NOT NULL,
+    street TEXT NOT NULL,
+    house TEXT NOT NULL,
+    apartment TEXT,
+    postal_code TEXT,
+    latitude REAL,
+    longitude REAL,
+    is_default BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_user_addresses_user ON user_addresses(user_id);
+CREATE UNIQUE INDEX idx_user_addresses_default ON user_addresses(user_id) WHERE is_default;
+
+-- Контакт и геопозиция, отправленные через бота, для заполнения адреса в WebApp
+CREATE TABLE user_address_drafts (
+    user_id BIGINT PRIMARY KEY,
+    recipient_name TEXT,
+    phone TEXT,
+    latitude REAL,
+    longitude REAL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Структурированный адрес на момент заказа (JSON)
+ALTER TABLE orders ADD COLUMN delivery_address_details TEXT;
@@ -0,0 +1,16 @@
+-- Отправления заказов: служба доставки, трек-номер и последний известный статус
+CREATE TABLE shipments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id INTEGER NOT NULL UNIQUE,
+    carrier TEXT NOT NULL,
+    tracking_number TEXT NOT NULL,
+    -- created | in_transit | arrived | delivered | returned
+    status TEXT NOT NULL DEFAULT 'created',
+    status_description TEXT,
+    last_checked_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_shipments_status ON shipments(status);
@@ -0,0 +1,18 @@
+-- Самовывоз со стойки зала: одноразовый код выдачи заказа
+INSERT INTO delivery_methods (code, name, kind, rate_type, base_cost, cost_per_kg, free_threshold)
+VALUES ('gym_pickup', 'Самовывоз из зала', 'self_pickup', 'zone', 0, 0, NULL);
+
+INSERT INTO delivery_rates (method_id, zone_id, cost)
+SELECT m.id, z.id, 0
+FROM delivery_methods m, delivery_zones z
+WHERE m.code = 'gym_pickup' AND z.name = 'Москва';
+
+CREATE TABLE order_pickups (
+    order_id INTEGER PRIMARY KEY,
+    -- SHA-256 от кода выдачи, сам код отправляется только покупателю
+    code_hash TEXT NOT NULL UNIQUE,
+    ready_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    handed_over_at TIMESTAMP,
+    handed_over_by BIGINT,
+    FOREIGN KEY (order_id) REFERENCES orders(id) ON DELETE CASCADE
+);
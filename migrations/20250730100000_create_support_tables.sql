@@ -0,0 +1,47 @@
+-- Обращения в поддержку: переписка по заказу или общий вопрос
+CREATE TABLE support_threads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id BIGINT NOT NULL,
+    order_id INTEGER,
+    -- open | closed
+    status TEXT NOT NULL DEFAULT 'open',
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    closed_at TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_support_threads_user ON support_threads(user_id, status);
+-- Не больше одного открытого обращения на заказ (и одного общего на пользователя)
+CREATE UNIQUE INDEX idx_support_threads_open
+    ON support_threads(user_id, COALESCE(order_id, 0)) WHERE status = 'open';
+
+-- Все сообщения обращения в обе стороны
+CREATE TABLE support_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    thread_id INTEGER NOT NULL,
+    -- user | admin | system
+    direction TEXT NOT NULL,
+    sender_id BIGINT,
+    text TEXT,
+    -- ID сообщения в чате покупателя и в чате администраторов
+    user_message_id INTEGER,
+    admin_message_id INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (thread_id) REFERENCES support_threads(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_support_messages_thread ON support_messages(thread_id);
+CREATE INDEX idx_support_messages_admin ON support_messages(admin_message_id);
+CREATE INDEX idx_support_messages_user ON support_messages(user_message_id);
+
+-- Обращение, выбранное пользователем командой /support
+CREATE TABLE support_user_state (
+    user_id BIGINT PRIMARY KEY,
+    thread_id INTEGER NOT NULL,
+    FOREIGN KEY (thread_id) REFERENCES support_threads(id) ON DELETE CASCADE
+);
+
+-- Переносим активные диалоги по заказам
+INSERT INTO support_threads (user_id, order_id)
+SELECT user_id, id FROM orders WHERE dialog_active = TRUE;
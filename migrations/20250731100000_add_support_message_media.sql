@@ -0,0 +1,5 @@
+-- Вложения в переписке поддержки: фото, документы, голосовые и стикеры
+-- photo | document | voice | sticker
+ALTER TABLE support_messages ADD COLUMN media_type TEXT;
+-- file_id Telegram, по нему вложение можно отправить повторно
+ALTER TABLE support_messages ADD COLUMN file_id TEXT;
@@ -0,0 +1,4 @@
+-- Тема форума в чате администраторов, где ведется обращение
+ALTER TABLE support_threads ADD COLUMN admin_topic_id INTEGER;
+
+CREATE INDEX idx_support_threads_topic ON support_threads(admin_topic_id);
@@ -0,0 +1,21 @@
+-- Внутренние заметки администраторов к заказам (покупателю не показываются)
+CREATE TABLE order_notes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id INTEGER NOT NULL,
+    author_id BIGINT NOT NULL,
+    text TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_order_notes_order ON order_notes(order_id);
+
+-- Ожидаемый ввод администратора после нажатия кнопки в карточке заказа
+CREATE TABLE admin_pending_inputs (
+    admin_id BIGINT PRIMARY KEY,
+    -- note | msg
+    action TEXT NOT NULL,
+    order_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(id) ON DELETE CASCADE
+);
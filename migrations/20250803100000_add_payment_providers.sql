@@ -0,0 +1,19 @@
+-- Миграция создания payments выполняется целиком вместе с секцией Down и удаляет таблицу:
+-- восстанавливаем ее, если таблицы нет
+CREATE TABLE IF NOT EXISTS payments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id TEXT NOT NULL UNIQUE,
+    user_id BIGINT NOT NULL,
+    amount REAL NOT NULL,
+    wallet_address TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    ton_payment_id TEXT,
+    transaction_hash TEXT
+);
+
+-- Способ оплаты: перевод TON или счет Telegram Payments
+ALTER TABLE payments ADD COLUMN provider TEXT NOT NULL DEFAULT 'ton';
+ALTER TABLE payments ADD COLUMN currency TEXT NOT NULL DEFAULT 'TON';
+-- Идентификатор платежа у провайдера (charge id Telegram Payments)
+ALTER TABLE payments ADD COLUMN provider_payment_id TEXT;
@@ -0,0 +1,16 @@
+-- Одноразовые payload для ton_proof: выдаются WebApp перед подключением кошелька
+CREATE TABLE IF NOT EXISTS ton_proof_payloads (
+    payload TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+-- Кошелек TON, подключенный пользователем через TON Connect (подтвержден ton_proof)
+CREATE TABLE IF NOT EXISTS user_wallets (
+    user_id INTEGER PRIMARY KEY,
+    address TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    linked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_wallets_address ON user_wallets(address);
@@ -0,0 +1,18 @@
+-- Входящие переводы на кошелек магазина для сверки с payments/orders
+CREATE TABLE IF NOT EXISTS onchain_transfers (
+    transaction_hash TEXT PRIMARY KEY,
+    source TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    amount REAL NOT NULL,
+    comment TEXT,
+    order_id INTEGER REFERENCES orders(id) ON DELETE SET NULL,
+    -- NULL - перевод сопоставлен с оплатой заказа
+    discrepancy TEXT,
+    -- Привязан администратором вручную: сверка его больше не пересчитывает
+    linked_manually BOOLEAN NOT NULL DEFAULT FALSE,
+    received_at TIMESTAMP NOT NULL,
+    checked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_onchain_transfers_discrepancy ON onchain_transfers(discrepancy);
+CREATE INDEX IF NOT EXISTS idx_onchain_transfers_order ON onchain_transfers(order_id);
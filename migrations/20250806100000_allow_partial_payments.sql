@@ -0,0 +1,54 @@
+-- Частичная оплата: по заказу может быть несколько платежей, пока их сумма не покроет заказ.
+-- SQLite не умеет снимать UNIQUE с колонки, поэтому таблица пересоздается
+CREATE TABLE payments_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    amount REAL NOT NULL,
+    wallet_address TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    ton_payment_id TEXT,
+    transaction_hash TEXT,
+    provider TEXT NOT NULL DEFAULT 'ton',
+    currency TEXT NOT NULL DEFAULT 'TON',
+    provider_payment_id TEXT,
+    -- Сколько TON платеж закрывает в сумме заказа (amount - в валюте платежа)
+    credited_amount REAL NOT NULL DEFAULT 0
+);
+
+-- До частичных оплат подтвержденный платеж всегда закрывал заказ целиком
+INSERT INTO payments_new
+    (id, order_id, user_id, amount, wallet_address, status, created_at, ton_payment_id, transaction_hash,
+     provider, currency, provider_payment_id, credited_amount)
+SELECT
+    p.id, p.order_id, p.user_id, p.amount, p.wallet_address, p.status, p.created_at, p.ton_payment_id, p.transaction_hash,
+    p.provider, p.currency, p.provider_payment_id,
+    CASE
+        WHEN p.status = 'confirmed' THEN COALESCE((SELECT o.total_amount FROM orders o WHERE CAST(o.id AS TEXT) = p.order_id), p.amount)
+        WHEN p.currency = 'TON' THEN p.amount
+        ELSE 0
+    END
+FROM payments p;
+
+DROP TABLE payments;
+ALTER TABLE payments_new RENAME TO payments;
+
+CREATE INDEX idx_payments_order ON payments(order_id);
+-- Повторное уведомление о той же транзакции не записывает платеж второй раз
+CREATE UNIQUE INDEX idx_payments_transaction ON payments(transaction_hash) WHERE transaction_hash IS NOT NULL;
+
+-- Переплата по заказу: зачисляется баллами лояльности (kind = 'credit' в loyalty_ledger)
+-- или ждет ручного возврата
+CREATE TABLE order_overpayments (
+    order_id INTEGER PRIMARY KEY REFERENCES orders(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL,
+    -- Сумма переплаты в TON
+    amount REAL NOT NULL,
+    -- credited | refund_pending | refunded
+    status TEXT NOT NULL,
+    points INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_order_overpayments_status ON order_overpayments(status);
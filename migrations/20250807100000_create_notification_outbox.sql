@@ -0,0 +1,19 @@
+-- Исходящие уведомления Telegram. Запись создается в той же транзакции, что и изменение заказа,
+-- и доставляется фоновым обработчиком с повторами
+CREATE TABLE notification_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- order_confirmation | admin_new_order | order_status
+    kind TEXT NOT NULL,
+    order_id INTEGER,
+    -- Уведомление целиком в JSON
+    payload TEXT NOT NULL,
+    -- pending | sent | dead
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    sent_at TIMESTAMP
+);
+
+CREATE INDEX idx_notification_outbox_due ON notification_outbox(status, next_attempt_at);
@@ -0,0 +1,6 @@
+-- Язык пользователя из Telegram (language_code) для выбора шаблонов уведомлений
+CREATE TABLE user_languages (
+    user_id BIGINT PRIMARY KEY,
+    language_code TEXT NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,8 @@
+-- Неверные коды выдачи: частота проверок ограничивается, чтобы код нельзя было подобрать
+CREATE TABLE pickup_code_attempts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    staff_id BIGINT NOT NULL,
+    attempted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_pickup_code_attempts_staff ON pickup_code_attempts(staff_id, attempted_at);
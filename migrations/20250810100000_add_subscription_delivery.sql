@@ -0,0 +1,3 @@
+-- Способ и зона доставки подписки: стоимость доставки считается по тарифу на момент каждого заказа
+ALTER TABLE subscriptions ADD COLUMN delivery_method_id INTEGER REFERENCES delivery_methods(id);
+ALTER TABLE subscriptions ADD COLUMN delivery_zone_id INTEGER REFERENCES delivery_zones(id);
@@ -0,0 +1,4 @@
+-- Название и описание в нижнем регистре для поиска: LIKE в SQLite не различает регистр только для ASCII,
+-- поэтому значения приводятся к нижнему регистру в приложении
+ALTER TABLE products ADD COLUMN name_search TEXT;
+ALTER TABLE products ADD COLUMN description_search TEXT;
@@ -0,0 +1,19 @@
+-- Баланс покупателя в TON: переплаты по заказам. В отличие от бонусных баллов не сгорает
+-- и списывается при оформлении заказа без ограничения на долю заказа
+CREATE TABLE store_credit_ledger (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id BIGINT NOT NULL,
+    order_id INTEGER,
+    -- overpayment | spent | restored | reversed
+    kind TEXT NOT NULL,
+    -- Сумма в TON: зачисления положительные, списания отрицательные
+    amount REAL NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_store_credit_ledger_user ON store_credit_ledger(user_id);
+
+-- Каждая операция по заказу выполняется не более одного раза
+CREATE UNIQUE INDEX idx_store_credit_ledger_order ON store_credit_ledger(order_id, kind) WHERE order_id IS NOT NULL;
+
+ALTER TABLE orders ADD COLUMN store_credit_applied REAL NOT NULL DEFAULT 0;
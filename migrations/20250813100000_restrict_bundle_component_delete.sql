@@ -0,0 +1,34 @@
+-- Товар, входящий в состав набора, удалить нельзя: удаление упирается во внешний ключ,
+-- а не в проверку перед удалением. SQLite не меняет внешние ключи, поэтому таблица пересоздается
+DROP VIEW product_stock;
+
+CREATE TABLE bundle_components_new (
+    bundle_id INTEGER NOT NULL,
+    component_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (bundle_id, component_id),
+    FOREIGN KEY (bundle_id) REFERENCES products(id) ON DELETE CASCADE,
+    FOREIGN KEY (component_id) REFERENCES products(id) ON DELETE RESTRICT
+);
+
+INSERT INTO bundle_components_new (bundle_id, component_id, quantity)
+SELECT bundle_id, component_id, quantity FROM bundle_components;
+
+DROP TABLE bundle_components;
+
+ALTER TABLE bundle_components_new RENAME TO bundle_components;
+
+-- Доступный остаток: для набора считается по остаткам компонентов
+CREATE VIEW product_stock AS
+SELECT
+    p.id AS product_id,
+    CASE
+        WHEN p.is_bundle THEN COALESCE((
+            SELECT MAX(0, MIN(c.stock / bc.quantity))
+            FROM bundle_components bc
+            JOIN products c ON c.id = bc.component_id
+            WHERE bc.bundle_id = p.id
+        ), 0)
+        ELSE p.stock
+    END AS available_stock
+FROM products p;
@@ -0,0 +1,18 @@
+-- Позиция удаленного товара остается в корзине с пустым product_id: проверка корзины
+-- показывает покупателю предупреждение product_deleted и убирает позицию
+CREATE TABLE cart_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    cart_id INTEGER NOT NULL,
+    product_id INTEGER,
+    quantity INTEGER NOT NULL,
+    price_at_add REAL,
+    FOREIGN KEY (cart_id) REFERENCES carts(id) ON DELETE CASCADE,
+    FOREIGN KEY (product_id) REFERENCES products(id) ON DELETE SET NULL,
+    UNIQUE (cart_id, product_id)
+);
+
+INSERT INTO cart_new (id, cart_id, product_id, quantity, price_at_add)
+SELECT id, cart_id, product_id, quantity, price_at_add FROM cart;
+
+DROP TABLE cart;
+ALTER TABLE cart_new RENAME TO cart;
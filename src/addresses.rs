@@ -0,0 +1,349 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AddressError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Адрес не найден")]
+    NotFound,
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl AddressError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, AddressError::DbError(_))
+    }
+}
+
+const MAX_FIELD_LENGTH: usize = 200;
+
+// Структурированный адрес доставки
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DeliveryAddress {
+    pub recipient_name: String,
+    pub phone: String,
+    pub city: String,
+    pub street: String,
+    pub house: String,
+    #[serde(default)]
+    pub apartment: Option<String>,
+    #[serde(default)]
+    pub postal_code: Option<String>,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+}
+
+fn clean_required(value: &str, field: &str) -> Result<String, AddressError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(AddressError::Invalid(format!("Поле \"{}\" обязательно", field)));
+    }
+    clean_length(value, field)
+}
+
+fn clean_optional(value: &Option<String>, field: &str) -> Result<Option<String>, AddressError> {
+    match value.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => clean_length(value, field).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn clean_length(value: &str, field: &str) -> Result<String, AddressError> {
+    if value.chars().count() > MAX_FIELD_LENGTH {
+        return Err(AddressError::Invalid(format!("Поле \"{}\" слишком длинное", field)));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(AddressError::Invalid(format!("Поле \"{}\" содержит недопустимые символы", field)));
+    }
+    Ok(value.to_string())
+}
+
+// Телефон приводится к виду +<цифры>
+pub fn normalize_phone(phone: &str) -> Result<String, AddressError> {
+    let digits: String = phone.chars().filter(char::is_ascii_digit).collect();
+    let allowed = phone.chars().all(|c| c.is_ascii_digit() || " +-()".contains(c));
+    if !allowed || !(10..=15).contains(&digits.len()) {
+        return Err(AddressError::Invalid("Некорректный номер телефона".to_string()));
+    }
+
+    // Российский номер, записанный через 8
+    if digits.len() == 11 && digits.starts_with('8') && !phone.trim_start().starts_with('+') {
+        return Ok(format!("+7{}", &digits[1..]));
+    }
+    Ok(format!("+{}", digits))
+}
+
+impl DeliveryAddress {
+    // Проверяет поля и возвращает нормализованную копию адреса
+    pub fn validate(&self) -> Result<DeliveryAddress, AddressError> {
+        let postal_code = clean_optional(&self.postal_code, "Индекс")?;
+        if let Some(code) = &postal_code {
+            if code.len() != 6 || !code.chars().all(|c| c.is_ascii_digit()) {
+                return Err(AddressError::Invalid("Индекс должен состоять из 6 цифр".to_string()));
+            }
+        }
+
+        let coordinates_valid = match (self.latitude, self.longitude) {
+            (Some(lat), Some(lon)) => (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon),
+            (None, None) => true,
+            _ => false,
+        };
+        if !coordinates_valid {
+            return Err(AddressError::Invalid("Некорректные координаты".to_string()));
+        }
+
+        Ok(DeliveryAddress {
+            recipient_name: clean_required(&self.recipient_name, "Получатель")?,
+            phone: normalize_phone(&self.phone)?,
+            city: clean_required(&self.city, "Город")?,
+            street: clean_required(&self.street, "Улица")?,
+            house: clean_required(&self.house, "Дом")?,
+            apartment: clean_optional(&self.apartment, "Квартира")?,
+            postal_code,
+            latitude: self.latitude,
+            longitude: self.longitude,
+        })
+    }
+
+    // Адрес одной строкой для заказа и сообщений
+    pub fn format_line(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(code) = &self.postal_code {
+            parts.push(code.clone());
+        }
+        parts.push(self.city.clone());
+        parts.push(self.street.clone());
+        parts.push(format!("д. {}", self.house));
+        if let Some(apartment) = &self.apartment {
+            parts.push(format!("кв. {}", apartment));
+        }
+        format!("{}, {} — {}", self.recipient_name, self.phone, parts.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct SavedAddress {
+    pub id: i64,
+    pub label: Option<String>,
+    pub is_default: bool,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub address: DeliveryAddress,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveAddress {
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub is_default: bool,
+    #[serde(flatten)]
+    pub address: DeliveryAddress,
+}
+
+// Контакт и геопозиция, присланные боту
+#[derive(Debug, Default, Serialize, FromRow)]
+pub struct AddressDraft {
+    pub recipient_name: Option<String>,
+    pub phone: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+const ADDRESS_COLUMNS: &str =
+    "id, label, is_default, recipient_name, phone, city, street, house, apartment, postal_code, latitude, longitude";
+
+pub async fn list(pool: &SqlitePool, user_id: i64) -> Result<Vec<SavedAddress>, sqlx::Error> {
+    sqlx::query_as::<_, SavedAddress>(&format!(
+        "SELECT {} FROM user_addresses WHERE user_id = ? ORDER BY is_default DESC, id DESC",
+        ADDRESS_COLUMNS
+    ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get(pool: &SqlitePool, user_id: i64, address_id: i64) -> Result<SavedAddress, AddressError> {
+    sqlx::query_as::<_, SavedAddress>(&format!(
+        "SELECT {} FROM user_addresses WHERE id = ? AND user_id = ?",
+        ADDRESS_COLUMNS
+    ))
+        .bind(address_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AddressError::NotFound)
+}
+
+// Создает адрес или обновляет существующий (address_id), первый адрес становится основным
+pub async fn save(
+    pool: &SqlitePool,
+    user_id: i64,
+    address_id: Option<i64>,
+    input: &SaveAddress,
+) -> Result<SavedAddress, AddressError> {
+    let address = input.address.validate()?;
+    let label = clean_optional(&input.label, "Название")?;
+
+    let mut tx = pool.begin().await?;
+
+    let has_default: bool = sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM user_addresses WHERE user_id = ? AND is_default AND id != COALESCE(?, 0))"
+    )
+        .bind(user_id)
+        .bind(address_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let is_default = input.is_default || !has_default;
+
+    if is_default {
+        sqlx::query("UPDATE user_addresses SET is_default = FALSE WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let id = match address_id {
+        Some(id) => {
+            let result = sqlx::query(
+                r#"
+                UPDATE user_addresses
+                SET label = ?, is_default = ?, recipient_name = ?, phone = ?, city = ?, street = ?, house = ?,
+                    apartment = ?, postal_code = ?, latitude = ?, longitude = ?
+                WHERE id = ? AND user_id = ?
+                "#
+            )
+                .bind(&label)
+                .bind(is_default)
+                .bind(&address.recipient_name)
+                .bind(&address.phone)
+                .bind(&address.city)
+                .bind(&address.street)
+                .bind(&address.house)
+                .bind(&address.apartment)
+                .bind(&address.postal_code)
+                .bind(address.latitude)
+                .bind(address.longitude)
+                .bind(id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(AddressError::NotFound);
+            }
+            id
+        }
+        None => {
+            sqlx::query_scalar::<_, i64>(
+                r#"
+                INSERT INTO user_addresses (
+                    user_id, label, is_default, recipient_name, phone, city, street, house,
+                    apartment, postal_code, latitude, longitude
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                RETURNING id
+                "#
+            )
+                .bind(user_id)
+                .bind(&label)
+                .bind(is_default)
+                .bind(&address.recipient_name)
+                .bind(&address.phone)
+                .bind(&address.city)
+                .bind(&address.street)
+                .bind(&address.house)
+                .bind(&address.apartment)
+                .bind(&address.postal_code)
+                .bind(address.latitude)
+                .bind(address.longitude)
+                .fetch_one(&mut *tx)
+                .await?
+        }
+    };
+
+    tx.commit().await?;
+
+    Ok(SavedAddress {
+        id,
+        label,
+        is_default,
+        address,
+    })
+}
+
+pub async fn delete(pool: &SqlitePool, user_id: i64, address_id: i64) -> Result<(), AddressError> {
+    let result = sqlx::query("DELETE FROM user_addresses WHERE id = ? AND user_id = ?")
+        .bind(address_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AddressError::NotFound);
+    }
+    Ok(())
+}
+
+pub async fn draft(pool: &SqlitePool, user_id: i64) -> Result<AddressDraft, sqlx::Error> {
+    sqlx::query_as::<_, AddressDraft>(
+        "SELECT recipient_name, phone, latitude, longitude FROM user_address_drafts WHERE user_id = ?"
+    )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map(Option::unwrap_or_default)
+}
+
+pub async fn save_draft_contact(
+    pool: &SqlitePool,
+    user_id: i64,
+    recipient_name: &str,
+    phone: &str,
+) -> Result<(), AddressError> {
+    let phone = normalize_phone(phone)?;
+    let recipient_name = clean_required(recipient_name, "Получатель")?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO user_address_drafts (user_id, recipient_name, phone) VALUES (?, ?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET
+            recipient_name = excluded.recipient_name,
+            phone = excluded.phone,
+            updated_at = CURRENT_TIMESTAMP
+        "#
+    )
+        .bind(user_id)
+        .bind(recipient_name)
+        .bind(phone)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn save_draft_location(
+    pool: &SqlitePool,
+    user_id: i64,
+    latitude: f64,
+    longitude: f64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO user_address_drafts (user_id, latitude, longitude) VALUES (?, ?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET
+            latitude = excluded.latitude,
+            longitude = excluded.longitude,
+            updated_at = CURRENT_TIMESTAMP
+        "#
+    )
+        .bind(user_id)
+        .bind(latitude)
+        .bind(longitude)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
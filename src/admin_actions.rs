@@ -0,0 +1,448 @@
+use chrono::NaiveDateTime;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashSet;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use thiserror::Error;
+use crate::bundles;
+use crate::loyalty::{LoyaltyError, LoyaltyProgram};
+use crate::outbox::{self, OutboxMessage};
+use crate::store_credit;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Данные кнопок карточки заказа: adm:{действие}:{ID заказа}:{подпись}
+const CALLBACK_PREFIX: &str = "adm:";
+
+// Длина подписи в байтах (callback_data ограничена 64 байтами)
+const SIGNATURE_LEN: usize = 8;
+
+// Статусы оплаченного заказа, из которых оформляется возврат
+const REFUNDABLE_STATUSES: &[&str] = &["paid", "packed", "completed"];
+
+// Ожидание заметки или сообщения покупателю после нажатия кнопки
+const PENDING_INPUT_TTL_MINUTES: i64 = 10;
+
+#[derive(Error, Debug)]
+pub enum AdminActionError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Loyalty error: {0}")]
+    LoyaltyError(#[from] LoyaltyError),
+    #[error("Заказ не найден")]
+    OrderNotFound,
+    #[error("Действие недоступно для заказа в статусе \"{0}\"")]
+    InvalidTransition(String),
+}
+
+impl AdminActionError {
+    // Ошибки, которые можно показать администратору как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, AdminActionError::DbError(_) | AdminActionError::LoyaltyError(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAction {
+    Pack,
+    Ship,
+    Ready,
+    Complete,
+    Cancel,
+    Note,
+    Message,
+}
+
+impl AdminAction {
+    fn code(&self) -> &'static str {
+        match self {
+            AdminAction::Pack => "pack",
+            AdminAction::Ship => "ship",
+            AdminAction::Ready => "ready",
+            AdminAction::Complete => "complete",
+            AdminAction::Cancel => "cancel",
+            AdminAction::Note => "note",
+            AdminAction::Message => "msg",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "pack" => Some(AdminAction::Pack),
+            "ship" => Some(AdminAction::Ship),
+            "ready" => Some(AdminAction::Ready),
+            "complete" => Some(AdminAction::Complete),
+            "cancel" => Some(AdminAction::Cancel),
+            "note" => Some(AdminAction::Note),
+            "msg" => Some(AdminAction::Message),
+            _ => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            AdminAction::Pack => "📦 Собран",
+            AdminAction::Ship => "🚚 Отправлен",
+            AdminAction::Ready => "Готов к выдаче",
+            AdminAction::Complete => "Выполнено",
+            AdminAction::Cancel => "❌ Отменить",
+            AdminAction::Note => "📝 Заметка",
+            AdminAction::Message => "✉️ Написать покупателю",
+        }
+    }
+
+    // Статусы заказа, из которых допустимо действие, и новый статус. Действуют только для оплаченных
+    // заказов: неоплаченные отменяются сами по истечении срока оплаты
+    fn transition(&self) -> Option<(&'static [&'static str], &'static str)> {
+        match self {
+            AdminAction::Pack => Some((&["paid"], "packed")),
+            AdminAction::Ship => Some((&["paid", "packed"], "shipped")),
+            AdminAction::Complete => Some((&["paid", "packed", "shipped", "ready_for_pickup"], "completed")),
+            AdminAction::Cancel => Some((&["paid", "packed", "ready_for_pickup"], "cancelled")),
+            _ => None,
+        }
+    }
+
+    fn is_available(&self, status: &str) -> bool {
+        self.transition().is_some_and(|(from, _)| from.contains(&status))
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct OrderNote {
+    pub id: i64,
+    pub author_id: i64,
+    pub text: String,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+// Подписанные кнопки карточки заказа и список администраторов, которым они доступны
+pub struct AdminCallbacks {
+    secret_key: Vec<u8>,
+    admin_ids: HashSet<i64>,
+}
+
+impl AdminCallbacks {
+    pub fn new(bot_token: &str) -> Self {
+        // secret_key = HMAC_SHA256(key = "AdminCallbacks", message = bot_token)
+        let mut mac = HmacSha256::new_from_slice(b"AdminCallbacks")
+            .expect("HMAC accepts keys of any size");
+        mac.update(bot_token.as_bytes());
+
+        Self {
+            secret_key: mac.finalize().into_bytes().to_vec(),
+            admin_ids: HashSet::new(),
+        }
+    }
+
+    pub fn with_admin_ids(mut self, admin_ids: HashSet<i64>) -> Self {
+        self.admin_ids = admin_ids;
+        self
+    }
+
+    pub fn is_admin(&self, user_id: i64) -> bool {
+        self.admin_ids.contains(&user_id)
+    }
+
+    fn mac(&self, action: AdminAction, order_id: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret_key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(format!("{}:{}", action.code(), order_id).as_bytes());
+        mac
+    }
+
+    pub fn callback_data(&self, action: AdminAction, order_id: i64) -> String {
+        let signature = self.mac(action, order_id).finalize().into_bytes();
+        format!(
+            "{}{}:{}:{}",
+            CALLBACK_PREFIX,
+            action.code(),
+            order_id,
+            hex::encode(&signature[..SIGNATURE_LEN])
+        )
+    }
+
+    pub fn is_admin_callback(data: &str) -> bool {
+        data.starts_with(CALLBACK_PREFIX)
+    }
+
+    // Действие из данных кнопки; None, если подпись не совпадает
+    pub fn parse_callback(&self, data: &str) -> Option<(AdminAction, i64)> {
+        let mut parts = data.strip_prefix(CALLBACK_PREFIX)?.split(':');
+        let action = AdminAction::from_code(parts.next()?)?;
+        let order_id = parts.next()?.parse::<i64>().ok()?;
+        let signature = hex::decode(parts.next()?).ok()?;
+        if parts.next().is_some() || signature.len() != SIGNATURE_LEN {
+            return None;
+        }
+
+        self.mac(action, order_id)
+            .verify_truncated_left(&signature)
+            .ok()
+            .map(|_| (action, order_id))
+    }
+
+    fn button(&self, action: AdminAction, order_id: i64) -> InlineKeyboardButton {
+        InlineKeyboardButton::callback(action.label(), self.callback_data(action, order_id))
+    }
+
+    // Кнопки карточки заказа для текущего статуса
+    pub fn order_keyboard(&self, order_id: i64, status: &str, self_pickup: bool) -> InlineKeyboardMarkup {
+        let mut rows = Vec::new();
+
+        let mut progress = Vec::new();
+        match (status, self_pickup) {
+            ("paid", false) => {
+                progress.push(self.button(AdminAction::Pack, order_id));
+                progress.push(self.button(AdminAction::Ship, order_id));
+            }
+            ("packed", false) => progress.push(self.button(AdminAction::Ship, order_id)),
+            ("paid" | "packed", true) => progress.push(self.button(AdminAction::Ready, order_id)),
+            _ => {}
+        }
+        if !progress.is_empty() {
+            rows.push(progress);
+        }
+
+        let mut closing = Vec::new();
+        for action in [AdminAction::Complete, AdminAction::Cancel] {
+            if action.is_available(status) {
+                closing.push(self.button(action, order_id));
+            }
+        }
+        if !closing.is_empty() {
+            rows.push(closing);
+        }
+
+        rows.push(vec![
+            self.button(AdminAction::Note, order_id),
+            self.button(AdminAction::Message, order_id),
+        ]);
+        InlineKeyboardMarkup::new(rows)
+    }
+}
+
+// Список ID администраторов из строки "123,456"
+pub fn parse_admin_ids(value: &str) -> HashSet<i64> {
+    value
+        .split(',')
+        .filter_map(|id| id.trim().parse::<i64>().ok())
+        .collect()
+}
+
+// Текущий статус заказа и признак самовывоза
+pub async fn order_state(pool: &SqlitePool, order_id: i64) -> Result<(String, bool), AdminActionError> {
+    sqlx::query_as::<_, (String, bool)>(
+        r#"
+        SELECT o.status, COALESCE(dm.kind = 'self_pickup', FALSE)
+        FROM orders o
+        LEFT JOIN delivery_methods dm ON dm.id = o.delivery_method_id
+        WHERE o.id = ?
+        "#
+    )
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AdminActionError::OrderNotFound)
+}
+
+// Смена статуса заказа кнопкой (собран, отправлен, выполнен, отменен). Возвращает новый статус.
+// Отмена в той же транзакции возвращает товары на склад и баллы покупателю; деньги возвращает вызывающий
+// через PaymentService::refund после фиксации статуса
+pub async fn apply_transition(
+    pool: &SqlitePool,
+    loyalty: &LoyaltyProgram,
+    order_id: i64,
+    action: AdminAction,
+) -> Result<&'static str, AdminActionError> {
+    let Some((_, to)) = action.transition() else {
+        return Err(AdminActionError::InvalidTransition(action.code().to_string()));
+    };
+
+    let (status, _) = order_state(pool, order_id).await?;
+    if !action.is_available(&status) {
+        return Err(AdminActionError::InvalidTransition(status));
+    }
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query("UPDATE orders SET status = ? WHERE id = ? AND status = ?")
+        .bind(to)
+        .bind(order_id)
+        .bind(&status)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        // Статус успели изменить параллельно
+        tx.rollback().await?;
+        let (status, _) = order_state(pool, order_id).await?;
+        return Err(AdminActionError::InvalidTransition(status));
+    }
+
+    // Отмененный заказ возвращает зарезервированные остатки, потраченные баллы и баланс
+    if to == "cancelled" {
+        bundles::restock_order(&mut tx, order_id).await?;
+        loyalty.reverse_for_order(&mut tx, order_id).await?;
+        store_credit::reverse_for_order(&mut tx, order_id).await?;
+    }
+
+    // Покупателю сообщаем о сборке и отправке, об отмене - отдельным сообщением
+    if matches!(to, "packed" | "shipped") {
+        outbox::enqueue(&mut tx, &OutboxMessage::OrderStatus { order_id, status: to.to_string() }).await?;
+    }
+    tx.commit().await?;
+
+    Ok(to)
+}
+
+// Возврат заказа: смена статуса и отмена баллов в одной транзакции, повторный или параллельный
+// возврат не пройдет. Возвращает прежний статус заказа.
+pub async fn mark_refunded(
+    pool: &SqlitePool,
+    loyalty: &LoyaltyProgram,
+    order_id: i64,
+) -> Result<String, AdminActionError> {
+    let (status, _) = order_state(pool, order_id).await?;
+    if !REFUNDABLE_STATUSES.contains(&status.as_str()) {
+        return Err(AdminActionError::InvalidTransition(status));
+    }
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query("UPDATE orders SET status = 'refunded' WHERE id = ? AND status = ?")
+        .bind(order_id)
+        .bind(&status)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        tx.rollback().await?;
+        let (status, _) = order_state(pool, order_id).await?;
+        return Err(AdminActionError::InvalidTransition(status));
+    }
+
+    // Товар еще не передан покупателю - возвращаем его на склад
+    if matches!(status.as_str(), "paid" | "packed") {
+        bundles::restock_order(&mut tx, order_id).await?;
+    }
+    loyalty.reverse_for_order(&mut tx, order_id).await?;
+    store_credit::reverse_for_order(&mut tx, order_id).await?;
+    tx.commit().await?;
+
+    Ok(status)
+}
+
+pub async fn add_note(pool: &SqlitePool, order_id: i64, author_id: i64, text: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO order_notes (order_id, author_id, text) VALUES (?, ?, ?)")
+        .bind(order_id)
+        .bind(author_id)
+        .bind(text)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn notes(pool: &SqlitePool, order_id: i64) -> Result<Vec<OrderNote>, sqlx::Error> {
+    sqlx::query_as::<_, OrderNote>(
+        "SELECT id, author_id, text, created_at FROM order_notes WHERE order_id = ? ORDER BY id"
+    )
+        .bind(order_id)
+        .fetch_all(pool)
+        .await
+}
+
+// Следующее сообщение администратора станет заметкой или сообщением покупателю
+pub async fn set_pending_input(
+    pool: &SqlitePool,
+    admin_id: i64,
+    action: AdminAction,
+    order_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO admin_pending_inputs (admin_id, action, order_id) VALUES (?, ?, ?)
+        ON CONFLICT(admin_id) DO UPDATE SET
+            action = excluded.action,
+            order_id = excluded.order_id,
+            created_at = CURRENT_TIMESTAMP
+        "#
+    )
+        .bind(admin_id)
+        .bind(action.code())
+        .bind(order_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Забирает ожидаемый ввод администратора, если он еще не устарел
+pub async fn take_pending_input(pool: &SqlitePool, admin_id: i64) -> Result<Option<(AdminAction, i64)>, sqlx::Error> {
+    let pending = sqlx::query_as::<_, (String, i64, bool)>(&format!(
+        r#"
+        DELETE FROM admin_pending_inputs WHERE admin_id = ?
+        RETURNING action, order_id, created_at >= datetime('now', '-{} minutes')
+        "#,
+        PENDING_INPUT_TTL_MINUTES
+    ))
+        .bind(admin_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(pending
+        .filter(|(_, _, fresh)| *fresh)
+        .and_then(|(action, order_id, _)| AdminAction::from_code(&action).map(|action| (action, order_id))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Подписи посчитаны отдельно (Python, hmac + hashlib): первые 8 байт HMAC-SHA256 от "<действие>:<заказ>"
+    fn callbacks() -> AdminCallbacks {
+        AdminCallbacks::new("123456:TEST-token")
+    }
+
+    #[test]
+    fn signs_callback_data_with_truncated_hmac() {
+        assert_eq!(callbacks().callback_data(AdminAction::Cancel, 42), "adm:cancel:42:0af52d4e52448595");
+        assert_eq!(callbacks().callback_data(AdminAction::Pack, 7), "adm:pack:7:26a824a8968f7fda");
+    }
+
+    #[test]
+    fn parses_signed_callback() {
+        assert_eq!(
+            callbacks().parse_callback("adm:cancel:42:0af52d4e52448595"),
+            Some((AdminAction::Cancel, 42))
+        );
+    }
+
+    #[test]
+    fn rejects_signature_of_other_order_or_action() {
+        assert_eq!(callbacks().parse_callback("adm:cancel:43:0af52d4e52448595"), None);
+        assert_eq!(callbacks().parse_callback("adm:pack:42:0af52d4e52448595"), None);
+    }
+
+    #[test]
+    fn accepts_only_leading_signature_bytes() {
+        // Полная подпись и ее последние 8 байт не принимаются
+        let full = "0af52d4e52448595f2fa2bae904e262404592a24faedd903c87bb37d9c576a0a";
+        assert_eq!(callbacks().parse_callback(&format!("adm:cancel:42:{}", full)), None);
+        assert_eq!(callbacks().parse_callback(&format!("adm:cancel:42:{}", &full[48..])), None);
+        assert_eq!(callbacks().parse_callback("adm:cancel:42:0af52d4e524485"), None);
+    }
+
+    #[test]
+    fn rejects_signature_from_other_bot() {
+        let other = AdminCallbacks::new("654321:OTHER-token");
+        assert_eq!(other.parse_callback("adm:cancel:42:0af52d4e52448595"), None);
+    }
+
+    #[test]
+    fn admin_actions_apply_only_to_paid_orders() {
+        assert!(!AdminAction::Cancel.is_available("pending"));
+        assert!(!AdminAction::Complete.is_available("pending"));
+        assert!(AdminAction::Cancel.is_available("paid"));
+        assert!(!AdminAction::Cancel.is_available("shipped"));
+        assert!(AdminAction::Complete.is_available("shipped"));
+        assert!(!AdminAction::Pack.is_available("packed"));
+    }
+}
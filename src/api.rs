@@ -1,11 +1,31 @@
 use std::collections::HashMap;
-use actix_web::{get, post, web, HttpResponse, Responder, delete, put};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, delete, put};
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, SqlitePool, Row};
 use actix_session::Session;
 use serde_json::json;
-use uuid::Uuid;
 use crate::AppState;
+use crate::cart;
+use crate::catalog;
+use crate::promotions;
+use crate::bundles;
+use crate::admin_actions;
+use crate::addresses::{self, DeliveryAddress, SaveAddress};
+use crate::checkout;
+use crate::notification_templates;
+use crate::payments::{Settlement, PAYMENT_TOLERANCE};
+use crate::pickup;
+use crate::store_credit;
+use crate::reconciliation::{self, ReconciliationError};
+use crate::outbox::OutboxError;
+use crate::ton_connect::{TonConnectError, TonProofRequest};
+use crate::ton_payment::{CallbackOutcome, PaymentCallback, TonPaymentError, CALLBACK_SIGNATURE_HEADER};
+use crate::shipping::{self, AttachShipment};
+use crate::telegram_bot::{AdminComment, TelegramBot};
+use teloxide::types::MessageId;
+use crate::delivery;
+use crate::subscriptions::{CreateSubscription, SubscriptionAction};
+use crate::loyalty::PointsRedemption;
 use crate::models::Product; // Переиспользуем Product из models.rs, чтобы не было рассинхрона структур
 
 #[derive(Debug, Deserialize)]
@@ -18,6 +38,8 @@ pub struct CreateProduct {
     pub image_url: Option<String>,
     #[serde(default)]
     pub category_id: Option<i64>,
+    #[serde(default)]
+    pub weight_grams: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -50,14 +72,6 @@ pub struct UpdateCategory {
 }
 
 // Cart models
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct CartItem {
-    pub id: i64,
-    pub product_id: i64,
-    pub quantity: i32,
-    pub session_id: String,
-}
-
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct CartItemWithProduct {
     pub id: i64,
@@ -88,9 +102,10 @@ pub async fn list_products(
             name,
             description,
             price,
-            stock,
+            (SELECT available_stock FROM product_stock WHERE product_id = products.id) as stock,
             image_url,
             category_id,
+            is_bundle,
             strftime('%Y-%m-%d %H:%M:%S', created_at) as created_at
         FROM products
         "#
@@ -145,7 +160,8 @@ pub async fn get_product_handler(
 
     match sqlx::query_as::<_, Product>(
         r#"
-        SELECT id, name, price, stock, description, image_url, category_id, created_at
+        SELECT id, name, price, description, image_url, category_id, is_bundle, created_at,
+            (SELECT available_stock FROM product_stock WHERE product_id = products.id) as stock
         FROM products
         WHERE id = ?
         "#
@@ -205,8 +221,10 @@ pub async fn create_product(
 
     match sqlx::query(
         r#"
-        INSERT INTO products (name, description, price, stock, image_url, category_id)
-        VALUES (?, ?, ?, ?, ?, ?)
+        INSERT INTO products (
+            name, description, price, stock, image_url, category_id, weight_grams, name_search, description_search
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#
     )
         .bind(&product.name)
@@ -215,6 +233,9 @@ pub async fn create_product(
         .bind(product.stock)
         .bind(&product.image_url)
         .bind(product.category_id)
+        .bind(product.weight_grams.unwrap_or(0))
+        .bind(catalog::search_key(&product.name))
+        .bind(catalog::search_key(&product.description))
         .execute(pool)
         .await
     {
@@ -281,7 +302,8 @@ pub async fn update_product(
     match sqlx::query(
         r#"
         UPDATE products
-        SET name = ?, description = ?, price = ?, stock = ?, image_url = ?, category_id = ?
+        SET name = ?, description = ?, price = ?, stock = ?, image_url = ?, category_id = ?,
+            weight_grams = COALESCE(?, weight_grams), name_search = ?, description_search = ?
         WHERE id = ?
         "#
     )
@@ -291,6 +313,9 @@ pub async fn update_product(
         .bind(product.stock)
         .bind(&product.image_url)
         .bind(product.category_id)
+        .bind(product.weight_grams)
+        .bind(catalog::search_key(&product.name))
+        .bind(catalog::search_key(&product.description))
         .bind(id)
         .execute(pool)
         .await
@@ -303,37 +328,33 @@ pub async fn update_product(
     }
 }
 
+// Нарушение внешнего ключа. ON DELETE RESTRICT SQLite сообщает кодом SQLITE_CONSTRAINT_TRIGGER (1811),
+// который sqlx не относит к нарушениям внешнего ключа
+fn is_foreign_key_error(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => e.is_foreign_key_violation() || e.code().as_deref() == Some("1811"),
+        _ => false,
+    }
+}
+
 #[delete("/products/{id}")]
 #[doc = "// Удаление продукта"]
 pub async fn delete_product(
     state: web::Data<AppState>,
     product_id: web::Path<i64>,
 ) -> impl Responder {
-    let pool = &state.db_pool;
-    let id = product_id.into_inner();
-
-    // Сначала удаляем связанные записи из корзины
-    match sqlx::query("DELETE FROM cart WHERE product_id = ?")
-        .bind(id)
-        .execute(pool)
-        .await
-    {
-        Ok(_) => println!("DEBUG: Removed cart items for product {}", id),
-        Err(e) => {
-            eprintln!("DEBUG: Failed to remove cart items: {}", e);
-            return HttpResponse::InternalServerError()
-                .json(format!("Failed to remove cart items: {}", e));
-        }
-    }
-
-    // Затем удаляем сам товар
+    // Позиции товара в корзинах остаются с пустым product_id: проверка корзины предупредит покупателя
     match sqlx::query("DELETE FROM products WHERE id = ?")
-        .bind(id)
-        .execute(pool)
+        .bind(product_id.into_inner())
+        .execute(&state.db_pool)
         .await
     {
         Ok(result) if result.rows_affected() > 0 => HttpResponse::NoContent().finish(),
         Ok(_) => HttpResponse::NotFound().json("Product not found"),
+        // Товар, входящий в состав набора или в заказы, удалять нельзя - это держит внешний ключ
+        Err(e) if is_foreign_key_error(&e) => {
+            HttpResponse::Conflict().json("Cannot delete product used as a bundle component or in orders")
+        }
         Err(e) => {
             eprintln!("DEBUG: Product delete error: {}", e);
             HttpResponse::InternalServerError().json(format!("Delete error: {}", e))
@@ -341,6 +362,44 @@ pub async fn delete_product(
     }
 }
 
+#[get("/products/{id}/components")]
+#[doc = "// Получение состава набора"]
+pub async fn get_bundle_components(
+    state: web::Data<AppState>,
+    product_id: web::Path<i64>,
+) -> impl Responder {
+    match bundles::components(&state.db_pool, product_id.into_inner()).await {
+        Ok(components) => HttpResponse::Ok().json(components),
+        Err(e) => {
+            eprintln!("Failed to fetch bundle components: {}", e);
+            HttpResponse::InternalServerError().json("Failed to fetch bundle components")
+        }
+    }
+}
+
+#[put("/products/{id}/components")]
+#[doc = "// Задание состава набора"]
+pub async fn set_bundle_components(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    product_id: web::Path<i64>,
+    components: web::Json<Vec<bundles::BundleComponentInput>>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match bundles::set_components(&state.db_pool, product_id.into_inner(), &components).await {
+        Ok(()) => HttpResponse::Ok().json("Bundle components updated"),
+        Err(bundles::BundleError::DbError(e)) => {
+            eprintln!("Failed to update bundle components: {}", e);
+            HttpResponse::InternalServerError().json(format!("Failed to update bundle components: {}", e))
+        }
+        Err(e @ bundles::BundleError::ProductNotFound(_)) => HttpResponse::NotFound().json(e.to_string()),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
 pub async fn get_products_by_category(
     state: web::Data<AppState>,
     category_id: web::Path<i64>,
@@ -355,9 +414,10 @@ pub async fn get_products_by_category(
             name,
             description,
             price,
-            stock,
+            (SELECT available_stock FROM product_stock WHERE product_id = products.id) as stock,
             image_url,
             category_id,
+            is_bundle,
             strftime('%Y-%m-%d %H:%M:%S', created_at) as created_at
         FROM products
         WHERE category_id = ?
@@ -527,87 +587,63 @@ pub async fn delete_category(
     }
 }
 
+// Корзина текущего покупателя: пользователя Telegram или анонимной сессии
+async fn current_cart_id(
+    state: &AppState,
+    req: &HttpRequest,
+    session: &Session,
+    create: bool,
+) -> Result<Option<i64>, HttpResponse> {
+    let user = state.telegram_auth.user_from_request(req);
+    cart::resolve_cart_id(&state.db_pool, session, user.as_ref(), create)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to resolve cart: {}", e);
+            HttpResponse::InternalServerError().json("Cart error")
+        })
+}
+
+// Администратор магазина: пользователь Telegram из ADMIN_USER_IDS с подписанной initData
+fn current_admin_id(state: &AppState, req: &HttpRequest) -> Result<i64, Box<HttpResponse>> {
+    let user = state
+        .telegram_auth
+        .user_from_request(req)
+        .ok_or_else(|| Box::new(HttpResponse::Unauthorized().json("Telegram authorization required")))?;
+    if !state.telegram_notifier.admin_callbacks.is_admin(user.id) {
+        return Err(Box::new(HttpResponse::Forbidden().json("Admin access required")));
+    }
+    Ok(user.id)
+}
+
 #[post("/cart")]
 pub async fn add_to_cart(
     state: web::Data<AppState>,
     item: web::Json<CartItemRequest>,
+    req: HttpRequest,
     session: Session,
 ) -> impl Responder {
     let pool = &state.db_pool;
-    let session_id = match session.get::<String>("session_id") {
+    let cart_id = match current_cart_id(&state, &req, &session, true).await {
         Ok(Some(id)) => id,
-        Ok(None) => {
-            let new_id = uuid::Uuid::new_v4().to_string();
-            session.insert("session_id", &new_id).unwrap();
-            new_id
-        }
-        Err(e) => {
-            eprintln!("Failed to get session: {}", e);
-            return HttpResponse::InternalServerError().json("Session error");
-        }
+        Ok(None) => return HttpResponse::InternalServerError().json("Cart error"),
+        Err(response) => return response,
     };
 
-    // Проверяем существование продукта
-    let product_id = item.product_id;
-    match sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM products WHERE id = ?")
-        .bind(product_id)
-        .fetch_one(pool)
-        .await {
-        Ok(count) if count == 0 => {
-            return HttpResponse::NotFound().json("Product not found");
-        },
-        Err(e) => {
-            eprintln!("Failed to check product: {}", e);
-            return HttpResponse::InternalServerError().json("Failed to check product");
-        }
-        _ => {}
-    };
+    if item.quantity < 1 {
+        return HttpResponse::BadRequest().json("Quantity must be at least 1");
+    }
 
-    // Check if item already in cart
-    match sqlx::query_as::<_, CartItem>(
-        "SELECT * FROM cart WHERE product_id = ? AND session_id = ?"
-    )
-        .bind(item.product_id)
-        .bind(&session_id)
-        .fetch_optional(pool)
-        .await {
-        Ok(Some(existing_item)) => {
-            // Update quantity if already exists
-            let new_quantity = existing_item.quantity + item.quantity;
-            match sqlx::query(
-                "UPDATE cart SET quantity = ? WHERE id = ?"
-            )
-                .bind(new_quantity)
-                .bind(existing_item.id)
-                .execute(pool)
-                .await {
-                Ok(_) => HttpResponse::Ok().json("Cart updated"),
-                Err(e) => {
-                    eprintln!("Failed to update cart: {}", e);
-                    HttpResponse::InternalServerError().json("Failed to update cart")
-                }
-            }
-        }
-        Ok(None) => {
-            // Add new item to cart
-            match sqlx::query(
-                "INSERT INTO cart (product_id, quantity, session_id) VALUES (?, ?, ?)"
-            )
-                .bind(item.product_id)
-                .bind(item.quantity)
-                .bind(&session_id)
-                .execute(pool)
-                .await {
-                Ok(_) => HttpResponse::Created().json("Item added to cart"),
-                Err(e) => {
-                    eprintln!("Failed to add to cart: {}", e);
-                    HttpResponse::InternalServerError().json("Failed to add to cart")
-                }
-            }
-        }
+    match cart::add_item(pool, cart_id, item.product_id, item.quantity).await {
+        Ok(added) if added.created => HttpResponse::Created().json("Item added to cart"),
+        Ok(_) => HttpResponse::Ok().json("Cart updated"),
+        Err(cart::CartError::ProductNotFound) => HttpResponse::NotFound().json("Product not found"),
+        Err(cart::CartError::InsufficientStock(stock)) => HttpResponse::BadRequest().json(json!({
+            "error": "Недостаточно товара на складе",
+            "available_stock": stock
+        })),
         Err(e) => {
-            eprintln!("Failed to check cart: {}", e);
-            HttpResponse::InternalServerError().json("Failed to check cart")
+            eprintln!("Failed to add to cart: {}", e);
+            HttpResponse::InternalServerError().json("Failed to add to cart")
         }
     }
 }
@@ -615,12 +651,14 @@ pub async fn add_to_cart(
 #[get("/cart")]
 pub async fn get_cart(
     state: web::Data<AppState>,
+    req: HttpRequest,
     session: Session,
 ) -> impl Responder {
     let pool = &state.db_pool;
-    let session_id = match session.get::<String>("session_id") {
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
         Ok(Some(id)) => id,
-        _ => return HttpResponse::Ok().json(Vec::<CartItemWithProduct>::new()),
+        Ok(None) => return HttpResponse::Ok().json(Vec::<CartItemWithProduct>::new()),
+        Err(response) => return response,
     };
 
     match sqlx::query_as::<_, CartItemWithProduct>(
@@ -628,10 +666,10 @@ pub async fn get_cart(
         SELECT c.id, c.product_id, c.quantity, p.name, p.price, p.image_url
         FROM cart c
         JOIN products p ON c.product_id = p.id
-        WHERE c.session_id = ?
+        WHERE c.cart_id = ?
         "#
     )
-        .bind(&session_id)
+        .bind(cart_id)
         .fetch_all(pool)
         .await {
         Ok(items) => HttpResponse::Ok().json(items),
@@ -648,23 +686,50 @@ pub async fn update_cart_item(
     state: web::Data<AppState>,
     item_id: web::Path<i64>,
     quantity: web::Json<HashMap<String, i32>>,  // Изменили тип параметра
+    req: HttpRequest,
     session: Session,
 ) -> impl Responder {
     let pool = &state.db_pool;
-    let session_id = match session.get::<String>("session_id") {
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
         Ok(Some(id)) => id,
-        _ => return HttpResponse::Unauthorized().json("Session required"),
+        Ok(None) => return HttpResponse::Unauthorized().json("Session required"),
+        Err(response) => return response,
     };
 
     // Получаем quantity из JSON
-    let new_quantity = quantity.get("quantity").cloned().unwrap_or(1);
+    let new_quantity = match quantity.get("quantity").cloned() {
+        Some(q) if q >= 1 => q,
+        Some(_) => return HttpResponse::BadRequest().json("Quantity must be at least 1"),
+        None => return HttpResponse::BadRequest().json("Quantity is required"),
+    };
+    let item_id = item_id.into_inner();
+
+    // Проверяем остаток товара на складе
+    let stock = match sqlx::query_scalar::<_, i32>(
+        "SELECT s.available_stock FROM cart c JOIN product_stock s ON c.product_id = s.product_id WHERE c.id = ? AND c.cart_id = ?"
+    )
+        .bind(item_id)
+        .bind(cart_id)
+        .fetch_optional(pool)
+        .await {
+        Ok(Some(stock)) => stock,
+        Ok(None) => return HttpResponse::NotFound().json("Item not found"),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Error: {}", e)),
+    };
+
+    if new_quantity > stock {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Недостаточно товара на складе",
+            "available_stock": stock
+        }));
+    }
 
     match sqlx::query(
-        "UPDATE cart SET quantity = ? WHERE id = ? AND session_id = ?"
+        "UPDATE cart SET quantity = ? WHERE id = ? AND cart_id = ?"
     )
         .bind(new_quantity)
-        .bind(item_id.into_inner())
-        .bind(session_id)
+        .bind(item_id)
+        .bind(cart_id)
         .execute(pool)
         .await {
         Ok(result) if result.rows_affected() > 0 => HttpResponse::Ok().json("Quantity updated"),
@@ -673,24 +738,53 @@ pub async fn update_cart_item(
     }
 }
 
+// Проверка корзины перед оформлением: актуальные цены, остатки и предупреждения.
+// Корзина сразу приводится в соответствие с результатом проверки.
+#[post("/cart/validate")]
+pub async fn validate_cart(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: Session,
+) -> impl Responder {
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return HttpResponse::Ok().json(cart::CartValidation {
+            valid: true,
+            items: Vec::new(),
+            total_amount: 0.0,
+        }),
+        Err(response) => return response,
+    };
+
+    match cart::validate_cart(&state.db_pool, cart_id, true).await {
+        Ok(validation) => HttpResponse::Ok().json(validation),
+        Err(e) => {
+            eprintln!("Failed to validate cart: {}", e);
+            HttpResponse::InternalServerError().json("Failed to validate cart")
+        }
+    }
+}
+
 // Удаление товара из корзины
 #[delete("/cart/{id}")]
 pub async fn remove_cart_item(
     state: web::Data<AppState>,
     item_id: web::Path<i64>,
+    req: HttpRequest,
     session: Session,
 ) -> impl Responder {
     let pool = &state.db_pool;
-    let session_id = match session.get::<String>("session_id") {
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
         Ok(Some(id)) => id,
-        _ => return HttpResponse::Unauthorized().json("Session required"),
+        Ok(None) => return HttpResponse::Unauthorized().json("Session required"),
+        Err(response) => return response,
     };
 
     match sqlx::query(
-        "DELETE FROM cart WHERE id = ? AND session_id = ?"
+        "DELETE FROM cart WHERE id = ? AND cart_id = ?"
     )
         .bind(item_id.into_inner())
-        .bind(session_id)
+        .bind(cart_id)
         .execute(pool)
         .await {
         Ok(result) if result.rows_affected() > 0 => HttpResponse::NoContent().finish(),
@@ -702,18 +796,20 @@ pub async fn remove_cart_item(
 
 pub async fn get_cart_count(
     state: web::Data<AppState>,
+    req: HttpRequest,
     session: Session,
 ) -> impl Responder {
     let pool = &state.db_pool;
-    let session_id = match session.get::<String>("session_id") {
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
         Ok(Some(id)) => id,
-        _ => return HttpResponse::Ok().json(0),
+        Ok(None) => return HttpResponse::Ok().json(0),
+        Err(response) => return response,
     };
 
     match sqlx::query_scalar::<_, Option<i64>>(
-        "SELECT SUM(quantity) FROM cart WHERE session_id = ?"
+        "SELECT SUM(quantity) FROM cart WHERE cart_id = ?"
     )
-        .bind(&session_id)
+        .bind(cart_id)
         .fetch_one(pool)
         .await {
         Ok(Some(count)) => HttpResponse::Ok().json(count),
@@ -725,17 +821,157 @@ pub async fn get_cart_count(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PromoCodeRequest {
+    pub code: String,
+}
+
+// Применение промокода к корзине
+#[post("/cart/promo")]
+pub async fn apply_promo_code(
+    state: web::Data<AppState>,
+    promo: web::Json<PromoCodeRequest>,
+    req: HttpRequest,
+    session: Session,
+) -> impl Responder {
+    let user = state.telegram_auth.user_from_request(&req);
+    let cart_id = match cart::resolve_cart_id(&state.db_pool, &session, user.as_ref(), false).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return HttpResponse::BadRequest().json(json!({
+            "error": "Корзина пуста"
+        })),
+        Err(e) => {
+            eprintln!("Failed to resolve cart: {}", e);
+            return HttpResponse::InternalServerError().json("Cart error");
+        }
+    };
+
+    match promotions::apply_code(&state.db_pool, cart_id, &promo.code, user.map(|u| u.id)).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to apply promo code: {}", e);
+            HttpResponse::InternalServerError().json("Failed to apply promo code")
+        }
+    }
+}
+
+// Отмена промокода в корзине
+#[delete("/cart/promo/{code}")]
+pub async fn remove_promo_code(
+    state: web::Data<AppState>,
+    code: web::Path<String>,
+    req: HttpRequest,
+    session: Session,
+) -> impl Responder {
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return HttpResponse::NotFound().json("Promo code not applied"),
+        Err(response) => return response,
+    };
+
+    match promotions::remove_code(&state.db_pool, cart_id, &code).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json("Promo code not applied"),
+        Err(e) => {
+            eprintln!("Failed to remove promo code: {}", e);
+            HttpResponse::InternalServerError().json("Failed to remove promo code")
+        }
+    }
+}
+
+#[get("/promotions")]
+#[doc = "// Получение списка акций (только администраторы)"]
+pub async fn list_promotions(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match promotions::list_promotions(&state.db_pool).await {
+        Ok(promotions) => HttpResponse::Ok().json(promotions),
+        Err(e) => {
+            eprintln!("Failed to fetch promotions: {}", e);
+            HttpResponse::InternalServerError().json("Failed to fetch promotions")
+        }
+    }
+}
+
+#[post("/promotions")]
+#[doc = "// Создание новой акции (только администраторы)"]
+pub async fn create_promotion(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    promotion: web::Json<promotions::CreatePromotion>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match promotions::create_promotion(&state.db_pool, &promotion).await {
+        Ok(id) => HttpResponse::Created().json(json!({ "id": id })),
+        Err(promotions::PromotionError::DbError(e)) => {
+            eprintln!("Failed to create promotion: {}", e);
+            HttpResponse::InternalServerError().json(format!("Failed to create promotion: {}", e))
+        }
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+#[delete("/promotions/{id}")]
+#[doc = "// Отключение акции, история заказов сохраняется (только администраторы)"]
+pub async fn deactivate_promotion(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    promotion_id: web::Path<i64>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match promotions::deactivate_promotion(&state.db_pool, promotion_id.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json("Promotion not found"),
+        Err(e) => {
+            eprintln!("Failed to deactivate promotion: {}", e);
+            HttpResponse::InternalServerError().json("Failed to deactivate promotion")
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct OrderRequest {
     pub user_id: i64,
+    // Адрес строкой - для клиентов, которые не передают структурированный адрес
+    #[serde(default)]
     pub delivery_address: String,
+    #[serde(default)]
+    pub address: Option<DeliveryAddress>,
+    #[serde(default)]
+    pub address_id: Option<i64>,
+    #[serde(default)]
+    pub save_address: bool,
     pub telegram_username: Option<String>,
+    #[serde(default)]
+    pub redeem_points: Option<i64>,
+    #[serde(default)]
+    pub delivery_method_id: Option<i64>,
+    #[serde(default)]
+    pub delivery_zone_id: Option<i64>,
+    // Оплатить заказ с баланса покупателя, сколько его хватит
+    #[serde(default)]
+    pub use_store_credit: bool,
 }
 
 #[post("/orders")]
 pub async fn create_order(
     state: web::Data<AppState>,
     order_data: web::Json<OrderRequest>,
+    req: HttpRequest,
     session: Session
 ) -> Result<HttpResponse, actix_web::Error> {
     println!("=== Получен запрос на создание заказа ===");
@@ -744,103 +980,245 @@ pub async fn create_order(
     
     let pool = &state.db_pool;
 
-    // Получаем ID сессии
-    let session_id = match session.get::<String>("session_id") {
-        Ok(Some(id)) => {
-            println!("Найден существующий session_id: {}", id);
-            id
+    // Подтвержденный через initData пользователь важнее user_id из тела запроса
+    let telegram_user = state.telegram_auth.user_from_request(&req);
+    let user_id = telegram_user.as_ref().map(|user| user.id).unwrap_or(order_data.user_id);
+
+    // Язык из Telegram: на нем придут подтверждение заказа и дальнейшие уведомления
+    if let Some(user) = telegram_user.as_ref() {
+        if let Some(language_code) = user.language_code.as_deref() {
+            if let Err(e) = notification_templates::remember_language(pool, user.id, language_code).await {
+                eprintln!("Ошибка сохранения языка пользователя {}: {:?}", user.id, e);
+            }
+        }
+    }
+
+    // Адрес доставки: из адресной книги, структурированный или строкой
+    let address_details = match (order_data.address_id, &order_data.address) {
+        (Some(address_id), _) => {
+            let user = match telegram_user.as_ref() {
+                Some(user) => user,
+                None => return Ok(HttpResponse::Unauthorized().json(json!({
+                    "error": "Для выбора сохраненного адреса нужна авторизация Telegram"
+                }))),
+            };
+            match addresses::get(pool, user.id, address_id).await {
+                Ok(saved) => Some(saved.address),
+                Err(e) if e.is_user_error() => {
+                    return Ok(HttpResponse::BadRequest().json(json!({ "error": e.to_string() })));
+                }
+                Err(e) => {
+                    eprintln!("Ошибка получения адреса: {:?}", e);
+                    return Err(actix_web::error::ErrorInternalServerError("Ошибка получения адреса"));
+                }
+            }
+        }
+        (None, Some(address)) => match address.validate() {
+            Ok(address) => Some(address),
+            Err(e) => return Ok(HttpResponse::BadRequest().json(json!({ "error": e.to_string() }))),
         },
-        _ => {
-            // Генерируем новый ID сессии, если его нет
-            let new_id = Uuid::new_v4().to_string();
-            println!("Создан новый session_id: {}", new_id);
-            session.insert("session_id", new_id.clone())?;
-            new_id
+        (None, None) => None,
+    };
+
+    let delivery_address = match &address_details {
+        Some(address) => address.format_line(),
+        None => {
+            let address = order_data.delivery_address.trim();
+            if address.is_empty() || address.chars().count() > 500 {
+                return Ok(HttpResponse::BadRequest().json(json!({
+                    "error": "Укажите адрес доставки"
+                })));
+            }
+            address.to_string()
+        }
+    };
+
+    // Получаем корзину покупателя
+    let cart_id = match cart::resolve_cart_id(pool, &session, telegram_user.as_ref(), false).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            println!("ОШИБКА: Корзина не найдена для пользователя {}", user_id);
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Корзина пуста. Добавьте товары в корзину перед оформлением заказа."
+            })));
+        }
+        Err(e) => {
+            eprintln!("Ошибка определения корзины: {:?}", e);
+            return Err(actix_web::error::ErrorInternalServerError("Ошибка получения корзины"));
         }
     };
 
     // Получаем товары из корзины
-    println!("Ищем товары в корзине для session_id: {}", session_id);
-    let cart_items = sqlx::query(
-        r#"
-        SELECT c.product_id, c.quantity, p.name, p.price
-        FROM cart c
-        JOIN products p ON c.product_id = p.id
-        WHERE c.session_id = ?
-        "#
-    )
-    .bind(&session_id)
-    .fetch_all(pool)
-    .await
-    .map_err(|e| {
-        eprintln!("Ошибка получения корзины: {:?}", e);
-        actix_web::error::ErrorInternalServerError("Ошибка получения корзины")
-    })?;
+    println!("Ищем товары в корзине {}", cart_id);
+    let cart_items = checkout::cart_lines(pool, cart_id)
+        .await
+        .map_err(|e| {
+            eprintln!("Ошибка получения корзины: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Ошибка получения корзины")
+        })?;
 
     println!("Найдено товаров в корзине: {}", cart_items.len());
 
+    // Проверяем цены и остатки, корзину с предупреждениями не оформляем. Проверка идет до проверки
+    // на пустую корзину: позиции удаленных товаров в нее не попадают, но предупреждение по ним нужно
+    let validation = cart::validate_cart(pool, cart_id, false)
+        .await
+        .map_err(|e| {
+            eprintln!("Ошибка проверки корзины: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Ошибка проверки корзины")
+        })?;
+
+    if !validation.valid {
+        println!("ОШИБКА: Корзина {} не прошла проверку", cart_id);
+        return Ok(HttpResponse::Conflict().json(json!({
+            "error": "Цены или наличие товаров изменились. Проверьте корзину перед оформлением заказа.",
+            "validation": validation
+        })));
+    }
+
     if cart_items.is_empty() {
-        println!("ОШИБКА: Корзина пуста для session_id: {}", session_id);
+        println!("ОШИБКА: Корзина {} пуста", cart_id);
         return Ok(HttpResponse::BadRequest().json(json!({
             "error": "Корзина пуста. Добавьте товары в корзину перед оформлением заказа."
         })));
     }
 
-    // Вычисляем общую сумму
-    let total_amount: f64 = cart_items.iter()
-        .map(|item| {
-            let price: f64 = item.get("price");
-            let quantity: i32 = item.get("quantity");
-            price * quantity as f64
-        })
-        .sum();
-
-    // Создаем новый заказ
-    let order_id = sqlx::query(
-        r#"
-        INSERT INTO orders (user_id, total_amount, status, delivery_address)
-        VALUES (?, ?, 'pending', ?)
-        RETURNING id
-        "#
-    )
-    .bind(order_data.user_id)
-    .bind(total_amount)
-    .bind(&order_data.delivery_address)
-    .fetch_one(pool)
-    .await
-    .map_err(|e| {
-        eprintln!("Ошибка создания заказа: {:?}", e);
-        actix_web::error::ErrorInternalServerError("Ошибка создания заказа")
-    })?
-    .get("id");
-
-    // Добавляем товары в order_items
-    for item in &cart_items {
-        let product_id: i64 = item.get("product_id");
-        let quantity: i32 = item.get("quantity");
-        let price: f64 = item.get("price");
-        
-        sqlx::query(
-            r#"
-            INSERT INTO order_items (order_id, product_id, quantity, price)
-            VALUES (?, ?, ?, ?)
-            "#
-        )
-        .bind(order_id)
-        .bind(product_id)
-        .bind(quantity)
-        .bind(price)
-        .execute(pool)
+    // Вычисляем общую сумму с учетом промокодов
+    // Лимиты промокодов на пользователя проверяются только для подтвержденного через Telegram пользователя
+    let discount_summary = promotions::evaluate_cart(pool, cart_id, telegram_user.as_ref().map(|user| user.id))
         .await
         .map_err(|e| {
-            eprintln!("Ошибка добавления товара в заказ: {:?}", e);
-            actix_web::error::ErrorInternalServerError("Ошибка добавления товара в заказ")
+            eprintln!("Ошибка расчета скидок: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Ошибка расчета скидок")
         })?;
-    }
+
+    // Списывать баллы можно только пользователю, подтвержденному через Telegram
+    let redemption = match (order_data.redeem_points, telegram_user.as_ref()) {
+        (Some(points), Some(user)) if points > 0 => state.loyalty
+            .redeemable_points(user.id, points, discount_summary.total_amount)
+            .await
+            .map_err(|e| {
+                eprintln!("Ошибка расчета списания баллов: {:?}", e);
+                actix_web::error::ErrorInternalServerError("Ошибка списания баллов")
+            })?,
+        _ => PointsRedemption { points: 0, discount: 0.0 },
+    };
+
+    // Стоимость доставки; бесплатная доставка по промоакции или порогу суммы
+    let delivery_quote = match (order_data.delivery_method_id, order_data.delivery_zone_id) {
+        (Some(method_id), Some(zone_id)) => {
+            let weight = delivery::cart_weight(pool, cart_id)
+                .await
+                .map_err(|e| {
+                    eprintln!("Ошибка расчета веса корзины: {:?}", e);
+                    actix_web::error::ErrorInternalServerError("Ошибка расчета доставки")
+                })?;
+            match delivery::quote(
+                pool,
+                method_id,
+                zone_id,
+                weight,
+                discount_summary.total_amount,
+                discount_summary.free_delivery,
+            ).await {
+                Ok(quote) => Some(quote),
+                Err(e) if e.is_user_error() => {
+                    return Ok(HttpResponse::BadRequest().json(json!({
+                        "error": e.to_string()
+                    })));
+                }
+                Err(e) => {
+                    eprintln!("Ошибка расчета доставки: {:?}", e);
+                    return Err(actix_web::error::ErrorInternalServerError("Ошибка расчета доставки"));
+                }
+            }
+        }
+        (None, None) => {
+            // Без выбора доставки заказ оформляется, только если способы доставки не настроены
+            let configured = delivery::is_configured(pool)
+                .await
+                .map_err(|e| {
+                    eprintln!("Ошибка получения способов доставки: {:?}", e);
+                    actix_web::error::ErrorInternalServerError("Ошибка расчета доставки")
+                })?;
+            if configured {
+                return Ok(HttpResponse::BadRequest().json(json!({
+                    "error": "Выберите способ и зону доставки"
+                })));
+            }
+            None
+        }
+        _ => {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Укажите способ и зону доставки"
+            })));
+        }
+    };
+
+    // Баланс покупателя списывается без ограничения на долю заказа, но не больше суммы к оплате.
+    // Тратить его можно только пользователю, подтвержденному через Telegram
+    let store_credit = match (order_data.use_store_credit, telegram_user.as_ref()) {
+        (true, Some(user)) => {
+            let balance = store_credit::balance(pool, user.id)
+                .await
+                .map_err(|e| {
+                    eprintln!("Ошибка получения баланса: {:?}", e);
+                    actix_web::error::ErrorInternalServerError("Ошибка списания баланса")
+                })?;
+            let due = discount_summary.total_amount - redemption.discount
+                + delivery_quote.as_ref().map(|quote| quote.cost).unwrap_or(0.0);
+            (balance.min(due).max(0.0) * 100.0).round() / 100.0
+        }
+        _ => 0.0,
+    };
+
+    let checkout_request = checkout::CheckoutRequest {
+        user_id,
+        verified_user_id: telegram_user.as_ref().map(|user| user.id),
+        lines: &cart_items,
+        discounts: &discount_summary,
+        redemption,
+        store_credit,
+        delivery: delivery_quote.as_ref(),
+        delivery_address: &delivery_address,
+        address_details: address_details.as_ref(),
+    };
+
+    let order = match checkout::place_order(pool, &state.loyalty, &state.telegram_notifier.templates, &checkout_request).await {
+        Ok(order) => order,
+        Err(checkout::CheckoutError::OutOfStock(name)) => {
+            println!("ОШИБКА: Недостаточно товара {} для корзины {}", name, cart_id);
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": format!("Товар \"{}\" закончился. Проверьте корзину перед оформлением заказа.", name)
+            })));
+        }
+        Err(checkout::CheckoutError::PromotionError(e)) if e.is_user_error() => {
+            println!("ОШИБКА: Промокод корзины {} больше недоступен: {}", cart_id, e);
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": e.to_string()
+            })));
+        }
+        Err(checkout::CheckoutError::LoyaltyError(e)) if e.is_user_error() => {
+            println!("ОШИБКА: Баллы пользователя {} уже потрачены: {}", user_id, e);
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": "Баллов на балансе уже недостаточно. Обновите корзину и оформите заказ снова."
+            })));
+        }
+        Err(checkout::CheckoutError::StoreCreditError(e)) if e.is_user_error() => {
+            println!("ОШИБКА: Баланс пользователя {} уже потрачен: {}", user_id, e);
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": "Средств на балансе уже недостаточно. Обновите корзину и оформите заказ снова."
+            })));
+        }
+        Err(e) => {
+            eprintln!("Ошибка создания заказа: {:?}", e);
+            return Err(actix_web::error::ErrorInternalServerError("Ошибка создания заказа"));
+        }
+    };
 
     // Очищаем корзину
-    sqlx::query("DELETE FROM cart WHERE session_id = ?")
-        .bind(&session_id)
+    sqlx::query("DELETE FROM cart WHERE cart_id = ?")
+        .bind(cart_id)
         .execute(pool)
         .await
         .map_err(|e| {
@@ -848,76 +1226,661 @@ pub async fn create_order(
             actix_web::error::ErrorInternalServerError("Ошибка очистки корзины")
         })?;
 
-    // Отправляем сообщение пользователю с подтверждением заказа и кнопкой оплаты
-    match state.telegram_notifier.send_order_confirmation(
-        order_id,
-        order_data.user_id,
-        &cart_items.iter().map(|item| crate::telegram_notifications::CartItemData {
-            product_id: item.get("product_id"),
-            quantity: item.get("quantity"),
-            name: item.get("name"),
-            price: item.get("price"),
-        }).collect::<Vec<_>>(),
-        &order_data.delivery_address,
-        total_amount,
-        order_data.telegram_username.as_deref()
-    ).await {
-        Ok(_) => {
-            println!("✅ Сообщение с подтверждением заказа отправлено пользователю");
-        },
-        Err(e) => {
-            eprintln!("⚠️ Не удалось отправить сообщение в Telegram: {:?}", e);
-            
-            // Если ошибка ChatNotFound, попробуем инициализировать диалог
-            if e.to_string().contains("ChatNotFound") {
-                println!("🔄 Попытка инициализации диалога с пользователем...");
-                
-                // Попробуем отправить приветственное сообщение
-                if let Err(init_error) = state.telegram_notifier.initialize_user_dialog(order_data.user_id).await {
-                    eprintln!("❌ Не удалось инициализировать диалог: {:?}", init_error);
-                    println!("ℹ️ Пользователь должен сначала написать боту /start");
-                } else {
-                    println!("✅ Диалог инициализирован, повторная попытка отправки заказа...");
-                    
-                    // Повторная попытка отправки заказа
-                    if let Err(retry_error) = state.telegram_notifier.send_order_confirmation(
-                        order_id,
-                        order_data.user_id,
-                        &cart_items.iter().map(|item| crate::telegram_notifications::CartItemData {
-                            product_id: item.get("product_id"),
-                            quantity: item.get("quantity"),
-                            name: item.get("name"),
-                            price: item.get("price"),
-                        }).collect::<Vec<_>>(),
-                        &order_data.delivery_address,
-                        total_amount,
-                        order_data.telegram_username.as_deref()
-                    ).await {
-                        eprintln!("❌ Повторная попытка не удалась: {:?}", retry_error);
-                    } else {
-                        println!("✅ Заказ отправлен после инициализации диалога");
-                    }
-                }
-            }
+    sqlx::query("DELETE FROM cart_promotions WHERE cart_id = ?")
+        .bind(cart_id)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Ошибка очистки промокодов корзины: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Ошибка очистки корзины")
+        })?;
+
+    // Сохраняем новый адрес в адресную книгу
+    if let (true, None, Some(user), Some(address)) =
+        (order_data.save_address, order_data.address_id, telegram_user.as_ref(), address_details.as_ref())
+    {
+        let input = SaveAddress {
+            label: None,
+            is_default: false,
+            address: address.clone(),
+        };
+        if let Err(e) = addresses::save(pool, user.id, None, &input).await {
+            eprintln!("Ошибка сохранения адреса: {:?}", e);
         }
     }
 
-    println!("✅ Заказ №{} успешно создан на сумму {:.2} TON", order_id, total_amount);
+    // Подтверждение заказа с кнопкой оплаты уже в очереди уведомлений - отправляем сразу
+    state.outbox.wake();
 
     Ok(HttpResponse::Ok().json(json!({
-        "order_id": order_id,
-        "total_amount": total_amount,
-        "status": "pending",
+        "order_id": order.order_id,
+        "total_amount": order.total_amount,
+        "discount_amount": discount_summary.discount_amount,
+        "points_redeemed": checkout_request.redemption.points,
+        "points_discount": checkout_request.redemption.discount,
+        "store_credit": checkout_request.store_credit,
+        "delivery": delivery_quote,
+        "status": if order.total_amount <= PAYMENT_TOLERANCE { "paid" } else { "pending" },
         "message": "Заказ создан успешно"
     })))
 }
 
+// Адресная книга текущего пользователя и данные, присланные через бота
+#[get("/addresses")]
+pub async fn list_addresses(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    let saved = match addresses::list(&state.db_pool, user.id).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            eprintln!("Failed to list addresses: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to list addresses");
+        }
+    };
+
+    match addresses::draft(&state.db_pool, user.id).await {
+        Ok(draft) => HttpResponse::Ok().json(json!({
+            "addresses": saved,
+            "draft": draft
+        })),
+        Err(e) => {
+            eprintln!("Failed to get address draft: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list addresses")
+        }
+    }
+}
+
+async fn save_address(
+    state: &AppState,
+    req: &HttpRequest,
+    address_id: Option<i64>,
+    input: &SaveAddress,
+) -> HttpResponse {
+    let user = match state.telegram_auth.user_from_request(req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match addresses::save(&state.db_pool, user.id, address_id, input).await {
+        Ok(saved) if address_id.is_none() => HttpResponse::Created().json(saved),
+        Ok(saved) => HttpResponse::Ok().json(saved),
+        Err(addresses::AddressError::NotFound) => HttpResponse::NotFound().json("Address not found"),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to save address: {}", e);
+            HttpResponse::InternalServerError().json("Failed to save address")
+        }
+    }
+}
+
+#[post("/addresses")]
+pub async fn create_address(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    address: web::Json<SaveAddress>,
+) -> impl Responder {
+    save_address(&state, &req, None, &address).await
+}
+
+#[put("/addresses/{id}")]
+pub async fn update_address(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    address_id: web::Path<i64>,
+    address: web::Json<SaveAddress>,
+) -> impl Responder {
+    save_address(&state, &req, Some(address_id.into_inner()), &address).await
+}
+
+#[delete("/addresses/{id}")]
+pub async fn delete_address(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    address_id: web::Path<i64>,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match addresses::delete(&state.db_pool, user.id, address_id.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json("Address deleted"),
+        Err(addresses::AddressError::NotFound) => HttpResponse::NotFound().json("Address not found"),
+        Err(e) => {
+            eprintln!("Failed to delete address: {}", e);
+            HttpResponse::InternalServerError().json("Failed to delete address")
+        }
+    }
+}
+
+#[get("/delivery/methods")]
+pub async fn list_delivery_methods(state: web::Data<AppState>) -> impl Responder {
+    match delivery::list_methods(&state.db_pool).await {
+        Ok(methods) => HttpResponse::Ok().json(methods),
+        Err(e) => {
+            eprintln!("Failed to list delivery methods: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list delivery methods")
+        }
+    }
+}
+
+#[get("/delivery/zones")]
+pub async fn list_delivery_zones(state: web::Data<AppState>) -> impl Responder {
+    match delivery::list_zones(&state.db_pool).await {
+        Ok(zones) => HttpResponse::Ok().json(zones),
+        Err(e) => {
+            eprintln!("Failed to list delivery zones: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list delivery zones")
+        }
+    }
+}
+
+// Ошибки настройки доставки: не найдено - 404, неверные параметры - 400
+fn delivery_admin_error(e: delivery::DeliveryError, action: &str) -> HttpResponse {
+    match e {
+        delivery::DeliveryError::MethodNotFound | delivery::DeliveryError::ZoneNotFound => {
+            HttpResponse::NotFound().json(json!({ "error": e.to_string() }))
+        }
+        e if e.is_user_error() => HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        e => {
+            eprintln!("Failed to {}: {}", action, e);
+            HttpResponse::InternalServerError().json(format!("Failed to {}", action))
+        }
+    }
+}
+
+#[post("/delivery/methods")]
+#[doc = "// Создание способа доставки (только администраторы)"]
+pub async fn create_delivery_method(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    method: web::Json<delivery::SaveDeliveryMethod>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match delivery::create_method(&state.db_pool, &method).await {
+        Ok(id) => HttpResponse::Created().json(json!({ "id": id })),
+        Err(e) => delivery_admin_error(e, "create delivery method"),
+    }
+}
+
+#[put("/delivery/methods/{id}")]
+#[doc = "// Изменение способа доставки; active = false скрывает его из оформления заказа"]
+pub async fn update_delivery_method(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    method_id: web::Path<i64>,
+    method: web::Json<delivery::SaveDeliveryMethod>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match delivery::update_method(&state.db_pool, method_id.into_inner(), &method).await {
+        Ok(()) => HttpResponse::Ok().json("Delivery method updated"),
+        Err(e) => delivery_admin_error(e, "update delivery method"),
+    }
+}
+
+#[post("/delivery/zones")]
+#[doc = "// Создание зоны доставки (только администраторы)"]
+pub async fn create_delivery_zone(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    zone: web::Json<delivery::SaveDeliveryZone>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match delivery::create_zone(&state.db_pool, &zone).await {
+        Ok(id) => HttpResponse::Created().json(json!({ "id": id })),
+        Err(e) => delivery_admin_error(e, "create delivery zone"),
+    }
+}
+
+#[put("/delivery/zones/{id}")]
+#[doc = "// Изменение зоны доставки"]
+pub async fn update_delivery_zone(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    zone_id: web::Path<i64>,
+    zone: web::Json<delivery::SaveDeliveryZone>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match delivery::update_zone(&state.db_pool, zone_id.into_inner(), &zone).await {
+        Ok(()) => HttpResponse::Ok().json("Delivery zone updated"),
+        Err(e) => delivery_admin_error(e, "update delivery zone"),
+    }
+}
+
+#[get("/delivery/rates")]
+#[doc = "// Тарифы способов доставки по зонам (только администраторы)"]
+pub async fn list_delivery_rates(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match delivery::list_rates(&state.db_pool).await {
+        Ok(rates) => HttpResponse::Ok().json(rates),
+        Err(e) => {
+            eprintln!("Failed to list delivery rates: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list delivery rates")
+        }
+    }
+}
+
+#[put("/delivery/rates")]
+#[doc = "// Установка стоимости способа доставки в зоне"]
+pub async fn set_delivery_rate(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    rate: web::Json<delivery::DeliveryRate>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    match delivery::set_rate(&state.db_pool, &rate).await {
+        Ok(()) => HttpResponse::Ok().json(rate.into_inner()),
+        Err(e) => delivery_admin_error(e, "set delivery rate"),
+    }
+}
+
+#[delete("/delivery/rates/{method_id}/{zone_id}")]
+#[doc = "// Удаление тарифа: способ становится недоступен в зоне"]
+pub async fn delete_delivery_rate(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(i64, i64)>,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+
+    let (method_id, zone_id) = path.into_inner();
+    match delivery::delete_rate(&state.db_pool, method_id, zone_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json("Delivery rate not found"),
+        Err(e) => {
+            eprintln!("Failed to delete delivery rate: {}", e);
+            HttpResponse::InternalServerError().json("Failed to delete delivery rate")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuoteRequest {
+    pub zone_id: i64,
+    #[serde(default)]
+    pub method_id: Option<i64>,
+}
+
+// Расчет доставки для текущей корзины: по выбранному способу или по всем доступным в зоне
+#[post("/delivery/quote")]
+pub async fn quote_delivery(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    session: Session,
+    quote_request: web::Json<DeliveryQuoteRequest>,
+) -> impl Responder {
+    let pool = &state.db_pool;
+    let user = state.telegram_auth.user_from_request(&req);
+
+    let cart_id = match current_cart_id(&state, &req, &session, false).await {
+        Ok(cart_id) => cart_id,
+        Err(response) => return response,
+    };
+
+    let (summary, weight) = match cart_id {
+        Some(cart_id) => {
+            let summary = match promotions::evaluate_cart(pool, cart_id, user.map(|u| u.id)).await {
+                Ok(summary) => summary,
+                Err(e) => {
+                    eprintln!("Failed to evaluate cart discounts: {}", e);
+                    return HttpResponse::InternalServerError().json("Failed to calculate delivery");
+                }
+            };
+            let weight = match delivery::cart_weight(pool, cart_id).await {
+                Ok(weight) => weight,
+                Err(e) => {
+                    eprintln!("Failed to calculate cart weight: {}", e);
+                    return HttpResponse::InternalServerError().json("Failed to calculate delivery");
+                }
+            };
+            (summary, weight)
+        }
+        None => (promotions::DiscountSummary::default(), 0),
+    };
+
+    let result = match quote_request.method_id {
+        Some(method_id) => delivery::quote(
+            pool,
+            method_id,
+            quote_request.zone_id,
+            weight,
+            summary.total_amount,
+            summary.free_delivery,
+        )
+            .await
+            .map(|quote| vec![quote]),
+        None => delivery::quotes(
+            pool,
+            quote_request.zone_id,
+            weight,
+            summary.total_amount,
+            summary.free_delivery,
+        ).await,
+    };
+
+    match result {
+        Ok(quotes) => HttpResponse::Ok().json(json!({
+            "weight_grams": weight,
+            "subtotal": summary.total_amount,
+            "quotes": quotes
+        })),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to quote delivery: {}", e);
+            HttpResponse::InternalServerError().json("Failed to calculate delivery")
+        }
+    }
+}
+
+// Баланс бонусных баллов текущего пользователя Telegram
+#[get("/loyalty/balance")]
+pub async fn get_points_balance(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    let balance = match state.loyalty.balance(user.id).await {
+        Ok(balance) => balance,
+        Err(e) => {
+            eprintln!("Failed to get points balance: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to get points balance");
+        }
+    };
+
+    // Зачисленные переплаты - отдельный баланс в TON, без ограничения на долю заказа
+    match store_credit::balance(&state.db_pool, user.id).await {
+        Ok(store_credit) => HttpResponse::Ok().json(json!({
+            "balance": balance,
+            "max_redeem_percent": state.loyalty.config().max_redeem_percent,
+            "point_value": state.loyalty.config().point_value,
+            "store_credit": store_credit
+        })),
+        Err(e) => {
+            eprintln!("Failed to get store credit balance: {}", e);
+            HttpResponse::InternalServerError().json("Failed to get points balance")
+        }
+    }
+}
+
+// Подписки текущего пользователя Telegram
+#[get("/subscriptions")]
+pub async fn list_subscriptions(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match state.subscriptions.list_for_user(user.id).await {
+        Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+        Err(e) => {
+            eprintln!("Failed to list subscriptions: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list subscriptions")
+        }
+    }
+}
+
+// Оформление регулярного заказа. Управление - кнопками в боте.
+#[post("/subscriptions")]
+pub async fn create_subscription(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    subscription: web::Json<CreateSubscription>,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    let mut input = subscription.into_inner();
+    if input.telegram_username.is_none() {
+        input.telegram_username = user.username.clone();
+    }
+
+    match state.subscriptions.create(user.id, &input).await {
+        Ok(subscription) => {
+            if let Err(e) = state.telegram_notifier.send_subscription_controls(&subscription, None).await {
+                eprintln!("Failed to send subscription controls: {:?}", e);
+            }
+            HttpResponse::Created().json(subscription)
+        }
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to create subscription: {}", e);
+            HttpResponse::InternalServerError().json("Failed to create subscription")
+        }
+    }
+}
+
+// Отмена подписки из WebApp
+#[delete("/subscriptions/{id}")]
+pub async fn cancel_subscription(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    subscription_id: web::Path<i64>,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match state.subscriptions.apply_action(user.id, subscription_id.into_inner(), SubscriptionAction::Cancel).await {
+        Ok(subscription) => HttpResponse::Ok().json(subscription),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to cancel subscription: {}", e);
+            HttpResponse::InternalServerError().json("Failed to cancel subscription")
+        }
+    }
+}
+
+// Привязка трек-номера к заказу, покупатель получает ссылку для отслеживания
+#[post("/orders/{id}/shipment")]
+pub async fn attach_shipment(
+    state: web::Data<AppState>,
+    order_id: web::Path<i64>,
+    shipment: web::Json<AttachShipment>,
+) -> impl Responder {
+    match state.shipments.attach(order_id.into_inner(), &shipment).await {
+        Ok(shipment) => HttpResponse::Ok().json(shipment),
+        Err(shipping::ShippingError::OrderNotFound) => HttpResponse::NotFound().json("Order not found"),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to attach shipment: {}", e);
+            HttpResponse::InternalServerError().json("Failed to attach shipment")
+        }
+    }
+}
+
+#[get("/orders/{id}/shipment")]
+pub async fn get_shipment(
+    state: web::Data<AppState>,
+    order_id: web::Path<i64>,
+) -> impl Responder {
+    match state.shipments.get(order_id.into_inner()).await {
+        Ok(Some(shipment)) => HttpResponse::Ok().json(shipment),
+        Ok(None) => HttpResponse::NotFound().json("Shipment not found"),
+        Err(e) => {
+            eprintln!("Failed to get shipment: {}", e);
+            HttpResponse::InternalServerError().json("Failed to get shipment")
+        }
+    }
+}
+
+// Внутренние заметки администраторов к заказу (только для администраторов)
+#[get("/orders/{id}/notes")]
+pub async fn get_order_notes(
+    state: web::Data<AppState>,
+    order_id: web::Path<i64>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match admin_actions::notes(&state.db_pool, order_id.into_inner()).await {
+        Ok(notes) => HttpResponse::Ok().json(notes),
+        Err(e) => {
+            eprintln!("Failed to get order notes: {}", e);
+            HttpResponse::InternalServerError().json("Failed to get order notes")
+        }
+    }
+}
+
+// Заказ на самовывоз собран: покупатель получает одноразовый код и QR
+#[post("/orders/{id}/pickup/ready")]
+pub async fn mark_pickup_ready(
+    state: web::Data<AppState>,
+    order_id: web::Path<i64>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match pickup::mark_ready(&state.db_pool, &state.telegram_notifier, order_id.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json("Pickup code sent"),
+        Err(pickup::PickupError::OrderNotFound) => HttpResponse::NotFound().json("Order not found"),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to prepare pickup: {}", e);
+            HttpResponse::InternalServerError().json("Failed to prepare pickup")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PickupVerifyRequest {
+    pub code: String,
+}
+
+// Проверка кода на стойке и выдача заказа. Выдавший сотрудник - администратор из initData
+#[post("/pickup/verify")]
+pub async fn verify_pickup(
+    state: web::Data<AppState>,
+    request: web::Json<PickupVerifyRequest>,
+    req: HttpRequest,
+) -> impl Responder {
+    let staff_id = match current_admin_id(&state, &req) {
+        Ok(staff_id) => staff_id,
+        Err(response) => return *response,
+    };
+    let order_id = match pickup::hand_over(&state.db_pool, &request.code, staff_id).await {
+        Ok(order_id) => order_id,
+        Err(pickup::PickupError::TooManyAttempts) => return HttpResponse::TooManyRequests().json(json!({
+            "error": pickup::PickupError::TooManyAttempts.to_string()
+        })),
+        Err(e) if e.is_user_error() => return HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to verify pickup code: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to verify pickup code");
+        }
+    };
+
+    if let Err(e) = state.telegram_notifier.handle_order_completion(order_id, None).await {
+        eprintln!("Failed to complete order {}: {:?}", order_id, e);
+    }
+
+    HttpResponse::Ok().json(json!({
+        "order_id": order_id,
+        "status": "completed"
+    }))
+}
+
+// Возврат заказа администратором: начисленные за него баллы аннулируются, потраченные возвращаются
+#[post("/orders/{id}/refund")]
+pub async fn refund_order(
+    state: web::Data<AppState>,
+    order_id: web::Path<i64>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin_id = match current_admin_id(&state, &req) {
+        Ok(admin_id) => admin_id,
+        Err(response) => return *response,
+    };
+    let order_id = order_id.into_inner();
+
+    match admin_actions::mark_refunded(&state.db_pool, &state.loyalty, order_id).await {
+        Ok(_) => {}
+        Err(admin_actions::AdminActionError::OrderNotFound) => return HttpResponse::NotFound().json("Order not found"),
+        Err(e) if e.is_user_error() => return HttpResponse::BadRequest().json(json!({
+            "error": e.to_string()
+        })),
+        Err(e) => {
+            eprintln!("Failed to refund order: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to refund order");
+        }
+    }
+    println!("↩️ Администратор {} оформил возврат заказа №{}", admin_id, order_id);
+
+    if let Err(e) = state.telegram_notifier.close_order_threads(order_id).await {
+        eprintln!("Failed to close support threads for order {}: {}", order_id, e);
+    }
+
+    // Деньги возвращаются после смены статуса, чтобы повторный запрос не вернул их дважды.
+    // Платежи, которые провайдер не вернул, остаются в статусе refund_pending для ручного возврата
+    match state.payments.refund(order_id).await {
+        Ok(payments) => HttpResponse::Ok().json(json!({
+            "order_id": order_id,
+            "status": "refunded",
+            "payments": payments
+        })),
+        Err(e) => {
+            eprintln!("Failed to refund payment for order {}: {}", order_id, e);
+            HttpResponse::InternalServerError().json("Order refunded, but payment refund failed")
+        }
+    }
+}
+
+// Хэш перевода из WebApp. Сумма и отправитель берутся из сети, а не из запроса
 #[derive(Debug, Deserialize)]
 pub struct PaymentConfirmation {
     pub order_id: i64,
     pub transaction_hash: String,
-    pub amount: f64,
-    pub wallet_address: String,
 }
 
 #[post("/payment-confirmation")]
@@ -927,75 +1890,369 @@ pub async fn confirm_payment(
 ) -> Result<HttpResponse, actix_web::Error> {
     let pool = &state.db_pool;
 
-    // Проверяем существование заказа
-    let order = sqlx::query(
-        "SELECT id, user_id, total_amount, status FROM orders WHERE id = ?"
-    )
-    .bind(payment_data.order_id)
-    .fetch_optional(pool)
-    .await
-    .map_err(|e| {
-        eprintln!("Ошибка получения заказа: {:?}", e);
-        actix_web::error::ErrorInternalServerError("Ошибка получения заказа")
-    })?;
-
-    let order = match order {
-        Some(order) => order,
+    // Проверяем, что заказ существует и еще не оплачен
+    let status = sqlx::query_scalar::<_, String>("SELECT status FROM orders WHERE id = ?")
+        .bind(payment_data.order_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Ошибка получения заказа: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Ошибка получения заказа")
+        })?;
+
+    match status.as_deref() {
         None => {
             return Ok(HttpResponse::NotFound().json(json!({
                 "error": "Заказ не найден"
             })));
         }
+        Some("pending") => {}
+        Some(_) => {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Заказ уже обработан"
+            })));
+        }
+    }
+
+    // Перевод проверяется в сети: недоплата зачитывается частично, остаток покупатель доплачивает.
+    // Карточка заказа и просьба о доплате ставятся в очередь вместе с записью платежа
+    let settlement = match state.reconciliation
+        .confirm_transfer(&state.payments, &payment_data.transaction_hash, payment_data.order_id)
+        .await
+    {
+        Ok(Some(settlement)) => settlement,
+        Ok(None) => {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Платеж уже обработан"
+            })));
+        }
+        Err(e) if e.is_user_error() => {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            })));
+        }
+        Err(e) => {
+            eprintln!("Ошибка проверки перевода: {:?}", e);
+            return Err(actix_web::error::ErrorBadGateway("Не удалось проверить перевод"));
+        }
     };
+    wake_outbox(&state, &settlement);
+
+    match settlement {
+        Settlement::Partial { paid, remaining } => Ok(HttpResponse::Ok().json(json!({
+            "status": "partial",
+            "message": "Платеж получен частично, ссылка на доплату отправлена в бот",
+            "paid": paid,
+            "remaining": remaining
+        }))),
+        _ => Ok(HttpResponse::Ok().json(json!({
+            "status": "success",
+            "message": "Платеж подтвержден"
+        }))),
+    }
+}
 
-    // Проверяем, что заказ еще не оплачен
-    let status: String = order.get("status");
-    if status != "pending" {
-        return Ok(HttpResponse::BadRequest().json(json!({
-            "error": "Заказ уже обработан"
-        })));
+// Зачет поставил в очередь карточку оплаченного заказа для администраторов или просьбу о доплате
+// для покупателя - отправляем сразу
+fn wake_outbox(state: &AppState, settlement: &Settlement) {
+    if matches!(settlement, Settlement::Covered { .. } | Settlement::Partial { .. }) {
+        state.outbox.wake();
     }
+}
 
-    // Проверяем сумму платежа (с небольшой погрешностью)
-    let order_total: f64 = order.get::<f64, _>("total_amount");
-    if (payment_data.amount - order_total).abs() > 0.01 {
-        return Ok(HttpResponse::BadRequest().json(json!({
-            "error": "Неверная сумма платежа"
-        })));
+// Ответ WebApp после зачета платежа
+fn settlement_response(state: &AppState, settlement: Settlement) -> HttpResponse {
+    wake_outbox(state, &settlement);
+    match settlement {
+        Settlement::Partial { paid, remaining } => HttpResponse::Ok().json(json!({
+            "status": "partial",
+            "paid": paid,
+            "remaining": remaining
+        })),
+        Settlement::Covered { overpaid } => HttpResponse::Ok().json(json!({
+            "status": "paid",
+            "overpaid": overpaid
+        })),
+        Settlement::Closed => HttpResponse::Ok().json(json!({ "status": "closed" })),
     }
+}
 
-    // Создаем запись о платеже
-    sqlx::query(
-        r#"
-        INSERT INTO payments (order_id, user_id, amount, wallet_address, status, transaction_hash)
-        VALUES (?, ?, ?, ?, 'confirmed', ?)
-        "#
-    )
-    .bind(payment_data.order_id.to_string())
-    .bind(order.get::<i64, _>("user_id"))
-    .bind(payment_data.amount)
-    .bind(&payment_data.wallet_address)
-    .bind(&payment_data.transaction_hash)
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        eprintln!("Ошибка создания платежа: {:?}", e);
-        actix_web::error::ErrorInternalServerError("Ошибка создания платежа")
-    })?;
-
-    // Получаем username пользователя (если есть)
-    let username = None; // TODO: Можно добавить поле username в orders или получать из Telegram API
-
-    // Уведомляем администраторов о новом заказе
-    if let Err(e) = state.telegram_notifier.notify_admin_new_order(payment_data.order_id, username).await {
-        eprintln!("Ошибка уведомления администраторов: {:?}", e);
-        // Продолжаем выполнение, даже если уведомление не отправлено
+#[derive(Debug, Deserialize)]
+pub struct ReconciliationQuery {
+    // По умолчанию только переводы с расхождениями
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkTransfer {
+    pub order_id: i64,
+}
+
+// Сверка входящих переводов с оплатами заказов (методы сверки - только для администраторов)
+#[get("/reconciliation")]
+pub async fn get_reconciliation(
+    state: web::Data<AppState>,
+    query: web::Query<ReconciliationQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
     }
+    match state.reconciliation.report(!query.all).await {
+        Ok(transfers) => HttpResponse::Ok().json(transfers),
+        Err(e) => {
+            eprintln!("Failed to build reconciliation report: {}", e);
+            HttpResponse::InternalServerError().json("Failed to build reconciliation report")
+        }
+    }
+}
 
-    Ok(HttpResponse::Ok().json(json!({
-        "status": "success",
-        "message": "Платеж подтвержден"
-    })))
+#[get("/reconciliation.csv")]
+pub async fn export_reconciliation(
+    state: web::Data<AppState>,
+    query: web::Query<ReconciliationQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match state.reconciliation.report(!query.all).await {
+        Ok(transfers) => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header(("Content-Disposition", "attachment; filename=\"reconciliation.csv\""))
+            .body(reconciliation::to_csv(&transfers)),
+        Err(e) => {
+            eprintln!("Failed to export reconciliation report: {}", e);
+            HttpResponse::InternalServerError().json("Failed to export reconciliation report")
+        }
+    }
+}
+
+// Запуск сверки вне расписания
+#[post("/reconciliation/run")]
+pub async fn run_reconciliation(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match state.reconciliation.run().await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => {
+            eprintln!("Failed to run reconciliation: {}", e);
+            HttpResponse::InternalServerError().json("Failed to run reconciliation")
+        }
+    }
+}
+
+// Ручная привязка перевода к заказу
+#[post("/reconciliation/transfers/{hash}/link")]
+pub async fn link_transfer(
+    state: web::Data<AppState>,
+    transaction_hash: web::Path<String>,
+    link: web::Json<LinkTransfer>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match state.reconciliation.link(&state.payments, &transaction_hash, link.order_id).await {
+        // Заказ уже оплачен или отменен: перевод только привязан
+        Ok(Settlement::Closed) => HttpResponse::Ok().json(json!({ "status": "linked" })),
+        Ok(settlement) => settlement_response(&state, settlement),
+        Err(e @ (ReconciliationError::TransferNotFound | ReconciliationError::OrderNotFound)) => {
+            HttpResponse::NotFound().json(json!({ "error": e.to_string() }))
+        }
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        Err(e) => {
+            eprintln!("Failed to link transfer: {}", e);
+            HttpResponse::InternalServerError().json("Failed to link transfer")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OutboxQuery {
+    // pending | sent | dead, по умолчанию недоставленные
+    pub status: Option<String>,
+}
+
+// Очередь уведомлений Telegram: недоставленные и ожидающие повтора
+#[get("/notifications/outbox")]
+pub async fn get_notification_outbox(
+    state: web::Data<AppState>,
+    query: web::Query<OutboxQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match state.outbox.list(query.status.as_deref().unwrap_or("dead")).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            eprintln!("Failed to load notification outbox: {}", e);
+            HttpResponse::InternalServerError().json("Failed to load notification outbox")
+        }
+    }
+}
+
+// Повторная отправка недоставленного уведомления
+#[post("/notifications/outbox/{id}/replay")]
+pub async fn replay_notification(
+    state: web::Data<AppState>,
+    id: web::Path<i64>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = current_admin_id(&state, &req) {
+        return *response;
+    }
+    match state.outbox.replay(id.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json(json!({ "status": "pending" })),
+        Err(e @ OutboxError::NotFound) => HttpResponse::NotFound().json(json!({ "error": e.to_string() })),
+        Err(e) if e.is_user_error() => HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        Err(e) => {
+            eprintln!("Failed to replay notification: {}", e);
+            HttpResponse::InternalServerError().json("Failed to replay notification")
+        }
+    }
+}
+
+// Уведомление платежного сервиса (CALLBACK_URL). Подпись проверяется по сырому телу запроса
+#[post("/payment-callback")]
+pub async fn payment_callback(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let signature = req
+        .headers()
+        .get(CALLBACK_SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !state.ton_processor.verify_callback_signature(&body, signature) {
+        return HttpResponse::Unauthorized().json("Invalid signature");
+    }
+
+    let callback = match serde_json::from_slice::<PaymentCallback>(&body) {
+        Ok(callback) => callback,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+    };
+
+    match state.ton_processor.handle_callback(&state.payments, &callback).await {
+        // Платеж подтвержден и зачтен в заказ в одной транзакции; при ошибке сервис повторит уведомление
+        Ok(CallbackOutcome::Paid(settlement)) => settlement_response(&state, settlement),
+        Ok(CallbackOutcome::Closed) => HttpResponse::Ok().json(json!({ "status": "closed" })),
+        // Повтор уже обработанного уведомления - отвечаем успехом, чтобы сервис не слал его снова
+        Ok(CallbackOutcome::Ignored) => HttpResponse::Ok().json(json!({ "status": "ignored" })),
+        Err(TonPaymentError::UnknownPayment(payment_id)) => HttpResponse::NotFound().json(json!({
+            "error": format!("Unknown payment: {}", payment_id)
+        })),
+        Err(TonPaymentError::InvalidAmount) => HttpResponse::BadRequest().json(json!({
+            "error": "Invalid payment amount"
+        })),
+        Err(e) => {
+            eprintln!("Failed to handle payment callback: {}", e);
+            HttpResponse::InternalServerError().json("Failed to handle payment callback")
+        }
+    }
+}
+
+fn ton_connect_error_response(e: TonConnectError, context: &str) -> HttpResponse {
+    match e {
+        TonConnectError::OrderNotFound => HttpResponse::NotFound().json(json!({ "error": e.to_string() })),
+        e if e.is_user_error() => HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        e => {
+            eprintln!("{}: {}", context, e);
+            HttpResponse::InternalServerError().json(context)
+        }
+    }
+}
+
+// TON Connect: payload для ton_proof при подключении кошелька в WebApp
+#[post("/ton-connect/payload")]
+pub async fn ton_connect_payload(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match state.ton_connect.issue_payload(user.id).await {
+        Ok(payload) => HttpResponse::Ok().json(json!({ "payload": payload })),
+        Err(e) => ton_connect_error_response(e, "Failed to issue ton_proof payload"),
+    }
+}
+
+#[post("/ton-connect/proof")]
+pub async fn ton_connect_proof(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    proof: web::Json<TonProofRequest>,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match state.ton_connect.verify_proof(user.id, &proof).await {
+        Ok(wallet) => HttpResponse::Ok().json(wallet),
+        Err(e) => ton_connect_error_response(e, "Failed to verify ton_proof"),
+    }
+}
+
+#[get("/ton-connect/wallet")]
+pub async fn ton_connect_wallet(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match state.ton_connect.linked_wallet(user.id).await {
+        Ok(wallet) => HttpResponse::Ok().json(wallet),
+        Err(e) => ton_connect_error_response(e, "Failed to get linked wallet"),
+    }
+}
+
+// Перевод по заказу для tonConnectUI.sendTransaction
+#[get("/orders/{id}/ton-connect")]
+pub async fn ton_connect_transaction(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    order_id: web::Path<i64>,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+
+    match state.ton_connect.transfer(order_id.into_inner(), user.id).await {
+        Ok(transaction) => HttpResponse::Ok().json(transaction),
+        Err(e) => ton_connect_error_response(e, "Failed to build TON Connect transaction"),
+    }
+}
+
+// WebApp опрашивает после отправки перевода, пока транзакция не появится в сети
+#[post("/orders/{id}/ton-connect/check")]
+pub async fn ton_connect_check(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    order_id: web::Path<i64>,
+) -> impl Responder {
+    let user = match state.telegram_auth.user_from_request(&req) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().json("Telegram authorization required"),
+    };
+    let order_id = order_id.into_inner();
+
+    match state.ton_connect.check_payment(&state.payments, order_id, user.id, user.username.as_deref()).await {
+        Ok(Some(settlement)) => settlement_response(&state, settlement),
+        Ok(None) => HttpResponse::Ok().json(json!({ "status": "pending" })),
+        Err(e) => ton_connect_error_response(e, "Failed to check TON Connect payment"),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -1011,6 +2268,10 @@ pub struct TelegramMessage {
     pub chat: TelegramChat,
     pub text: Option<String>,
     pub reply_to_message: Option<Box<TelegramMessage>>,
+    #[serde(default)]
+    pub message_thread_id: Option<i32>,
+    #[serde(default)]
+    pub is_topic_message: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -1027,25 +2288,46 @@ pub struct TelegramChat {
     pub chat_type: String,
 }
 
+// Заголовок с secret_token, заданным при регистрации вебхука
+const TELEGRAM_WEBHOOK_SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
 #[post("/telegram-webhook")]
 pub async fn telegram_webhook(
     state: web::Data<AppState>,
     update: web::Json<TelegramWebhookUpdate>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, actix_web::Error> {
+    // Telegram передает secret_token из setWebhook в заголовке. Без TELEGRAM_WEBHOOK_SECRET вебхук не принимается
+    let secret = req
+        .headers()
+        .get(TELEGRAM_WEBHOOK_SECRET_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (&state.telegram_webhook_secret, secret) {
+        (Some(expected), Some(secret)) if expected == secret => {}
+        _ => return Ok(HttpResponse::Unauthorized().json("Invalid secret token")),
+    }
+
     // Проверяем, что сообщение пришло из админ-канала
     if let Some(message) = &update.message {
-        if message.chat.id == -1002502108391 { // ID админ-канала
-            // Проверяем, является ли это ответом на сообщение о заказе
-            if let Some(reply_to) = &message.reply_to_message {
-                if let Some(text) = &reply_to.text {
-                    // Извлекаем ID заказа из оригинального сообщения
-                    if let Some(order_id) = extract_order_id_from_text(text) {
-                        if let Some(comment_text) = &message.text {
-                            // Пересылаем комментарий пользователю
-                            if let Err(e) = state.telegram_notifier.forward_admin_comment_to_user(order_id, comment_text).await {
-                                eprintln!("Ошибка пересылки комментария администратора: {:?}", e);
-                            }
-                        }
+        if message.chat.id == state.telegram_notifier.admin_chat_id().0 {
+            // Сообщение в теме обращения или ответ на сообщение обращения
+            if let Some(comment_text) = &message.text {
+                let admin_id = message.from.as_ref().map(|user| user.id).unwrap_or(0);
+                let topic_id = message.message_thread_id.filter(|_| message.is_topic_message);
+                // Пересылаем комментарий пользователю (или выполняем /track, /close)
+                let comment = AdminComment {
+                    admin_message_id: MessageId(message.message_id as i32),
+                    topic_id,
+                    reply_to_message_id: message.reply_to_message.as_ref().map(|reply_to| MessageId(reply_to.message_id as i32)),
+                    admin_id,
+                    text: Some(comment_text.as_str()),
+                    media: None,
+                };
+                match TelegramBot::handle_admin_comment(&state.telegram_notifier, &state.shipments, &comment).await {
+                    Ok(Some(answer)) => println!("{}", answer),
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("Ошибка пересылки комментария администратора: {:?}", e);
                     }
                 }
             }
@@ -1057,20 +2339,6 @@ pub async fn telegram_webhook(
     })))
 }
 
-// Вспомогательная функция для извлечения ID заказа из текста сообщения
-fn extract_order_id_from_text(text: &str) -> Option<i64> {
-    // Ищем паттерн "ID: число)" в тексте
-    if let Some(start) = text.find("(ID: ") {
-        let start = start + 5; // Длина "(ID: "
-        if let Some(end) = text[start..].find(')') {
-            if let Ok(order_id) = text[start..start + end].parse::<i64>() {
-                return Some(order_id);
-            }
-        }
-    }
-    None
-}
-
 // Helper function to list all tables in the database
 async fn list_all_tables(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
     println!("\nListing all tables in the database...");
@@ -1117,6 +2385,8 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             .service(update_product)
             .service(delete_product)
             .service(get_product_handler)  // Добавили get_product_handler в корень конфигурации
+            .service(get_bundle_components)
+            .service(set_bundle_components)
             .service(
                 web::resource("/categories/{id}/products")
                     .route(web::get().to(get_products_by_category))
@@ -1134,6 +2404,14 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             .service(get_cart)
             .service(update_cart_item)
             .service(remove_cart_item)
+            .service(validate_cart)
+            .service(apply_promo_code)
+            .service(remove_promo_code)
+
+            // Promotions routes
+            .service(list_promotions)
+            .service(create_promotion)
+            .service(deactivate_promotion)
 
             // Cart count route - using route() for handlers without macros
             .service(
@@ -1143,7 +2421,290 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 
             // Order and payment routes - using service() for handlers with macros
             .service(create_order)
+            .service(refund_order)
+            .service(attach_shipment)
+            .service(get_shipment)
+            .service(get_order_notes)
+            .service(mark_pickup_ready)
+            .service(verify_pickup)
+            .service(get_points_balance)
+            .service(list_addresses)
+            .service(create_address)
+            .service(update_address)
+            .service(delete_address)
+            .service(list_delivery_methods)
+            .service(list_delivery_zones)
+            .service(create_delivery_method)
+            .service(update_delivery_method)
+            .service(create_delivery_zone)
+            .service(update_delivery_zone)
+            .service(list_delivery_rates)
+            .service(set_delivery_rate)
+            .service(delete_delivery_rate)
+            .service(quote_delivery)
+            .service(list_subscriptions)
+            .service(create_subscription)
+            .service(cancel_subscription)
             .service(confirm_payment)
+            .service(payment_callback)
+            .service(get_reconciliation)
+            .service(export_reconciliation)
+            .service(run_reconciliation)
+            .service(link_transfer)
+            .service(get_notification_outbox)
+            .service(replay_notification)
+            .service(ton_connect_payload)
+            .service(ton_connect_proof)
+            .service(ton_connect_wallet)
+            .service(ton_connect_transaction)
+            .service(ton_connect_check)
             .service(telegram_webhook) // Добавили telegram_webhook в корень конфигурации
     );
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use actix_session::storage::CookieSessionStore;
+    use actix_session::SessionMiddleware;
+    use actix_web::cookie::Key;
+    use actix_web::{test, App};
+    use sqlx::sqlite::SqlitePoolOptions;
+    use crate::loyalty::{LoyaltyConfig, LoyaltyProgram};
+    use crate::notification_templates::NotificationTemplates;
+    use crate::outbox::{NotificationOutbox, OutboxConfig};
+    use crate::payments::PaymentService;
+    use crate::reconciliation::ReconciliationService;
+    use crate::shipping::ShipmentService;
+    use crate::subscriptions::SubscriptionService;
+    use crate::telegram_auth::{TelegramAuth, INIT_DATA_HEADER};
+    use crate::telegram_notifications::TelegramNotifier;
+    use crate::ton_connect::{TonConnectConfig, TonConnectService};
+    use crate::ton_payment::TonProcessor;
+    use crate::toncenter::ToncenterClient;
+
+    const BOT_TOKEN: &str = "123456:TEST-token";
+    // initData пользователя 42 (не администратор), подпись - как в тестах telegram_auth
+    const USER_INIT_DATA: &str = "auth_date=4102444800&query_id=AAH&user=%7B%22id%22%3A42%2C%22first_name%22%3A%22%D0%98%D0%B2%D0%B0%D0%BD%22%2C%22username%22%3A%22ivan_petrov%22%2C%22language_code%22%3A%22ru%22%7D&hash=ea8257c52458c2ea9905bf2ceb21b54abc1c0a58c00e2d76340e57f61227fef6";
+
+    // Состояние приложения на пустой базе в памяти: бот не запускается, фоновых задач нет
+    async fn app_state() -> web::Data<AppState> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::database::run_migrations(&pool).await.unwrap();
+
+        std::env::set_var("TON_API_URL", "http://127.0.0.1:9");
+        std::env::set_var("TON_API_KEY", "test");
+        let loyalty = Arc::new(LoyaltyProgram::new(pool.clone(), LoyaltyConfig::from_env()));
+        let templates = Arc::new(NotificationTemplates::load("templates/notifications", "ru").unwrap());
+        let telegram_notifier = Arc::new(TelegramNotifier::new(
+            BOT_TOKEN.to_string(),
+            -100,
+            pool.clone(),
+            loyalty.clone(),
+            templates,
+        ));
+        let toncenter = ToncenterClient::from_env();
+        let merchant_wallet = "UQCbShhQNTKUd3GvKJsBxeiwLHuJghq9r7FQrkC5mSOfLXgy".to_string();
+
+        web::Data::new(AppState {
+            db_pool: pool.clone(),
+            ton_processor: Arc::new(TonProcessor::new(pool.clone()).unwrap()),
+            telegram_notifier: telegram_notifier.clone(),
+            telegram_auth: Arc::new(TelegramAuth::new(BOT_TOKEN)),
+            loyalty: loyalty.clone(),
+            subscriptions: Arc::new(SubscriptionService::new(pool.clone(), telegram_notifier.clone(), loyalty)),
+            shipments: Arc::new(ShipmentService::new(pool.clone(), telegram_notifier.clone())),
+            payments: Arc::new(PaymentService::new(pool.clone())),
+            ton_connect: Arc::new(TonConnectService::new(
+                pool.clone(),
+                toncenter.clone(),
+                TonConnectConfig::from_env(merchant_wallet.clone()),
+            )),
+            reconciliation: Arc::new(ReconciliationService::new(pool.clone(), toncenter, merchant_wallet, None)),
+            outbox: Arc::new(NotificationOutbox::new(pool, telegram_notifier, OutboxConfig::from_env())),
+            telegram_webhook_secret: None,
+        })
+    }
+
+    async fn product(state: &AppState, price: f64) -> i64 {
+        sqlx::query_scalar::<_, i64>(
+            "INSERT INTO products (name, description, price, stock, image_url) VALUES ('Мяч', '', ?, 10, '') RETURNING id"
+        )
+            .bind(price)
+            .fetch_one(&state.db_pool)
+            .await
+            .unwrap()
+    }
+
+    fn as_customer(request: test::TestRequest) -> test::TestRequest {
+        request.insert_header((INIT_DATA_HEADER, USER_INIT_DATA))
+    }
+
+    async fn promotions_count(state: &AppState) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM promotions")
+            .fetch_one(&state.db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn promotions_require_admin() {
+        let state = app_state().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(list_promotions)
+                .service(create_promotion)
+                .service(deactivate_promotion),
+        ).await;
+        let promotion = json!({ "code": "FREE", "discount_type": "percentage", "discount_value": 100.0 });
+
+        let anonymous = [
+            test::TestRequest::get().uri("/promotions").to_request(),
+            test::TestRequest::post().uri("/promotions").set_json(&promotion).to_request(),
+            test::TestRequest::delete().uri("/promotions/1").to_request(),
+        ];
+        for request in anonymous {
+            assert_eq!(test::call_service(&app, request).await.status(), 401);
+        }
+
+        let customer = test::TestRequest::post()
+            .uri("/promotions")
+            .insert_header((INIT_DATA_HEADER, USER_INIT_DATA))
+            .set_json(&promotion)
+            .to_request();
+        assert_eq!(test::call_service(&app, customer).await.status(), 403);
+
+        assert_eq!(promotions_count(&state).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn order_requires_delivery_choice_when_methods_are_configured() {
+        let state = app_state().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .service(add_to_cart)
+                .service(create_order),
+        ).await;
+        let product_id = product(&state, 10.0).await;
+        let added = as_customer(test::TestRequest::post().uri("/cart"))
+            .set_json(json!({ "product_id": product_id, "quantity": 1 }))
+            .to_request();
+        assert!(test::call_service(&app, added).await.status().is_success());
+
+        let without_delivery = as_customer(test::TestRequest::post().uri("/orders"))
+            .set_json(json!({ "user_id": 42, "delivery_address": "Москва, ул. Спортивная, 1" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, without_delivery).await.status(), 400);
+
+        // Курьер по Москве - первые способ и зона из начальных данных
+        let with_delivery = as_customer(test::TestRequest::post().uri("/orders"))
+            .set_json(json!({
+                "user_id": 42,
+                "delivery_address": "Москва, ул. Спортивная, 1",
+                "delivery_method_id": 1,
+                "delivery_zone_id": 1
+            }))
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, with_delivery).await;
+        assert_eq!(response["total_amount"], json!(13.0));
+    }
+
+    #[actix_web::test]
+    async fn bundle_components_require_admin_and_block_component_delete() {
+        let state = app_state().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(set_bundle_components)
+                .service(delete_product),
+        ).await;
+        let bundle_id = product(&state, 30.0).await;
+        let component_id = product(&state, 10.0).await;
+        let components = json!([{ "product_id": component_id, "quantity": 2 }]);
+
+        let anonymous = test::TestRequest::put()
+            .uri(&format!("/products/{}/components", bundle_id))
+            .set_json(&components)
+            .to_request();
+        assert_eq!(test::call_service(&app, anonymous).await.status(), 401);
+        let customer = as_customer(test::TestRequest::put().uri(&format!("/products/{}/components", bundle_id)))
+            .set_json(&components)
+            .to_request();
+        assert_eq!(test::call_service(&app, customer).await.status(), 403);
+        assert!(bundles::components(&state.db_pool, bundle_id).await.unwrap().is_empty());
+
+        bundles::set_components(
+            &state.db_pool,
+            bundle_id,
+            &[bundles::BundleComponentInput { product_id: component_id, quantity: 2 }],
+        ).await.unwrap();
+
+        let delete_component = test::TestRequest::delete()
+            .uri(&format!("/products/{}", component_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, delete_component).await.status(), 409);
+        assert_eq!(bundles::components(&state.db_pool, bundle_id).await.unwrap().len(), 1);
+
+        // Набор удаляется вместе с составом, после чего компонент уже свободен
+        let delete_bundle = test::TestRequest::delete()
+            .uri(&format!("/products/{}", bundle_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, delete_bundle).await.status(), 204);
+        let delete_component = test::TestRequest::delete()
+            .uri(&format!("/products/{}", component_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, delete_component).await.status(), 204);
+    }
+
+    #[actix_web::test]
+    async fn deleted_product_stays_in_cart_with_warning() {
+        let state = app_state().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .service(add_to_cart)
+                .service(delete_product)
+                .service(validate_cart),
+        ).await;
+        let kept_id = product(&state, 10.0).await;
+        let deleted_id = product(&state, 20.0).await;
+        for product_id in [kept_id, deleted_id] {
+            let added = as_customer(test::TestRequest::post().uri("/cart"))
+                .set_json(json!({ "product_id": product_id, "quantity": 1 }))
+                .to_request();
+            assert!(test::call_service(&app, added).await.status().is_success());
+        }
+
+        let deleted = test::TestRequest::delete()
+            .uri(&format!("/products/{}", deleted_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, deleted).await.status(), 204);
+
+        let validation: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            as_customer(test::TestRequest::post().uri("/cart/validate")).to_request(),
+        ).await;
+        assert_eq!(validation["valid"], json!(false));
+        assert_eq!(validation["total_amount"], json!(10.0));
+        let items = validation["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1]["product_id"], json!(null));
+        assert_eq!(items[1]["warnings"], json!(["product_deleted"]));
+
+        // Проверка с apply убрала позицию удаленного товара
+        let validation: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            as_customer(test::TestRequest::post().uri("/cart/validate")).to_request(),
+        ).await;
+        assert_eq!(validation["valid"], json!(true));
+        assert_eq!(validation["items"].as_array().unwrap().len(), 1);
+    }
+}
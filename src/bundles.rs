@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool, Transaction};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Product {0} not found")]
+    ProductNotFound(i64),
+    #[error("Product {0} is a bundle and cannot be a component")]
+    NestedBundle(i64),
+    #[error("Bundle cannot contain itself")]
+    SelfReference,
+    #[error("Component quantity must be at least 1")]
+    InvalidQuantity,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BundleComponentInput {
+    pub product_id: i64,
+    pub quantity: i32,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct BundleComponent {
+    pub product_id: i64,
+    pub name: String,
+    pub quantity: i32,
+    pub stock: i32,
+}
+
+// Состав набора. Читается и из пула, и внутри транзакции оформления заказа
+pub async fn components<'e, E>(executor: E, bundle_id: i64) -> Result<Vec<BundleComponent>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_as::<_, BundleComponent>(
+        r#"
+        SELECT bc.component_id AS product_id, p.name, bc.quantity, p.stock
+        FROM bundle_components bc
+        JOIN products p ON p.id = bc.component_id
+        WHERE bc.bundle_id = ?
+        ORDER BY p.name
+        "#
+    )
+        .bind(bundle_id)
+        .fetch_all(executor)
+        .await
+}
+
+// Задает состав набора. Пустой список превращает набор обратно в обычный товар.
+pub async fn set_components(
+    pool: &SqlitePool,
+    bundle_id: i64,
+    items: &[BundleComponentInput],
+) -> Result<(), BundleError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM products WHERE id = ?)")
+        .bind(bundle_id)
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Err(BundleError::ProductNotFound(bundle_id));
+    }
+
+    for item in items {
+        if item.quantity < 1 {
+            return Err(BundleError::InvalidQuantity);
+        }
+        if item.product_id == bundle_id {
+            return Err(BundleError::SelfReference);
+        }
+        let is_bundle = sqlx::query_scalar::<_, bool>("SELECT is_bundle FROM products WHERE id = ?")
+            .bind(item.product_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(BundleError::ProductNotFound(item.product_id))?;
+        if is_bundle {
+            return Err(BundleError::NestedBundle(item.product_id));
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM bundle_components WHERE bundle_id = ?")
+        .bind(bundle_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for item in items {
+        sqlx::query(
+            r#"
+            INSERT INTO bundle_components (bundle_id, component_id, quantity) VALUES (?, ?, ?)
+            ON CONFLICT(bundle_id, component_id) DO UPDATE SET quantity = quantity + excluded.quantity
+            "#
+        )
+            .bind(bundle_id)
+            .bind(item.product_id)
+            .bind(item.quantity)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    sqlx::query("UPDATE products SET is_bundle = ? WHERE id = ?")
+        .bind(!items.is_empty())
+        .bind(bundle_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+// Списывает остаток при оформлении заказа. Для набора списываются компоненты.
+// Возвращает false, если какого-то товара не хватает (транзакцию нужно откатить).
+pub async fn decrement_stock(
+    tx: &mut Transaction<'_, Sqlite>,
+    product_id: i64,
+    quantity: i32,
+) -> Result<bool, sqlx::Error> {
+    let is_bundle = sqlx::query_scalar::<_, bool>("SELECT is_bundle FROM products WHERE id = ?")
+        .bind(product_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .unwrap_or(false);
+
+    let targets = if is_bundle {
+        sqlx::query_as::<_, (i64, i32)>(
+            "SELECT component_id, quantity * ? FROM bundle_components WHERE bundle_id = ?"
+        )
+            .bind(quantity)
+            .bind(product_id)
+            .fetch_all(&mut **tx)
+            .await?
+    } else {
+        vec![(product_id, quantity)]
+    };
+
+    if targets.is_empty() {
+        return Ok(false);
+    }
+
+    for (target_id, target_quantity) in targets {
+        let result = sqlx::query("UPDATE products SET stock = stock - ? WHERE id = ? AND stock >= ?")
+            .bind(target_quantity)
+            .bind(target_id)
+            .bind(target_quantity)
+            .execute(&mut **tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Возвращает на склад остатки, зарезервированные заказом (для наборов - компоненты).
+// Вызывается в транзакции, которая переводит заказ из статуса с резервом, поэтому срабатывает один раз.
+pub async fn restock_order(tx: &mut Transaction<'_, Sqlite>, order_id: i64) -> Result<(), sqlx::Error> {
+    // Строки набора не списывались сами: их остатки - в дочерних строках компонентов
+    sqlx::query(
+        r#"
+        UPDATE products SET stock = stock + (
+            SELECT SUM(oi.quantity) FROM order_items oi
+            WHERE oi.order_id = ? AND oi.product_id = products.id
+              AND NOT EXISTS (SELECT 1 FROM order_items c WHERE c.parent_item_id = oi.id)
+        )
+        WHERE id IN (
+            SELECT oi.product_id FROM order_items oi
+            WHERE oi.order_id = ?
+              AND NOT EXISTS (SELECT 1 FROM order_items c WHERE c.parent_item_id = oi.id)
+        )
+        "#
+    )
+        .bind(order_id)
+        .bind(order_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+// Сохраняет состав набора в заказе дочерними строками order_items
+pub async fn record_order_components(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    order_item_id: i64,
+    bundle_id: i64,
+    quantity: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO order_items (order_id, product_id, quantity, price, parent_item_id)
+        SELECT ?, component_id, quantity * ?, 0, ? FROM bundle_components WHERE bundle_id = ?
+        "#
+    )
+        .bind(order_id)
+        .bind(quantity)
+        .bind(order_item_id)
+        .bind(bundle_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
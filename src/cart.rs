@@ -0,0 +1,340 @@
+use actix_session::Session;
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+use thiserror::Error;
+use uuid::Uuid;
+use crate::telegram_auth::WebAppUser;
+
+#[derive(Error, Debug)]
+pub enum CartError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Session error: {0}")]
+    SessionError(String),
+    #[error("Товар не найден")]
+    ProductNotFound,
+    #[error("Недостаточно товара на складе")]
+    InsufficientStock(i32),
+}
+
+impl CartError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        matches!(self, CartError::ProductNotFound | CartError::InsufficientStock(_))
+    }
+}
+
+// Определяет корзину текущего покупателя.
+// Для пользователя Telegram корзина общая на всех устройствах; анонимная корзина
+// сессии при первом авторизованном запросе переносится в неё.
+// Анонимному покупателю при create = false новая корзина не заводится и может вернуться None.
+pub async fn resolve_cart_id(
+    pool: &SqlitePool,
+    session: &Session,
+    user: Option<&WebAppUser>,
+    create: bool,
+) -> Result<Option<i64>, CartError> {
+    let session_id = match session.get::<String>("session_id") {
+        Ok(Some(id)) => Some(id),
+        Ok(None) if create && user.is_none() => {
+            let new_id = Uuid::new_v4().to_string();
+            session.insert("session_id", &new_id)
+                .map_err(|e| CartError::SessionError(e.to_string()))?;
+            Some(new_id)
+        }
+        Ok(None) => None,
+        Err(e) => return Err(CartError::SessionError(e.to_string())),
+    };
+
+    let anonymous_cart_id = match &session_id {
+        Some(session_id) => sqlx::query_scalar::<_, i64>(
+            "SELECT id FROM carts WHERE session_id = ? AND telegram_user_id IS NULL"
+        )
+            .bind(session_id)
+            .fetch_optional(pool)
+            .await?,
+        None => None,
+    };
+
+    let user = match user {
+        Some(user) => user,
+        None => {
+            if anonymous_cart_id.is_some() || !create {
+                return Ok(anonymous_cart_id);
+            }
+            let cart_id = sqlx::query_scalar::<_, i64>(
+                "INSERT INTO carts (session_id) VALUES (?) RETURNING id"
+            )
+                .bind(&session_id)
+                .fetch_one(pool)
+                .await?;
+            return Ok(Some(cart_id));
+        }
+    };
+
+    let user_cart_id = user_cart_id(pool, user.id).await?;
+
+    if let Some(anonymous_cart_id) = anonymous_cart_id {
+        merge_carts(pool, anonymous_cart_id, user_cart_id).await?;
+        println!("🛒 Анонимная корзина {} объединена с корзиной пользователя {}", anonymous_cart_id, user.id);
+    }
+
+    Ok(Some(user_cart_id))
+}
+
+// Корзина пользователя Telegram (общая для WebApp и бота), создается при необходимости
+pub async fn user_cart_id(pool: &SqlitePool, telegram_user_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO carts (telegram_user_id) VALUES (?)
+        ON CONFLICT(telegram_user_id) DO UPDATE SET updated_at = CURRENT_TIMESTAMP
+        RETURNING id
+        "#
+    )
+        .bind(telegram_user_id)
+        .fetch_one(pool)
+        .await
+}
+
+#[derive(Debug)]
+pub struct AddedItem {
+    // Количество товара в корзине после добавления
+    pub quantity: i32,
+    // Позиция создана, а не увеличена существующая
+    pub created: bool,
+}
+
+// Добавляет товар в корзину с проверкой остатка и фиксацией текущей цены
+pub async fn add_item(
+    pool: &SqlitePool,
+    cart_id: i64,
+    product_id: i64,
+    quantity: i32,
+) -> Result<AddedItem, CartError> {
+    let (price, stock) = sqlx::query_as::<_, (f64, i32)>(
+        "SELECT p.price, s.available_stock FROM products p JOIN product_stock s ON s.product_id = p.id WHERE p.id = ?"
+    )
+        .bind(product_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(CartError::ProductNotFound)?;
+
+    let existing = sqlx::query_as::<_, (i64, i32)>(
+        "SELECT id, quantity FROM cart WHERE product_id = ? AND cart_id = ?"
+    )
+        .bind(product_id)
+        .bind(cart_id)
+        .fetch_optional(pool)
+        .await?;
+
+    match existing {
+        Some((item_id, current)) => {
+            let new_quantity = current + quantity;
+            if new_quantity > stock {
+                return Err(CartError::InsufficientStock(stock));
+            }
+            sqlx::query("UPDATE cart SET quantity = ?, price_at_add = ? WHERE id = ?")
+                .bind(new_quantity)
+                .bind(price)
+                .bind(item_id)
+                .execute(pool)
+                .await?;
+            Ok(AddedItem { quantity: new_quantity, created: false })
+        }
+        None => {
+            if quantity > stock {
+                return Err(CartError::InsufficientStock(stock));
+            }
+            sqlx::query("INSERT INTO cart (cart_id, product_id, quantity, price_at_add) VALUES (?, ?, ?, ?)")
+                .bind(cart_id)
+                .bind(product_id)
+                .bind(quantity)
+                .bind(price)
+                .execute(pool)
+                .await?;
+            Ok(AddedItem { quantity, created: true })
+        }
+    }
+}
+
+// Переносит позиции из корзины from в корзину into и удаляет from
+async fn merge_carts(pool: &SqlitePool, from: i64, into: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // WHERE true нужен SQLite для разбора INSERT ... SELECT ... ON CONFLICT
+    sqlx::query(
+        r#"
+        INSERT INTO cart (cart_id, product_id, quantity, price_at_add)
+        SELECT ?, product_id, quantity, price_at_add FROM cart WHERE cart_id = ? AND true
+        ON CONFLICT(cart_id, product_id) DO UPDATE SET quantity = quantity + excluded.quantity
+        "#
+    )
+        .bind(into)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM cart WHERE cart_id = ?")
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT OR IGNORE INTO cart_promotions (cart_id, promotion_id, applied_at) SELECT ?, promotion_id, applied_at FROM cart_promotions WHERE cart_id = ?"
+    )
+        .bind(into)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM cart_promotions WHERE cart_id = ?")
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM carts WHERE id = ?")
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE carts SET updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(into)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CartWarning {
+    PriceChanged,
+    OutOfStock,
+    InsufficientStock,
+    ProductDeleted,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartLineValidation {
+    pub item_id: i64,
+    // Пусто, если товар удален из каталога
+    pub product_id: Option<i64>,
+    pub name: Option<String>,
+    pub quantity: i32,
+    pub capped_quantity: i32,
+    pub price_at_add: Option<f64>,
+    pub current_price: Option<f64>,
+    pub available_stock: i32,
+    pub warnings: Vec<CartWarning>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartValidation {
+    pub valid: bool,
+    pub items: Vec<CartLineValidation>,
+    pub total_amount: f64,
+}
+
+#[derive(Debug, FromRow)]
+struct CartLineRow {
+    id: i64,
+    product_id: Option<i64>,
+    quantity: i32,
+    price_at_add: Option<f64>,
+    name: Option<String>,
+    price: Option<f64>,
+    stock: Option<i32>,
+}
+
+// Сверяет корзину с текущими ценами и остатками.
+// При apply = true корзина приводится в порядок: цены фиксируются текущие,
+// количество урезается до остатка, позиции удаленных товаров убираются.
+pub async fn validate_cart(
+    pool: &SqlitePool,
+    cart_id: i64,
+    apply: bool,
+) -> Result<CartValidation, sqlx::Error> {
+    let rows = sqlx::query_as::<_, CartLineRow>(
+        r#"
+        SELECT c.id, c.product_id, c.quantity, c.price_at_add, p.name, p.price, s.available_stock AS stock
+        FROM cart c
+        LEFT JOIN products p ON c.product_id = p.id
+        LEFT JOIN product_stock s ON c.product_id = s.product_id
+        WHERE c.cart_id = ?
+        ORDER BY c.id
+        "#
+    )
+        .bind(cart_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut items = Vec::with_capacity(rows.len());
+    let mut total_amount = 0.0;
+
+    for row in rows {
+        let mut warnings = Vec::new();
+        let available_stock = row.stock.unwrap_or(0).max(0);
+
+        let capped_quantity = match row.price {
+            None => {
+                warnings.push(CartWarning::ProductDeleted);
+                0
+            }
+            Some(price) => {
+                if let Some(price_at_add) = row.price_at_add {
+                    if (price - price_at_add).abs() > 0.001 {
+                        warnings.push(CartWarning::PriceChanged);
+                    }
+                }
+                if available_stock == 0 {
+                    warnings.push(CartWarning::OutOfStock);
+                } else if row.quantity > available_stock {
+                    warnings.push(CartWarning::InsufficientStock);
+                }
+                let capped = row.quantity.clamp(0, available_stock);
+                total_amount += price * capped as f64;
+                capped
+            }
+        };
+
+        items.push(CartLineValidation {
+            item_id: row.id,
+            product_id: row.product_id,
+            name: row.name,
+            quantity: row.quantity,
+            capped_quantity,
+            price_at_add: row.price_at_add,
+            current_price: row.price,
+            available_stock,
+            warnings,
+        });
+    }
+
+    let valid = items.iter().all(|item| item.warnings.is_empty());
+
+    if apply && !valid {
+        let mut tx = pool.begin().await?;
+        for item in &items {
+            if item.capped_quantity == 0 {
+                sqlx::query("DELETE FROM cart WHERE id = ?")
+                    .bind(item.item_id)
+                    .execute(&mut *tx)
+                    .await?;
+            } else {
+                sqlx::query("UPDATE cart SET quantity = ?, price_at_add = ? WHERE id = ?")
+                    .bind(item.capped_quantity)
+                    .bind(item.current_price)
+                    .bind(item.item_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+    }
+
+    Ok(CartValidation {
+        valid,
+        items,
+        total_amount,
+    })
+}
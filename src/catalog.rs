@@ -0,0 +1,111 @@
+use sqlx::{FromRow, SqlitePool};
+use crate::models::Product;
+
+// Те же поля, что отдает API каталога
+const PRODUCT_COLUMNS: &str = r#"
+    id,
+    name,
+    description,
+    price,
+    (SELECT available_stock FROM product_stock WHERE product_id = products.id) as stock,
+    image_url,
+    category_id,
+    is_bundle,
+    strftime('%Y-%m-%d %H:%M:%S', created_at) as created_at
+"#;
+
+#[derive(Debug, Clone, FromRow)]
+pub struct CatalogCategory {
+    pub id: i64,
+    pub name: String,
+    pub products: i64,
+}
+
+// Категории, в которых есть товары
+pub async fn categories(pool: &SqlitePool) -> Result<Vec<CatalogCategory>, sqlx::Error> {
+    sqlx::query_as::<_, CatalogCategory>(
+        r#"
+        SELECT c.id, c.name, COUNT(p.id) AS products
+        FROM categories c
+        JOIN products p ON p.category_id = c.id
+        GROUP BY c.id, c.name
+        ORDER BY c.name
+        "#
+    )
+        .fetch_all(pool)
+        .await
+}
+
+// Товар категории по порядковому номеру и общее число товаров в категории
+pub async fn product_at(
+    pool: &SqlitePool,
+    category_id: i64,
+    index: i64,
+) -> Result<Option<(Product, i64)>, sqlx::Error> {
+    let total = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM products WHERE category_id = ?")
+        .bind(category_id)
+        .fetch_one(pool)
+        .await?;
+    if total == 0 {
+        return Ok(None);
+    }
+
+    let product = sqlx::query_as::<_, Product>(&format!(
+        "SELECT {} FROM products WHERE category_id = ? ORDER BY id LIMIT 1 OFFSET ?",
+        PRODUCT_COLUMNS
+    ))
+        .bind(category_id)
+        .bind(index.clamp(0, total - 1))
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(product.map(|product| (product, total)))
+}
+
+// Значение для поисковых колонок name_search и description_search
+pub fn search_key(text: &str) -> String {
+    text.to_lowercase()
+}
+
+// Заполняет поисковые колонки товаров, добавленных в обход API (миграциями или до появления колонок)
+pub async fn backfill_search(pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let products = sqlx::query_as::<_, (i64, String, Option<String>)>(
+        "SELECT id, name, description FROM products WHERE name_search IS NULL"
+    )
+        .fetch_all(pool)
+        .await?;
+
+    for (id, name, description) in &products {
+        sqlx::query("UPDATE products SET name_search = ?, description_search = ? WHERE id = ?")
+            .bind(search_key(name))
+            .bind(description.as_deref().map(search_key))
+            .bind(id)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(products.len())
+}
+
+// Поиск товаров по названию и описанию без учета регистра (для inline-режима бота).
+// Пустой запрос возвращает последние добавленные товары.
+pub async fn search(pool: &SqlitePool, query: &str, limit: i64) -> Result<Vec<Product>, sqlx::Error> {
+    let pattern = format!(
+        "%{}%",
+        search_key(query.trim()).replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+    );
+
+    sqlx::query_as::<_, Product>(&format!(
+        r#"
+        SELECT {} FROM products
+        WHERE name_search LIKE ?1 ESCAPE '\' OR description_search LIKE ?1 ESCAPE '\'
+        ORDER BY name_search LIKE ?1 ESCAPE '\' DESC, id DESC
+        LIMIT ?2
+        "#,
+        PRODUCT_COLUMNS
+    ))
+        .bind(pattern)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
@@ -0,0 +1,297 @@
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+use thiserror::Error;
+use crate::addresses::DeliveryAddress;
+use crate::bundles;
+use crate::delivery::DeliveryQuote;
+use crate::loyalty::{LoyaltyError, LoyaltyProgram, PointsRedemption};
+use crate::notification_templates::{
+    self, DeliveryLine, DiscountLine, Notification, NotificationTemplates, OrderLine, TemplateError,
+};
+use crate::outbox::{self, OutboxMessage};
+use crate::payments::PAYMENT_TOLERANCE;
+use crate::promotions::{self, DiscountSummary, PromotionError};
+use crate::store_credit::{self, StoreCreditError};
+use crate::telegram_notifications::CartItemData;
+
+#[derive(Error, Debug)]
+pub enum CheckoutError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Loyalty error: {0}")]
+    LoyaltyError(#[from] LoyaltyError),
+    #[error("Template error: {0}")]
+    TemplateError(#[from] TemplateError),
+    #[error(transparent)]
+    PromotionError(#[from] PromotionError),
+    #[error(transparent)]
+    StoreCreditError(#[from] StoreCreditError),
+    #[error("Нет товаров для заказа")]
+    Empty,
+    #[error("Товар \"{0}\" закончился")]
+    OutOfStock(String),
+}
+
+// Строка заказа по текущей цене товара
+#[derive(Debug, FromRow)]
+pub struct CheckoutLine {
+    pub product_id: i64,
+    pub quantity: i32,
+    pub name: String,
+    pub price: f64,
+    pub is_bundle: bool,
+}
+
+pub struct CheckoutRequest<'a> {
+    pub user_id: i64,
+    // Пользователь, подтвержденный через initData; нужен для промокодов с лимитом на пользователя
+    pub verified_user_id: Option<i64>,
+    pub lines: &'a [CheckoutLine],
+    pub discounts: &'a DiscountSummary,
+    pub redemption: PointsRedemption,
+    // Сколько TON списать с баланса покупателя (зачисленные переплаты)
+    pub store_credit: f64,
+    pub delivery: Option<&'a DeliveryQuote>,
+    pub delivery_address: &'a str,
+    pub address_details: Option<&'a DeliveryAddress>,
+}
+
+pub struct PlacedOrder {
+    pub order_id: i64,
+    pub total_amount: f64,
+}
+
+pub async fn cart_lines(pool: &SqlitePool, cart_id: i64) -> Result<Vec<CheckoutLine>, sqlx::Error> {
+    sqlx::query_as::<_, CheckoutLine>(
+        r#"
+        SELECT c.product_id, c.quantity, p.name, p.price, p.is_bundle
+        FROM cart c
+        JOIN products p ON c.product_id = p.id
+        WHERE c.cart_id = ?
+        "#
+    )
+        .bind(cart_id)
+        .fetch_all(pool)
+        .await
+}
+
+// Оформление заказа: резерв остатков, запись заказа, скидок, баллов и позиций и постановка
+// подтверждения с кнопкой оплаты в очередь уведомлений - все в одной транзакции.
+// Используется и корзиной WebApp, и подписками.
+pub async fn place_order(
+    pool: &SqlitePool,
+    loyalty: &LoyaltyProgram,
+    templates: &NotificationTemplates,
+    request: &CheckoutRequest<'_>,
+) -> Result<PlacedOrder, CheckoutError> {
+    let mut tx = pool.begin().await?;
+    let placed = place_order_in(&mut tx, loyalty, templates, request).await?;
+    tx.commit().await?;
+
+    println!("✅ Заказ №{} успешно создан на сумму {:.2} TON", placed.order_id, placed.total_amount);
+
+    Ok(placed)
+}
+
+// То же внутри транзакции вызывающего: подписка фиксирует заказ вместе со своим графиком.
+// При ошибке транзакцию нужно откатить.
+pub async fn place_order_in(
+    tx: &mut Transaction<'_, Sqlite>,
+    loyalty: &LoyaltyProgram,
+    templates: &NotificationTemplates,
+    request: &CheckoutRequest<'_>,
+) -> Result<PlacedOrder, CheckoutError> {
+    if request.lines.is_empty() {
+        return Err(CheckoutError::Empty);
+    }
+
+    let delivery_cost = request.delivery.map(|delivery| delivery.cost).unwrap_or(0.0);
+    let total_amount = ((request.discounts.total_amount - request.redemption.discount - request.store_credit
+        + delivery_cost) * 100.0).round() / 100.0;
+    // Заказ, целиком оплаченный балансом, сразу считается оплаченным
+    let status = if total_amount <= PAYMENT_TOLERANCE { "paid" } else { "pending" };
+
+    // Резервируем остатки (для наборов - остатки компонентов)
+    for line in request.lines {
+        if !bundles::decrement_stock(tx, line.product_id, line.quantity).await? {
+            return Err(CheckoutError::OutOfStock(line.name.clone()));
+        }
+    }
+
+    // Создаем новый заказ
+    let order_id = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO orders (
+            user_id, total_amount, status, delivery_address, discount_amount, points_redeemed, points_discount,
+            delivery_method_id, delivery_zone_id, delivery_cost, delivery_address_details, store_credit_applied
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        RETURNING id
+        "#
+    )
+        .bind(request.user_id)
+        .bind(total_amount)
+        .bind(status)
+        .bind(request.delivery_address)
+        .bind(request.discounts.discount_amount)
+        .bind(request.redemption.points)
+        .bind(request.redemption.discount)
+        .bind(request.delivery.map(|delivery| delivery.method_id))
+        .bind(request.delivery.map(|delivery| delivery.zone_id))
+        .bind(delivery_cost)
+        .bind(request.address_details.and_then(|address| serde_json::to_string(address).ok()))
+        .bind(request.store_credit)
+        .fetch_one(&mut **tx)
+        .await?;
+
+    // Сохраняем разбивку скидок в заказе
+    promotions::record_order_discounts(tx, order_id, request.verified_user_id, request.discounts).await?;
+
+    // Списываем бонусные баллы; если их уже не хватает, заказ не создается
+    loyalty.redeem(tx, request.user_id, order_id, request.redemption.points).await?;
+
+    // Списываем баланс покупателя; он тоже перечитывается внутри транзакции
+    store_credit::spend(tx, request.user_id, order_id, request.store_credit).await?;
+
+    // Добавляем товары в order_items
+    let mut items = Vec::with_capacity(request.lines.len());
+    for line in request.lines {
+        let order_item_id = sqlx::query_scalar::<_, i64>(
+            r#"
+            INSERT INTO order_items (order_id, product_id, quantity, price)
+            VALUES (?, ?, ?, ?)
+            RETURNING id
+            "#
+        )
+            .bind(order_id)
+            .bind(line.product_id)
+            .bind(line.quantity)
+            .bind(line.price)
+            .fetch_one(&mut **tx)
+            .await?;
+
+        // Для набора сохраняем его состав
+        let components = if line.is_bundle {
+            bundles::record_order_components(tx, order_id, order_item_id, line.product_id, line.quantity).await?;
+            bundles::components(&mut **tx, line.product_id).await?
+        } else {
+            Vec::new()
+        };
+
+        items.push(CartItemData {
+            product_id: line.product_id,
+            quantity: line.quantity,
+            name: line.name.clone(),
+            price: line.price,
+            components,
+        });
+    }
+
+    // Подтверждение с кнопкой оплаты уйдет из очереди, даже если Telegram сейчас недоступен.
+    // Текст - на языке покупателя из Telegram
+    let language = notification_templates::user_language(&mut **tx, request.user_id).await?;
+    let text = confirmation_text(templates, language.as_deref(), &items, request, total_amount)?;
+    outbox::enqueue(tx, &OutboxMessage::OrderConfirmation {
+        order_id,
+        user_id: request.user_id,
+        text,
+    }).await?;
+    if status == "paid" {
+        outbox::enqueue(tx, &OutboxMessage::AdminNewOrder { order_id, username: None }).await?;
+    }
+
+    Ok(PlacedOrder {
+        order_id,
+        total_amount,
+    })
+}
+
+// Отмена заказов, не оплаченных за ttl_hours: резерв остатков снимается, потраченные баллы возвращаются.
+// Заказы с зачтенными или ожидающими подтверждения платежами не отменяются. Возвращает ID отмененных заказов.
+pub async fn expire_unpaid_orders(
+    pool: &SqlitePool,
+    loyalty: &LoyaltyProgram,
+    ttl_hours: i64,
+) -> Result<Vec<i64>, CheckoutError> {
+    let order_ids = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT o.id FROM orders o
+        WHERE o.status = 'pending' AND o.created_at <= datetime('now', ?)
+          AND NOT EXISTS (
+              SELECT 1 FROM payments p
+              WHERE p.order_id = CAST(o.id AS TEXT) AND p.status IN ('pending', 'confirmed')
+          )
+        "#
+    )
+        .bind(format!("-{} hours", ttl_hours))
+        .fetch_all(pool)
+        .await?;
+
+    let mut expired = Vec::new();
+    for order_id in order_ids {
+        let mut tx = pool.begin().await?;
+        let result = sqlx::query("UPDATE orders SET status = 'cancelled' WHERE id = ? AND status = 'pending'")
+            .bind(order_id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            // Заказ успели оплатить или отменить
+            tx.rollback().await?;
+            continue;
+        }
+
+        bundles::restock_order(&mut tx, order_id).await?;
+        loyalty.reverse_for_order(&mut tx, order_id).await?;
+        store_credit::reverse_for_order(&mut tx, order_id).await?;
+        tx.commit().await?;
+        expired.push(order_id);
+    }
+
+    Ok(expired)
+}
+
+// Текст подтверждения заказа на языке покупателя
+fn confirmation_text(
+    templates: &NotificationTemplates,
+    language_code: Option<&str>,
+    items: &[CartItemData],
+    request: &CheckoutRequest<'_>,
+    total_amount: f64,
+) -> Result<String, TemplateError> {
+    let mut lines = Vec::new();
+    for item in items {
+        lines.push(OrderLine { name: item.name.clone(), quantity: item.quantity, component: false });
+        for component in &item.components {
+            lines.push(OrderLine {
+                name: component.name.clone(),
+                quantity: component.quantity * item.quantity,
+                component: true,
+            });
+        }
+    }
+
+    let discounts = request
+        .discounts
+        .discounts
+        .iter()
+        .map(|discount| DiscountLine {
+            code: discount.code.clone(),
+            amount: discount.amount,
+            free_delivery: discount.free_delivery,
+        })
+        .collect::<Vec<_>>();
+    let delivery = request.delivery.map(|delivery| DeliveryLine {
+        name: delivery.name.clone(),
+        cost: delivery.cost,
+        free: delivery.free,
+    });
+
+    templates.render(language_code, &Notification::OrderConfirmation {
+        lines: &lines,
+        discounts: &discounts,
+        points_discount: request.redemption.discount,
+        store_credit: request.store_credit,
+        delivery: delivery.as_ref(),
+        address: request.delivery_address,
+        total: total_amount,
+    })
+}
@@ -0,0 +1,469 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DeliveryError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Способ доставки не найден")]
+    MethodNotFound,
+    #[error("Зона доставки не найдена")]
+    ZoneNotFound,
+    #[error("Способ доставки \"{0}\" недоступен в выбранной зоне")]
+    NotAvailable(String),
+    #[error("Некорректные параметры доставки: {0}")]
+    Invalid(String),
+    #[error("\"{0}\" уже существует")]
+    Duplicate(String),
+}
+
+impl DeliveryError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, DeliveryError::DbError(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryKind {
+    Courier,
+    Pickup,
+    Post,
+    // Самовывоз со стойки зала по одноразовому коду
+    SelfPickup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum RateType {
+    // Фиксированная стоимость для каждой зоны
+    Zone,
+    // Базовая стоимость плюс тариф за каждый начатый килограмм
+    Weight,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct DeliveryMethod {
+    pub id: i64,
+    pub code: String,
+    pub name: String,
+    pub kind: DeliveryKind,
+    pub rate_type: RateType,
+    pub base_cost: f64,
+    pub cost_per_kg: f64,
+    pub free_threshold: Option<f64>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct DeliveryZone {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryQuote {
+    pub method_id: i64,
+    pub zone_id: i64,
+    pub code: String,
+    pub name: String,
+    pub kind: DeliveryKind,
+    // Стоимость по тарифу до применения бесплатной доставки
+    pub base_cost: f64,
+    pub cost: f64,
+    pub free: bool,
+}
+
+const METHOD_COLUMNS: &str =
+    "id, code, name, kind, rate_type, base_cost, cost_per_kg, free_threshold";
+
+pub async fn list_methods(pool: &SqlitePool) -> Result<Vec<DeliveryMethod>, sqlx::Error> {
+    sqlx::query_as::<_, DeliveryMethod>(&format!(
+        "SELECT {} FROM delivery_methods WHERE active = TRUE ORDER BY id",
+        METHOD_COLUMNS
+    ))
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn list_zones(pool: &SqlitePool) -> Result<Vec<DeliveryZone>, sqlx::Error> {
+    sqlx::query_as::<_, DeliveryZone>("SELECT id, name FROM delivery_zones WHERE active = TRUE ORDER BY id")
+        .fetch_all(pool)
+        .await
+}
+
+// Настроена ли доставка: при активных способах заказ без выбора способа и зоны не оформляется
+pub async fn is_configured(pool: &SqlitePool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM delivery_methods WHERE active = TRUE)")
+        .fetch_one(pool)
+        .await
+}
+
+// Суммарный вес товаров в корзине, в граммах
+pub async fn cart_weight(pool: &SqlitePool, cart_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, Option<i64>>(
+        r#"
+        SELECT SUM(c.quantity * p.weight_grams)
+        FROM cart c
+        JOIN products p ON p.id = c.product_id
+        WHERE c.cart_id = ?
+        "#
+    )
+        .bind(cart_id)
+        .fetch_one(pool)
+        .await
+        .map(|weight| weight.unwrap_or(0))
+}
+
+async fn method_cost(
+    pool: &SqlitePool,
+    method: &DeliveryMethod,
+    zone_id: i64,
+    weight_grams: i64,
+) -> Result<Option<f64>, sqlx::Error> {
+    match method.rate_type {
+        RateType::Zone => {
+            sqlx::query_scalar::<_, f64>("SELECT cost FROM delivery_rates WHERE method_id = ? AND zone_id = ?")
+                .bind(method.id)
+                .bind(zone_id)
+                .fetch_optional(pool)
+                .await
+        }
+        RateType::Weight => {
+            let kilograms = (weight_grams.max(0) as f64 / 1000.0).ceil();
+            Ok(Some(method.base_cost + method.cost_per_kg * kilograms))
+        }
+    }
+}
+
+fn build_quote(method: &DeliveryMethod, zone_id: i64, cost: f64, order_total: f64, free_delivery: bool) -> DeliveryQuote {
+    let free = free_delivery
+        || method
+            .free_threshold
+            .map(|threshold| order_total >= threshold)
+            .unwrap_or(false);
+
+    DeliveryQuote {
+        method_id: method.id,
+        zone_id,
+        code: method.code.clone(),
+        name: method.name.clone(),
+        kind: method.kind,
+        base_cost: cost,
+        cost: if free { 0.0 } else { (cost * 100.0).round() / 100.0 },
+        free,
+    }
+}
+
+async fn ensure_zone(pool: &SqlitePool, zone_id: i64) -> Result<(), DeliveryError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM delivery_zones WHERE id = ? AND active = TRUE)")
+        .bind(zone_id)
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Err(DeliveryError::ZoneNotFound);
+    }
+    Ok(())
+}
+
+// Стоимость выбранного способа доставки.
+// order_total - сумма товаров после скидок, free_delivery - результат промоакций.
+pub async fn quote(
+    pool: &SqlitePool,
+    method_id: i64,
+    zone_id: i64,
+    weight_grams: i64,
+    order_total: f64,
+    free_delivery: bool,
+) -> Result<DeliveryQuote, DeliveryError> {
+    ensure_zone(pool, zone_id).await?;
+
+    let method = sqlx::query_as::<_, DeliveryMethod>(&format!(
+        "SELECT {} FROM delivery_methods WHERE id = ? AND active = TRUE",
+        METHOD_COLUMNS
+    ))
+        .bind(method_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(DeliveryError::MethodNotFound)?;
+
+    let cost = method_cost(pool, &method, zone_id, weight_grams)
+        .await?
+        .ok_or_else(|| DeliveryError::NotAvailable(method.name.clone()))?;
+
+    Ok(build_quote(&method, zone_id, cost, order_total, free_delivery))
+}
+
+// Все способы доставки, доступные в зоне, с расчетом стоимости
+pub async fn quotes(
+    pool: &SqlitePool,
+    zone_id: i64,
+    weight_grams: i64,
+    order_total: f64,
+    free_delivery: bool,
+) -> Result<Vec<DeliveryQuote>, DeliveryError> {
+    ensure_zone(pool, zone_id).await?;
+
+    let mut result = Vec::new();
+    for method in list_methods(pool).await? {
+        if let Some(cost) = method_cost(pool, &method, zone_id, weight_grams).await? {
+            result.push(build_quote(&method, zone_id, cost, order_total, free_delivery));
+        }
+    }
+    Ok(result)
+}
+
+// Настройка доставки администратором
+
+#[derive(Debug, Deserialize)]
+pub struct SaveDeliveryMethod {
+    pub code: String,
+    pub name: String,
+    pub kind: DeliveryKind,
+    #[serde(default = "default_rate_type")]
+    pub rate_type: RateType,
+    #[serde(default)]
+    pub base_cost: f64,
+    #[serde(default)]
+    pub cost_per_kg: f64,
+    #[serde(default)]
+    pub free_threshold: Option<f64>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveDeliveryZone {
+    pub name: String,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct DeliveryRate {
+    pub method_id: i64,
+    pub zone_id: i64,
+    pub cost: f64,
+}
+
+fn default_rate_type() -> RateType {
+    RateType::Zone
+}
+
+fn default_active() -> bool {
+    true
+}
+
+fn validate_method(method: &SaveDeliveryMethod) -> Result<(), DeliveryError> {
+    let code = method.code.trim();
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(DeliveryError::Invalid(
+            "code must contain only latin letters, digits, '_' and '-'".to_string(),
+        ));
+    }
+    if method.name.trim().is_empty() {
+        return Err(DeliveryError::Invalid("name is required".to_string()));
+    }
+    if method.base_cost < 0.0 || method.cost_per_kg < 0.0 || method.free_threshold.is_some_and(|t| t < 0.0) {
+        return Err(DeliveryError::Invalid("costs must not be negative".to_string()));
+    }
+    Ok(())
+}
+
+// Нарушение уникальности code способа или name зоны
+fn duplicate_or(error: sqlx::Error, value: &str) -> DeliveryError {
+    match error.as_database_error() {
+        Some(db_error) if db_error.is_unique_violation() => DeliveryError::Duplicate(value.to_string()),
+        _ => DeliveryError::DbError(error),
+    }
+}
+
+pub async fn create_method(pool: &SqlitePool, method: &SaveDeliveryMethod) -> Result<i64, DeliveryError> {
+    validate_method(method)?;
+
+    sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO delivery_methods (code, name, kind, rate_type, base_cost, cost_per_kg, free_threshold, active)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        RETURNING id
+        "#
+    )
+        .bind(method.code.trim())
+        .bind(method.name.trim())
+        .bind(method.kind)
+        .bind(method.rate_type)
+        .bind(method.base_cost)
+        .bind(method.cost_per_kg)
+        .bind(method.free_threshold)
+        .bind(method.active)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| duplicate_or(e, method.code.trim()))
+}
+
+pub async fn update_method(pool: &SqlitePool, method_id: i64, method: &SaveDeliveryMethod) -> Result<(), DeliveryError> {
+    validate_method(method)?;
+
+    let result = sqlx::query(
+        r#"
+        UPDATE delivery_methods
+        SET code = ?, name = ?, kind = ?, rate_type = ?, base_cost = ?, cost_per_kg = ?, free_threshold = ?, active = ?
+        WHERE id = ?
+        "#
+    )
+        .bind(method.code.trim())
+        .bind(method.name.trim())
+        .bind(method.kind)
+        .bind(method.rate_type)
+        .bind(method.base_cost)
+        .bind(method.cost_per_kg)
+        .bind(method.free_threshold)
+        .bind(method.active)
+        .bind(method_id)
+        .execute(pool)
+        .await
+        .map_err(|e| duplicate_or(e, method.code.trim()))?;
+
+    if result.rows_affected() == 0 {
+        return Err(DeliveryError::MethodNotFound);
+    }
+    Ok(())
+}
+
+pub async fn create_zone(pool: &SqlitePool, zone: &SaveDeliveryZone) -> Result<i64, DeliveryError> {
+    let name = zone.name.trim();
+    if name.is_empty() {
+        return Err(DeliveryError::Invalid("name is required".to_string()));
+    }
+
+    sqlx::query_scalar::<_, i64>("INSERT INTO delivery_zones (name, active) VALUES (?, ?) RETURNING id")
+        .bind(name)
+        .bind(zone.active)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| duplicate_or(e, name))
+}
+
+pub async fn update_zone(pool: &SqlitePool, zone_id: i64, zone: &SaveDeliveryZone) -> Result<(), DeliveryError> {
+    let name = zone.name.trim();
+    if name.is_empty() {
+        return Err(DeliveryError::Invalid("name is required".to_string()));
+    }
+
+    let result = sqlx::query("UPDATE delivery_zones SET name = ?, active = ? WHERE id = ?")
+        .bind(name)
+        .bind(zone.active)
+        .bind(zone_id)
+        .execute(pool)
+        .await
+        .map_err(|e| duplicate_or(e, name))?;
+
+    if result.rows_affected() == 0 {
+        return Err(DeliveryError::ZoneNotFound);
+    }
+    Ok(())
+}
+
+pub async fn list_rates(pool: &SqlitePool) -> Result<Vec<DeliveryRate>, sqlx::Error> {
+    sqlx::query_as::<_, DeliveryRate>("SELECT method_id, zone_id, cost FROM delivery_rates ORDER BY method_id, zone_id")
+        .fetch_all(pool)
+        .await
+}
+
+// Стоимость способа с тарифом по зонам в зоне; существующий тариф заменяется
+pub async fn set_rate(pool: &SqlitePool, rate: &DeliveryRate) -> Result<(), DeliveryError> {
+    if rate.cost < 0.0 {
+        return Err(DeliveryError::Invalid("cost must not be negative".to_string()));
+    }
+
+    let rate_type = sqlx::query_scalar::<_, RateType>("SELECT rate_type FROM delivery_methods WHERE id = ?")
+        .bind(rate.method_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(DeliveryError::MethodNotFound)?;
+    if rate_type != RateType::Zone {
+        return Err(DeliveryError::Invalid("rates are used only by methods with rate_type = zone".to_string()));
+    }
+
+    let zone_exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM delivery_zones WHERE id = ?)")
+        .bind(rate.zone_id)
+        .fetch_one(pool)
+        .await?;
+    if !zone_exists {
+        return Err(DeliveryError::ZoneNotFound);
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO delivery_rates (method_id, zone_id, cost) VALUES (?, ?, ?)
+        ON CONFLICT(method_id, zone_id) DO UPDATE SET cost = excluded.cost
+        "#
+    )
+        .bind(rate.method_id)
+        .bind(rate.zone_id)
+        .bind(rate.cost)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// Удаление тарифа: способ становится недоступен в зоне
+pub async fn delete_rate(pool: &SqlitePool, method_id: i64, zone_id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM delivery_rates WHERE method_id = ? AND zone_id = ?")
+        .bind(method_id)
+        .bind(zone_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    // Курьер по Москве из начальных данных: 3 TON, бесплатно от 50 TON
+    const COURIER: i64 = 1;
+    const MOSCOW: i64 = 1;
+    const POST: i64 = 3;
+
+    // База в памяти живет, пока открыто единственное соединение
+    async fn pool() -> SqlitePool {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::database::run_migrations(&pool).await.unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn delivery_is_free_from_threshold() {
+        let pool = pool().await;
+
+        let below = quote(&pool, COURIER, MOSCOW, 1000, 49.99, false).await.unwrap();
+        assert!(!below.free);
+        assert_eq!(below.cost, 3.0);
+
+        let at_threshold = quote(&pool, COURIER, MOSCOW, 1000, 50.0, false).await.unwrap();
+        assert!(at_threshold.free);
+        assert_eq!(at_threshold.cost, 0.0);
+        assert_eq!(at_threshold.base_cost, 3.0);
+
+        // Промокод на бесплатную доставку действует и ниже порога
+        let promo = quote(&pool, COURIER, MOSCOW, 1000, 10.0, true).await.unwrap();
+        assert!(promo.free);
+        assert_eq!(promo.cost, 0.0);
+
+        // Почта без порога: платная при любой сумме
+        let post = quote(&pool, POST, MOSCOW, 1000, 100.0, false).await.unwrap();
+        assert!(!post.free);
+        assert_eq!(post.cost, 2.0);
+    }
+}
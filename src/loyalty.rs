@@ -0,0 +1,584 @@
+use chrono::{Months, NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool, Transaction};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LoyaltyError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Order not found")]
+    OrderNotFound,
+    #[error("Недостаточно баллов для списания")]
+    InsufficientPoints,
+}
+
+impl LoyaltyError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        matches!(self, LoyaltyError::InsufficientPoints)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoyaltyConfig {
+    // Сколько баллов начисляется за 1 TON оплаченного заказа
+    pub points_per_ton: f64,
+    // Стоимость одного балла в TON при списании
+    pub point_value: f64,
+    // Максимальная доля заказа, которую можно оплатить баллами, в процентах
+    pub max_redeem_percent: f64,
+    // Срок жизни начисленных баллов
+    pub expiry_months: u32,
+}
+
+impl LoyaltyConfig {
+    pub fn from_env() -> Self {
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            points_per_ton: env_or("LOYALTY_POINTS_PER_TON", 10.0),
+            point_value: env_or("LOYALTY_POINT_VALUE", 0.01),
+            max_redeem_percent: env_or("LOYALTY_MAX_REDEEM_PERCENT", 30.0),
+            expiry_months: env_or("LOYALTY_EXPIRY_MONTHS", 12),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PointsBalance {
+    pub user_id: i64,
+    pub points: i64,
+    pub value: f64,
+    pub next_expiration: Option<NaiveDateTime>,
+    pub points_expiring: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PointsRedemption {
+    pub points: i64,
+    pub discount: f64,
+}
+
+#[derive(Debug, FromRow)]
+struct OpenAccrual {
+    id: i64,
+    remaining: i64,
+}
+
+pub struct LoyaltyProgram {
+    db_pool: SqlitePool,
+    config: LoyaltyConfig,
+}
+
+impl LoyaltyProgram {
+    pub fn new(db_pool: SqlitePool, config: LoyaltyConfig) -> Self {
+        Self { db_pool, config }
+    }
+
+    pub fn config(&self) -> &LoyaltyConfig {
+        &self.config
+    }
+
+    fn expiry_date(&self) -> NaiveDateTime {
+        let now = Utc::now().naive_utc();
+        now.checked_add_months(Months::new(self.config.expiry_months))
+            .unwrap_or(now)
+    }
+
+    // Доступный баланс: несписанные и несгоревшие остатки начислений
+    pub async fn balance(&self, user_id: i64) -> Result<PointsBalance, LoyaltyError> {
+        let now = Utc::now().naive_utc();
+        let points = available_points(&self.db_pool, user_id).await?;
+
+        let next = sqlx::query_as::<_, (NaiveDateTime, i64)>(
+            r#"
+            SELECT expires_at, SUM(remaining) FROM loyalty_ledger
+            WHERE user_id = ? AND remaining > 0 AND expires_at > ?
+            GROUP BY expires_at
+            ORDER BY expires_at
+            LIMIT 1
+            "#
+        )
+            .bind(user_id)
+            .bind(now)
+            .fetch_optional(&self.db_pool)
+            .await?;
+
+        Ok(PointsBalance {
+            user_id,
+            points,
+            value: points as f64 * self.config.point_value,
+            next_expiration: next.map(|(expires_at, _)| expires_at),
+            points_expiring: next.map(|(_, points)| points).unwrap_or(0),
+        })
+    }
+
+    // Сколько баллов реально можно списать с учетом баланса и ограничения на долю заказа
+    pub async fn redeemable_points(
+        &self,
+        user_id: i64,
+        requested: i64,
+        order_total: f64,
+    ) -> Result<PointsRedemption, LoyaltyError> {
+        if requested <= 0 || order_total <= 0.0 || self.config.point_value <= 0.0 {
+            return Ok(PointsRedemption { points: 0, discount: 0.0 });
+        }
+
+        let balance = self.balance(user_id).await?.points;
+        let max_discount = order_total * self.config.max_redeem_percent.clamp(0.0, 100.0) / 100.0;
+        let max_points = (max_discount / self.config.point_value).floor() as i64;
+        let points = requested.min(balance).min(max_points).max(0);
+
+        Ok(PointsRedemption {
+            points,
+            discount: (points as f64 * self.config.point_value * 100.0).round() / 100.0,
+        })
+    }
+
+    // Списание баллов в счет заказа (сначала самые ранние по сроку сгорания) в транзакции оформления заказа.
+    // Если баллов уже не хватает, возвращает InsufficientPoints - заказ с такой скидкой создавать нельзя
+    pub async fn redeem(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        order_id: i64,
+        points: i64,
+    ) -> Result<(), LoyaltyError> {
+        if points <= 0 {
+            return Ok(());
+        }
+
+        // Баланс перечитывается в транзакции заказа: посчитанный до нее мог уже уйти на другой заказ
+        if available_points(&mut **tx, user_id).await? < points {
+            return Err(LoyaltyError::InsufficientPoints);
+        }
+
+        if take_points(tx, user_id, points).await? > 0 {
+            return Err(LoyaltyError::InsufficientPoints);
+        }
+
+        sqlx::query(
+            "INSERT INTO loyalty_ledger (user_id, order_id, kind, points) VALUES (?, ?, 'redemption', ?)"
+        )
+            .bind(user_id)
+            .bind(order_id)
+            .bind(-points)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    // Начисление баллов за выполненный заказ. Возвращает число начисленных баллов.
+    pub async fn accrue_for_order(&self, order_id: i64) -> Result<i64, LoyaltyError> {
+        let (user_id, total_amount) = sqlx::query_as::<_, (i64, f64)>(
+            "SELECT user_id, total_amount FROM orders WHERE id = ?"
+        )
+            .bind(order_id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(LoyaltyError::OrderNotFound)?;
+
+        let points = (total_amount * self.config.points_per_ton).floor() as i64;
+        if points <= 0 {
+            return Ok(0);
+        }
+
+        let mut tx = self.db_pool.begin().await?;
+        let accrual_id = sqlx::query_scalar::<_, i64>(
+            r#"
+            INSERT OR IGNORE INTO loyalty_ledger (user_id, order_id, kind, points, remaining, expires_at)
+            VALUES (?, ?, 'accrual', ?, ?, ?)
+            RETURNING id
+            "#
+        )
+            .bind(user_id)
+            .bind(order_id)
+            .bind(points)
+            .bind(points)
+            .bind(self.expiry_date())
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        // Повторное завершение заказа баллы не удваивает
+        let Some(accrual_id) = accrual_id else {
+            return Ok(0);
+        };
+        repay_debt(&mut tx, user_id, accrual_id).await?;
+        tx.commit().await?;
+
+        Ok(points)
+    }
+
+    // Отмена баллов при возврате или отмене заказа в транзакции смены его статуса: начисление за заказ
+    // аннулируется целиком, потраченные на заказ баллы возвращаются пользователю. Повторный вызов ничего не меняет.
+    pub async fn reverse_for_order(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        order_id: i64,
+    ) -> Result<(), LoyaltyError> {
+        // Сгоревшая часть начисления уже списана записью expiration, ее не трогаем
+        let accrual = sqlx::query_as::<_, (i64, i64, i64, i64)>(
+            r#"
+            SELECT a.id, a.user_id, a.remaining,
+                   a.points - a.remaining + COALESCE(
+                       (SELECT SUM(e.points) FROM loyalty_ledger e WHERE e.order_id = a.order_id AND e.kind = 'expiration'),
+                       0
+                   )
+            FROM loyalty_ledger a
+            WHERE a.order_id = ? AND a.kind = 'accrual'
+              AND NOT EXISTS (SELECT 1 FROM loyalty_ledger r WHERE r.order_id = a.order_id AND r.kind = 'reversal')
+            "#
+        )
+            .bind(order_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+        if let Some((accrual_id, user_id, remaining, spent)) = accrual {
+            sqlx::query("UPDATE loyalty_ledger SET remaining = 0 WHERE id = ?")
+                .bind(accrual_id)
+                .execute(&mut **tx)
+                .await?;
+            // Уже потраченную часть начисления забираем из других остатков, а чего не хватило -
+            // записываем долгом (отрицательный остаток), который погасят следующие начисления
+            let debt = take_points(tx, user_id, spent).await?;
+            sqlx::query(
+                "INSERT INTO loyalty_ledger (user_id, order_id, kind, points, remaining) VALUES (?, ?, 'reversal', ?, ?)"
+            )
+                .bind(user_id)
+                .bind(order_id)
+                .bind(-(remaining + spent))
+                .bind(-debt)
+                .execute(&mut **tx)
+                .await?;
+        }
+
+        // Потраченные баллы возвращаются один раз
+        let refunded = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM loyalty_ledger WHERE order_id = ? AND kind = 'refund')"
+        )
+            .bind(order_id)
+            .fetch_one(&mut **tx)
+            .await?;
+        if refunded {
+            return Ok(());
+        }
+
+        let redeemed = sqlx::query_as::<_, (i64, i64)>(
+            "SELECT user_id, -SUM(points) FROM loyalty_ledger WHERE order_id = ? AND kind = 'redemption' GROUP BY user_id"
+        )
+            .bind(order_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+        if let Some((user_id, points)) = redeemed {
+            if points > 0 {
+                let refund_id = sqlx::query_scalar::<_, i64>(
+                    r#"
+                    INSERT INTO loyalty_ledger (user_id, order_id, kind, points, remaining, expires_at)
+                    VALUES (?, ?, 'refund', ?, ?, ?)
+                    RETURNING id
+                    "#
+                )
+                    .bind(user_id)
+                    .bind(order_id)
+                    .bind(points)
+                    .bind(points)
+                    .bind(self.expiry_date())
+                    .fetch_one(&mut **tx)
+                    .await?;
+                repay_debt(tx, user_id, refund_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Сжигание просроченных остатков. Возвращает число обработанных начислений.
+    pub async fn expire_points(&self) -> Result<u64, LoyaltyError> {
+        let mut tx = self.db_pool.begin().await?;
+        let now = Utc::now().naive_utc();
+
+        sqlx::query(
+            r#"
+            INSERT INTO loyalty_ledger (user_id, order_id, kind, points)
+            SELECT user_id, order_id, 'expiration', -remaining FROM loyalty_ledger
+            WHERE remaining > 0 AND expires_at IS NOT NULL AND expires_at <= ?
+            "#
+        )
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+
+        let result = sqlx::query(
+            "UPDATE loyalty_ledger SET remaining = 0 WHERE remaining > 0 AND expires_at IS NOT NULL AND expires_at <= ?"
+        )
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+}
+
+// Несписанные и несгоревшие баллы пользователя за вычетом долга. Читается и из пула, и внутри транзакции заказа
+async fn available_points<'e, E>(executor: E, user_id: i64) -> Result<i64, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar::<_, Option<i64>>(
+        r#"
+        SELECT SUM(remaining) FROM loyalty_ledger
+        WHERE user_id = ? AND (remaining < 0 OR (remaining > 0 AND (expires_at IS NULL OR expires_at > ?)))
+        "#
+    )
+        .bind(user_id)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(executor)
+        .await
+        .map(|points| points.unwrap_or(0))
+}
+
+
+// Списание из открытых остатков: сначала самые ранние по сроку сгорания, бессрочные - последними.
+// Возвращает, сколько баллов списать не удалось
+async fn take_points(tx: &mut Transaction<'_, Sqlite>, user_id: i64, points: i64) -> Result<i64, sqlx::Error> {
+    if points <= 0 {
+        return Ok(0);
+    }
+
+    let accruals = sqlx::query_as::<_, OpenAccrual>(
+        r#"
+        SELECT id, remaining FROM loyalty_ledger
+        WHERE user_id = ? AND remaining > 0 AND (expires_at IS NULL OR expires_at > ?)
+        ORDER BY expires_at IS NULL, expires_at, id
+        "#
+    )
+        .bind(user_id)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&mut **tx)
+        .await?;
+
+    let mut left = points;
+    for accrual in accruals {
+        if left == 0 {
+            break;
+        }
+        let take = left.min(accrual.remaining);
+        sqlx::query("UPDATE loyalty_ledger SET remaining = remaining - ? WHERE id = ?")
+            .bind(take)
+            .bind(accrual.id)
+            .execute(&mut **tx)
+            .await?;
+        left -= take;
+    }
+
+    Ok(left)
+}
+
+// Новое начисление сначала гасит долг пользователя, оставшийся после отмены уже потраченных баллов
+async fn repay_debt(tx: &mut Transaction<'_, Sqlite>, user_id: i64, accrual_id: i64) -> Result<(), sqlx::Error> {
+    let debts = sqlx::query_as::<_, OpenAccrual>(
+        "SELECT id, remaining FROM loyalty_ledger WHERE user_id = ? AND remaining < 0 ORDER BY id"
+    )
+        .bind(user_id)
+        .fetch_all(&mut **tx)
+        .await?;
+    if debts.is_empty() {
+        return Ok(());
+    }
+
+    let mut available = sqlx::query_scalar::<_, i64>("SELECT remaining FROM loyalty_ledger WHERE id = ?")
+        .bind(accrual_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    for debt in debts {
+        if available == 0 {
+            break;
+        }
+        let repay = available.min(-debt.remaining);
+        sqlx::query("UPDATE loyalty_ledger SET remaining = remaining + ? WHERE id = ?")
+            .bind(repay)
+            .bind(debt.id)
+            .execute(&mut **tx)
+            .await?;
+        available -= repay;
+    }
+
+    sqlx::query("UPDATE loyalty_ledger SET remaining = ? WHERE id = ?")
+        .bind(available)
+        .bind(accrual_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    const USER_ID: i64 = 7;
+
+    // База в памяти живет, пока открыто единственное соединение
+    async fn program() -> LoyaltyProgram {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::database::run_migrations(&pool).await.unwrap();
+        LoyaltyProgram::new(pool, LoyaltyConfig {
+            points_per_ton: 10.0,
+            point_value: 0.01,
+            max_redeem_percent: 30.0,
+            expiry_months: 12,
+        })
+    }
+
+    async fn order(program: &LoyaltyProgram, total_amount: f64) -> i64 {
+        sqlx::query_scalar::<_, i64>(
+            "INSERT INTO orders (user_id, total_amount, status, delivery_address) VALUES (?, ?, 'pending', '') RETURNING id"
+        )
+            .bind(USER_ID)
+            .bind(total_amount)
+            .fetch_one(&program.db_pool)
+            .await
+            .unwrap()
+    }
+
+    async fn redeem(program: &LoyaltyProgram, order_id: i64, points: i64) -> Result<(), LoyaltyError> {
+        let mut tx = program.db_pool.begin().await.unwrap();
+        program.redeem(&mut tx, USER_ID, order_id, points).await?;
+        tx.commit().await.unwrap();
+        Ok(())
+    }
+
+    async fn points(program: &LoyaltyProgram) -> i64 {
+        program.balance(USER_ID).await.unwrap().points
+    }
+
+    #[tokio::test]
+    async fn redeem_fails_when_points_already_spent() {
+        let program = program().await;
+        let paid = order(&program, 10.0).await;
+        assert_eq!(program.accrue_for_order(paid).await.unwrap(), 100);
+
+        // Обе корзины посчитали списание по балансу 100, но списать его можно только один раз
+        redeem(&program, order(&program, 5.0).await, 80).await.unwrap();
+        let second = order(&program, 5.0).await;
+        assert!(matches!(redeem(&program, second, 80).await, Err(LoyaltyError::InsufficientPoints)));
+
+        assert_eq!(points(&program).await, 20);
+        let redeemed: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM loyalty_ledger WHERE order_id = ?")
+            .bind(second)
+            .fetch_one(&program.db_pool)
+            .await
+            .unwrap();
+        assert_eq!(redeemed, 0);
+    }
+    async fn reverse(program: &LoyaltyProgram, order_id: i64) {
+        let mut tx = program.db_pool.begin().await.unwrap();
+        program.reverse_for_order(&mut tx, order_id).await.unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reversal_claws_back_points_spent_on_other_orders() {
+        let program = program().await;
+        let refunded = order(&program, 10.0).await;
+        program.accrue_for_order(refunded).await.unwrap();
+        redeem(&program, order(&program, 5.0).await, 80).await.unwrap();
+
+        // Из начисления 100 осталось 20; отменяются все 100, и 80 уходят в долг
+        reverse(&program, refunded).await;
+        assert_eq!(points(&program).await, -80);
+        // Повторная отмена долг не удваивает
+        reverse(&program, refunded).await;
+        assert_eq!(points(&program).await, -80);
+        assert!(matches!(redeem(&program, order(&program, 5.0).await, 1).await, Err(LoyaltyError::InsufficientPoints)));
+
+        // Следующее начисление сначала гасит долг
+        let next = order(&program, 15.0).await;
+        program.accrue_for_order(next).await.unwrap();
+        assert_eq!(points(&program).await, 70);
+        redeem(&program, order(&program, 5.0).await, 70).await.unwrap();
+        assert_eq!(points(&program).await, 0);
+    }
+
+    #[tokio::test]
+    async fn reversal_takes_spent_points_from_other_accruals() {
+        let program = program().await;
+        let earlier = order(&program, 5.0).await;
+        program.accrue_for_order(earlier).await.unwrap();
+        let refunded = order(&program, 10.0).await;
+        program.accrue_for_order(refunded).await.unwrap();
+        // 50 списываются с первого начисления, еще 30 - с отменяемого
+        redeem(&program, order(&program, 5.0).await, 80).await.unwrap();
+        let later = order(&program, 5.0).await;
+        program.accrue_for_order(later).await.unwrap();
+
+        // Потраченные 30 забираются из нового начисления, долга не остается
+        reverse(&program, refunded).await;
+        assert_eq!(points(&program).await, 20);
+        let debt: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(remaining), 0) FROM loyalty_ledger WHERE remaining < 0")
+            .fetch_one(&program.db_pool)
+            .await
+            .unwrap();
+        assert_eq!(debt, 0);
+    }
+
+    async fn expire_in_days(program: &LoyaltyProgram, order_id: i64, days: i64) {
+        sqlx::query("UPDATE loyalty_ledger SET expires_at = ? WHERE order_id = ? AND kind = 'accrual'")
+            .bind(Utc::now().naive_utc() + chrono::Duration::days(days))
+            .bind(order_id)
+            .execute(&program.db_pool)
+            .await
+            .unwrap();
+    }
+
+    async fn remaining(program: &LoyaltyProgram, order_id: i64) -> i64 {
+        sqlx::query_scalar("SELECT remaining FROM loyalty_ledger WHERE order_id = ? AND kind = 'accrual'")
+            .bind(order_id)
+            .fetch_one(&program.db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redemption_takes_earliest_expiring_points_first() {
+        let program = program().await;
+        let later = order(&program, 5.0).await;
+        program.accrue_for_order(later).await.unwrap();
+        let sooner = order(&program, 10.0).await;
+        program.accrue_for_order(sooner).await.unwrap();
+        let sooner_still = order(&program, 2.0).await;
+        program.accrue_for_order(sooner_still).await.unwrap();
+        // Порядок сгорания не совпадает с порядком начисления
+        expire_in_days(&program, later, 90).await;
+        expire_in_days(&program, sooner, 30).await;
+        expire_in_days(&program, sooner_still, 10).await;
+
+        let balance = program.balance(USER_ID).await.unwrap();
+        assert_eq!(balance.points, 170);
+        assert_eq!(balance.points_expiring, 20);
+
+        // 20 сгорающих первыми, затем 100 следующих и 10 из самых поздних
+        redeem(&program, order(&program, 5.0).await, 130).await.unwrap();
+        assert_eq!(remaining(&program, sooner_still).await, 0);
+        assert_eq!(remaining(&program, sooner).await, 0);
+        assert_eq!(remaining(&program, later).await, 40);
+
+        let balance = program.balance(USER_ID).await.unwrap();
+        assert_eq!(balance.points, 40);
+        assert_eq!(balance.points_expiring, 40);
+    }
+}
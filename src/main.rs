@@ -8,9 +8,22 @@ use actix_session::storage::CookieSessionStore;
 use actix_web::cookie::Key;
 use sqlx::{Pool, Sqlite};
 use crate::telegram_notifications::TelegramNotifier;
-use crate::ton_payment::TonProcessor;
+use crate::ton_payment::{JettonConfig, TonProcessor};
 use crate::telegram_bot::TelegramBot;
+use crate::telegram_auth::TelegramAuth;
+use crate::loyalty::{LoyaltyConfig, LoyaltyProgram};
+use crate::subscriptions::SubscriptionService;
+use crate::payments::{
+    JettonTransferProvider, PaymentService, Settlement, TelegramInvoiceConfig, TelegramInvoiceProvider, TonTransferProvider,
+};
+use crate::ton_connect::{TonConnectConfig, TonConnectService};
+use crate::toncenter::ToncenterClient;
+use crate::outbox::{NotificationOutbox, OutboxConfig};
+use crate::notification_templates::NotificationTemplates;
+use crate::reconciliation::ReconciliationService;
+use crate::shipping::{LinkOnlyTracker, MockCarrierTracker, ShipmentService};
 use std::sync::Arc;
+use teloxide::Bot;
 
 mod api;
 mod models;
@@ -18,11 +31,43 @@ mod database;
 mod ton_payment;
 mod telegram_notifications;
 mod telegram_bot;
+mod telegram_auth;
+mod cart;
+mod promotions;
+mod loyalty;
+mod bundles;
+mod checkout;
+mod subscriptions;
+mod delivery;
+mod addresses;
+mod shipping;
+mod pickup;
+mod support;
+mod admin_actions;
+mod catalog;
+mod payments;
+mod ton_connect;
+mod ton_boc;
+mod toncenter;
+mod reconciliation;
+mod outbox;
+mod store_credit;
+mod notification_templates;
 
 pub struct AppState {
     db_pool: Pool<Sqlite>,
     ton_processor: Arc<TonProcessor>,
     telegram_notifier: Arc<TelegramNotifier>,
+    telegram_auth: Arc<TelegramAuth>,
+    loyalty: Arc<LoyaltyProgram>,
+    subscriptions: Arc<SubscriptionService>,
+    shipments: Arc<ShipmentService>,
+    payments: Arc<PaymentService>,
+    ton_connect: Arc<TonConnectService>,
+    reconciliation: Arc<ReconciliationService>,
+    outbox: Arc<NotificationOutbox>,
+    // secret_token вебхука Telegram (setWebhook), без него /api/telegram-webhook отклоняет запросы
+    telegram_webhook_secret: Option<String>,
 }
 
 async fn serve_cart() -> impl Responder {
@@ -48,6 +93,13 @@ async fn main() -> std::io::Result<()> {
     let pool = database::init_db().await
         .expect("Failed to initialize database");
 
+    // Поисковые колонки для товаров, добавленных в обход API
+    match catalog::backfill_search(&pool).await {
+        Ok(0) => {}
+        Ok(count) => println!("🔎 Заполнены поисковые поля для {} товаров", count),
+        Err(e) => eprintln!("Ошибка заполнения поисковых полей товаров: {:?}", e),
+    }
+
     // Получаем токен бота и ID админ-чата
     let bot_token = std::env::var("TELEGRAM_BOT_TOKEN")
         .expect("TELEGRAM_BOT_TOKEN must be set");
@@ -58,23 +110,240 @@ async fn main() -> std::io::Result<()> {
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
     // Инициализируем компоненты
+    let loyalty = Arc::new(LoyaltyProgram::new(pool.clone(), LoyaltyConfig::from_env()));
+
+    // Администраторы, которым доступны кнопки карточки заказа
+    let admin_ids = admin_actions::parse_admin_ids(&std::env::var("ADMIN_USER_IDS").unwrap_or_default());
+    if admin_ids.is_empty() {
+        eprintln!("⚠️ ADMIN_USER_IDS не задан: действия с заказами из чата администраторов недоступны");
+    }
+
+    // Чат администраторов - форум: каждое обращение ведется в своей теме
+    let admin_forum_topics = std::env::var("ADMIN_FORUM_TOPICS")
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+
+    // Шаблоны уведомлений по языкам: проверяются при запуске, с ошибкой в шаблоне сервер не стартует
+    let templates_dir = std::env::var("NOTIFICATION_TEMPLATES_DIR")
+        .unwrap_or_else(|_| "templates/notifications".to_string());
+    let default_locale = std::env::var("NOTIFICATION_DEFAULT_LOCALE").unwrap_or_else(|_| "ru".to_string());
+    let templates = Arc::new(NotificationTemplates::load(&templates_dir, &default_locale)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?);
+
+    // Способы оплаты: перевод TON по ссылке, счета Telegram Payments (если задан токен провайдера) и звезды
+    let mut payment_service = PaymentService::new(pool.clone());
+    let merchant_wallet = std::env::var("MERCHANT_WALLET")
+        .unwrap_or_else(|_| "UQCbShhQNTKUd3GvKJsBxeiwLHuJghq9r7FQrkC5mSOfLXgy".to_string());
+    payment_service.register(Arc::new(TonTransferProvider::new(
+        Bot::new(&bot_token),
+        merchant_wallet.clone(),
+        templates.clone(),
+    )));
+    // Оплата жетонами (USDT), если задан кошелек жетонов магазина
+    let jetton = JettonConfig::from_env();
+    if let Some(jetton) = &jetton {
+        payment_service.register(Arc::new(JettonTransferProvider::new(
+            Bot::new(&bot_token),
+            merchant_wallet.clone(),
+            jetton.clone(),
+            templates.clone(),
+        )));
+    }
+    if let Some(config) = TelegramInvoiceConfig::from_env() {
+        payment_service.register_invoices(Arc::new(TelegramInvoiceProvider::card(Bot::new(&bot_token), config)));
+    }
+    if let Some(config) = TelegramInvoiceConfig::stars_from_env() {
+        payment_service.register_invoices(Arc::new(TelegramInvoiceProvider::stars(Bot::new(&bot_token), config)));
+    }
+    // Переплата по умолчанию зачисляется на баланс покупателя, OVERPAYMENT_POLICY=refund - только отмечается к возврату
+    if std::env::var("OVERPAYMENT_POLICY").map(|policy| policy != "refund").unwrap_or(true) {
+        payment_service.credit_overpayments();
+    }
+    let payments = Arc::new(payment_service);
+
+    // Оплата через TON Connect в WebApp: кошелек подтверждается ton_proof
+    let toncenter = ToncenterClient::from_env();
+    let ton_connect = Arc::new(TonConnectService::new(
+        pool.clone(),
+        toncenter.clone(),
+        TonConnectConfig::from_env(merchant_wallet.clone()),
+    ));
+
+    // Сверка входящих переводов с оплатами заказов
+    let reconciliation = Arc::new(ReconciliationService::new(
+        pool.clone(),
+        toncenter.clone(),
+        merchant_wallet,
+        jetton.clone(),
+    ));
+
     let telegram_notifier = Arc::new(TelegramNotifier::new(
         bot_token.clone(),
         admin_chat_id,
         pool.clone(),
+        loyalty.clone(),
+        templates,
+    )
+        .with_forum_topics(admin_forum_topics)
+        .with_admin_ids(admin_ids)
+        .with_payment_methods(payments.methods()));
+
+    // Очередь уведомлений: записи создаются вместе с изменением заказа, отправка - с повторами
+    let outbox = Arc::new(NotificationOutbox::new(pool.clone(), telegram_notifier.clone(), OutboxConfig::from_env()));
+    tokio::spawn(outbox.clone().run());
+
+    let telegram_auth = Arc::new(TelegramAuth::new(&bot_token));
+
+    let mut ton_processor = TonProcessor::new(pool.clone())
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    if let Some(jetton) = jetton {
+        ton_processor = ton_processor.with_jetton(toncenter.clone(), jetton);
+    }
+    let ton_processor = Arc::new(ton_processor);
+
+    let subscriptions = Arc::new(SubscriptionService::new(
+        pool.clone(),
+        telegram_notifier.clone(),
+        loyalty.clone(),
     ));
 
-    let ton_processor = Arc::new(TonProcessor::new(pool.clone())
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?);
+    // Службы доставки для отслеживания отправлений
+    let mut shipment_service = ShipmentService::new(pool.clone(), telegram_notifier.clone());
+    shipment_service.register(Arc::new(MockCarrierTracker::new()));
+    shipment_service.register(Arc::new(LinkOnlyTracker::new(
+        "pochta",
+        "Почта России",
+        "https://www.pochta.ru/tracking?barcode={number}",
+    )));
+    shipment_service.register(Arc::new(LinkOnlyTracker::new(
+        "cdek",
+        "СДЭК",
+        "https://www.cdek.ru/ru/tracking?order_id={number}",
+    )));
+    let shipments = Arc::new(shipment_service);
 
     // Создаем и запускаем Telegram бота в отдельном потоке
+    // Ссылка на WebApp для кнопки "Купить" в inline-режиме (по умолчанию - основное WebApp бота)
+    let webapp_link = std::env::var("WEBAPP_LINK").ok();
     let bot_notifier = telegram_notifier.clone();
     let bot_processor = ton_processor.clone();
+    let bot_subscriptions = subscriptions.clone();
+    let bot_shipments = shipments.clone();
+    let bot_payments = payments.clone();
     tokio::spawn(async move {
-        let bot = TelegramBot::new(bot_token, bot_notifier, bot_processor);
+        let bot = TelegramBot::new(bot_token, bot_notifier, bot_processor, bot_subscriptions, bot_shipments, bot_payments)
+            .with_webapp_link(webapp_link);
         bot.start().await;
     });
 
+    // Раз в час сжигаем просроченные бонусные баллы
+    let expiry_loyalty = loyalty.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match expiry_loyalty.expire_points().await {
+                Ok(0) => {}
+                Ok(count) => println!("🎁 Сгорели баллы по {} начислениям", count),
+                Err(e) => eprintln!("Ошибка сжигания баллов: {:?}", e),
+            }
+        }
+    });
+
+    // Каждые 10 минут создаем заказы по наступившим подпискам
+    let scheduler_subscriptions = subscriptions.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10 * 60));
+        loop {
+            interval.tick().await;
+            match scheduler_subscriptions.run_due().await {
+                Ok(0) => {}
+                Ok(count) => println!("🔁 Создано заказов по подпискам: {}", count),
+                Err(e) => eprintln!("Ошибка обработки подписок: {:?}", e),
+            }
+        }
+    });
+
+    // Каждые 10 минут отменяем неоплаченные заказы и снимаем их резерв остатков
+    let unpaid_order_ttl_hours = std::env::var("UNPAID_ORDER_TTL_HOURS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(24);
+    let expiry_pool = pool.clone();
+    let expiry_order_loyalty = loyalty.clone();
+    let expiry_notifier = telegram_notifier.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10 * 60));
+        loop {
+            interval.tick().await;
+            match checkout::expire_unpaid_orders(&expiry_pool, &expiry_order_loyalty, unpaid_order_ttl_hours).await {
+                Ok(expired) => {
+                    for order_id in expired {
+                        println!("⌛ Заказ №{} не оплачен вовремя и отменен", order_id);
+                        if let Err(e) = expiry_notifier.handle_order_cancellation(order_id, None).await {
+                            eprintln!("Ошибка уведомления об отмене заказа №{}: {:?}", order_id, e);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Ошибка отмены неоплаченных заказов: {:?}", e),
+            }
+        }
+    });
+
+    // Каждые 30 минут опрашиваем службы доставки
+    let tracking_shipments = shipments.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(30 * 60));
+        loop {
+            interval.tick().await;
+            match tracking_shipments.poll().await {
+                Ok(0) => {}
+                Ok(count) => println!("🚚 Обновлены статусы отправлений: {}", count),
+                Err(e) => eprintln!("Ошибка опроса служб доставки: {:?}", e),
+            }
+        }
+    });
+
+    // Каждые 2 минуты ищем переводы жетонов (USDT) по заказам
+    let jetton_processor = ton_processor.clone();
+    let jetton_payments = payments.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(2 * 60));
+        loop {
+            interval.tick().await;
+            match jetton_processor.poll_jetton_payments(&jetton_payments).await {
+                Ok(paid) => {
+                    for (order_id, settlement) in paid {
+                        match settlement {
+                            Settlement::Covered { .. } => println!("💵 Заказ №{} оплачен жетонами", order_id),
+                            Settlement::Partial { remaining, .. } => {
+                                println!("💵 Заказ №{} оплачен жетонами частично, осталось {:.2} TON", order_id, remaining)
+                            }
+                            Settlement::Closed => {}
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Ошибка поиска оплат жетонами: {:?}", e),
+            }
+        }
+    });
+
+    // Раз в час сверяем входящие переводы с оплатами
+    let job_reconciliation = reconciliation.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match job_reconciliation.run().await {
+                Ok(summary) if summary.discrepancies > 0 => {
+                    println!("🧾 Сверка: {} переводов, расхождений: {}", summary.checked, summary.discrepancies)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Ошибка сверки платежей: {:?}", e),
+            }
+        }
+    });
+
     // Для production используйте фиксированный ключ из конфига!
     let secret_key = Key::generate();
 
@@ -83,6 +352,15 @@ async fn main() -> std::io::Result<()> {
         db_pool: pool.clone(),
         ton_processor: ton_processor.clone(),
         telegram_notifier: telegram_notifier.clone(),
+        telegram_auth: telegram_auth.clone(),
+        loyalty: loyalty.clone(),
+        subscriptions: subscriptions.clone(),
+        shipments: shipments.clone(),
+        payments: payments.clone(),
+        ton_connect: ton_connect.clone(),
+        reconciliation: reconciliation.clone(),
+        outbox: outbox.clone(),
+        telegram_webhook_secret: std::env::var("TELEGRAM_WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty()),
     });
 
     HttpServer::new(move || {
@@ -94,6 +372,7 @@ async fn main() -> std::io::Result<()> {
                 header::CONTENT_TYPE,
                 header::AUTHORIZATION,
                 header::ACCEPT,
+                header::HeaderName::from_static("x-telegram-init-data"),
             ])
             .supports_credentials()
             .max_age(3600);
@@ -65,6 +65,8 @@ pub struct Product {
     pub description: String,
     pub image_url: String,
     pub category_id: i32,
+    #[serde(default)]
+    pub is_bundle: bool,
     #[serde(with = "naive_datetime_serde", skip_serializing_if = "Option::is_none")]
     pub created_at: Option<NaiveDateTime>,
 }
@@ -82,6 +84,7 @@ impl<'r> sqlx::FromRow<'r, sqlx::sqlite::SqliteRow> for Product {
             description: row.try_get("description")?,
             image_url: row.try_get("image_url")?,
             category_id: row.try_get("category_id")?,
+            is_bundle: row.try_get("is_bundle").unwrap_or(false),
             created_at: created_at.map(|dt| dt.naive_local()),
         })
     }
@@ -125,7 +128,6 @@ pub struct Order {
     pub total_amount: f64,
     pub status: String,
     pub delivery_address: Option<String>,
-    pub dialog_active: Option<bool>,
     pub telegram_message_id: Option<i64>,
     #[serde(with = "naive_datetime_serde", skip_serializing_if = "Option::is_none")]
     pub created_at: Option<NaiveDateTime>,
@@ -0,0 +1,431 @@
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Executor, Sqlite, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use tera::{Context, Tera};
+use thiserror::Error;
+use crate::shipping::ShipmentStatus;
+use crate::subscriptions::SubscriptionStatus;
+use crate::telegram_notifications::escape_markdown;
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("Не удалось загрузить шаблоны из {dir}: {reason}")]
+    Load { dir: String, reason: String },
+    #[error("Нет шаблонов для языка по умолчанию {0}")]
+    MissingLocale(String),
+    #[error("Шаблон {0} отсутствует для языка по умолчанию")]
+    Missing(String),
+    #[error("Неизвестный шаблон {0}")]
+    Unknown(String),
+    #[error("Ошибка шаблона {template}: {reason}")]
+    Render { template: String, reason: String },
+}
+
+// Строка состава заказа; компоненты набора идут сразу после строки набора
+#[derive(Debug, Serialize)]
+pub struct OrderLine {
+    pub name: String,
+    pub quantity: i32,
+    pub component: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiscountLine {
+    pub code: String,
+    pub amount: f64,
+    pub free_delivery: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeliveryLine {
+    pub name: String,
+    pub cost: f64,
+    pub free: bool,
+}
+
+// Сохраненный адрес в ответе на /address
+#[derive(Debug, Serialize)]
+pub struct AddressLine {
+    pub line: String,
+    pub default: bool,
+}
+
+// Уведомление и данные для его шаблона. Пользовательский текст передается как есть:
+// шаблоны экранируют его для Markdown фильтром md
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Notification<'a> {
+    OrderConfirmation {
+        lines: &'a [OrderLine],
+        discounts: &'a [DiscountLine],
+        points_discount: f64,
+        store_credit: f64,
+        delivery: Option<&'a DeliveryLine>,
+        address: &'a str,
+        total: f64,
+    },
+    OrderCompleted { order_id: i64, points: i64 },
+    OrderCancelled { order_id: i64 },
+    OrderPacked { order_id: i64 },
+    OrderShipped { order_id: i64 },
+    SupportReply { order_id: Option<i64> },
+    Subscription {
+        id: i64,
+        interval_days: i64,
+        status: SubscriptionStatus,
+        // Unix time: в шаблоне форматируется фильтром date
+        next_run_at: i64,
+        notice: Option<&'a str>,
+    },
+    TrackingInfo { order_id: i64, carrier: &'a str, tracking_number: &'a str },
+    ShipmentStatus { order_id: i64, status: ShipmentStatus, description: Option<&'a str> },
+    PickupCode { order_id: i64, code: &'a str },
+    // Недоплата по заказу: кнопки способов оплаты добавляются при отправке
+    PaymentTopUp { order_id: i64, paid: f64, total: f64, remaining: f64 },
+    // Ссылка на перевод TON или жетонов: amount - сумма перевода в currency
+    PaymentRequest { order_id: i64, paid: f64, total: f64, amount: f64, currency: &'a str, jetton: bool },
+    PaymentReceived { order_id: i64 },
+    // Ответ на /points. Unix time: в шаблоне форматируется фильтром date
+    LoyaltyBalance { points: i64, value: f64, points_expiring: i64, expires_at: Option<i64> },
+    // Ответ на /address
+    SavedAddresses { addresses: &'a [AddressLine] },
+    // Сообщения чата администраторов - всегда на языке по умолчанию
+    AdminNewOrder {
+        order_id: i64,
+        user_id: i64,
+        username: Option<&'a str>,
+        address: Option<&'a str>,
+        delivery: Option<&'a DeliveryLine>,
+        lines: &'a [OrderLine],
+        total: f64,
+    },
+    AdminUserMessage { user_id: i64, thread_id: i64, order_id: Option<i64> },
+    AdminTopic { user_id: i64, order_id: Option<i64> },
+}
+
+impl Notification<'_> {
+    fn template(&self) -> &'static str {
+        match self {
+            Notification::OrderConfirmation { .. } => "order_confirmation.md",
+            Notification::OrderCompleted { .. } => "order_completed.md",
+            Notification::OrderCancelled { .. } => "order_cancelled.md",
+            Notification::OrderPacked { .. } => "order_packed.md",
+            Notification::OrderShipped { .. } => "order_shipped.md",
+            Notification::SupportReply { .. } => "support_reply.md",
+            Notification::Subscription { .. } => "subscription.md",
+            Notification::TrackingInfo { .. } => "tracking_info.md",
+            Notification::ShipmentStatus { .. } => "shipment_status.md",
+            Notification::PickupCode { .. } => "pickup_code.md",
+            Notification::PaymentTopUp { .. } => "payment_top_up.md",
+            Notification::PaymentRequest { .. } => "payment_request.md",
+            Notification::PaymentReceived { .. } => "payment_received.txt",
+            Notification::LoyaltyBalance { .. } => "loyalty_balance.md",
+            Notification::SavedAddresses { .. } => "saved_addresses.md",
+            Notification::AdminNewOrder { .. } => "admin_new_order.md",
+            Notification::AdminUserMessage { .. } => "admin_user_message.md",
+            Notification::AdminTopic { .. } => "admin_topic.txt",
+        }
+    }
+}
+
+// Подписи кнопок
+#[derive(Debug, Clone, Copy)]
+pub enum Label {
+    TrackParcel,
+    SubscriptionSkip,
+    SubscriptionPause,
+    SubscriptionResume,
+    SubscriptionCancel,
+    ShareContact,
+    ShareLocation,
+}
+
+impl Label {
+    const ALL: [Label; 7] = [
+        Label::TrackParcel,
+        Label::SubscriptionSkip,
+        Label::SubscriptionPause,
+        Label::SubscriptionResume,
+        Label::SubscriptionCancel,
+        Label::ShareContact,
+        Label::ShareLocation,
+    ];
+
+    fn template(&self) -> &'static str {
+        match self {
+            Label::TrackParcel => "buttons/track_parcel.txt",
+            Label::SubscriptionSkip => "buttons/subscription_skip.txt",
+            Label::SubscriptionPause => "buttons/subscription_pause.txt",
+            Label::SubscriptionResume => "buttons/subscription_resume.txt",
+            Label::SubscriptionCancel => "buttons/subscription_cancel.txt",
+            Label::ShareContact => "buttons/share_contact.txt",
+            Label::ShareLocation => "buttons/share_location.txt",
+        }
+    }
+}
+
+// Шаблоны уведомлений: <каталог>/<язык>/<шаблон>. Читаются при запуске, поэтому текст можно менять
+// без пересборки. Для языка без своего варианта шаблона используется язык по умолчанию
+pub struct NotificationTemplates {
+    tera: Tera,
+    // Имена загруженных шаблонов вида "ru/order_confirmation.md"
+    names: HashSet<String>,
+    default_locale: String,
+    locales: HashSet<String>,
+}
+
+impl NotificationTemplates {
+    // Загрузка с проверкой: у языка по умолчанию есть все шаблоны, лишних файлов нет,
+    // и каждый шаблон каждого языка отрисовывается на примере данных
+    pub fn load(dir: &str, default_locale: &str) -> Result<Self, TemplateError> {
+        let mut tera = Tera::new(&format!("{}/**/*", dir.trim_end_matches('/'))).map_err(|e| TemplateError::Load {
+            dir: dir.to_string(),
+            reason: describe(&e),
+        })?;
+        tera.register_filter("ton", ton_filter);
+        tera.register_filter("md", md_filter);
+
+        let samples = Samples::new();
+        let known = samples
+            .notifications()
+            .iter()
+            .map(|sample| sample.template())
+            .chain(Label::ALL.iter().map(|label| label.template()))
+            .collect::<HashSet<_>>();
+
+        let names = tera.get_template_names().map(str::to_string).collect::<HashSet<_>>();
+        let mut locales = HashSet::new();
+        for name in &names {
+            match name.split_once('/') {
+                Some((locale, template)) if known.contains(template) => {
+                    locales.insert(locale.to_string());
+                }
+                _ => return Err(TemplateError::Unknown(name.clone())),
+            }
+        }
+        if !locales.contains(default_locale) {
+            return Err(TemplateError::MissingLocale(default_locale.to_string()));
+        }
+
+        let templates = Self {
+            tera,
+            names,
+            default_locale: default_locale.to_string(),
+            locales,
+        };
+        for template in &known {
+            if !templates.has(default_locale, template) {
+                return Err(TemplateError::Missing(template.to_string()));
+            }
+        }
+        for locale in &templates.locales {
+            for sample in samples.notifications() {
+                templates.render(Some(locale), &sample)?;
+            }
+            for label in Label::ALL {
+                templates.label(Some(locale), label)?;
+            }
+        }
+
+        let mut locales = templates.locales.iter().map(String::as_str).collect::<Vec<_>>();
+        locales.sort();
+        println!("🌐 Загружены шаблоны уведомлений: {}", locales.join(", "));
+        Ok(templates)
+    }
+
+    fn has(&self, locale: &str, template: &str) -> bool {
+        self.names.contains(&format!("{}/{}", locale, template))
+    }
+
+    // Язык шаблонов по language_code из Telegram: "en-US" -> "en", неизвестный - язык по умолчанию
+    fn locale(&self, language_code: Option<&str>) -> &str {
+        language_code
+            .and_then(|code| code.split(['-', '_']).next())
+            .map(|code| code.to_lowercase())
+            .and_then(|code| self.locales.get(&code))
+            .unwrap_or(&self.default_locale)
+    }
+
+    fn render_template(&self, language_code: Option<&str>, template: &str, context: &Context) -> Result<String, TemplateError> {
+        let locale = self.locale(language_code);
+        let locale = if self.has(locale, template) { locale } else { &self.default_locale };
+        let name = format!("{}/{}", locale, template);
+
+        self.tera
+            .render(&name, context)
+            .map(|text| text.trim_end().to_string())
+            .map_err(|e| TemplateError::Render { template: name, reason: describe(&e) })
+    }
+
+    pub fn render(&self, language_code: Option<&str>, notification: &Notification) -> Result<String, TemplateError> {
+        let context = Context::from_serialize(notification).map_err(|e| TemplateError::Render {
+            template: notification.template().to_string(),
+            reason: describe(&e),
+        })?;
+        self.render_template(language_code, notification.template(), &context)
+    }
+
+    pub fn label(&self, language_code: Option<&str>, label: Label) -> Result<String, TemplateError> {
+        self.render_template(language_code, label.template(), &Context::new())
+    }
+}
+
+// Ошибки Tera содержат причину во вложенных ошибках
+fn describe(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+// {{ address | md }} - пользовательский текст, экранированный для ParseMode::Markdown
+fn md_filter(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    value
+        .as_str()
+        .map(|text| Value::String(escape_markdown(text)))
+        .ok_or_else(|| tera::Error::msg(format!("Filter `md` expects a string, got {}", value)))
+}
+
+// {{ total | ton }} - сумма с двумя знаками после запятой
+fn ton_filter(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    value
+        .as_f64()
+        .map(|amount| Value::String(format!("{:.2}", amount)))
+        .ok_or_else(|| tera::Error::msg(format!("Filter `ton` expects a number, got {}", value)))
+}
+
+// Примеры данных для шаблонов: по ним шаблоны проверяются при запуске.
+// Для шаблонов с условиями - по примеру на каждую ветку
+struct Samples {
+    lines: Vec<OrderLine>,
+    discounts: Vec<DiscountLine>,
+    delivery: DeliveryLine,
+    addresses: Vec<AddressLine>,
+}
+
+impl Samples {
+    fn new() -> Self {
+        Self {
+            lines: vec![
+                OrderLine { name: "Протеиновый набор".to_string(), quantity: 1, component: false },
+                OrderLine { name: "Протеин".to_string(), quantity: 2, component: true },
+            ],
+            discounts: vec![
+                DiscountLine { code: "SPORT10".to_string(), amount: 4.2, free_delivery: false },
+                DiscountLine { code: "FREESHIP".to_string(), amount: 0.0, free_delivery: true },
+            ],
+            delivery: DeliveryLine { name: "Курьер".to_string(), cost: 2.5, free: false },
+            addresses: vec![
+                AddressLine { line: "Москва, ул. Спортивная, 1".to_string(), default: true },
+                AddressLine { line: "Москва, ул. Беговая, 2".to_string(), default: false },
+            ],
+        }
+    }
+
+    fn notifications(&self) -> Vec<Notification<'_>> {
+        vec![
+            Notification::OrderConfirmation {
+                lines: &self.lines,
+                discounts: &self.discounts,
+                points_discount: 1.5,
+                store_credit: 0.5,
+                delivery: Some(&self.delivery),
+                address: "Москва, ул. Спортивная, 1",
+                total: 42.0,
+            },
+            Notification::OrderConfirmation {
+                lines: &self.lines,
+                discounts: &[],
+                points_discount: 0.0,
+                store_credit: 0.0,
+                delivery: None,
+                address: "Москва, ул. Спортивная, 1",
+                total: 42.0,
+            },
+            Notification::OrderCompleted { order_id: 1, points: 10 },
+            Notification::OrderCancelled { order_id: 1 },
+            Notification::OrderPacked { order_id: 1 },
+            Notification::OrderShipped { order_id: 1 },
+            Notification::SupportReply { order_id: Some(1) },
+            Notification::SupportReply { order_id: None },
+            Notification::Subscription {
+                id: 1,
+                interval_days: 30,
+                status: SubscriptionStatus::Active,
+                next_run_at: 0,
+                notice: Some("notice"),
+            },
+            Notification::Subscription {
+                id: 1,
+                interval_days: 30,
+                status: SubscriptionStatus::Paused,
+                next_run_at: 0,
+                notice: None,
+            },
+            Notification::TrackingInfo { order_id: 1, carrier: "carrier", tracking_number: "RA123456789RU" },
+            Notification::ShipmentStatus { order_id: 1, status: ShipmentStatus::InTransit, description: Some("description") },
+            Notification::ShipmentStatus { order_id: 1, status: ShipmentStatus::Delivered, description: None },
+            Notification::PickupCode { order_id: 1, code: "123456" },
+            Notification::PaymentTopUp { order_id: 1, paid: 9.5, total: 10.0, remaining: 0.5 },
+            Notification::PaymentRequest { order_id: 1, paid: 0.0, total: 10.0, amount: 10.0, currency: "TON", jetton: false },
+            Notification::PaymentRequest { order_id: 1, paid: 9.5, total: 10.0, amount: 0.5, currency: "USDT", jetton: true },
+            Notification::PaymentReceived { order_id: 1 },
+            Notification::LoyaltyBalance { points: 100, value: 1.0, points_expiring: 20, expires_at: Some(1) },
+            Notification::LoyaltyBalance { points: 0, value: 0.0, points_expiring: 0, expires_at: None },
+            Notification::SavedAddresses { addresses: &self.addresses },
+            Notification::SavedAddresses { addresses: &[] },
+            Notification::AdminNewOrder {
+                order_id: 1,
+                user_id: 1,
+                username: Some("username"),
+                address: Some("Москва, ул. Спортивная, 1"),
+                delivery: Some(&self.delivery),
+                lines: &self.lines,
+                total: 42.0,
+            },
+            Notification::AdminNewOrder {
+                order_id: 1,
+                user_id: 1,
+                username: None,
+                address: None,
+                delivery: None,
+                lines: &self.lines,
+                total: 42.0,
+            },
+            Notification::AdminUserMessage { user_id: 1, thread_id: 1, order_id: Some(1) },
+            Notification::AdminUserMessage { user_id: 1, thread_id: 1, order_id: None },
+            Notification::AdminTopic { user_id: 1, order_id: Some(1) },
+            Notification::AdminTopic { user_id: 1, order_id: None },
+        ]
+    }
+}
+
+// Язык пользователя из Telegram (language_code) - для уведомлений, которые отправляются без запроса пользователя
+pub async fn remember_language(pool: &SqlitePool, user_id: i64, language_code: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO user_languages (user_id, language_code) VALUES (?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET language_code = excluded.language_code, updated_at = CURRENT_TIMESTAMP
+        "#
+    )
+        .bind(user_id)
+        .bind(language_code)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn user_language<'e, E>(executor: E, user_id: i64) -> Result<Option<String>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar::<_, String>("SELECT language_code FROM user_languages WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(executor)
+        .await
+}
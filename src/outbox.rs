@@ -0,0 +1,308 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+use std::sync::Arc;
+use teloxide::{ApiError, RequestError};
+use thiserror::Error;
+use tokio::sync::Notify;
+use crate::telegram_notifications::{NotificationError, TelegramNotifier};
+
+// Как часто обработчик проверяет очередь, если его не разбудили
+const POLL_INTERVAL_SECS: u64 = 5;
+// Сколько уведомлений отправлять за один проход
+const BATCH_SIZE: i64 = 50;
+
+#[derive(Error, Debug)]
+pub enum OutboxError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Уведомление не найдено")]
+    NotFound,
+    #[error("Повторить можно только недоставленное уведомление")]
+    NotDead,
+}
+
+impl OutboxError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, OutboxError::DbError(_))
+    }
+}
+
+// Уведомление, которое нужно доставить. Хранится в payload как JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxMessage {
+    // Подтверждение заказа покупателю, кнопки оплаты добавляются при отправке
+    OrderConfirmation { order_id: i64, user_id: i64, text: String },
+    // Карточка оплаченного заказа в чате администраторов
+    AdminNewOrder { order_id: i64, username: Option<String> },
+    // Покупателю: заказ собран или отправлен
+    OrderStatus { order_id: i64, status: String },
+    // Покупателю: платеж не покрыл заказ, нужна доплата. provider - способ последнего платежа
+    PaymentRequest { order_id: i64, user_id: i64, provider: Option<String> },
+}
+
+impl OutboxMessage {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboxMessage::OrderConfirmation { .. } => "order_confirmation",
+            OutboxMessage::AdminNewOrder { .. } => "admin_new_order",
+            OutboxMessage::OrderStatus { .. } => "order_status",
+            OutboxMessage::PaymentRequest { .. } => "payment_request",
+        }
+    }
+
+    fn order_id(&self) -> i64 {
+        match self {
+            OutboxMessage::OrderConfirmation { order_id, .. }
+            | OutboxMessage::AdminNewOrder { order_id, .. }
+            | OutboxMessage::OrderStatus { order_id, .. }
+            | OutboxMessage::PaymentRequest { order_id, .. } => *order_id,
+        }
+    }
+}
+
+// Ставит уведомление в очередь в транзакции изменения, о котором оно сообщает
+pub async fn enqueue(tx: &mut Transaction<'_, Sqlite>, message: &OutboxMessage) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(message).expect("outbox message is always serializable");
+    sqlx::query("INSERT INTO notification_outbox (kind, order_id, payload) VALUES (?, ?, ?)")
+        .bind(message.kind())
+        .bind(message.order_id())
+        .bind(payload)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub kind: String,
+    pub order_id: Option<i64>,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    // После стольких неудачных попыток уведомление считается недоставленным
+    pub max_attempts: i64,
+    // Первая пауза перед повтором, дальше удваивается
+    pub retry_base_secs: i64,
+    pub retry_max_secs: i64,
+}
+
+impl OutboxConfig {
+    pub fn from_env() -> Self {
+        fn env_or(name: &str, default: i64) -> i64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|value: &i64| *value > 0)
+                .unwrap_or(default)
+        }
+
+        Self {
+            max_attempts: env_or("NOTIFICATION_MAX_ATTEMPTS", 8),
+            retry_base_secs: env_or("NOTIFICATION_RETRY_BASE_SECS", 30),
+            retry_max_secs: env_or("NOTIFICATION_RETRY_MAX_SECS", 60 * 60),
+        }
+    }
+
+    fn retry_delay(&self, attempts: i64) -> Duration {
+        let exponent = (attempts - 1).clamp(0, 20) as u32;
+        let delay = self.retry_base_secs.saturating_mul(2i64.saturating_pow(exponent));
+        Duration::seconds(delay.min(self.retry_max_secs))
+    }
+}
+
+// Чем закончилась попытка доставки
+enum Failure {
+    // Telegram просит подождать: ждем, попытка не засчитывается
+    RateLimited(std::time::Duration),
+    // Повтор не поможет (бот заблокирован, чат не найден) - сразу в недоставленные
+    Permanent(String),
+    Transient(String),
+}
+
+fn classify(error: &NotificationError) -> Failure {
+    match error {
+        NotificationError::TelegramError(RequestError::RetryAfter(seconds)) => Failure::RateLimited(seconds.duration()),
+        NotificationError::TemplateError(_) => Failure::Permanent(error.to_string()),
+        NotificationError::TelegramError(RequestError::Api(
+            ApiError::BotBlocked
+            | ApiError::ChatNotFound
+            | ApiError::UserDeactivated
+            | ApiError::BotKicked
+            | ApiError::CantInitiateConversation
+            | ApiError::CantTalkWithBots,
+        )) => Failure::Permanent(error.to_string()),
+        _ => Failure::Transient(error.to_string()),
+    }
+}
+
+pub struct NotificationOutbox {
+    db_pool: SqlitePool,
+    notifier: Arc<TelegramNotifier>,
+    config: OutboxConfig,
+    wake: Notify,
+}
+
+impl NotificationOutbox {
+    pub fn new(db_pool: SqlitePool, notifier: Arc<TelegramNotifier>, config: OutboxConfig) -> Self {
+        Self {
+            db_pool,
+            notifier,
+            config,
+            wake: Notify::new(),
+        }
+    }
+
+    // Отправить очередь сейчас, не дожидаясь следующей проверки
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    // Фоновый обработчик очереди
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.deliver_due().await {
+                eprintln!("Ошибка обработки очереди уведомлений: {:?}", e);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(POLL_INTERVAL_SECS)) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+
+    // Отправляет уведомления, срок которых подошел. Возвращает число доставленных
+    pub async fn deliver_due(&self) -> Result<usize, OutboxError> {
+        let entries = sqlx::query_as::<_, OutboxEntry>(
+            r#"
+            SELECT id, kind, order_id, payload, status, attempts, next_attempt_at, last_error, created_at, sent_at
+            FROM notification_outbox
+            WHERE status = 'pending' AND next_attempt_at <= ?
+            ORDER BY id
+            LIMIT ?
+            "#
+        )
+            .bind(Utc::now().naive_utc())
+            .bind(BATCH_SIZE)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let mut delivered = 0;
+        for entry in entries {
+            let result = match serde_json::from_str::<OutboxMessage>(&entry.payload) {
+                Ok(message) => self.notifier.deliver(&message).await.map_err(|e| classify(&e)),
+                Err(e) => Err(Failure::Permanent(format!("Invalid payload: {}", e))),
+            };
+
+            match result {
+                Ok(()) => {
+                    sqlx::query(
+                        "UPDATE notification_outbox SET status = 'sent', attempts = attempts + 1, sent_at = ? WHERE id = ?"
+                    )
+                        .bind(Utc::now().naive_utc())
+                        .bind(entry.id)
+                        .execute(&self.db_pool)
+                        .await?;
+                    delivered += 1;
+                }
+                Err(Failure::RateLimited(wait)) => {
+                    // Ограничение действует на весь бот: откладываем уведомление и заканчиваем проход
+                    let wait = Duration::from_std(wait).unwrap_or_else(|_| Duration::seconds(self.config.retry_base_secs));
+                    sqlx::query("UPDATE notification_outbox SET next_attempt_at = ?, last_error = ? WHERE id = ?")
+                        .bind(Utc::now().naive_utc() + wait)
+                        .bind(format!("Retry after {}s", wait.num_seconds()))
+                        .bind(entry.id)
+                        .execute(&self.db_pool)
+                        .await?;
+                    println!("⏳ Telegram ограничил частоту отправки, пауза {} с", wait.num_seconds());
+                    break;
+                }
+                Err(Failure::Permanent(error)) => self.dead_letter(&entry, &error).await?,
+                Err(Failure::Transient(error)) => {
+                    let attempts = entry.attempts + 1;
+                    if attempts >= self.config.max_attempts {
+                        self.dead_letter(&entry, &error).await?;
+                        continue;
+                    }
+                    sqlx::query(
+                        "UPDATE notification_outbox SET attempts = ?, next_attempt_at = ?, last_error = ? WHERE id = ?"
+                    )
+                        .bind(attempts)
+                        .bind(Utc::now().naive_utc() + self.config.retry_delay(attempts))
+                        .bind(&error)
+                        .bind(entry.id)
+                        .execute(&self.db_pool)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    async fn dead_letter(&self, entry: &OutboxEntry, error: &str) -> Result<(), OutboxError> {
+        sqlx::query(
+            "UPDATE notification_outbox SET status = 'dead', attempts = attempts + 1, last_error = ? WHERE id = ?"
+        )
+            .bind(error)
+            .bind(entry.id)
+            .execute(&self.db_pool)
+            .await?;
+        eprintln!("☠️ Уведомление {} ({}) не доставлено: {}", entry.id, entry.kind, error);
+        Ok(())
+    }
+
+    // Очередь для администратора: по умолчанию недоставленные, новые первыми
+    pub async fn list(&self, status: &str) -> Result<Vec<OutboxEntry>, OutboxError> {
+        Ok(sqlx::query_as::<_, OutboxEntry>(
+            r#"
+            SELECT id, kind, order_id, payload, status, attempts, next_attempt_at, last_error, created_at, sent_at
+            FROM notification_outbox
+            WHERE status = ?
+            ORDER BY id DESC
+            LIMIT 200
+            "#
+        )
+            .bind(status)
+            .fetch_all(&self.db_pool)
+            .await?)
+    }
+
+    // Возвращает недоставленное уведомление в очередь с обнуленным счетчиком попыток
+    pub async fn replay(&self, id: i64) -> Result<(), OutboxError> {
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM notification_outbox WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(OutboxError::NotFound)?;
+        if status != "dead" {
+            return Err(OutboxError::NotDead);
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE notification_outbox
+            SET status = 'pending', attempts = 0, next_attempt_at = ?, last_error = NULL
+            WHERE id = ? AND status = 'dead'
+            "#
+        )
+            .bind(Utc::now().naive_utc())
+            .bind(id)
+            .execute(&self.db_pool)
+            .await?;
+
+        self.wake();
+        Ok(())
+    }
+}
@@ -0,0 +1,947 @@
+use reqwest::Url;
+use serde::Serialize;
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool, Transaction};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineKeyboardButton, InlineKeyboardMarkup, LabeledPrice, ParseMode, PreCheckoutQuery, SuccessfulPayment,
+    TelegramTransactionId, UserId,
+};
+use thiserror::Error;
+use crate::loyalty::LoyaltyError;
+use crate::notification_templates::{self, Notification, NotificationTemplates, TemplateError};
+use crate::outbox::{self, OutboxMessage};
+use crate::store_credit;
+use crate::ton_payment::JettonConfig;
+
+// Допустимое расхождение суммы оплаты с суммой заказа, TON
+pub const PAYMENT_TOLERANCE: f64 = 0.01;
+
+#[derive(Error, Debug)]
+pub enum PaymentError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Telegram API error: {0}")]
+    TelegramError(#[from] teloxide::RequestError),
+    #[error("Loyalty error: {0}")]
+    LoyaltyError(#[from] LoyaltyError),
+    #[error("Неизвестный способ оплаты: {0}")]
+    UnknownProvider(String),
+    #[error("Заказ не найден")]
+    OrderNotFound,
+    #[error("Заказ уже оплачен или отменен")]
+    OrderNotPayable,
+    #[error("Платеж не найден")]
+    PaymentNotFound,
+    #[error("Сумма платежа не совпадает с суммой заказа")]
+    AmountMismatch,
+    #[error("Товар \"{0}\" закончился")]
+    OutOfStock(String),
+    #[error("Invalid payment URL: {0}")]
+    InvalidPaymentUrl(#[from] url::ParseError),
+    #[error("Template error: {0}")]
+    TemplateError(#[from] TemplateError),
+}
+
+impl PaymentError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(
+            self,
+            PaymentError::DbError(_)
+                | PaymentError::TelegramError(_)
+                | PaymentError::LoyaltyError(_)
+                | PaymentError::InvalidPaymentUrl(_)
+                | PaymentError::TemplateError(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct PayableOrder {
+    pub id: i64,
+    pub user_id: i64,
+    pub total_amount: f64,
+    pub status: String,
+    // Сколько TON уже зачтено подтвержденными платежами
+    pub paid_amount: f64,
+}
+
+impl PayableOrder {
+    // Остаток к оплате: после частичной оплаты запрашивается только доплата
+    pub fn amount_due(&self) -> f64 {
+        (self.total_amount - self.paid_amount).max(0.0)
+    }
+
+    fn is_top_up(&self) -> bool {
+        self.paid_amount > 0.0
+    }
+
+    // Сообщение со ссылкой на перевод: amount - сумма перевода в currency
+    fn payment_request<'a>(&self, amount: f64, currency: &'a str, jetton: bool) -> Notification<'a> {
+        Notification::PaymentRequest {
+            order_id: self.id,
+            paid: self.paid_amount,
+            total: self.total_amount,
+            amount,
+            currency,
+            jetton,
+        }
+    }
+}
+
+// Подтвержденный платеж из таблицы payments
+#[derive(Debug, Clone, FromRow)]
+pub struct PaymentRecord {
+    pub id: i64,
+    pub user_id: i64,
+    pub provider: String,
+    pub transaction_hash: Option<String>,
+}
+
+pub type PaymentFuture<'a> = Pin<Box<dyn Future<Output = Result<(), PaymentError>> + Send + 'a>>;
+
+// Чем закончился возврат платежа у провайдера
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefundOutcome {
+    // Провайдер вернул деньги покупателю
+    Returned,
+    // Провайдер деньги не возвращает: платеж ждет ручного возврата
+    Manual,
+}
+
+pub type RefundFuture<'a> = Pin<Box<dyn Future<Output = Result<RefundOutcome, PaymentError>> + Send + 'a>>;
+
+// Итог возврата денег по заказу
+#[derive(Debug, Default, Serialize)]
+pub struct RefundSummary {
+    // Платежи, деньги по которым вернул провайдер (status = refunded)
+    pub refunded: usize,
+    // Платежи, которые нужно вернуть вручную (status = refund_pending)
+    pub pending: usize,
+}
+
+// Способ оплаты: code - часть callback data кнопки, title - надпись на кнопке
+pub trait PaymentProvider: Send + Sync {
+    fn code(&self) -> &str;
+    fn title(&self) -> &str;
+    // Отправляет покупателю ссылку или счет на оплату заказа на языке покупателя
+    fn request_payment<'a>(&'a self, order: &'a PayableOrder, language: Option<&'a str>) -> PaymentFuture<'a>;
+
+    // Возврат денег при возврате заказа. По умолчанию деньги возвращаются вручную
+    fn refund<'a>(&'a self, _payment: &'a PaymentRecord) -> RefundFuture<'a> {
+        Box::pin(async { Ok(RefundOutcome::Manual) })
+    }
+}
+
+// Комментарий перевода, по которому оплата в TON и жетонах сопоставляется с заказом
+pub fn order_comment(order_id: i64) -> String {
+    format!("Order_{}", order_id)
+}
+
+pub fn parse_order_comment(comment: &str) -> Option<i64> {
+    comment.trim().strip_prefix("Order_")?.parse().ok()
+}
+
+// Перевод TON на кошелек магазина по ссылке ton://transfer
+pub struct TonTransferProvider {
+    bot: Bot,
+    wallet: String,
+    templates: Arc<NotificationTemplates>,
+}
+
+impl TonTransferProvider {
+    pub fn new(bot: Bot, wallet: String, templates: Arc<NotificationTemplates>) -> Self {
+        Self { bot, wallet, templates }
+    }
+}
+
+impl PaymentProvider for TonTransferProvider {
+    fn code(&self) -> &str {
+        "ton"
+    }
+
+    fn title(&self) -> &str {
+        "Оплатить в TON"
+    }
+
+    fn request_payment<'a>(&'a self, order: &'a PayableOrder, language: Option<&'a str>) -> PaymentFuture<'a> {
+        Box::pin(async move {
+            let payment_url = format!(
+                "ton://transfer/{}?amount={}&text={}",
+                self.wallet,
+                (order.amount_due() * 1_000_000_000.0) as i64, // Convert to nanotons
+                order_comment(order.id)
+            );
+
+            let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::url("Оплатить в TON", Url::parse(&payment_url)?)
+            ]]);
+
+            self.bot
+                .send_message(
+                    ChatId(order.user_id),
+                    self.templates.render(language, &order.payment_request(order.amount_due(), "TON", false))?,
+                )
+                .parse_mode(ParseMode::Markdown)
+                .reply_markup(keyboard)
+                .send()
+                .await?;
+
+            Ok(())
+        })
+    }
+}
+
+// Валюта Telegram Stars: счета без токена провайдера, сумма в целых звездах
+pub const STARS_CURRENCY: &str = "XTR";
+
+// Перевод жетонов (USDT) на кошелек магазина: ссылка ton://transfer с параметром jetton
+pub struct JettonTransferProvider {
+    bot: Bot,
+    wallet: String,
+    title: String,
+    config: JettonConfig,
+    templates: Arc<NotificationTemplates>,
+}
+
+impl JettonTransferProvider {
+    pub fn new(bot: Bot, wallet: String, config: JettonConfig, templates: Arc<NotificationTemplates>) -> Self {
+        let title = format!("Оплатить в {}", config.symbol);
+        Self { bot, wallet, title, config, templates }
+    }
+}
+
+impl PaymentProvider for JettonTransferProvider {
+    fn code(&self) -> &str {
+        &self.config.code
+    }
+
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn request_payment<'a>(&'a self, order: &'a PayableOrder, language: Option<&'a str>) -> PaymentFuture<'a> {
+        Box::pin(async move {
+            let units = self.config.amount_units(order.amount_due());
+            let payment_url = format!(
+                "ton://transfer/{}?jetton={}&amount={}&text={}",
+                self.wallet,
+                self.config.master_address,
+                units,
+                order_comment(order.id)
+            );
+
+            let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::url(self.title.clone(), Url::parse(&payment_url)?)
+            ]]);
+
+            self.bot
+                .send_message(
+                    ChatId(order.user_id),
+                    self.templates.render(
+                        language,
+                        &order.payment_request(self.config.amount_from_units(units), &self.config.symbol, true),
+                    )?,
+                )
+                .parse_mode(ParseMode::Markdown)
+                .reply_markup(keyboard)
+                .send()
+                .await?;
+
+            Ok(())
+        })
+    }
+}
+
+// Настройки счетов Telegram: токен провайдера из @BotFather, валюта счета и курс TON к ней
+#[derive(Debug, Clone)]
+pub struct TelegramInvoiceConfig {
+    pub provider_token: Option<String>,
+    pub currency: String,
+    pub ton_rate: f64,
+}
+
+fn ton_rate_from_env(name: &str) -> Option<f64> {
+    match std::env::var(name).ok().and_then(|rate| rate.parse::<f64>().ok()) {
+        Some(rate) if rate > 0.0 => Some(rate),
+        _ => None,
+    }
+}
+
+impl TelegramInvoiceConfig {
+    // Оплата картой. None, если токен провайдера не задан
+    pub fn from_env() -> Option<Self> {
+        let provider_token = std::env::var("TELEGRAM_PAYMENTS_PROVIDER_TOKEN").ok().filter(|token| !token.is_empty())?;
+        let Some(ton_rate) = ton_rate_from_env("TELEGRAM_PAYMENTS_TON_RATE") else {
+            eprintln!("⚠️ TELEGRAM_PAYMENTS_TON_RATE не задан: оплата счетами Telegram отключена");
+            return None;
+        };
+
+        Some(Self {
+            provider_token: Some(provider_token),
+            currency: std::env::var("TELEGRAM_PAYMENTS_CURRENCY").unwrap_or_else(|_| "RUB".to_string()),
+            ton_rate,
+        })
+    }
+
+    // Оплата звездами. None, если не задан курс звезд к TON
+    pub fn stars_from_env() -> Option<Self> {
+        Some(Self {
+            provider_token: None,
+            currency: STARS_CURRENCY.to_string(),
+            ton_rate: ton_rate_from_env("TELEGRAM_STARS_TON_RATE")?,
+        })
+    }
+}
+
+const INVOICE_PAYLOAD_PREFIX: &str = "order_";
+
+pub fn invoice_payload(order_id: i64) -> String {
+    format!("{}{}", INVOICE_PAYLOAD_PREFIX, order_id)
+}
+
+pub fn parse_invoice_payload(payload: &str) -> Option<i64> {
+    payload.strip_prefix(INVOICE_PAYLOAD_PREFIX)?.parse().ok()
+}
+
+// Счет Telegram (sendInvoice): оплата картой через провайдера или звездами
+pub struct TelegramInvoiceProvider {
+    bot: Bot,
+    code: &'static str,
+    title: &'static str,
+    config: TelegramInvoiceConfig,
+}
+
+impl TelegramInvoiceProvider {
+    pub fn card(bot: Bot, config: TelegramInvoiceConfig) -> Self {
+        Self { bot, code: "telegram", title: "Оплатить картой", config }
+    }
+
+    pub fn stars(bot: Bot, config: TelegramInvoiceConfig) -> Self {
+        Self { bot, code: "stars", title: "Оплатить звездами ⭐", config }
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.config.currency
+    }
+
+    fn is_stars(&self) -> bool {
+        self.config.currency == STARS_CURRENCY
+    }
+
+    // Сумма счета в минимальных единицах валюты (копейки, центы); звезды - целые, округляем вверх
+    pub fn invoice_amount(&self, total_amount: f64) -> u32 {
+        if self.is_stars() {
+            (total_amount * self.config.ton_rate).ceil() as u32
+        } else {
+            (total_amount * self.config.ton_rate * 100.0).round() as u32
+        }
+    }
+
+    // Обратное преобразование: сумма платежа в валюте счета
+    pub fn paid_amount(&self, total_amount: u32) -> f64 {
+        if self.is_stars() {
+            total_amount as f64
+        } else {
+            total_amount as f64 / 100.0
+        }
+    }
+}
+
+impl PaymentProvider for TelegramInvoiceProvider {
+    fn code(&self) -> &str {
+        self.code
+    }
+
+    fn title(&self) -> &str {
+        self.title
+    }
+
+    fn request_payment<'a>(&'a self, order: &'a PayableOrder, _language: Option<&'a str>) -> PaymentFuture<'a> {
+        Box::pin(async move {
+            let title = format!("Заказ №{}", order.id);
+            let description = if order.is_top_up() {
+                format!("Доплата по заказу №{} в SportShop", order.id)
+            } else {
+                format!("Оплата заказа №{} в SportShop", order.id)
+            };
+            let mut invoice = self.bot
+                .send_invoice(
+                    ChatId(order.user_id),
+                    title.clone(),
+                    description,
+                    invoice_payload(order.id),
+                    self.config.currency.clone(),
+                    vec![LabeledPrice::new(title, self.invoice_amount(order.amount_due()))],
+                );
+            if let Some(provider_token) = &self.config.provider_token {
+                invoice = invoice.provider_token(provider_token.clone());
+            }
+            invoice.send().await?;
+
+            Ok(())
+        })
+    }
+
+    // Звезды Telegram возвращает сам (refundStarPayment), платежи картой - через провайдера вручную
+    fn refund<'a>(&'a self, payment: &'a PaymentRecord) -> RefundFuture<'a> {
+        Box::pin(async move {
+            if !self.is_stars() {
+                return Ok(RefundOutcome::Manual);
+            }
+            let charge_id = payment.transaction_hash.clone().ok_or(PaymentError::PaymentNotFound)?;
+            self.bot
+                .refund_star_payment(UserId(payment.user_id as u64), TelegramTransactionId(charge_id))
+                .send()
+                .await?;
+
+            Ok(RefundOutcome::Returned)
+        })
+    }
+}
+
+// Данные подтвержденного платежа для таблицы payments
+pub struct NewPaymentRecord<'a> {
+    pub order_id: i64,
+    pub user_id: i64,
+    pub provider: &'a str,
+    pub amount: f64,
+    pub currency: &'a str,
+    pub wallet_address: &'a str,
+    pub transaction_hash: Option<&'a str>,
+    pub provider_payment_id: Option<&'a str>,
+    // Сколько TON платеж закрывает в сумме заказа
+    pub credited_amount: f64,
+}
+
+// Записывает подтвержденный платеж в транзакции его зачета в заказ.
+// false - эта транзакция уже была записана (повторное уведомление)
+pub async fn record_payment(
+    tx: &mut Transaction<'_, Sqlite>,
+    payment: &NewPaymentRecord<'_>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO payments
+            (order_id, user_id, amount, wallet_address, status, transaction_hash, provider, currency, provider_payment_id, credited_amount)
+        VALUES (?, ?, ?, ?, 'confirmed', ?, ?, ?, ?, ?)
+        ON CONFLICT DO NOTHING
+        "#
+    )
+    .bind(payment.order_id.to_string())
+    .bind(payment.user_id)
+    .bind(payment.amount)
+    .bind(payment.wallet_address)
+    .bind(payment.transaction_hash)
+    .bind(payment.provider)
+    .bind(payment.currency)
+    .bind(payment.provider_payment_id)
+    .bind(payment.credited_amount)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+// Сумма подтвержденных платежей по заказу в TON
+pub async fn paid_amount(pool: &SqlitePool, order_id: i64) -> Result<f64, sqlx::Error> {
+    sqlx::query_scalar::<_, f64>(
+        "SELECT COALESCE(SUM(credited_amount), 0.0) FROM payments WHERE order_id = ? AND status = 'confirmed'"
+    )
+    .bind(order_id.to_string())
+    .fetch_one(pool)
+    .await
+}
+
+// Заказ вместе с уже зачтенной суммой. user_id = None - без проверки владельца
+async fn load_order<'e, E>(executor: E, order_id: i64, user_id: Option<i64>) -> Result<Option<PayableOrder>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_as::<_, PayableOrder>(
+        r#"
+        SELECT o.id, o.user_id, o.total_amount, o.status,
+               COALESCE((
+                   SELECT SUM(p.credited_amount) FROM payments p
+                   WHERE p.order_id = CAST(o.id AS TEXT) AND p.status = 'confirmed'
+               ), 0.0) AS paid_amount
+        FROM orders o
+        WHERE o.id = ? AND (? IS NULL OR o.user_id = ?)
+        "#
+    )
+    .bind(order_id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await
+}
+
+// Итог зачисления платежей по заказу
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Settlement {
+    // Сумма еще не покрыта: просьба о доплате остатка поставлена в очередь уведомлений
+    Partial { paid: f64, remaining: f64 },
+    // Заказ оплачен полностью и переведен в paid, карточка для администраторов в очереди; overpaid - переплата в TON
+    Covered { overpaid: f64 },
+    // Заказ уже оплачен или отменен
+    Closed,
+}
+
+pub struct PaymentService {
+    db_pool: SqlitePool,
+    providers: Vec<Arc<dyn PaymentProvider>>,
+    invoices: Vec<Arc<TelegramInvoiceProvider>>,
+    // Переплаты зачисляются на баланс покупателя; иначе ждут ручного возврата
+    credit_overpayments: bool,
+}
+
+impl PaymentService {
+    pub fn new(db_pool: SqlitePool) -> Self {
+        Self {
+            db_pool,
+            providers: Vec::new(),
+            invoices: Vec::new(),
+            credit_overpayments: false,
+        }
+    }
+
+    pub fn credit_overpayments(&mut self) {
+        self.credit_overpayments = true;
+    }
+
+    pub fn register(&mut self, provider: Arc<dyn PaymentProvider>) {
+        self.providers.push(provider);
+    }
+
+    // Счета Telegram дополнительно проверяются в pre_checkout_query
+    pub fn register_invoices(&mut self, provider: Arc<TelegramInvoiceProvider>) {
+        self.invoices.push(provider.clone());
+        self.register(provider);
+    }
+
+    // Способы оплаты (code, title) в порядке регистрации - для кнопок под заказом
+    pub fn methods(&self) -> Vec<(String, String)> {
+        self.providers
+            .iter()
+            .map(|provider| (provider.code().to_string(), provider.title().to_string()))
+            .collect()
+    }
+
+    async fn payable_order(&self, order_id: i64, user_id: i64) -> Result<PayableOrder, PaymentError> {
+        let order = load_order(&self.db_pool, order_id, Some(user_id))
+            .await?
+            .ok_or(PaymentError::OrderNotFound)?;
+
+        if order.status != "pending" {
+            return Err(PaymentError::OrderNotPayable);
+        }
+        Ok(order)
+    }
+
+    // Товары резервируются при оформлении заказа и возвращаются на склад при его отмене или истечении срока
+    // оплаты: резерв держится, только пока заказ ждет оплаты. Статус перечитывается прямо перед оплатой,
+    // вместе с товарами заказа, которых уже нет в каталоге
+    async fn check_stock(&self, order_id: i64) -> Result<(), PaymentError> {
+        let (status, missing) = sqlx::query_as::<_, (String, Option<String>)>(
+            r#"
+            SELECT o.status, (
+                SELECT COALESCE(p.name, 'удаленный товар')
+                FROM order_items oi
+                LEFT JOIN products p ON p.id = oi.product_id
+                WHERE oi.order_id = o.id AND p.id IS NULL
+                LIMIT 1
+            )
+            FROM orders o
+            WHERE o.id = ?
+            "#
+        )
+        .bind(order_id)
+        .fetch_optional(&self.db_pool)
+        .await?
+        .ok_or(PaymentError::OrderNotFound)?;
+
+        if status != "pending" {
+            return Err(PaymentError::OrderNotPayable);
+        }
+        match missing {
+            Some(name) => Err(PaymentError::OutOfStock(name)),
+            None => Ok(()),
+        }
+    }
+
+    // Провайдер счета определяется по валюте платежа
+    fn invoice_provider(&self, currency: &str) -> Result<&TelegramInvoiceProvider, PaymentError> {
+        self.invoices
+            .iter()
+            .find(|provider| provider.currency() == currency)
+            .map(|provider| provider.as_ref())
+            .ok_or_else(|| PaymentError::UnknownProvider(currency.to_string()))
+    }
+
+    pub async fn request(&self, code: &str, order_id: i64, user_id: i64) -> Result<(), PaymentError> {
+        let provider = self.providers
+            .iter()
+            .find(|provider| provider.code() == code)
+            .ok_or_else(|| PaymentError::UnknownProvider(code.to_string()))?;
+
+        let order = self.payable_order(order_id, user_id).await?;
+        self.check_stock(order_id).await?;
+        let language = notification_templates::user_language(&self.db_pool, user_id).await?;
+        provider.request_payment(&order, language.as_deref()).await
+    }
+
+    // Последняя проверка перед списанием: заказ еще ждет оплаты, сумма и товары не изменились
+    pub async fn validate_pre_checkout(&self, query: &PreCheckoutQuery) -> Result<(), PaymentError> {
+        let invoices = self.invoice_provider(&query.currency)?;
+        let order_id = parse_invoice_payload(&query.invoice_payload).ok_or(PaymentError::OrderNotFound)?;
+        let order = self.payable_order(order_id, query.from.id.0 as i64).await?;
+
+        if query.total_amount != invoices.invoice_amount(order.amount_due()) {
+            return Err(PaymentError::AmountMismatch);
+        }
+        self.check_stock(order_id).await
+    }
+
+    // Записывает и зачитывает оплату по счету. None - платеж уже был записан
+    pub async fn complete_invoice(
+        &self,
+        user_id: i64,
+        payment: &SuccessfulPayment,
+        username: Option<&str>,
+    ) -> Result<Option<(i64, Settlement)>, PaymentError> {
+        let invoices = self.invoice_provider(&payment.currency)?;
+        let order_id = parse_invoice_payload(&payment.invoice_payload).ok_or(PaymentError::OrderNotFound)?;
+        // Счет выставлен на остаток с округлением вверх: если сумма совпала, зачитывается ровно остаток
+        let credited_amount = match load_order(&self.db_pool, order_id, Some(user_id)).await? {
+            Some(order) if invoices.invoice_amount(order.amount_due()) == payment.total_amount => order.amount_due(),
+            _ => invoices.paid_amount(payment.total_amount) / invoices.config.ton_rate,
+        };
+        // Сумма записывается в валюте счета
+        let settlement = self.apply_payment(&NewPaymentRecord {
+            order_id,
+            user_id,
+            provider: invoices.code(),
+            amount: invoices.paid_amount(payment.total_amount),
+            currency: &payment.currency,
+            wallet_address: "",
+            transaction_hash: Some(&payment.telegram_payment_charge_id.0),
+            provider_payment_id: Some(&payment.provider_payment_charge_id),
+            credited_amount,
+        }, username).await?;
+
+        Ok(settlement.map(|settlement| (order_id, settlement)))
+    }
+
+    // Записывает платеж и зачитывает его в заказ в одной транзакции.
+    // None - платеж уже был записан: повтор уведомления или опроса ничего не меняет и ничего не отправляет
+    pub async fn apply_payment(
+        &self,
+        payment: &NewPaymentRecord<'_>,
+        username: Option<&str>,
+    ) -> Result<Option<Settlement>, PaymentError> {
+        let mut tx = self.db_pool.begin().await?;
+        if !record_payment(&mut tx, payment).await? {
+            return Ok(None);
+        }
+        let settlement = self.settle_in(&mut tx, payment.order_id, username).await?;
+        tx.commit().await?;
+
+        Ok(Some(settlement))
+    }
+
+    // Зачитывает платежи по заказу в транзакции, которая записала новый платеж: ставит в очередь просьбу
+    // доплатить остаток или закрывает заказ и оформляет переплату
+    pub async fn settle_in(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        order_id: i64,
+        username: Option<&str>,
+    ) -> Result<Settlement, PaymentError> {
+        let order = load_order(&mut **tx, order_id, None).await?.ok_or(PaymentError::OrderNotFound)?;
+        if order.status != "pending" {
+            return Ok(Settlement::Closed);
+        }
+
+        let remaining = order.amount_due();
+        if remaining > PAYMENT_TOLERANCE {
+            // Доплата предлагается тем же способом, которым пришел последний платеж
+            let provider = sqlx::query_scalar::<_, String>(
+                "SELECT provider FROM payments WHERE order_id = ? AND status = 'confirmed' ORDER BY id DESC LIMIT 1"
+            )
+            .bind(order_id.to_string())
+            .fetch_optional(&mut **tx)
+            .await?;
+            outbox::enqueue(tx, &OutboxMessage::PaymentRequest {
+                order_id,
+                user_id: order.user_id,
+                provider,
+            }).await?;
+            return Ok(Settlement::Partial { paid: order.paid_amount, remaining });
+        }
+
+        // Заказ закрывает только один из одновременно пришедших платежей.
+        // Переплата оформляется в той же транзакции, что и оплата заказа
+        let updated = sqlx::query("UPDATE orders SET status = 'paid' WHERE id = ? AND status = 'pending'")
+            .bind(order_id)
+            .execute(&mut **tx)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(Settlement::Closed);
+        }
+        outbox::enqueue(tx, &OutboxMessage::AdminNewOrder {
+            order_id,
+            username: username.map(str::to_string),
+        }).await?;
+
+        let overpaid = order.paid_amount - order.total_amount;
+        let overpaid = if overpaid > PAYMENT_TOLERANCE {
+            self.record_overpayment(tx, &order, overpaid).await?;
+            overpaid
+        } else {
+            0.0
+        };
+
+        if overpaid > 0.0 {
+            if self.credit_overpayments {
+                println!("🎁 Переплата {:.2} TON по заказу №{} зачислена на баланс покупателя", overpaid, order_id);
+            } else {
+                println!("↩️ Переплата {:.2} TON по заказу №{} ждет возврата", overpaid, order_id);
+            }
+        }
+        Ok(Settlement::Covered { overpaid })
+    }
+
+    // Переплата зачисляется на баланс покупателя, который списывается при следующем заказе целиком,
+    // без ограничения на долю заказа, как у баллов
+    async fn record_overpayment(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        order: &PayableOrder,
+        amount: f64,
+    ) -> Result<(), PaymentError> {
+        let status = if self.credit_overpayments { "credited" } else { "refund_pending" };
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO order_overpayments (order_id, user_id, amount, status)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(order_id) DO NOTHING
+            "#
+        )
+        .bind(order.id)
+        .bind(order.user_id)
+        .bind(amount)
+        .bind(status)
+        .execute(&mut **tx)
+        .await?;
+
+        if inserted.rows_affected() == 1 && self.credit_overpayments {
+            store_credit::credit_overpayment(tx, order.user_id, order.id, amount).await?;
+        }
+        Ok(())
+    }
+
+    // Возвращает деньги за оплаченный заказ через провайдеров, которыми он был оплачен.
+    // Платеж отмечается возвращенным, только если деньги вернул провайдер; иначе он ждет ручного возврата
+    pub async fn refund(&self, order_id: i64) -> Result<RefundSummary, PaymentError> {
+        // Заказ мог быть оплачен несколькими платежами; неоплаченный заказ возвращать не нужно
+        let payments = sqlx::query_as::<_, PaymentRecord>(
+            "SELECT id, user_id, provider, transaction_hash FROM payments WHERE order_id = ? AND status = 'confirmed'"
+        )
+        .bind(order_id.to_string())
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut summary = RefundSummary::default();
+        for payment in payments {
+            let outcome = match self.providers.iter().find(|provider| provider.code() == payment.provider) {
+                Some(provider) => provider.refund(&payment).await,
+                None => Err(PaymentError::UnknownProvider(payment.provider.clone())),
+            };
+            let status = match outcome {
+                Ok(RefundOutcome::Returned) => {
+                    summary.refunded += 1;
+                    "refunded"
+                }
+                Ok(RefundOutcome::Manual) => {
+                    summary.pending += 1;
+                    "refund_pending"
+                }
+                Err(e) => {
+                    eprintln!("⚠️ Платеж {} по заказу №{} не возвращен: {}", payment.id, order_id, e);
+                    summary.pending += 1;
+                    "refund_pending"
+                }
+            };
+
+            sqlx::query("UPDATE payments SET status = ? WHERE id = ? AND status = 'confirmed'")
+                .bind(status)
+                .bind(payment.id)
+                .execute(&self.db_pool)
+                .await?;
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    // База в памяти живет, пока открыто единственное соединение
+    async fn service() -> PaymentService {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::database::run_migrations(&pool).await.unwrap();
+        PaymentService::new(pool)
+    }
+
+    async fn order(service: &PaymentService, total_amount: f64) -> i64 {
+        sqlx::query_scalar::<_, i64>(
+            "INSERT INTO orders (user_id, total_amount, status, delivery_address) VALUES (7, ?, 'pending', '') RETURNING id"
+        )
+            .bind(total_amount)
+            .fetch_one(&service.db_pool)
+            .await
+            .unwrap()
+    }
+
+    async fn pay(service: &PaymentService, order_id: i64, hash: &str, credited_amount: f64) -> Option<Settlement> {
+        service.apply_payment(&NewPaymentRecord {
+            order_id,
+            user_id: 7,
+            provider: "ton",
+            amount: credited_amount,
+            currency: "TON",
+            wallet_address: "",
+            transaction_hash: Some(hash),
+            provider_payment_id: None,
+            credited_amount,
+        }, None).await.unwrap()
+    }
+
+    async fn settle(service: &PaymentService, order_id: i64) -> Settlement {
+        let mut tx = service.db_pool.begin().await.unwrap();
+        let settlement = service.settle_in(&mut tx, order_id, None).await.unwrap();
+        tx.commit().await.unwrap();
+        settlement
+    }
+
+    async fn order_status(service: &PaymentService, order_id: i64) -> String {
+        sqlx::query_scalar("SELECT status FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(&service.db_pool)
+            .await
+            .unwrap()
+    }
+
+    async fn queued(service: &PaymentService, kind: &str) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM notification_outbox WHERE kind = ?")
+            .bind(kind)
+            .fetch_one(&service.db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn partial_payment_keeps_order_pending() {
+        let service = service().await;
+        let order_id = order(&service, 10.0).await;
+
+        assert_eq!(pay(&service, order_id, "tx1", 9.5).await, Some(Settlement::Partial { paid: 9.5, remaining: 0.5 }));
+        assert_eq!(order_status(&service, order_id).await, "pending");
+        assert_eq!(queued(&service, "payment_request").await, 1);
+    }
+
+    #[tokio::test]
+    async fn replayed_partial_payment_does_not_request_top_up_again() {
+        let service = service().await;
+        let order_id = order(&service, 10.0).await;
+        pay(&service, order_id, "tx1", 9.5).await;
+
+        assert_eq!(pay(&service, order_id, "tx1", 9.5).await, None);
+        assert_eq!(queued(&service, "payment_request").await, 1);
+    }
+
+    #[tokio::test]
+    async fn top_up_covers_order() {
+        let service = service().await;
+        let order_id = order(&service, 10.0).await;
+        pay(&service, order_id, "tx1", 9.5).await;
+
+        assert_eq!(pay(&service, order_id, "tx2", 0.5).await, Some(Settlement::Covered { overpaid: 0.0 }));
+        assert_eq!(order_status(&service, order_id).await, "paid");
+        assert_eq!(queued(&service, "admin_new_order").await, 1);
+    }
+
+    #[tokio::test]
+    async fn shortfall_within_tolerance_covers_order() {
+        let service = service().await;
+        let order_id = order(&service, 10.0).await;
+
+        assert_eq!(pay(&service, order_id, "tx1", 9.995).await, Some(Settlement::Covered { overpaid: 0.0 }));
+    }
+
+    #[tokio::test]
+    async fn overpayment_waits_for_refund_without_store_credit() {
+        let service = service().await;
+        let order_id = order(&service, 10.0).await;
+        pay(&service, order_id, "tx1", 6.0).await;
+
+        assert_eq!(pay(&service, order_id, "tx2", 4.5).await, Some(Settlement::Covered { overpaid: 0.5 }));
+        let (amount, status): (f64, String) =
+            sqlx::query_as("SELECT amount, status FROM order_overpayments WHERE order_id = ?")
+                .bind(order_id)
+                .fetch_one(&service.db_pool)
+                .await
+                .unwrap();
+        assert_eq!((amount, status.as_str()), (0.5, "refund_pending"));
+    }
+
+    #[tokio::test]
+    async fn replayed_settlement_does_not_pay_twice() {
+        let service = service().await;
+        let order_id = order(&service, 10.0).await;
+        pay(&service, order_id, "tx1", 10.5).await;
+
+        assert_eq!(pay(&service, order_id, "tx1", 10.5).await, None);
+        assert_eq!(settle(&service, order_id).await, Settlement::Closed);
+        let (overpayments, queued): (i64, i64) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM order_overpayments), (SELECT COUNT(*) FROM notification_outbox)"
+        )
+            .fetch_one(&service.db_pool)
+            .await
+            .unwrap();
+        assert_eq!((overpayments, queued), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn overpayment_is_booked_as_store_credit() {
+        let mut service = service().await;
+        service.credit_overpayments();
+        let order_id = order(&service, 10.0).await;
+
+        assert_eq!(pay(&service, order_id, "tx1", 12.5).await, Some(Settlement::Covered { overpaid: 2.5 }));
+        let status: String = sqlx::query_scalar("SELECT status FROM order_overpayments WHERE order_id = ?")
+            .bind(order_id)
+            .fetch_one(&service.db_pool)
+            .await
+            .unwrap();
+        assert_eq!(status, "credited");
+        assert_eq!(store_credit::balance(&service.db_pool, 7).await.unwrap(), 2.5);
+
+        // Повтор не зачисляет переплату второй раз, возврат заказа ее снимает
+        assert_eq!(settle(&service, order_id).await, Settlement::Closed);
+        let mut tx = service.db_pool.begin().await.unwrap();
+        store_credit::reverse_for_order(&mut tx, order_id).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(store_credit::balance(&service.db_pool, 7).await.unwrap(), 0.0);
+    }
+}
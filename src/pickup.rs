@@ -0,0 +1,219 @@
+use image::{ImageFormat, Luma};
+use qrcode::QrCode;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::io::Cursor;
+use thiserror::Error;
+use uuid::Uuid;
+use crate::telegram_notifications::{NotificationError, TelegramNotifier};
+
+#[derive(Error, Debug)]
+pub enum PickupError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Notification error: {0}")]
+    NotificationError(#[from] NotificationError),
+    #[error("QR error: {0}")]
+    QrError(String),
+    #[error("Заказ не найден")]
+    OrderNotFound,
+    #[error("Заказ оформлен не на самовывоз")]
+    NotPickupOrder,
+    #[error("Код выдачи не найден")]
+    InvalidCode,
+    #[error("Заказ уже выдан")]
+    AlreadyHandedOver,
+    #[error("Заказ в статусе \"{0}\" нельзя выдать")]
+    InvalidStatus(String),
+    #[error("Слишком много неверных кодов, попробуйте через {} минут", ATTEMPTS_WINDOW_MINUTES)]
+    TooManyAttempts,
+}
+
+impl PickupError {
+    // Ошибки, которые можно показать сотруднику как есть
+    pub fn is_user_error(&self) -> bool {
+        matches!(
+            self,
+            PickupError::OrderNotFound
+                | PickupError::NotPickupOrder
+                | PickupError::InvalidCode
+                | PickupError::AlreadyHandedOver
+                | PickupError::InvalidStatus(_)
+                | PickupError::TooManyAttempts
+        )
+    }
+}
+
+// Префикс содержимого QR-кода, сканер на стойке принимает и его, и код вручную
+const QR_PREFIX: &str = "SPORTSHOP-PICKUP:";
+
+// Неверных кодов от одного сотрудника за окно, после которых проверка блокируется
+const MAX_FAILED_ATTEMPTS: i64 = 5;
+const ATTEMPTS_WINDOW_MINUTES: i64 = 15;
+
+fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+fn generate_code() -> String {
+    format!("{:08}", Uuid::new_v4().as_u128() % 100_000_000)
+}
+
+// Код из введенного текста или отсканированного QR
+pub fn normalize_code(input: &str) -> String {
+    let input = input.trim();
+    input
+        .strip_prefix(QR_PREFIX)
+        .unwrap_or(input)
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect()
+}
+
+pub fn render_qr(code: &str) -> Result<Vec<u8>, PickupError> {
+    let qr = QrCode::new(format!("{}{}", QR_PREFIX, code))
+        .map_err(|e| PickupError::QrError(e.to_string()))?;
+    let image = qr.render::<Luma<u8>>().min_dimensions(320, 320).build();
+
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| PickupError::QrError(e.to_string()))?;
+    Ok(png)
+}
+
+pub async fn is_pickup_order(pool: &SqlitePool, order_id: i64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM orders o
+            JOIN delivery_methods dm ON dm.id = o.delivery_method_id
+            WHERE o.id = ? AND dm.kind = 'self_pickup'
+        )
+        "#
+    )
+        .bind(order_id)
+        .fetch_one(pool)
+        .await
+}
+
+// Заказ собран: выдаем новый одноразовый код и отправляем покупателю QR.
+// Код выдается только оплаченному заказу; повторный вызов заменяет код, если заказ еще не выдан.
+pub async fn mark_ready(
+    pool: &SqlitePool,
+    notifier: &TelegramNotifier,
+    order_id: i64,
+) -> Result<(), PickupError> {
+    let (user_id, status) = sqlx::query_as::<_, (i64, String)>("SELECT user_id, status FROM orders WHERE id = ?")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(PickupError::OrderNotFound)?;
+
+    if !is_pickup_order(pool, order_id).await? {
+        return Err(PickupError::NotPickupOrder);
+    }
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        "UPDATE orders SET status = 'ready_for_pickup' WHERE id = ? AND status IN ('paid', 'packed', 'ready_for_pickup')"
+    )
+        .bind(order_id)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Err(PickupError::InvalidStatus(status));
+    }
+
+    let code = generate_code();
+    let result = sqlx::query(
+        r#"
+        INSERT INTO order_pickups (order_id, code_hash) VALUES (?, ?)
+        ON CONFLICT(order_id) DO UPDATE SET code_hash = excluded.code_hash, ready_at = CURRENT_TIMESTAMP
+        WHERE order_pickups.handed_over_at IS NULL
+        "#
+    )
+        .bind(order_id)
+        .bind(hash_code(&code))
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Err(PickupError::AlreadyHandedOver);
+    }
+    tx.commit().await?;
+
+    let qr = render_qr(&code)?;
+    notifier.send_pickup_code(user_id, order_id, &code, qr).await?;
+    Ok(())
+}
+
+// Проверка кода на стойке: заказ выдается и переводится в completed. Возвращает ID выданного заказа.
+// Неверные коды учитываются: после MAX_FAILED_ATTEMPTS за окно сотрудник ждет, код не подобрать перебором
+pub async fn hand_over(pool: &SqlitePool, code: &str, staff_id: i64) -> Result<i64, PickupError> {
+    let failed_attempts = sqlx::query_scalar::<_, i64>(&format!(
+        "SELECT COUNT(*) FROM pickup_code_attempts WHERE staff_id = ? AND attempted_at >= datetime('now', '-{} minutes')",
+        ATTEMPTS_WINDOW_MINUTES
+    ))
+        .bind(staff_id)
+        .fetch_one(pool)
+        .await?;
+    if failed_attempts >= MAX_FAILED_ATTEMPTS {
+        return Err(PickupError::TooManyAttempts);
+    }
+
+    let code = normalize_code(code);
+    let pickup = sqlx::query_as::<_, (i64, bool, String)>(
+        r#"
+        SELECT p.order_id, p.handed_over_at IS NOT NULL, o.status
+        FROM order_pickups p
+        JOIN orders o ON o.id = p.order_id
+        WHERE p.code_hash = ?
+        "#
+    )
+        .bind(hash_code(&code))
+        .fetch_optional(pool)
+        .await?;
+
+    let Some((order_id, handed_over, status)) = pickup else {
+        sqlx::query("INSERT INTO pickup_code_attempts (staff_id) VALUES (?)")
+            .bind(staff_id)
+            .execute(pool)
+            .await?;
+        return Err(PickupError::InvalidCode);
+    };
+    if handed_over {
+        return Err(PickupError::AlreadyHandedOver);
+    }
+    // Заказ могли отменить или вернуть после выдачи кода
+    if status != "ready_for_pickup" {
+        return Err(PickupError::InvalidStatus(status));
+    }
+
+    // Выдача и завершение заказа - одной транзакцией
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        "UPDATE order_pickups SET handed_over_at = CURRENT_TIMESTAMP, handed_over_by = ? WHERE order_id = ? AND handed_over_at IS NULL"
+    )
+        .bind(staff_id)
+        .bind(order_id)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Err(PickupError::AlreadyHandedOver);
+    }
+
+    let result = sqlx::query("UPDATE orders SET status = 'completed' WHERE id = ? AND status = 'ready_for_pickup'")
+        .bind(order_id)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Err(PickupError::InvalidStatus(status));
+    }
+    tx.commit().await?;
+
+    Ok(order_id)
+}
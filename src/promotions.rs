@@ -0,0 +1,680 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PromotionError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Промокод не найден")]
+    NotFound,
+    #[error("Промокод неактивен")]
+    Inactive,
+    #[error("Акция еще не началась")]
+    NotStarted,
+    #[error("Срок действия промокода истек")]
+    Expired,
+    #[error("Минимальная сумма заказа для промокода: {0:.2} TON")]
+    MinOrderTotal(f64),
+    #[error("Промокод больше недоступен")]
+    UsageLimitReached,
+    #[error("Вы уже использовали этот промокод")]
+    UserUsageLimitReached,
+    #[error("Для этого промокода нужно открыть магазин через Telegram")]
+    AuthRequired,
+    #[error("Промокод уже применен")]
+    AlreadyApplied,
+    #[error("Промокод нельзя совмещать с другими")]
+    NotStackable,
+    #[error("Промокод не подходит к товарам в корзине")]
+    NotApplicable,
+    #[error("Invalid promotion: {0}")]
+    InvalidPromotion(String),
+}
+
+impl PromotionError {
+    // Ошибки, которые можно показать покупателю (все, кроме ошибок БД)
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, PromotionError::DbError(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum DiscountType {
+    Percentage,
+    Fixed,
+    FreeDelivery,
+    BuyXGetY,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum PromotionScope {
+    Order,
+    Category,
+    Product,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Promotion {
+    pub id: i64,
+    pub code: String,
+    pub description: Option<String>,
+    pub discount_type: DiscountType,
+    pub discount_value: f64,
+    pub buy_quantity: Option<i32>,
+    pub get_quantity: Option<i32>,
+    pub scope: PromotionScope,
+    pub scope_id: Option<i64>,
+    pub min_order_total: Option<f64>,
+    pub starts_at: Option<NaiveDateTime>,
+    pub ends_at: Option<NaiveDateTime>,
+    pub usage_limit: Option<i64>,
+    pub usage_limit_per_user: Option<i64>,
+    pub stackable: bool,
+    pub is_active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePromotion {
+    pub code: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub discount_type: DiscountType,
+    #[serde(default)]
+    pub discount_value: f64,
+    #[serde(default)]
+    pub buy_quantity: Option<i32>,
+    #[serde(default)]
+    pub get_quantity: Option<i32>,
+    #[serde(default = "default_scope")]
+    pub scope: PromotionScope,
+    #[serde(default)]
+    pub scope_id: Option<i64>,
+    #[serde(default)]
+    pub min_order_total: Option<f64>,
+    #[serde(default)]
+    pub starts_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub ends_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub usage_limit: Option<i64>,
+    #[serde(default)]
+    pub usage_limit_per_user: Option<i64>,
+    #[serde(default)]
+    pub stackable: bool,
+}
+
+fn default_scope() -> PromotionScope {
+    PromotionScope::Order
+}
+
+// Позиция корзины в том виде, в котором ее видит движок скидок
+#[derive(Debug, FromRow)]
+pub struct PromoCartLine {
+    pub product_id: i64,
+    pub category_id: Option<i64>,
+    pub quantity: i32,
+    pub price: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedDiscount {
+    pub promotion_id: i64,
+    pub code: String,
+    pub description: Option<String>,
+    pub amount: f64,
+    pub free_delivery: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DiscountSummary {
+    pub subtotal: f64,
+    pub discounts: Vec<AppliedDiscount>,
+    pub discount_amount: f64,
+    pub free_delivery: bool,
+    pub total_amount: f64,
+}
+
+const PROMOTION_COLUMNS: &str = r#"
+    id, code, description, discount_type, discount_value, buy_quantity, get_quantity,
+    scope, scope_id, min_order_total, starts_at, ends_at, usage_limit, usage_limit_per_user,
+    stackable, is_active
+"#;
+
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn round_money(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+// Проверка параметров новой акции
+pub fn validate_new_promotion(promo: &CreatePromotion) -> Result<(), PromotionError> {
+    let code = normalize_code(&promo.code);
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(PromotionError::InvalidPromotion(
+            "code must contain only latin letters, digits and '-'".to_string(),
+        ));
+    }
+
+    match promo.discount_type {
+        DiscountType::Percentage if !(0.0..=100.0).contains(&promo.discount_value) => {
+            return Err(PromotionError::InvalidPromotion("percentage must be between 0 and 100".to_string()));
+        }
+        DiscountType::Fixed if promo.discount_value <= 0.0 => {
+            return Err(PromotionError::InvalidPromotion("fixed discount must be positive".to_string()));
+        }
+        DiscountType::BuyXGetY
+            if promo.buy_quantity.unwrap_or(0) <= 0 || promo.get_quantity.unwrap_or(0) <= 0 =>
+        {
+            return Err(PromotionError::InvalidPromotion("buy_quantity and get_quantity are required".to_string()));
+        }
+        _ => {}
+    }
+
+    if promo.scope != PromotionScope::Order && promo.scope_id.is_none() {
+        return Err(PromotionError::InvalidPromotion("scope_id is required for this scope".to_string()));
+    }
+
+    if let (Some(starts_at), Some(ends_at)) = (promo.starts_at, promo.ends_at) {
+        if ends_at <= starts_at {
+            return Err(PromotionError::InvalidPromotion("ends_at must be after starts_at".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn create_promotion(pool: &SqlitePool, promo: &CreatePromotion) -> Result<i64, PromotionError> {
+    validate_new_promotion(promo)?;
+
+    let id = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO promotions (
+            code, description, discount_type, discount_value, buy_quantity, get_quantity,
+            scope, scope_id, min_order_total, starts_at, ends_at, usage_limit,
+            usage_limit_per_user, stackable
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        RETURNING id
+        "#
+    )
+        .bind(normalize_code(&promo.code))
+        .bind(&promo.description)
+        .bind(promo.discount_type)
+        .bind(promo.discount_value)
+        .bind(promo.buy_quantity)
+        .bind(promo.get_quantity)
+        .bind(promo.scope)
+        .bind(promo.scope_id)
+        .bind(promo.min_order_total)
+        .bind(promo.starts_at)
+        .bind(promo.ends_at)
+        .bind(promo.usage_limit)
+        .bind(promo.usage_limit_per_user)
+        .bind(promo.stackable)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(id)
+}
+
+pub async fn list_promotions(pool: &SqlitePool) -> Result<Vec<Promotion>, sqlx::Error> {
+    sqlx::query_as::<_, Promotion>(&format!(
+        "SELECT {} FROM promotions ORDER BY id DESC",
+        PROMOTION_COLUMNS
+    ))
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn find_by_code(pool: &SqlitePool, code: &str) -> Result<Promotion, PromotionError> {
+    sqlx::query_as::<_, Promotion>(&format!(
+        "SELECT {} FROM promotions WHERE code = ?",
+        PROMOTION_COLUMNS
+    ))
+        .bind(normalize_code(code))
+        .fetch_optional(pool)
+        .await?
+        .ok_or(PromotionError::NotFound)
+}
+
+async fn cart_promotions(pool: &SqlitePool, cart_id: i64) -> Result<Vec<Promotion>, sqlx::Error> {
+    sqlx::query_as::<_, Promotion>(&format!(
+        r#"
+        SELECT {} FROM promotions
+        JOIN cart_promotions ON cart_promotions.promotion_id = promotions.id
+        WHERE cart_promotions.cart_id = ?
+        ORDER BY cart_promotions.applied_at, promotions.id
+        "#,
+        PROMOTION_COLUMNS
+    ))
+        .bind(cart_id)
+        .fetch_all(pool)
+        .await
+}
+
+async fn cart_lines(pool: &SqlitePool, cart_id: i64) -> Result<Vec<PromoCartLine>, sqlx::Error> {
+    sqlx::query_as::<_, PromoCartLine>(
+        r#"
+        SELECT c.product_id, p.category_id, c.quantity, p.price
+        FROM cart c
+        JOIN products p ON c.product_id = p.id
+        WHERE c.cart_id = ?
+        "#
+    )
+        .bind(cart_id)
+        .fetch_all(pool)
+        .await
+}
+
+// Проверка срока действия, минимальной суммы и лимитов использования
+pub async fn check_eligibility(
+    pool: &SqlitePool,
+    promo: &Promotion,
+    user_id: Option<i64>,
+    subtotal: f64,
+) -> Result<(), PromotionError> {
+    if !promo.is_active {
+        return Err(PromotionError::Inactive);
+    }
+
+    let now = Utc::now().naive_utc();
+    if promo.starts_at.is_some_and(|starts_at| starts_at > now) {
+        return Err(PromotionError::NotStarted);
+    }
+    if promo.ends_at.is_some_and(|ends_at| ends_at < now) {
+        return Err(PromotionError::Expired);
+    }
+
+    if let Some(min_order_total) = promo.min_order_total {
+        if subtotal < min_order_total {
+            return Err(PromotionError::MinOrderTotal(min_order_total));
+        }
+    }
+
+    let mut conn = pool.acquire().await?;
+    check_usage_limits(&mut conn, promo.id, promo.usage_limit, promo.usage_limit_per_user, user_id).await
+}
+
+// Лимиты использования считаются по заказам, которые не отменены и не возвращены
+async fn check_usage_limits(
+    conn: &mut SqliteConnection,
+    promotion_id: i64,
+    usage_limit: Option<i64>,
+    usage_limit_per_user: Option<i64>,
+    user_id: Option<i64>,
+) -> Result<(), PromotionError> {
+    if let Some(usage_limit) = usage_limit {
+        let used = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT COUNT(*) FROM order_discounts d
+            JOIN orders o ON d.order_id = o.id
+            WHERE d.promotion_id = ? AND o.status NOT IN ('cancelled', 'refunded')
+            "#
+        )
+            .bind(promotion_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if used >= usage_limit {
+            return Err(PromotionError::UsageLimitReached);
+        }
+    }
+
+    if let Some(limit_per_user) = usage_limit_per_user {
+        let user_id = user_id.ok_or(PromotionError::AuthRequired)?;
+        let used = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT COUNT(*) FROM order_discounts d
+            JOIN orders o ON d.order_id = o.id
+            WHERE d.promotion_id = ? AND o.user_id = ? AND o.status NOT IN ('cancelled', 'refunded')
+            "#
+        )
+            .bind(promotion_id)
+            .bind(user_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if used >= limit_per_user {
+            return Err(PromotionError::UserUsageLimitReached);
+        }
+    }
+
+    Ok(())
+}
+
+fn in_scope(promo: &Promotion, line: &PromoCartLine) -> bool {
+    match promo.scope {
+        PromotionScope::Order => true,
+        PromotionScope::Category => line.category_id.is_some() && line.category_id == promo.scope_id,
+        PromotionScope::Product => Some(line.product_id) == promo.scope_id,
+    }
+}
+
+// Скидка по одной акции без учета других акций
+pub fn discount_for(promo: &Promotion, lines: &[PromoCartLine]) -> AppliedDiscount {
+    let eligible: Vec<&PromoCartLine> = lines.iter().filter(|line| in_scope(promo, line)).collect();
+    let eligible_total: f64 = eligible.iter().map(|line| line.price * line.quantity as f64).sum();
+
+    let amount = match promo.discount_type {
+        DiscountType::Percentage => eligible_total * promo.discount_value.clamp(0.0, 100.0) / 100.0,
+        DiscountType::Fixed => promo.discount_value.max(0.0).min(eligible_total),
+        DiscountType::FreeDelivery => 0.0,
+        DiscountType::BuyXGetY => {
+            let buy = promo.buy_quantity.unwrap_or(0);
+            let get = promo.get_quantity.unwrap_or(0);
+            if buy <= 0 || get <= 0 {
+                0.0
+            } else {
+                // Из каждых buy + get единиц товара get единиц бесплатно
+                eligible.iter()
+                    .map(|line| line.price * ((line.quantity / (buy + get)) * get) as f64)
+                    .sum()
+            }
+        }
+    };
+
+    AppliedDiscount {
+        promotion_id: promo.id,
+        code: promo.code.clone(),
+        description: promo.description.clone(),
+        amount: round_money(amount),
+        free_delivery: promo.discount_type == DiscountType::FreeDelivery && !eligible.is_empty(),
+    }
+}
+
+// Пересчет скидок корзины. Акции, переставшие проходить проверки, пропускаются.
+pub async fn evaluate_cart(
+    pool: &SqlitePool,
+    cart_id: i64,
+    user_id: Option<i64>,
+) -> Result<DiscountSummary, PromotionError> {
+    let lines = cart_lines(pool, cart_id).await?;
+    let subtotal = round_money(lines.iter().map(|line| line.price * line.quantity as f64).sum());
+
+    let mut summary = DiscountSummary {
+        subtotal,
+        total_amount: subtotal,
+        ..Default::default()
+    };
+
+    for promo in cart_promotions(pool, cart_id).await? {
+        match check_eligibility(pool, &promo, user_id, subtotal).await {
+            Ok(()) => {}
+            Err(PromotionError::DbError(e)) => return Err(PromotionError::DbError(e)),
+            Err(e) => {
+                println!("⚠️ Промокод {} не применен к корзине {}: {}", promo.code, cart_id, e);
+                continue;
+            }
+        }
+
+        let mut discount = discount_for(&promo, &lines);
+        // Суммарная скидка не может превышать стоимость товаров
+        discount.amount = discount.amount.min(round_money(subtotal - summary.discount_amount));
+        summary.discount_amount = round_money(summary.discount_amount + discount.amount);
+        summary.free_delivery |= discount.free_delivery;
+        summary.discounts.push(discount);
+    }
+
+    summary.total_amount = round_money(subtotal - summary.discount_amount);
+    Ok(summary)
+}
+
+// Применение промокода к корзине с проверкой правил совмещения
+pub async fn apply_code(
+    pool: &SqlitePool,
+    cart_id: i64,
+    code: &str,
+    user_id: Option<i64>,
+) -> Result<DiscountSummary, PromotionError> {
+    let promo = find_by_code(pool, code).await?;
+    let lines = cart_lines(pool, cart_id).await?;
+    let subtotal = lines.iter().map(|line| line.price * line.quantity as f64).sum();
+
+    check_eligibility(pool, &promo, user_id, subtotal).await?;
+
+    let applied = cart_promotions(pool, cart_id).await?;
+    if applied.iter().any(|p| p.id == promo.id) {
+        return Err(PromotionError::AlreadyApplied);
+    }
+    if !applied.is_empty() && (!promo.stackable || applied.iter().any(|p| !p.stackable)) {
+        return Err(PromotionError::NotStackable);
+    }
+
+    let discount = discount_for(&promo, &lines);
+    if discount.amount <= 0.0 && !discount.free_delivery {
+        return Err(PromotionError::NotApplicable);
+    }
+
+    sqlx::query("INSERT INTO cart_promotions (cart_id, promotion_id) VALUES (?, ?)")
+        .bind(cart_id)
+        .bind(promo.id)
+        .execute(pool)
+        .await?;
+
+    evaluate_cart(pool, cart_id, user_id).await
+}
+
+pub async fn remove_code(pool: &SqlitePool, cart_id: i64, code: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM cart_promotions WHERE cart_id = ? AND promotion_id = (SELECT id FROM promotions WHERE code = ?)"
+    )
+        .bind(cart_id)
+        .bind(normalize_code(code))
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Сохраняет разбивку скидок в заказе. Лимиты использования перепроверяются в транзакции
+// оформления, чтобы параллельные заказы не превысили их. user_id - пользователь,
+// подтвержденный через Telegram: без него промокоды с лимитом на пользователя не применяются.
+pub async fn record_order_discounts(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    user_id: Option<i64>,
+    summary: &DiscountSummary,
+) -> Result<(), PromotionError> {
+    for discount in &summary.discounts {
+        let (usage_limit, usage_limit_per_user) = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(
+            "SELECT usage_limit, usage_limit_per_user FROM promotions WHERE id = ?"
+        )
+            .bind(discount.promotion_id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or(PromotionError::NotFound)?;
+        check_usage_limits(tx, discount.promotion_id, usage_limit, usage_limit_per_user, user_id).await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO order_discounts (order_id, promotion_id, code, description, amount, free_delivery)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#
+        )
+            .bind(order_id)
+            .bind(discount.promotion_id)
+            .bind(&discount.code)
+            .bind(&discount.description)
+            .bind(discount.amount)
+            .bind(discount.free_delivery)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn deactivate_promotion(pool: &SqlitePool, promotion_id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE promotions SET is_active = FALSE WHERE id = ?")
+        .bind(promotion_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    const USER_ID: i64 = 7;
+    const OTHER_USER_ID: i64 = 8;
+
+    // База в памяти живет, пока открыто единственное соединение
+    async fn pool() -> SqlitePool {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::database::run_migrations(&pool).await.unwrap();
+        pool
+    }
+
+    async fn promotion(pool: &SqlitePool, promo: serde_json::Value) -> i64 {
+        create_promotion(pool, &serde_json::from_value(promo).unwrap()).await.unwrap()
+    }
+
+    // Корзина с одним товаром за 10 TON. Пользователь передается в проверки промокодов отдельно
+    async fn cart(pool: &SqlitePool) -> i64 {
+        let product_id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO products (name, description, price, stock, image_url) VALUES ('Мяч', '', 10.0, 10, '') RETURNING id"
+        )
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let cart_id = sqlx::query_scalar::<_, i64>("INSERT INTO carts DEFAULT VALUES RETURNING id")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO cart (cart_id, product_id, quantity, price_at_add) VALUES (?, ?, 1, 10.0)")
+            .bind(cart_id)
+            .bind(product_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        cart_id
+    }
+
+    // Заказ со скидками, посчитанными по корзине, как при оформлении
+    async fn place_order(pool: &SqlitePool, user_id: i64, summary: &DiscountSummary) -> Result<i64, PromotionError> {
+        let mut tx = pool.begin().await?;
+        let order_id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO orders (user_id, total_amount, status, delivery_address) VALUES (?, ?, 'pending', '') RETURNING id"
+        )
+            .bind(user_id)
+            .bind(summary.total_amount)
+            .fetch_one(&mut *tx)
+            .await?;
+        record_order_discounts(&mut tx, order_id, Some(user_id), summary).await?;
+        tx.commit().await?;
+        Ok(order_id)
+    }
+
+    #[tokio::test]
+    async fn usage_limit_per_user_counts_only_active_orders_of_that_user() {
+        let pool = pool().await;
+        promotion(&pool, json!({
+            "code": "once",
+            "discount_type": "percentage",
+            "discount_value": 10.0,
+            "usage_limit_per_user": 1
+        })).await;
+
+        let first_cart = cart(&pool).await;
+        let summary = apply_code(&pool, first_cart, "ONCE", Some(USER_ID)).await.unwrap();
+        let first_order = place_order(&pool, USER_ID, &summary).await.unwrap();
+
+        // Второй раз тот же пользователь промокод не применит, другой - применит
+        let second_cart = cart(&pool).await;
+        assert!(matches!(
+            apply_code(&pool, second_cart, "ONCE", Some(USER_ID)).await,
+            Err(PromotionError::UserUsageLimitReached)
+        ));
+        let other_cart = cart(&pool).await;
+        assert_eq!(apply_code(&pool, other_cart, "ONCE", Some(OTHER_USER_ID)).await.unwrap().discount_amount, 1.0);
+        // Без пользователя Telegram лимит на пользователя не проверить
+        assert!(matches!(
+            apply_code(&pool, second_cart, "ONCE", None).await,
+            Err(PromotionError::AuthRequired)
+        ));
+
+        // Отмененный заказ использование не занимает
+        sqlx::query("UPDATE orders SET status = 'cancelled' WHERE id = ?")
+            .bind(first_order)
+            .execute(&pool)
+            .await
+            .unwrap();
+        apply_code(&pool, second_cart, "ONCE", Some(USER_ID)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn usage_limit_per_user_is_rechecked_when_order_is_placed() {
+        let pool = pool().await;
+        promotion(&pool, json!({
+            "code": "once",
+            "discount_type": "fixed",
+            "discount_value": 2.0,
+            "usage_limit_per_user": 1
+        })).await;
+
+        // Обе корзины посчитаны со скидкой до оформления первого заказа
+        let first_cart = cart(&pool).await;
+        let second_cart = cart(&pool).await;
+        let first = apply_code(&pool, first_cart, "ONCE", Some(USER_ID)).await.unwrap();
+        let second = apply_code(&pool, second_cart, "ONCE", Some(USER_ID)).await.unwrap();
+
+        place_order(&pool, USER_ID, &first).await.unwrap();
+        assert!(matches!(
+            place_order(&pool, USER_ID, &second).await,
+            Err(PromotionError::UserUsageLimitReached)
+        ));
+        let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(orders, 1);
+        // Пересчет второй корзины промокод уже пропускает
+        assert_eq!(evaluate_cart(&pool, second_cart, Some(USER_ID)).await.unwrap().discount_amount, 0.0);
+    }
+
+    #[tokio::test]
+    async fn expired_code_is_rejected_and_dropped_from_cart() {
+        let pool = pool().await;
+        let yesterday = Utc::now().naive_utc() - chrono::Duration::days(1);
+        promotion(&pool, json!({
+            "code": "old",
+            "discount_type": "percentage",
+            "discount_value": 10.0,
+            "ends_at": yesterday
+        })).await;
+        let summer = promotion(&pool, json!({
+            "code": "summer",
+            "discount_type": "fixed",
+            "discount_value": 2.0
+        })).await;
+
+        let cart_id = cart(&pool).await;
+        assert!(matches!(apply_code(&pool, cart_id, "OLD", Some(USER_ID)).await, Err(PromotionError::Expired)));
+
+        // Промокод, истекший после применения к корзине, при пересчете не учитывается
+        assert_eq!(apply_code(&pool, cart_id, "SUMMER", Some(USER_ID)).await.unwrap().total_amount, 8.0);
+        sqlx::query("UPDATE promotions SET ends_at = ? WHERE id = ?")
+            .bind(yesterday)
+            .bind(summer)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let summary = evaluate_cart(&pool, cart_id, Some(USER_ID)).await.unwrap();
+        assert!(summary.discounts.is_empty());
+        assert_eq!(summary.total_amount, 10.0);
+    }
+}
@@ -0,0 +1,477 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use thiserror::Error;
+use crate::payments::{self, parse_order_comment, NewPaymentRecord, PaymentError, PaymentService, Settlement};
+use crate::ton_connect::{parse_address, raw_address};
+use crate::ton_payment::{parse_transfer_notification, JettonConfig};
+use crate::toncenter::{ToncenterClient, ToncenterError, Transaction};
+
+// Размер страницы истории кошелька магазина (максимум toncenter)
+const TRANSACTIONS_LIMIT: u32 = 100;
+// Окно сверки по умолчанию: переводы за последние дни
+const DEFAULT_WINDOW_DAYS: i64 = 7;
+// Предел страниц за один запуск, чтобы сбой API не зациклил сверку
+const MAX_PAGES: usize = 100;
+// Допустимое расхождение суммы, как при подтверждении платежа
+const AMOUNT_TOLERANCE: f64 = 0.01;
+
+#[derive(Error, Debug)]
+pub enum ReconciliationError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("{0}")]
+    ApiError(#[from] ToncenterError),
+    #[error("Перевод не найден")]
+    TransferNotFound,
+    #[error("Заказ не найден")]
+    OrderNotFound,
+    #[error("Перевод уже записан как оплата")]
+    TransferAlreadyRecorded,
+    #[error("Комментарий перевода не относится к заказу")]
+    TransferOrderMismatch,
+    #[error("Payment error: {0}")]
+    PaymentError(#[from] PaymentError),
+}
+
+impl ReconciliationError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(
+            self,
+            ReconciliationError::DbError(_) | ReconciliationError::ApiError(_) | ReconciliationError::PaymentError(_)
+        )
+    }
+}
+
+// Почему входящий перевод не сопоставлен с оплатой заказа
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Discrepancy {
+    // В комментарии нет номера заказа или такого заказа нет
+    UnknownOrder,
+    Underpaid,
+    Overpaid,
+    // Заказ уже оплачен другими платежами
+    Duplicate,
+    // Перевод на остаток суммы заказа, но оплата не записана
+    Unrecorded,
+}
+
+impl Discrepancy {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Discrepancy::UnknownOrder => "unknown_order",
+            Discrepancy::Underpaid => "underpaid",
+            Discrepancy::Overpaid => "overpaid",
+            Discrepancy::Duplicate => "duplicate",
+            Discrepancy::Unrecorded => "unrecorded",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct OnchainTransfer {
+    pub transaction_hash: String,
+    pub source: String,
+    pub currency: String,
+    pub amount: f64,
+    pub comment: Option<String>,
+    pub order_id: Option<i64>,
+    pub discrepancy: Option<Discrepancy>,
+    pub linked_manually: bool,
+    pub received_at: NaiveDateTime,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReconciliationSummary {
+    pub checked: usize,
+    pub discrepancies: usize,
+}
+
+// Окно сверки в днях из RECONCILIATION_WINDOW_DAYS
+fn window_days_from_env() -> i64 {
+    std::env::var("RECONCILIATION_WINDOW_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_WINDOW_DAYS)
+}
+
+// Входящий перевод до сверки
+struct IncomingTransfer {
+    transaction_hash: String,
+    source: String,
+    currency: String,
+    amount: f64,
+    comment: Option<String>,
+    utime: i64,
+}
+
+pub struct ReconciliationService {
+    db_pool: SqlitePool,
+    toncenter: ToncenterClient,
+    merchant_wallet: String,
+    jetton: Option<JettonConfig>,
+    window_days: i64,
+}
+
+impl ReconciliationService {
+    pub fn new(
+        db_pool: SqlitePool,
+        toncenter: ToncenterClient,
+        merchant_wallet: String,
+        jetton: Option<JettonConfig>,
+    ) -> Self {
+        Self {
+            db_pool,
+            toncenter,
+            merchant_wallet,
+            jetton,
+            window_days: window_days_from_env(),
+        }
+    }
+
+    // Транзакции кошелька магазина за окно сверки: история листается страницами по lt и hash
+    // последней полученной транзакции, пока не дойдет до начала окна
+    async fn window_transactions(&self) -> Result<Vec<Transaction>, ReconciliationError> {
+        let window_start = chrono::Utc::now().timestamp() - self.window_days * 24 * 60 * 60;
+        let mut transactions: Vec<Transaction> = Vec::new();
+
+        for _ in 0..MAX_PAGES {
+            let from = transactions.last().map(|transaction| transaction.transaction_id.clone());
+            let page = self.toncenter
+                .transactions_from(&self.merchant_wallet, TRANSACTIONS_LIMIT, from.as_ref())
+                .await?;
+            let full_page = page.len() == TRANSACTIONS_LIMIT as usize;
+
+            let mut reached_start = false;
+            for transaction in page {
+                // Страница начинается с транзакции, от которой листали
+                if from.as_ref().is_some_and(|from| from.hash == transaction.transaction_id.hash) {
+                    continue;
+                }
+                if transaction.utime < window_start {
+                    reached_start = true;
+                    break;
+                }
+                transactions.push(transaction);
+            }
+            if reached_start || !full_page {
+                return Ok(transactions);
+            }
+        }
+
+        eprintln!(
+            "⚠️ Сверка: за {} дн. больше {} транзакций, более ранние переводы не проверены",
+            self.window_days,
+            transactions.len()
+        );
+        Ok(transactions)
+    }
+
+    // Загружает входящие переводы за окно сверки и заново классифицирует все, что не привязано вручную
+    pub async fn run(&self) -> Result<ReconciliationSummary, ReconciliationError> {
+        let mut summary = ReconciliationSummary::default();
+        for transfer in self.incoming_transfers().await? {
+            let (order_id, discrepancy) = self.classify(&transfer).await?;
+            sqlx::query(
+                r#"
+                INSERT INTO onchain_transfers
+                    (transaction_hash, source, currency, amount, comment, order_id, discrepancy, received_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, datetime(?, 'unixepoch'))
+                ON CONFLICT(transaction_hash) DO UPDATE SET
+                    order_id = excluded.order_id,
+                    discrepancy = excluded.discrepancy,
+                    checked_at = CURRENT_TIMESTAMP
+                WHERE onchain_transfers.linked_manually = FALSE
+                "#
+            )
+                .bind(&transfer.transaction_hash)
+                .bind(&transfer.source)
+                .bind(&transfer.currency)
+                .bind(transfer.amount)
+                .bind(&transfer.comment)
+                .bind(order_id)
+                .bind(discrepancy)
+                .bind(transfer.utime)
+                .execute(&self.db_pool)
+                .await?;
+
+            summary.checked += 1;
+            if discrepancy.is_some() {
+                summary.discrepancies += 1;
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn incoming_transfers(&self) -> Result<Vec<IncomingTransfer>, ReconciliationError> {
+        let jetton_wallet = self.jetton.as_ref().and_then(|jetton| parse_address(&jetton.merchant_jetton_wallet));
+        let mut transfers = Vec::new();
+
+        for transaction in self.window_transactions().await? {
+            let Some(message) = &transaction.in_msg else {
+                continue;
+            };
+            // Пустой отправитель - внешнее сообщение: исходящий перевод самого магазина
+            let Some(source) = parse_address(&message.source) else {
+                continue;
+            };
+
+            if let (Some(jetton), true) = (&self.jetton, Some(source) == jetton_wallet) {
+                let notification = message.msg_data
+                    .as_ref()
+                    .and_then(|data| data.body.as_deref())
+                    .and_then(parse_transfer_notification);
+                if let Some(notification) = notification {
+                    transfers.push(IncomingTransfer {
+                        transaction_hash: transaction.transaction_id.hash.clone(),
+                        source: notification.sender
+                            .map(|(workchain, hash)| raw_address(workchain, &hash))
+                            .unwrap_or_default(),
+                        currency: jetton.symbol.clone(),
+                        amount: jetton.amount_from_units(notification.amount),
+                        comment: notification.comment,
+                        utime: transaction.utime,
+                    });
+                }
+                continue;
+            }
+
+            let nanotons = message.value.parse::<i64>().unwrap_or(0);
+            if nanotons <= 0 {
+                continue;
+            }
+            transfers.push(IncomingTransfer {
+                transaction_hash: transaction.transaction_id.hash.clone(),
+                source: raw_address(source.0, &source.1),
+                currency: "TON".to_string(),
+                amount: nanotons as f64 / 1_000_000_000.0,
+                comment: Some(message.message.clone()).filter(|comment| !comment.is_empty()),
+                utime: transaction.utime,
+            });
+        }
+
+        Ok(transfers)
+    }
+
+    // Сумма заказа в валюте перевода
+    fn expected_amount(&self, currency: &str, total_amount: f64) -> f64 {
+        match &self.jetton {
+            Some(jetton) if jetton.symbol == currency => jetton.amount_from_units(jetton.amount_units(total_amount)),
+            _ => total_amount,
+        }
+    }
+
+    async fn classify(&self, transfer: &IncomingTransfer) -> Result<(Option<i64>, Option<Discrepancy>), ReconciliationError> {
+        // Перевод уже записан как оплата заказа
+        let recorded = sqlx::query_scalar::<_, String>(
+            "SELECT order_id FROM payments WHERE transaction_hash = ? AND status = 'confirmed'"
+        )
+            .bind(&transfer.transaction_hash)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .and_then(|order_id| order_id.parse::<i64>().ok());
+
+        let order_id = recorded.or_else(|| transfer.comment.as_deref().and_then(parse_order_comment));
+        let Some(order_id) = order_id else {
+            return Ok((None, Some(Discrepancy::UnknownOrder)));
+        };
+        let total_amount = sqlx::query_scalar::<_, f64>("SELECT total_amount FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        let Some(total_amount) = total_amount else {
+            return Ok((None, Some(Discrepancy::UnknownOrder)));
+        };
+
+        let expected = self.expected_amount(&transfer.currency, total_amount);
+        let overpaid = transfer.amount > expected + AMOUNT_TOLERANCE;
+        if recorded.is_some() {
+            return Ok((Some(order_id), overpaid.then_some(Discrepancy::Overpaid)));
+        }
+
+        // Незаписанный перевод сравниваем с остатком после уже зачтенных платежей
+        let paid = payments::paid_amount(&self.db_pool, order_id).await?;
+        let remaining = self.expected_amount(&transfer.currency, total_amount - paid);
+
+        let discrepancy = if paid + AMOUNT_TOLERANCE >= total_amount {
+            Discrepancy::Duplicate
+        } else if transfer.amount + AMOUNT_TOLERANCE < remaining {
+            Discrepancy::Underpaid
+        } else if transfer.amount > remaining + AMOUNT_TOLERANCE {
+            Discrepancy::Overpaid
+        } else {
+            Discrepancy::Unrecorded
+        };
+        Ok((Some(order_id), Some(discrepancy)))
+    }
+
+    pub async fn report(&self, only_discrepancies: bool) -> Result<Vec<OnchainTransfer>, ReconciliationError> {
+        Ok(sqlx::query_as::<_, OnchainTransfer>(
+            r#"
+            SELECT transaction_hash, source, currency, amount, comment, order_id, discrepancy, linked_manually, received_at
+            FROM onchain_transfers
+            WHERE discrepancy IS NOT NULL OR ? = FALSE
+            ORDER BY received_at DESC
+            "#
+        )
+            .bind(only_discrepancies)
+            .fetch_all(&self.db_pool)
+            .await?)
+    }
+
+    // Ручная привязка перевода к заказу: записывает оплату и снимает расхождение.
+    // Платеж записывается и зачитывается в заказ вместе; оплаченный или отмененный заказ вернет Closed
+    pub async fn link(
+        &self,
+        payments: &PaymentService,
+        transaction_hash: &str,
+        order_id: i64,
+    ) -> Result<Settlement, ReconciliationError> {
+        let transfer = sqlx::query_as::<_, OnchainTransfer>(
+            r#"
+            SELECT transaction_hash, source, currency, amount, comment, order_id, discrepancy, linked_manually, received_at
+            FROM onchain_transfers WHERE transaction_hash = ?
+            "#
+        )
+            .bind(transaction_hash)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(ReconciliationError::TransferNotFound)?;
+
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(ReconciliationError::OrderNotFound)?;
+
+        let payment = self.payment_record(
+            order_id,
+            user_id,
+            &transfer.transaction_hash,
+            &transfer.source,
+            &transfer.currency,
+            transfer.amount,
+        );
+        let settlement = payments
+            .apply_payment(&payment, None)
+            .await?
+            .ok_or(ReconciliationError::TransferAlreadyRecorded)?;
+
+        sqlx::query(
+            r#"
+            UPDATE onchain_transfers
+            SET order_id = ?, discrepancy = NULL, linked_manually = TRUE, checked_at = CURRENT_TIMESTAMP
+            WHERE transaction_hash = ?
+            "#
+        )
+            .bind(order_id)
+            .bind(transaction_hash)
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(settlement)
+    }
+
+    // Оплата, о которой сообщил WebApp: перевод ищется среди входящих транзакций магазина в сети,
+    // сумма и отправитель берутся из сети, комментарий должен указывать на этот заказ.
+    // Платеж записывается и зачитывается в заказ вместе. None - перевод уже был записан
+    pub async fn confirm_transfer(
+        &self,
+        payments: &PaymentService,
+        transaction_hash: &str,
+        order_id: i64,
+    ) -> Result<Option<Settlement>, ReconciliationError> {
+        let transfer = self
+            .incoming_transfers()
+            .await?
+            .into_iter()
+            .find(|transfer| transfer.transaction_hash == transaction_hash)
+            .ok_or(ReconciliationError::TransferNotFound)?;
+        if transfer.comment.as_deref().and_then(parse_order_comment) != Some(order_id) {
+            return Err(ReconciliationError::TransferOrderMismatch);
+        }
+
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(ReconciliationError::OrderNotFound)?;
+
+        let payment = self.payment_record(
+            order_id,
+            user_id,
+            &transfer.transaction_hash,
+            &transfer.source,
+            &transfer.currency,
+            transfer.amount,
+        );
+        Ok(payments.apply_payment(&payment, None).await?)
+    }
+
+    // Входящий перевод как оплата заказа; жетоны зачитываются в TON по курсу
+    fn payment_record<'a>(
+        &'a self,
+        order_id: i64,
+        user_id: i64,
+        transaction_hash: &'a str,
+        source: &'a str,
+        currency: &'a str,
+        amount: f64,
+    ) -> NewPaymentRecord<'a> {
+        let (provider, credited_amount) = match &self.jetton {
+            Some(jetton) if jetton.symbol == currency => (jetton.code.as_str(), jetton.ton_amount(amount)),
+            _ => ("ton", amount),
+        };
+        NewPaymentRecord {
+            order_id,
+            user_id,
+            provider,
+            amount,
+            currency,
+            wallet_address: source,
+            transaction_hash: Some(transaction_hash),
+            provider_payment_id: None,
+            credited_amount,
+        }
+    }
+}
+
+// Комментарии пишут покупатели: значения, похожие на формулы, экранируем для Excel
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+pub fn to_csv(transfers: &[OnchainTransfer]) -> String {
+    let mut csv = String::from(
+        "transaction_hash,received_at,source,currency,amount,comment,order_id,discrepancy,linked_manually\n"
+    );
+    for transfer in transfers {
+        let row = [
+            csv_field(&transfer.transaction_hash),
+            transfer.received_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            csv_field(&transfer.source),
+            csv_field(&transfer.currency),
+            format!("{:.9}", transfer.amount),
+            csv_field(transfer.comment.as_deref().unwrap_or_default()),
+            transfer.order_id.map(|id| id.to_string()).unwrap_or_default(),
+            transfer.discrepancy.map(|discrepancy| discrepancy.code()).unwrap_or_default().to_string(),
+            transfer.linked_manually.to_string(),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
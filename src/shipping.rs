@@ -0,0 +1,347 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use crate::telegram_notifications::TelegramNotifier;
+
+#[derive(Error, Debug)]
+pub enum ShippingError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Заказ не найден")]
+    OrderNotFound,
+    #[error("Неизвестная служба доставки: {0}")]
+    UnknownCarrier(String),
+    #[error("Некорректный трек-номер")]
+    InvalidTrackingNumber,
+    #[error("Служба доставки не поддерживает отслеживание")]
+    TrackingUnsupported,
+    #[error("Carrier error: {0}")]
+    CarrierError(String),
+}
+
+impl ShippingError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        matches!(
+            self,
+            ShippingError::OrderNotFound | ShippingError::UnknownCarrier(_) | ShippingError::InvalidTrackingNumber
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ShipmentStatus {
+    Created,
+    InTransit,
+    Arrived,
+    Delivered,
+    Returned,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackingStatus {
+    pub status: ShipmentStatus,
+    pub description: Option<String>,
+}
+
+pub type TrackerFuture<'a> = Pin<Box<dyn Future<Output = Result<TrackingStatus, ShippingError>> + Send + 'a>>;
+
+// Интеграция со службой доставки: ссылка для отслеживания и запрос статуса
+pub trait CarrierTracker: Send + Sync {
+    fn code(&self) -> &str;
+    fn name(&self) -> &str;
+    fn tracking_url(&self, tracking_number: &str) -> String;
+    fn fetch_status<'a>(&'a self, tracking_number: &'a str) -> TrackerFuture<'a>;
+}
+
+// Служба без API: только ссылка на страницу отслеживания
+pub struct LinkOnlyTracker {
+    code: String,
+    name: String,
+    url_template: String,
+}
+
+impl LinkOnlyTracker {
+    // url_template содержит {number} на месте трек-номера
+    pub fn new(code: &str, name: &str, url_template: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            name: name.to_string(),
+            url_template: url_template.to_string(),
+        }
+    }
+}
+
+impl CarrierTracker for LinkOnlyTracker {
+    fn code(&self) -> &str {
+        &self.code
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn tracking_url(&self, tracking_number: &str) -> String {
+        self.url_template.replace("{number}", tracking_number)
+    }
+
+    fn fetch_status<'a>(&'a self, _tracking_number: &'a str) -> TrackerFuture<'a> {
+        Box::pin(async { Err(ShippingError::TrackingUnsupported) })
+    }
+}
+
+// Тестовая служба: при каждом опросе отправление продвигается на один статус
+pub struct MockCarrierTracker {
+    progress: Mutex<HashMap<String, usize>>,
+}
+
+impl MockCarrierTracker {
+    const STEPS: [ShipmentStatus; 4] = [
+        ShipmentStatus::Created,
+        ShipmentStatus::InTransit,
+        ShipmentStatus::Arrived,
+        ShipmentStatus::Delivered,
+    ];
+
+    pub fn new() -> Self {
+        Self {
+            progress: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl CarrierTracker for MockCarrierTracker {
+    fn code(&self) -> &str {
+        "mock"
+    }
+
+    fn name(&self) -> &str {
+        "Тестовая доставка"
+    }
+
+    fn tracking_url(&self, tracking_number: &str) -> String {
+        format!("https://tracking.example.com/{}", tracking_number)
+    }
+
+    fn fetch_status<'a>(&'a self, tracking_number: &'a str) -> TrackerFuture<'a> {
+        Box::pin(async move {
+            let mut progress = self
+                .progress
+                .lock()
+                .map_err(|e| ShippingError::CarrierError(e.to_string()))?;
+            let step = progress.entry(tracking_number.to_string()).or_insert(0);
+            *step = (*step + 1).min(Self::STEPS.len() - 1);
+
+            Ok(TrackingStatus {
+                status: Self::STEPS[*step],
+                description: None,
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Shipment {
+    pub id: i64,
+    pub order_id: i64,
+    pub carrier: String,
+    pub tracking_number: String,
+    pub status: ShipmentStatus,
+    pub status_description: Option<String>,
+    pub last_checked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttachShipment {
+    pub carrier: String,
+    pub tracking_number: String,
+}
+
+const SHIPMENT_COLUMNS: &str =
+    "id, order_id, carrier, tracking_number, status, status_description, last_checked_at";
+
+// Команда администратора в ответ на сообщение о заказе: /track <служба> <трек-номер>
+pub fn parse_track_command(text: &str) -> Option<AttachShipment> {
+    let mut parts = text.split_whitespace();
+    let command = parts.next()?;
+    if command != "/track" && !command.starts_with("/track@") {
+        return None;
+    }
+    Some(AttachShipment {
+        carrier: parts.next()?.to_lowercase(),
+        tracking_number: parts.next()?.to_string(),
+    })
+}
+
+fn normalize_tracking_number(tracking_number: &str) -> Result<String, ShippingError> {
+    let tracking_number = tracking_number.trim().to_uppercase();
+    let valid = (4..=40).contains(&tracking_number.len())
+        && tracking_number.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err(ShippingError::InvalidTrackingNumber);
+    }
+    Ok(tracking_number)
+}
+
+pub struct ShipmentService {
+    db_pool: SqlitePool,
+    notifier: Arc<TelegramNotifier>,
+    trackers: HashMap<String, Arc<dyn CarrierTracker>>,
+}
+
+impl ShipmentService {
+    pub fn new(db_pool: SqlitePool, notifier: Arc<TelegramNotifier>) -> Self {
+        Self {
+            db_pool,
+            notifier,
+            trackers: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, tracker: Arc<dyn CarrierTracker>) {
+        self.trackers.insert(tracker.code().to_string(), tracker);
+    }
+
+    fn tracker(&self, carrier: &str) -> Result<&Arc<dyn CarrierTracker>, ShippingError> {
+        self.trackers
+            .get(carrier)
+            .ok_or_else(|| ShippingError::UnknownCarrier(carrier.to_string()))
+    }
+
+    pub async fn get(&self, order_id: i64) -> Result<Option<Shipment>, sqlx::Error> {
+        sqlx::query_as::<_, Shipment>(&format!(
+            "SELECT {} FROM shipments WHERE order_id = ?",
+            SHIPMENT_COLUMNS
+        ))
+            .bind(order_id)
+            .fetch_optional(&self.db_pool)
+            .await
+    }
+
+    // Привязывает трек-номер к заказу и отправляет покупателю ссылку для отслеживания.
+    // Повторный вызов заменяет трек-номер.
+    pub async fn attach(&self, order_id: i64, input: &AttachShipment) -> Result<Shipment, ShippingError> {
+        let carrier = input.carrier.trim().to_lowercase();
+        let tracker = self.tracker(&carrier)?;
+        let tracking_number = normalize_tracking_number(&input.tracking_number)?;
+
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(ShippingError::OrderNotFound)?;
+
+        let shipment = sqlx::query_as::<_, Shipment>(&format!(
+            r#"
+            INSERT INTO shipments (order_id, carrier, tracking_number) VALUES (?, ?, ?)
+            ON CONFLICT(order_id) DO UPDATE SET
+                carrier = excluded.carrier,
+                tracking_number = excluded.tracking_number,
+                status = 'created',
+                status_description = NULL,
+                last_checked_at = NULL,
+                updated_at = CURRENT_TIMESTAMP
+            RETURNING {}
+            "#,
+            SHIPMENT_COLUMNS
+        ))
+            .bind(order_id)
+            .bind(&carrier)
+            .bind(&tracking_number)
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        if let Err(e) = self.notifier.send_tracking_info(
+            user_id,
+            order_id,
+            tracker.name(),
+            &tracking_number,
+            &tracker.tracking_url(&tracking_number),
+        ).await {
+            eprintln!("Ошибка отправки трек-номера по заказу {}: {:?}", order_id, e);
+        }
+
+        Ok(shipment)
+    }
+
+    // Опрашивает службы доставки по незавершенным отправлениям и сообщает покупателям
+    // об изменении статуса. Возвращает число обновленных отправлений.
+    pub async fn poll(&self) -> Result<usize, ShippingError> {
+        let shipments = sqlx::query_as::<_, Shipment>(&format!(
+            "SELECT {} FROM shipments WHERE status NOT IN ('delivered', 'returned') ORDER BY last_checked_at",
+            SHIPMENT_COLUMNS
+        ))
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let mut updated = 0;
+        for shipment in shipments {
+            let Ok(tracker) = self.tracker(&shipment.carrier) else {
+                continue;
+            };
+
+            let tracking = match tracker.fetch_status(&shipment.tracking_number).await {
+                Ok(tracking) => tracking,
+                Err(ShippingError::TrackingUnsupported) => continue,
+                Err(e) => {
+                    eprintln!("Ошибка отслеживания отправления {}: {:?}", shipment.tracking_number, e);
+                    continue;
+                }
+            };
+
+            let changed = tracking.status != shipment.status;
+            sqlx::query(
+                r#"
+                UPDATE shipments
+                SET status = ?, status_description = ?, last_checked_at = ?, updated_at = CURRENT_TIMESTAMP
+                WHERE id = ?
+                "#
+            )
+                .bind(tracking.status)
+                .bind(&tracking.description)
+                .bind(Utc::now().naive_utc())
+                .bind(shipment.id)
+                .execute(&self.db_pool)
+                .await?;
+
+            if changed {
+                updated += 1;
+                if let Err(e) = self.notify_status(&shipment, tracker.as_ref(), &tracking).await {
+                    eprintln!("Ошибка уведомления о статусе отправления {}: {:?}", shipment.id, e);
+                }
+            }
+        }
+
+        Ok(updated)
+    }
+
+    async fn notify_status(
+        &self,
+        shipment: &Shipment,
+        tracker: &dyn CarrierTracker,
+        tracking: &TrackingStatus,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(shipment.order_id)
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        self.notifier
+            .send_shipment_status(
+                user_id,
+                shipment.order_id,
+                tracking.status,
+                tracking.description.as_deref(),
+                &tracker.tracking_url(&shipment.tracking_number),
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use sqlx::{Executor, Sqlite, Transaction};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreCreditError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Недостаточно средств на балансе")]
+    Insufficient,
+}
+
+impl StoreCreditError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, StoreCreditError::DbError(_))
+    }
+}
+
+fn round_ton(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+// Баланс покупателя в TON. Читается и из пула, и внутри транзакции заказа
+pub async fn balance<'e, E>(executor: E, user_id: i64) -> Result<f64, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar::<_, f64>("SELECT COALESCE(SUM(amount), 0.0) FROM store_credit_ledger WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(executor)
+        .await
+        .map(round_ton)
+}
+
+// Зачисление переплаты в транзакции, которая отмечает заказ оплаченным. false - уже зачислена
+pub async fn credit_overpayment(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    order_id: i64,
+    amount: f64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO store_credit_ledger (user_id, order_id, kind, amount) VALUES (?, ?, 'overpayment', ?)
+        ON CONFLICT DO NOTHING
+        "#
+    )
+        .bind(user_id)
+        .bind(order_id)
+        .bind(round_ton(amount))
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+// Списание баланса в счет заказа в транзакции оформления. Баланс перечитывается: посчитанный
+// до транзакции мог уже уйти на другой заказ
+pub async fn spend(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    order_id: i64,
+    amount: f64,
+) -> Result<(), StoreCreditError> {
+    if amount <= 0.0 {
+        return Ok(());
+    }
+    if balance(&mut **tx, user_id).await? + f64::EPSILON < round_ton(amount) {
+        return Err(StoreCreditError::Insufficient);
+    }
+
+    sqlx::query("INSERT INTO store_credit_ledger (user_id, order_id, kind, amount) VALUES (?, ?, 'spent', ?)")
+        .bind(user_id)
+        .bind(order_id)
+        .bind(-round_ton(amount))
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+// Отмена или возврат заказа в транзакции смены его статуса: списанный в заказ баланс возвращается,
+// а зачисленная по нему переплата снимается - деньги за заказ возвращаются целиком. Повторный вызов ничего не меняет
+pub async fn reverse_for_order(tx: &mut Transaction<'_, Sqlite>, order_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO store_credit_ledger (user_id, order_id, kind, amount)
+        SELECT user_id, order_id, 'restored', -amount FROM store_credit_ledger
+        WHERE order_id = ? AND kind = 'spent'
+        ON CONFLICT DO NOTHING
+        "#
+    )
+        .bind(order_id)
+        .execute(&mut **tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO store_credit_ledger (user_id, order_id, kind, amount)
+        SELECT user_id, order_id, 'reversed', -amount FROM store_credit_ledger
+        WHERE order_id = ? AND kind = 'overpayment'
+        ON CONFLICT DO NOTHING
+        "#
+    )
+        .bind(order_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
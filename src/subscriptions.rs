@@ -0,0 +1,488 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use std::sync::Arc;
+use thiserror::Error;
+use crate::checkout::{self, CheckoutError, CheckoutLine, CheckoutRequest};
+use crate::delivery::{self, DeliveryError};
+use crate::loyalty::{LoyaltyProgram, PointsRedemption};
+use crate::promotions::DiscountSummary;
+use crate::telegram_notifications::TelegramNotifier;
+
+#[derive(Error, Debug)]
+pub enum SubscriptionError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("Подписка не найдена")]
+    NotFound,
+    #[error("Подписка отменена")]
+    Cancelled,
+    #[error("Интервал должен быть от 1 до 365 дней")]
+    InvalidInterval,
+    #[error("В подписке нет товаров")]
+    NoItems,
+    #[error("Количество товара должно быть не меньше 1")]
+    InvalidQuantity,
+    #[error("Товар {0} не найден")]
+    ProductNotFound(i64),
+    #[error("Укажите способ и зону доставки")]
+    DeliveryIncomplete,
+    #[error(transparent)]
+    DeliveryError(#[from] DeliveryError),
+}
+
+impl SubscriptionError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        match self {
+            SubscriptionError::DbError(_) => false,
+            SubscriptionError::DeliveryError(e) => e.is_user_error(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Active,
+    Paused,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionAction {
+    Pause,
+    Resume,
+    Skip,
+    Cancel,
+}
+
+impl SubscriptionAction {
+    // Данные inline-кнопки: sub_<действие>_<id подписки>
+    pub fn callback_data(&self, subscription_id: i64) -> String {
+        let action = match self {
+            SubscriptionAction::Pause => "pause",
+            SubscriptionAction::Resume => "resume",
+            SubscriptionAction::Skip => "skip",
+            SubscriptionAction::Cancel => "cancel",
+        };
+        format!("sub_{}_{}", action, subscription_id)
+    }
+
+    pub fn parse_callback(data: &str) -> Option<(Self, i64)> {
+        let (action, id) = data.strip_prefix("sub_")?.split_once('_')?;
+        let action = match action {
+            "pause" => SubscriptionAction::Pause,
+            "resume" => SubscriptionAction::Resume,
+            "skip" => SubscriptionAction::Skip,
+            "cancel" => SubscriptionAction::Cancel,
+            _ => return None,
+        };
+        Some((action, id.parse().ok()?))
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Subscription {
+    pub id: i64,
+    pub user_id: i64,
+    pub interval_days: i64,
+    pub next_run_at: NaiveDateTime,
+    pub status: SubscriptionStatus,
+    pub delivery_address: String,
+    pub telegram_username: Option<String>,
+    pub last_order_id: Option<i64>,
+    pub delivery_method_id: Option<i64>,
+    pub delivery_zone_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct SubscriptionItem {
+    pub product_id: i64,
+    pub name: String,
+    pub quantity: i32,
+    pub price: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriptionDetails {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    pub items: Vec<SubscriptionItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionItemInput {
+    pub product_id: i64,
+    pub quantity: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSubscription {
+    pub interval_days: i64,
+    pub items: Vec<SubscriptionItemInput>,
+    pub delivery_address: String,
+    pub telegram_username: Option<String>,
+    // Способ и зона доставки; без них заказы оформляются без стоимости доставки
+    #[serde(default)]
+    pub delivery_method_id: Option<i64>,
+    #[serde(default)]
+    pub delivery_zone_id: Option<i64>,
+    // Дата первого заказа; по умолчанию - через один интервал
+    pub first_run_at: Option<NaiveDateTime>,
+}
+
+const SUBSCRIPTION_COLUMNS: &str =
+    "id, user_id, interval_days, next_run_at, status, delivery_address, telegram_username, last_order_id, \
+     delivery_method_id, delivery_zone_id";
+
+// Следующая дата заказа строго после now с сохранением исходного графика
+fn next_run_after(from: NaiveDateTime, interval_days: i64, now: NaiveDateTime) -> NaiveDateTime {
+    let interval = Duration::days(interval_days.max(1));
+    let mut next = from + interval;
+    while next <= now {
+        next += interval;
+    }
+    next
+}
+
+pub struct SubscriptionService {
+    db_pool: SqlitePool,
+    notifier: Arc<TelegramNotifier>,
+    loyalty: Arc<LoyaltyProgram>,
+}
+
+impl SubscriptionService {
+    pub fn new(db_pool: SqlitePool, notifier: Arc<TelegramNotifier>, loyalty: Arc<LoyaltyProgram>) -> Self {
+        Self { db_pool, notifier, loyalty }
+    }
+
+    pub async fn create(&self, user_id: i64, input: &CreateSubscription) -> Result<Subscription, SubscriptionError> {
+        if !(1..=365).contains(&input.interval_days) {
+            return Err(SubscriptionError::InvalidInterval);
+        }
+        if input.items.is_empty() {
+            return Err(SubscriptionError::NoItems);
+        }
+        for item in &input.items {
+            if item.quantity < 1 {
+                return Err(SubscriptionError::InvalidQuantity);
+            }
+            let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM products WHERE id = ?)")
+                .bind(item.product_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+            if !exists {
+                return Err(SubscriptionError::ProductNotFound(item.product_id));
+            }
+        }
+
+        // Способ должен быть доступен в зоне; стоимость считается при каждом заказе
+        match (input.delivery_method_id, input.delivery_zone_id) {
+            (Some(method_id), Some(zone_id)) => {
+                delivery::quote(&self.db_pool, method_id, zone_id, 0, 0.0, false).await?;
+            }
+            (None, None) => {}
+            _ => return Err(SubscriptionError::DeliveryIncomplete),
+        }
+
+        let now = Utc::now().naive_utc();
+        let next_run_at = input
+            .first_run_at
+            .filter(|first| *first > now)
+            .unwrap_or_else(|| now + Duration::days(input.interval_days));
+
+        let mut tx = self.db_pool.begin().await?;
+
+        let subscription = sqlx::query_as::<_, Subscription>(&format!(
+            r#"
+            INSERT INTO subscriptions (
+                user_id, interval_days, next_run_at, delivery_address, telegram_username,
+                delivery_method_id, delivery_zone_id
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING {}
+            "#,
+            SUBSCRIPTION_COLUMNS
+        ))
+            .bind(user_id)
+            .bind(input.interval_days)
+            .bind(next_run_at)
+            .bind(&input.delivery_address)
+            .bind(&input.telegram_username)
+            .bind(input.delivery_method_id)
+            .bind(input.delivery_zone_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        for item in &input.items {
+            sqlx::query(
+                r#"
+                INSERT INTO subscription_items (subscription_id, product_id, quantity) VALUES (?, ?, ?)
+                ON CONFLICT(subscription_id, product_id) DO UPDATE SET quantity = quantity + excluded.quantity
+                "#
+            )
+                .bind(subscription.id)
+                .bind(item.product_id)
+                .bind(item.quantity)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(subscription)
+    }
+
+    pub async fn get(&self, subscription_id: i64) -> Result<Subscription, SubscriptionError> {
+        sqlx::query_as::<_, Subscription>(&format!(
+            "SELECT {} FROM subscriptions WHERE id = ?",
+            SUBSCRIPTION_COLUMNS
+        ))
+            .bind(subscription_id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(SubscriptionError::NotFound)
+    }
+
+    pub async fn items(&self, subscription_id: i64) -> Result<Vec<SubscriptionItem>, sqlx::Error> {
+        sqlx::query_as::<_, SubscriptionItem>(
+            r#"
+            SELECT si.product_id, p.name, si.quantity, p.price
+            FROM subscription_items si
+            JOIN products p ON p.id = si.product_id
+            WHERE si.subscription_id = ?
+            ORDER BY p.name
+            "#
+        )
+            .bind(subscription_id)
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // Активные и приостановленные подписки пользователя
+    pub async fn list_for_user(&self, user_id: i64) -> Result<Vec<SubscriptionDetails>, SubscriptionError> {
+        let subscriptions = sqlx::query_as::<_, Subscription>(&format!(
+            "SELECT {} FROM subscriptions WHERE user_id = ? AND status != 'cancelled' ORDER BY id",
+            SUBSCRIPTION_COLUMNS
+        ))
+            .bind(user_id)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let mut result = Vec::with_capacity(subscriptions.len());
+        for subscription in subscriptions {
+            let items = self.items(subscription.id).await?;
+            result.push(SubscriptionDetails { subscription, items });
+        }
+        Ok(result)
+    }
+
+    // Пауза, возобновление, пропуск ближайшего заказа или отмена.
+    // Чужие подписки для пользователя не существуют.
+    pub async fn apply_action(
+        &self,
+        user_id: i64,
+        subscription_id: i64,
+        action: SubscriptionAction,
+    ) -> Result<Subscription, SubscriptionError> {
+        let subscription = self.get(subscription_id).await?;
+        if subscription.user_id != user_id {
+            return Err(SubscriptionError::NotFound);
+        }
+        if subscription.status == SubscriptionStatus::Cancelled {
+            return Err(SubscriptionError::Cancelled);
+        }
+
+        let now = Utc::now().naive_utc();
+        let (status, next_run_at) = match action {
+            SubscriptionAction::Pause => (SubscriptionStatus::Paused, subscription.next_run_at),
+            SubscriptionAction::Resume => {
+                // После долгой паузы не создаем заказ сразу, а продолжаем по графику
+                let next_run_at = if subscription.next_run_at > now {
+                    subscription.next_run_at
+                } else {
+                    next_run_after(subscription.next_run_at, subscription.interval_days, now)
+                };
+                (SubscriptionStatus::Active, next_run_at)
+            }
+            SubscriptionAction::Skip => (
+                subscription.status,
+                next_run_after(subscription.next_run_at, subscription.interval_days, now),
+            ),
+            SubscriptionAction::Cancel => (SubscriptionStatus::Cancelled, subscription.next_run_at),
+        };
+
+        sqlx::query(
+            "UPDATE subscriptions SET status = ?, next_run_at = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?"
+        )
+            .bind(status)
+            .bind(next_run_at)
+            .bind(subscription_id)
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(Subscription { status, next_run_at, ..subscription })
+    }
+
+    // Создает заказы по всем подпискам, срок которых наступил.
+    // Возвращает число созданных заказов.
+    pub async fn run_due(&self) -> Result<usize, SubscriptionError> {
+        let now = Utc::now().naive_utc();
+        let due = sqlx::query_as::<_, Subscription>(&format!(
+            "SELECT {} FROM subscriptions WHERE status = 'active' AND next_run_at <= ? ORDER BY next_run_at",
+            SUBSCRIPTION_COLUMNS
+        ))
+            .bind(now)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let mut created = 0;
+        for subscription in due {
+            match self.run_subscription(&subscription, now).await {
+                Ok(true) => created += 1,
+                Ok(false) => {}
+                Err(e) => eprintln!("Ошибка обработки подписки {}: {:?}", subscription.id, e),
+            }
+        }
+        Ok(created)
+    }
+
+    async fn run_subscription(&self, subscription: &Subscription, now: NaiveDateTime) -> Result<bool, SubscriptionError> {
+        // Заказ собирается по текущим ценам товаров
+        let lines = sqlx::query_as::<_, CheckoutLine>(
+            r#"
+            SELECT si.product_id, si.quantity, p.name, p.price, p.is_bundle
+            FROM subscription_items si
+            JOIN products p ON p.id = si.product_id
+            WHERE si.subscription_id = ?
+            "#
+        )
+            .bind(subscription.id)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+        let subtotal = lines.iter().map(|line| line.price * line.quantity as f64).sum::<f64>();
+        let discounts = DiscountSummary {
+            subtotal,
+            total_amount: subtotal,
+            ..Default::default()
+        };
+        let next_run_at = next_run_after(subscription.next_run_at, subscription.interval_days, now);
+
+        // Доставка по текущему тарифу, как и для заказа из корзины
+        let delivery_quote = match (subscription.delivery_method_id, subscription.delivery_zone_id) {
+            (Some(method_id), Some(zone_id)) => {
+                let weight = self.items_weight(subscription.id).await?;
+                match delivery::quote(&self.db_pool, method_id, zone_id, weight, subtotal, false).await {
+                    Ok(quote) => Some(quote),
+                    Err(DeliveryError::DbError(e)) => return Err(e.into()),
+                    Err(e) => {
+                        self.skip_run(subscription, next_run_at, &e.to_string()).await?;
+                        return Ok(false);
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let request = CheckoutRequest {
+            user_id: subscription.user_id,
+            // Подписку оформляет только авторизованный через Telegram пользователь
+            verified_user_id: Some(subscription.user_id),
+            lines: &lines,
+            discounts: &discounts,
+            redemption: PointsRedemption { points: 0, discount: 0.0 },
+            store_credit: 0.0,
+            delivery: delivery_quote.as_ref(),
+            delivery_address: &subscription.delivery_address,
+            address_details: None,
+        };
+
+        // Заказ, его привязка к подписке и сдвиг графика - в одной транзакции,
+        // чтобы сбой между ними не привел к повторному заказу
+        let mut tx = self.db_pool.begin().await?;
+        let placed = match checkout::place_order_in(&mut tx, &self.loyalty, &self.notifier.templates, &request).await {
+            Ok(placed) => placed,
+            Err(CheckoutError::DbError(e)) => return Err(e.into()),
+            Err(e) => {
+                // Нехватку товара или пустую подписку не повторяем до следующего срока
+                tx.rollback().await?;
+                self.skip_run(subscription, next_run_at, &e.to_string()).await?;
+                return Ok(false);
+            }
+        };
+
+        sqlx::query("UPDATE orders SET subscription_id = ? WHERE id = ?")
+            .bind(subscription.id)
+            .bind(placed.order_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE subscriptions
+            SET next_run_at = ?, last_order_id = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?
+            "#
+        )
+            .bind(next_run_at)
+            .bind(placed.order_id)
+            .bind(subscription.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        println!("✅ Заказ №{} по подписке {} создан на сумму {:.2} TON", placed.order_id, subscription.id, placed.total_amount);
+
+        let updated = Subscription {
+            next_run_at,
+            last_order_id: Some(placed.order_id),
+            ..subscription.clone()
+        };
+        if let Err(e) = self.notifier.send_subscription_controls(&updated, None).await {
+            eprintln!("Ошибка отправки кнопок подписки {}: {:?}", subscription.id, e);
+        }
+
+        Ok(true)
+    }
+
+    // Суммарный вес товаров подписки, в граммах
+    async fn items_weight(&self, subscription_id: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, Option<i64>>(
+            r#"
+            SELECT SUM(si.quantity * p.weight_grams)
+            FROM subscription_items si
+            JOIN products p ON p.id = si.product_id
+            WHERE si.subscription_id = ?
+            "#
+        )
+            .bind(subscription_id)
+            .fetch_one(&self.db_pool)
+            .await
+            .map(|weight| weight.unwrap_or(0))
+    }
+
+    // Заказ не создан: сдвигаем график и сообщаем покупателю причину
+    async fn skip_run(
+        &self,
+        subscription: &Subscription,
+        next_run_at: NaiveDateTime,
+        reason: &str,
+    ) -> Result<(), SubscriptionError> {
+        sqlx::query("UPDATE subscriptions SET next_run_at = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(next_run_at)
+            .bind(subscription.id)
+            .execute(&self.db_pool)
+            .await?;
+
+        let updated = Subscription { next_run_at, ..subscription.clone() };
+        let notice = format!("⚠️ Заказ по подписке не создан: {}", reason);
+        if let Err(e) = self.notifier.send_subscription_controls(&updated, Some(&notice)).await {
+            eprintln!("Ошибка уведомления о подписке {}: {:?}", subscription.id, e);
+        }
+        Ok(())
+    }
+}
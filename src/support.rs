@@ -0,0 +1,339 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use teloxide::types::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadStatus {
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MessageDirection {
+    // Сообщение покупателя
+    User,
+    // Ответ поддержки
+    Admin,
+    // Служебное сообщение бота (подтверждение заказа, уведомление администраторам)
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    Photo,
+    Document,
+    Voice,
+    Sticker,
+}
+
+// Вложение сообщения в переписке поддержки
+#[derive(Debug, Clone)]
+pub struct SupportMedia {
+    pub media_type: MediaType,
+    pub file_id: String,
+}
+
+impl SupportMedia {
+    // Вложение из сообщения Telegram (для фото берется самый большой размер)
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        let (media_type, file_id) = if let Some(photo) = msg.photo().and_then(|sizes| sizes.last()) {
+            (MediaType::Photo, &photo.file.id)
+        } else if let Some(document) = msg.document() {
+            (MediaType::Document, &document.file.id)
+        } else if let Some(voice) = msg.voice() {
+            (MediaType::Voice, &voice.file.id)
+        } else {
+            (MediaType::Sticker, &msg.sticker()?.file.id)
+        };
+
+        Some(Self {
+            media_type,
+            file_id: file_id.0.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct SupportThread {
+    pub id: i64,
+    pub user_id: i64,
+    pub order_id: Option<i64>,
+    pub status: ThreadStatus,
+    // Тема форума в чате администраторов (если включены темы)
+    pub admin_topic_id: Option<i32>,
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+impl SupportThread {
+    // Заголовок обращения для сообщений
+    pub fn title(&self) -> String {
+        match self.order_id {
+            Some(order_id) => format!("Обращение #{}, заказ №{}", self.id, order_id),
+            None => format!("Обращение #{}, общий вопрос", self.id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct SupportMessage {
+    pub id: i64,
+    pub direction: MessageDirection,
+    pub sender_id: Option<i64>,
+    pub text: Option<String>,
+    pub user_message_id: Option<i32>,
+    pub admin_message_id: Option<i32>,
+    pub media_type: Option<MediaType>,
+    pub file_id: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+// Новое сообщение в истории обращения
+pub struct NewSupportMessage<'a> {
+    pub direction: MessageDirection,
+    pub sender_id: Option<i64>,
+    pub text: Option<&'a str>,
+    pub user_message_id: Option<i32>,
+    pub admin_message_id: Option<i32>,
+    pub media: Option<&'a SupportMedia>,
+}
+
+const THREAD_COLUMNS: &str = "id, user_id, order_id, status, admin_topic_id, updated_at";
+
+// Открытое обращение пользователя по заказу (или общее), создается при необходимости
+pub async fn open_thread(pool: &SqlitePool, user_id: i64, order_id: Option<i64>) -> Result<SupportThread, sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO support_threads (user_id, order_id) VALUES (?, ?)
+        "#
+    )
+        .bind(user_id)
+        .bind(order_id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query_as::<_, SupportThread>(&format!(
+        "SELECT {} FROM support_threads WHERE user_id = ? AND COALESCE(order_id, 0) = COALESCE(?, 0) AND status = 'open'",
+        THREAD_COLUMNS
+    ))
+        .bind(user_id)
+        .bind(order_id)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn get_thread(pool: &SqlitePool, thread_id: i64) -> Result<Option<SupportThread>, sqlx::Error> {
+    sqlx::query_as::<_, SupportThread>(&format!(
+        "SELECT {} FROM support_threads WHERE id = ?",
+        THREAD_COLUMNS
+    ))
+        .bind(thread_id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn open_threads(pool: &SqlitePool, user_id: i64) -> Result<Vec<SupportThread>, sqlx::Error> {
+    sqlx::query_as::<_, SupportThread>(&format!(
+        "SELECT {} FROM support_threads WHERE user_id = ? AND status = 'open' ORDER BY updated_at DESC, id DESC",
+        THREAD_COLUMNS
+    ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn close_thread(pool: &SqlitePool, thread_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE support_threads SET status = 'closed', closed_at = CURRENT_TIMESTAMP WHERE id = ? AND status = 'open'"
+    )
+        .bind(thread_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Закрывает открытые обращения по заказу и возвращает их
+pub async fn close_order_threads(pool: &SqlitePool, order_id: i64) -> Result<Vec<SupportThread>, sqlx::Error> {
+    sqlx::query_as::<_, SupportThread>(&format!(
+        r#"
+        UPDATE support_threads SET status = 'closed', closed_at = CURRENT_TIMESTAMP
+        WHERE order_id = ? AND status = 'open'
+        RETURNING {}
+        "#,
+        THREAD_COLUMNS
+    ))
+        .bind(order_id)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn set_admin_topic(pool: &SqlitePool, thread_id: i64, topic_id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE support_threads SET admin_topic_id = ? WHERE id = ?")
+        .bind(topic_id)
+        .bind(thread_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Последнее обращение в теме форума (тема переиспользуется при повторном открытии)
+pub async fn thread_by_admin_topic(pool: &SqlitePool, topic_id: i32) -> Result<Option<SupportThread>, sqlx::Error> {
+    sqlx::query_as::<_, SupportThread>(&format!(
+        "SELECT {} FROM support_threads WHERE admin_topic_id = ? ORDER BY id DESC LIMIT 1",
+        THREAD_COLUMNS
+    ))
+        .bind(topic_id)
+        .fetch_optional(pool)
+        .await
+}
+
+// Тема прежнего обращения по тому же заказу (или общего вопроса) этого пользователя
+pub async fn previous_admin_topic(
+    pool: &SqlitePool,
+    user_id: i64,
+    order_id: Option<i64>,
+) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>(
+        r#"
+        SELECT admin_topic_id FROM support_threads
+        WHERE user_id = ? AND COALESCE(order_id, 0) = COALESCE(?, 0) AND admin_topic_id IS NOT NULL
+        ORDER BY id DESC LIMIT 1
+        "#
+    )
+        .bind(user_id)
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await
+}
+
+// Обращение по сообщению в чате администраторов, на которое ответил сотрудник
+pub async fn thread_by_admin_message(pool: &SqlitePool, admin_message_id: i32) -> Result<Option<SupportThread>, sqlx::Error> {
+    sqlx::query_as::<_, SupportThread>(&format!(
+        r#"
+        SELECT {} FROM support_threads
+        WHERE id = (SELECT thread_id FROM support_messages WHERE admin_message_id = ? ORDER BY id DESC LIMIT 1)
+        "#,
+        THREAD_COLUMNS
+    ))
+        .bind(admin_message_id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn set_current_thread(pool: &SqlitePool, user_id: i64, thread_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO support_user_state (user_id, thread_id) VALUES (?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET thread_id = excluded.thread_id
+        "#
+    )
+        .bind(user_id)
+        .bind(thread_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Куда направить сообщение покупателя:
+// 1) ответ на сообщение бота из обращения, 2) обращение, выбранное через /support,
+// 3) последнее активное открытое обращение, 4) новое общее обращение
+pub async fn resolve_user_thread(
+    pool: &SqlitePool,
+    user_id: i64,
+    reply_to_message_id: Option<i32>,
+) -> Result<SupportThread, sqlx::Error> {
+    if let Some(reply_to) = reply_to_message_id {
+        let thread = sqlx::query_as::<_, SupportThread>(&format!(
+            r#"
+            SELECT {} FROM support_threads
+            WHERE id = (
+                SELECT sm.thread_id FROM support_messages sm
+                JOIN support_threads st ON st.id = sm.thread_id
+                WHERE st.user_id = ? AND sm.user_message_id = ?
+                ORDER BY sm.id DESC LIMIT 1
+            )
+            "#,
+            THREAD_COLUMNS
+        ))
+            .bind(user_id)
+            .bind(reply_to)
+            .fetch_optional(pool)
+            .await?;
+
+        match thread {
+            Some(thread) if thread.status == ThreadStatus::Open => return Ok(thread),
+            // Ответ в закрытое обращение по заказу открывает его заново
+            Some(thread) => return open_thread(pool, user_id, thread.order_id).await,
+            None => {}
+        }
+    }
+
+    let current = sqlx::query_as::<_, SupportThread>(&format!(
+        r#"
+        SELECT {} FROM support_threads
+        WHERE id = (SELECT thread_id FROM support_user_state WHERE user_id = ?) AND status = 'open'
+        "#,
+        THREAD_COLUMNS
+    ))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(thread) = current {
+        return Ok(thread);
+    }
+
+    match open_threads(pool, user_id).await?.into_iter().next() {
+        Some(thread) => Ok(thread),
+        None => open_thread(pool, user_id, None).await,
+    }
+}
+
+pub async fn record_message(
+    pool: &SqlitePool,
+    thread_id: i64,
+    message: &NewSupportMessage<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO support_messages
+            (thread_id, direction, sender_id, text, user_message_id, admin_message_id, media_type, file_id)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+        .bind(thread_id)
+        .bind(message.direction)
+        .bind(message.sender_id)
+        .bind(message.text)
+        .bind(message.user_message_id)
+        .bind(message.admin_message_id)
+        .bind(message.media.map(|media| media.media_type))
+        .bind(message.media.map(|media| media.file_id.as_str()))
+        .execute(pool)
+        .await?;
+
+    sqlx::query("UPDATE support_threads SET updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(thread_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn history(pool: &SqlitePool, thread_id: i64) -> Result<Vec<SupportMessage>, sqlx::Error> {
+    sqlx::query_as::<_, SupportMessage>(
+        r#"
+        SELECT id, direction, sender_id, text, user_message_id, admin_message_id, media_type, file_id, created_at
+        FROM support_messages
+        WHERE thread_id = ?
+        ORDER BY id
+        "#
+    )
+        .bind(thread_id)
+        .fetch_all(pool)
+        .await
+}
@@ -0,0 +1,172 @@
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Заголовок, в котором WebApp передает строку Telegram.WebApp.initData
+pub const INIT_DATA_HEADER: &str = "X-Telegram-Init-Data";
+
+// initData старше суток считаем недействительной
+const MAX_INIT_DATA_AGE_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum TelegramAuthError {
+    #[error("initData does not contain hash")]
+    MissingHash,
+    #[error("initData signature mismatch")]
+    InvalidSignature,
+    #[error("initData is expired")]
+    Expired,
+    #[error("Parse error: {0}")]
+    ParseError(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebAppUser {
+    pub id: i64,
+    pub username: Option<String>,
+    pub language_code: Option<String>,
+}
+
+pub struct TelegramAuth {
+    secret_key: Vec<u8>,
+}
+
+impl TelegramAuth {
+    pub fn new(bot_token: &str) -> Self {
+        // secret_key = HMAC_SHA256(key = "WebAppData", message = bot_token)
+        let mut mac = HmacSha256::new_from_slice(b"WebAppData")
+            .expect("HMAC accepts keys of any size");
+        mac.update(bot_token.as_bytes());
+
+        Self {
+            secret_key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    // Проверка подписи initData по алгоритму из документации Telegram WebApp
+    pub fn verify_init_data(&self, init_data: &str) -> Result<WebAppUser, TelegramAuthError> {
+        let mut hash = None;
+        let mut auth_date = None;
+        let mut user_json = None;
+        let mut pairs = Vec::new();
+
+        for (key, value) in url::form_urlencoded::parse(init_data.as_bytes()) {
+            if key == "hash" {
+                hash = Some(value.into_owned());
+                continue;
+            }
+            if key == "auth_date" {
+                auth_date = value.parse::<i64>().ok();
+            }
+            if key == "user" {
+                user_json = Some(value.to_string());
+            }
+            pairs.push(format!("{}={}", key, value));
+        }
+
+        let hash = hash.ok_or(TelegramAuthError::MissingHash)?;
+        let expected = hex::decode(&hash)
+            .map_err(|e| TelegramAuthError::ParseError(e.to_string()))?;
+
+        pairs.sort();
+        let data_check_string = pairs.join("\n");
+
+        let mut mac = HmacSha256::new_from_slice(&self.secret_key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(data_check_string.as_bytes());
+        mac.verify_slice(&expected)
+            .map_err(|_| TelegramAuthError::InvalidSignature)?;
+
+        let auth_date = auth_date
+            .ok_or_else(|| TelegramAuthError::ParseError("auth_date is missing".to_string()))?;
+        if chrono::Utc::now().timestamp() - auth_date > MAX_INIT_DATA_AGE_SECS {
+            return Err(TelegramAuthError::Expired);
+        }
+
+        let user_json = user_json
+            .ok_or_else(|| TelegramAuthError::ParseError("user is missing".to_string()))?;
+        serde_json::from_str::<WebAppUser>(&user_json)
+            .map_err(|e| TelegramAuthError::ParseError(e.to_string()))
+    }
+
+    // Пользователь Telegram из заголовка запроса, если подпись верна
+    pub fn user_from_request(&self, req: &HttpRequest) -> Option<WebAppUser> {
+        let init_data = req.headers().get(INIT_DATA_HEADER)?.to_str().ok()?;
+        if init_data.is_empty() {
+            return None;
+        }
+
+        match self.verify_init_data(init_data) {
+            Ok(user) => Some(user),
+            Err(e) => {
+                eprintln!("⚠️ Неверные данные Telegram WebApp: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOT_TOKEN: &str = "123456:TEST-token";
+    const USER: &str = "user=%7B%22id%22%3A42%2C%22first_name%22%3A%22%D0%98%D0%B2%D0%B0%D0%BD%22%2C%22username%22%3A%22ivan_petrov%22%2C%22language_code%22%3A%22ru%22%7D";
+
+    // Подписи посчитаны отдельно (Python, hmac + hashlib) по алгоритму из документации Telegram.
+    // Действующие данные подписаны с auth_date в 2100 году, чтобы тесты не устаревали
+    fn init_data(auth_date: &str, hash: &str) -> String {
+        format!("auth_date={}&query_id=AAH&{}&hash={}", auth_date, USER, hash)
+    }
+
+    #[test]
+    fn accepts_valid_init_data() {
+        let auth = TelegramAuth::new(BOT_TOKEN);
+        let user = auth
+            .verify_init_data(&init_data("4102444800", "ea8257c52458c2ea9905bf2ceb21b54abc1c0a58c00e2d76340e57f61227fef6"))
+            .unwrap();
+
+        assert_eq!(user.id, 42);
+        assert_eq!(user.username.as_deref(), Some("ivan_petrov"));
+        assert_eq!(user.language_code.as_deref(), Some("ru"));
+    }
+
+    #[test]
+    fn rejects_tampered_init_data() {
+        let auth = TelegramAuth::new(BOT_TOKEN);
+        let tampered = init_data("4102444800", "ea8257c52458c2ea9905bf2ceb21b54abc1c0a58c00e2d76340e57f61227fef6")
+            .replace("%22id%22%3A42", "%22id%22%3A43");
+
+        assert!(matches!(auth.verify_init_data(&tampered), Err(TelegramAuthError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_other_bot_token() {
+        let auth = TelegramAuth::new("654321:OTHER-token");
+        let data = init_data("4102444800", "ea8257c52458c2ea9905bf2ceb21b54abc1c0a58c00e2d76340e57f61227fef6");
+
+        assert!(matches!(auth.verify_init_data(&data), Err(TelegramAuthError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_expired_init_data() {
+        let auth = TelegramAuth::new(BOT_TOKEN);
+        let data = init_data("1700000000", "5681bbcfd947d4f02e6ea21adaf72639ccb8f6a04fa8348220b4adc69d601529");
+
+        assert!(matches!(auth.verify_init_data(&data), Err(TelegramAuthError::Expired)));
+    }
+
+    #[test]
+    fn requires_hash() {
+        let auth = TelegramAuth::new(BOT_TOKEN);
+
+        assert!(matches!(
+            auth.verify_init_data(&format!("auth_date=4102444800&{}", USER)),
+            Err(TelegramAuthError::MissingHash)
+        ));
+    }
+}
@@ -1,43 +1,117 @@
-use crate::telegram_notifications::TelegramNotifier;
+use crate::telegram_notifications::{escape_markdown, TelegramNotifier};
+use crate::addresses;
+use crate::cart;
+use crate::catalog;
+use crate::admin_actions::{self, AdminAction, AdminCallbacks};
+use crate::support::{self, SupportMedia};
+use crate::notification_templates::{self, AddressLine, Label, Notification};
+use crate::payments::{PaymentService, Settlement};
+use crate::pickup;
+use crate::shipping::{self, ShipmentService};
+use crate::subscriptions::{SubscriptionAction, SubscriptionService};
 use crate::ton_payment::TonProcessor;
 use teloxide::prelude::*;
-use teloxide::types::{CallbackQuery, Message, Update};
+use teloxide::types::{
+    ButtonRequest, CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, InlineQuery, InlineQueryResult,
+    InlineQueryResultArticle, InputFile, InputMessageContent, InputMessageContentText, KeyboardButton,
+    KeyboardMarkup, KeyboardRemove, LinkPreviewOptions, Message, MessageId, ParseMode, PreCheckoutQuery, Update,
+};
 use teloxide::utils::command::BotCommands;
+use reqwest::Url;
+use sqlx::SqlitePool;
 use std::sync::Arc;
 use std::error::Error;
 
+// Сообщение администратора в чате администраторов: ответ в теме обращения или ответом на сообщение обращения
+pub struct AdminComment<'a> {
+    pub admin_message_id: MessageId,
+    pub topic_id: Option<i32>,
+    pub reply_to_message_id: Option<MessageId>,
+    pub admin_id: i64,
+    pub text: Option<&'a str>,
+    pub media: Option<&'a SupportMedia>,
+}
+
 pub struct TelegramBot {
     bot: Bot,
     notifier: Arc<TelegramNotifier>,
     ton_processor: Arc<TonProcessor>,
+    subscriptions: Arc<SubscriptionService>,
+    shipments: Arc<ShipmentService>,
+    payments: Arc<PaymentService>,
+    // Ссылка на WebApp (https://t.me/<бот>/<приложение>) для кнопок в inline-режиме
+    webapp_link: Option<String>,
 }
 
+// Сколько товаров показывать в ответе на inline-запрос
+const INLINE_RESULTS_LIMIT: i64 = 20;
+
 impl TelegramBot {
-    pub fn new(bot_token: String, notifier: Arc<TelegramNotifier>, ton_processor: Arc<TonProcessor>) -> Self {
+    pub fn new(
+        bot_token: String,
+        notifier: Arc<TelegramNotifier>,
+        ton_processor: Arc<TonProcessor>,
+        subscriptions: Arc<SubscriptionService>,
+        shipments: Arc<ShipmentService>,
+        payments: Arc<PaymentService>,
+    ) -> Self {
         Self {
             bot: Bot::new(bot_token),
             notifier,
             ton_processor,
+            subscriptions,
+            shipments,
+            payments,
+            webapp_link: None,
         }
     }
 
-    pub async fn start(self) {
+    pub fn with_webapp_link(mut self, webapp_link: Option<String>) -> Self {
+        self.webapp_link = webapp_link;
+        self
+    }
+
+    pub async fn start(mut self) {
+        // Без явной ссылки открываем основное WebApp бота
+        if self.webapp_link.is_none() {
+            match self.bot.get_me().await {
+                Ok(me) => self.webapp_link = Some(format!("https://t.me/{}", me.username())),
+                Err(e) => eprintln!("Ошибка получения данных бота: {:?}", e),
+            }
+        }
+
         let handler = dptree::entry()
             .branch(
                 Update::filter_message()
+                    .branch(
+                        dptree::filter(|msg: Message| msg.successful_payment().is_some())
+                            .endpoint(Self::successful_payment_handler)
+                    )
                     .branch(
                         dptree::filter(|msg: Message| msg.text().is_some())
                             .filter_command::<Command>()
                             .endpoint(Self::command_handler)
                     )
                     .branch(
-                        dptree::filter(|msg: Message| msg.text().is_some())
+                        dptree::filter(|msg: Message| msg.text().is_some() || SupportMedia::from_message(&msg).is_some())
                             .endpoint(Self::message_handler)
                     )
+                    .branch(
+                        dptree::filter(|msg: Message| msg.contact().is_some() || msg.location().is_some())
+                            .endpoint(Self::shared_address_handler)
+                    )
             )
             .branch(
                 Update::filter_callback_query()
                     .endpoint(Self::callback_handler),
+            )
+            .branch(
+                Update::filter_inline_query()
+                    .endpoint(Self::inline_query_handler),
+            )
+            .branch(
+                Update::filter_pre_checkout_query()
+                    .endpoint(Self::pre_checkout_handler),
             );
 
         let bot_instance = Arc::new(self);
@@ -54,17 +128,120 @@ impl TelegramBot {
         bot: Bot,
         msg: Message,
         cmd: Command,
-        _bot_instance: Arc<Self>,
+        bot_instance: Arc<Self>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Запоминаем язык пользователя для уведомлений
+        if let Some(user) = msg.from.as_ref() {
+            if let Some(language_code) = user.language_code.as_deref() {
+                let pool = &bot_instance.notifier.db_pool;
+                if let Err(e) = notification_templates::remember_language(pool, user.id.0 as i64, language_code).await {
+                    eprintln!("Ошибка сохранения языка пользователя {}: {:?}", user.id, e);
+                }
+            }
+        }
+
+        let language = msg.from.as_ref().and_then(|user| user.language_code.as_deref());
+
         match cmd {
             Command::Start => {
-                bot.send_message(msg.chat.id, "Добро пожаловать в SportShop! 🏃‍♂️\n\nИспользуйте WebApp для просмотра товаров и оформления заказов.")
+                bot.send_message(
+                    msg.chat.id,
+                    "Добро пожаловать в SportShop! 🏃‍♂️\n\nИспользуйте WebApp для просмотра товаров и оформления заказов \
+                     или откройте каталог прямо в чате командой /catalog."
+                )
                     .await?;
             }
+            Command::Catalog => {
+                Self::send_catalog_categories(&bot, &bot_instance.notifier.db_pool, msg.chat.id).await?;
+            }
             Command::Help => {
                 bot.send_message(msg.chat.id, Command::descriptions().to_string())
                     .await?;
             }
+            Command::Points => {
+                let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+                let balance = bot_instance.notifier.loyalty.balance(user_id).await?;
+
+                let text = bot_instance.notifier.templates.render(language, &Notification::LoyaltyBalance {
+                    points: balance.points,
+                    value: balance.value,
+                    points_expiring: balance.points_expiring,
+                    expires_at: balance.next_expiration.map(|expires_at| expires_at.and_utc().timestamp()),
+                })?;
+
+                bot.send_message(msg.chat.id, text)
+                    .parse_mode(ParseMode::Markdown)
+                    .await?;
+            }
+            Command::Address => {
+                let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+                let saved = addresses::list(&bot_instance.notifier.db_pool, user_id).await?;
+
+                let addresses = saved
+                    .iter()
+                    .map(|address| AddressLine { line: address.address.format_line(), default: address.is_default })
+                    .collect::<Vec<_>>();
+                let templates = &bot_instance.notifier.templates;
+                let text = templates.render(language, &Notification::SavedAddresses { addresses: &addresses })?;
+
+                let keyboard = KeyboardMarkup::new(vec![vec![
+                    KeyboardButton::new(templates.label(language, Label::ShareContact)?).request(ButtonRequest::Contact),
+                    KeyboardButton::new(templates.label(language, Label::ShareLocation)?).request(ButtonRequest::Location),
+                ]])
+                    .resize_keyboard()
+                    .one_time_keyboard();
+
+                bot.send_message(msg.chat.id, text)
+                    .parse_mode(ParseMode::Markdown)
+                    .reply_markup(keyboard)
+                    .await?;
+            }
+            Command::Pickup(code) => {
+                // Выдача заказа на стойке: только администраторы из чата администраторов
+                let staff_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+                if msg.chat.id != bot_instance.notifier.admin_chat_id()
+                    || !bot_instance.notifier.admin_callbacks.is_admin(staff_id)
+                {
+                    return Ok(());
+                }
+                let pool = &bot_instance.notifier.db_pool;
+
+                let answer = match pickup::hand_over(pool, &code, staff_id).await {
+                    Ok(order_id) => {
+                        bot_instance.notifier.handle_order_completion(order_id, None).await?;
+                        format!("✅ Заказ №{} выдан", order_id)
+                    }
+                    Err(e) if e.is_user_error() => format!("⚠️ {}", e),
+                    Err(e) => return Err(e.into()),
+                };
+                bot.send_message(msg.chat.id, answer).await?;
+            }
+            Command::Support => {
+                let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+                let threads = support::open_threads(&bot_instance.notifier.db_pool, user_id).await?;
+
+                let mut rows: Vec<Vec<InlineKeyboardButton>> = threads
+                    .iter()
+                    .map(|thread| vec![InlineKeyboardButton::callback(thread.title(), format!("support_{}", thread.id))])
+                    .collect();
+                rows.push(vec![InlineKeyboardButton::callback("✉️ Новый вопрос", "support_new")]);
+
+                bot.send_message(msg.chat.id, "Выберите обращение, в которое отправлять ваши сообщения:")
+                    .reply_markup(InlineKeyboardMarkup::new(rows))
+                    .await?;
+            }
+            Command::Subscriptions => {
+                let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+                let subscriptions = bot_instance.subscriptions.list_for_user(user_id).await?;
+
+                if subscriptions.is_empty() {
+                    bot.send_message(msg.chat.id, "У вас нет активных подписок.")
+                        .await?;
+                }
+                for details in subscriptions {
+                    bot_instance.notifier.send_subscription_controls(&details.subscription, None).await?;
+                }
+            }
         }
         Ok(())
     }
@@ -74,28 +251,92 @@ impl TelegramBot {
         msg: Message,
         bot_instance: Arc<Self>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        if let Some(text) = msg.text() {
-            let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
-            
-            // Проверяем, есть ли активный диалог у пользователя
-            if let Ok(is_active) = bot_instance.notifier.is_dialog_active(user_id).await {
-                if is_active {
-                    // Пересылаем сообщение пользователя администраторам
-                    if let Err(e) = bot_instance.notifier.forward_user_message_to_admin(user_id, text).await {
-                        eprintln!("Ошибка пересылки сообщения администраторам: {:?}", e);
-                    }
-                } else {
-                    // Диалог неактивен - сообщаем пользователю
-                    bot.send_message(
-                        msg.chat.id,
-                        "У вас нет активных заказов. Оформите заказ через WebApp для начала диалога с поддержкой."
-                    ).await?;
+        // Текст сообщения или подпись к вложению
+        let text = msg.text().or_else(|| msg.caption());
+        let media = SupportMedia::from_message(&msg);
+
+        // Сообщения администраторов в теме обращения или ответом на сообщение обращения
+        if msg.chat.id == bot_instance.notifier.admin_chat_id() {
+            let topic = msg.thread_id.filter(|_| msg.is_topic_message);
+            let admin_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+            let answer = match Self::handle_pending_input(&bot_instance.notifier, &msg, admin_id, text, media.as_ref()).await? {
+                Some(answer) => Some(answer),
+                None => Self::handle_admin_comment(&bot_instance.notifier, &bot_instance.shipments, &AdminComment {
+                    admin_message_id: msg.id,
+                    topic_id: topic.map(|topic| topic.0 .0),
+                    reply_to_message_id: msg.reply_to_message().map(|reply| reply.id),
+                    admin_id,
+                    text,
+                    media: media.as_ref(),
+                }).await?,
+            };
+            if let Some(answer) = answer {
+                let mut request = bot.send_message(msg.chat.id, answer);
+                if let Some(topic) = topic {
+                    request = request.message_thread_id(topic);
                 }
+                request.await?;
             }
+            return Ok(());
+        }
+
+        let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+
+        // Пересылаем сообщение пользователя администраторам в подходящее обращение
+        let thread = bot_instance.notifier
+            .forward_user_message_to_admin(
+                user_id,
+                msg.id,
+                msg.reply_to_message().map(|reply| reply.id),
+                text,
+                media.as_ref(),
+            )
+            .await?;
+
+        // Первое сообщение нового общего обращения
+        if thread.order_id.is_none()
+            && support::history(&bot_instance.notifier.db_pool, thread.id).await?.len() == 1
+        {
+            bot.send_message(
+                msg.chat.id,
+                "Ваше сообщение передано в поддержку. Ответ придет в этот чат."
+            ).await?;
         }
         Ok(())
     }
 
+    // Контакт или геопозиция для адреса доставки
+    async fn shared_address_handler(
+        bot: Bot,
+        msg: Message,
+        bot_instance: Arc<Self>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let user_id = msg.from.as_ref().map(|user| user.id.0 as i64).unwrap_or(0);
+        let pool = &bot_instance.notifier.db_pool;
+
+        let reply = if let Some(contact) = msg.contact() {
+            let name = match &contact.last_name {
+                Some(last_name) => format!("{} {}", contact.first_name, last_name),
+                None => contact.first_name.clone(),
+            };
+            match addresses::save_draft_contact(pool, user_id, &name, &contact.phone_number).await {
+                Ok(()) => "✅ Контакт сохранен и будет подставлен в адрес доставки.".to_string(),
+                Err(e) if e.is_user_error() => format!("⚠️ {}", e),
+                Err(e) => return Err(e.into()),
+            }
+        } else if let Some(location) = msg.location() {
+            addresses::save_draft_location(pool, user_id, location.latitude, location.longitude).await?;
+            "✅ Геопозиция сохранена. Уточните улицу и дом в форме адреса при оформлении заказа.".to_string()
+        } else {
+            return Ok(());
+        };
+
+        bot.send_message(msg.chat.id, reply)
+            .reply_markup(KeyboardRemove::new())
+            .await?;
+        Ok(())
+    }
+
     async fn callback_handler(
         bot: Bot,
         q: CallbackQuery,
@@ -104,41 +345,514 @@ impl TelegramBot {
         if let Some(data) = &q.data {
             let user_id = q.from.id.0 as i64;
 
-            if data.starts_with("pay_") {
-                // Обработка нажатия кнопки "Оплатить"
-                if let Ok(order_id) = data[4..].parse::<i64>() {
-                    if let Err(e) = bot_instance.notifier.handle_payment_request(order_id, user_id).await {
-                        eprintln!("Ошибка обработки запроса на оплату: {:?}", e);
+            if let Some(rest) = data.strip_prefix("pay_") {
+                // Кнопка оплаты: pay_{способ}_{заказ}, старые сообщения - pay_{заказ} (TON)
+                let (code, order_id) = rest.rsplit_once('_').unwrap_or(("ton", rest));
+                if let Ok(order_id) = order_id.parse::<i64>() {
+                    let text = match bot_instance.payments.request(code, order_id, user_id).await {
+                        Ok(()) => "Ссылка на оплату отправлена".to_string(),
+                        Err(e) if e.is_user_error() => e.to_string(),
+                        Err(e) => {
+                            eprintln!("Ошибка обработки запроса на оплату: {:?}", e);
+                            "Произошла ошибка при создании платежа".to_string()
+                        }
+                    };
+                    bot.answer_callback_query(q.id)
+                        .text(text)
+                        .await?;
+                }
+            } else if data == "catalog" || data.starts_with("catalog_") {
+                // Листание каталога: старое сообщение заменяется новым (фото и текст не редактируются друг в друга)
+                let chat_id = q.message.as_ref().map(|message| message.chat().id).unwrap_or(ChatId(user_id));
+                if let Some(message) = &q.message {
+                    if let Err(e) = bot.delete_message(message.chat().id, message.id()).await {
+                        eprintln!("Ошибка удаления сообщения каталога: {:?}", e);
+                    }
+                }
+
+                let pool = &bot_instance.notifier.db_pool;
+                let position = data
+                    .strip_prefix("catalog_")
+                    .and_then(|rest| rest.split_once('_'))
+                    .and_then(|(category_id, index)| Some((category_id.parse::<i64>().ok()?, index.parse::<i64>().ok()?)));
+                match position {
+                    Some((category_id, index)) => Self::send_catalog_product(&bot, pool, chat_id, category_id, index).await?,
+                    None => Self::send_catalog_categories(&bot, pool, chat_id).await?,
+                }
+                bot.answer_callback_query(q.id).await?;
+            } else if let Some(product_id) = data.strip_prefix("cart_add_") {
+                // Добавление в корзину из каталога: корзина общая с WebApp
+                let pool = &bot_instance.notifier.db_pool;
+                let text = match product_id.parse::<i64>() {
+                    Ok(product_id) => {
+                        let cart_id = cart::user_cart_id(pool, user_id).await?;
+                        match cart::add_item(pool, cart_id, product_id, 1).await {
+                            Ok(added) => format!("✅ Добавлено в корзину (в корзине: {} шт.)", added.quantity),
+                            Err(cart::CartError::InsufficientStock(stock)) => {
+                                format!("Недостаточно товара на складе (доступно: {})", stock)
+                            }
+                            Err(e) if e.is_user_error() => e.to_string(),
+                            Err(e) => {
+                                eprintln!("Ошибка добавления в корзину: {:?}", e);
+                                "Произошла ошибка при добавлении в корзину".to_string()
+                            }
+                        }
+                    }
+                    Err(_) => "Товар не найден".to_string(),
+                };
+                bot.answer_callback_query(q.id)
+                    .text(text)
+                    .await?;
+            } else if data == "cart_show" {
+                let chat_id = q.message.as_ref().map(|message| message.chat().id).unwrap_or(ChatId(user_id));
+                Self::send_cart_summary(&bot, &bot_instance.notifier.db_pool, chat_id, user_id).await?;
+                bot.answer_callback_query(q.id).await?;
+            } else if let Some(thread) = data.strip_prefix("support_") {
+                // Выбор обращения для следующих сообщений
+                let pool = &bot_instance.notifier.db_pool;
+                let thread = if thread == "new" {
+                    Some(support::open_thread(pool, user_id, None).await?)
+                } else {
+                    match thread.parse::<i64>() {
+                        Ok(thread_id) => support::get_thread(pool, thread_id).await?
+                            .filter(|thread| thread.user_id == user_id && thread.status == support::ThreadStatus::Open),
+                        Err(_) => None,
+                    }
+                };
+
+                let text = match thread {
+                    Some(thread) => {
+                        support::set_current_thread(pool, user_id, thread.id).await?;
+                        format!("Сообщения будут отправляться в: {}", thread.title())
+                    }
+                    None => "Обращение уже закрыто".to_string(),
+                };
+                bot.answer_callback_query(q.id)
+                    .text(text)
+                    .await?;
+            } else if AdminCallbacks::is_admin_callback(data) {
+                // Кнопки карточки заказа: подпись проверяется, нажимать могут только администраторы
+                let action = bot_instance.notifier.admin_callbacks.parse_callback(data);
+                let message = q.message.as_ref().and_then(|message| message.regular_message());
+
+                let text = match (action, message) {
+                    (Some((action, order_id)), Some(message))
+                        if message.chat.id == bot_instance.notifier.admin_chat_id()
+                            && bot_instance.notifier.admin_callbacks.is_admin(user_id) =>
+                    {
+                        Self::handle_admin_action(&bot, &bot_instance, message, user_id, action, order_id).await?
+                    }
+                    (Some(_), Some(_)) => "⛔ Недостаточно прав".to_string(),
+                    _ => "⚠️ Недействительная кнопка".to_string(),
+                };
+                bot.answer_callback_query(q.id)
+                    .text(text)
+                    .await?;
+            } else if let Some((action, subscription_id)) = SubscriptionAction::parse_callback(data) {
+                // Управление подпиской из сообщения с кнопками
+                match bot_instance.subscriptions.apply_action(user_id, subscription_id, action).await {
+                    Ok(subscription) => {
+                        if let Some(message) = &q.message {
+                            if let Err(e) = bot_instance.notifier
+                                .update_subscription_controls(message.chat().id, message.id(), &subscription)
+                                .await {
+                                eprintln!("Ошибка обновления сообщения подписки: {:?}", e);
+                            }
+                        }
+                        let text = match action {
+                            SubscriptionAction::Pause => "Подписка приостановлена",
+                            SubscriptionAction::Resume => "Подписка возобновлена",
+                            SubscriptionAction::Skip => "Ближайший заказ пропущен",
+                            SubscriptionAction::Cancel => "Подписка отменена",
+                        };
                         bot.answer_callback_query(q.id)
-                            .text("Произошла ошибка при создании платежа")
+                            .text(text)
                             .await?;
-                    } else {
+                    }
+                    Err(e) => {
+                        eprintln!("Ошибка управления подпиской {}: {:?}", subscription_id, e);
+                        let text = if e.is_user_error() {
+                            e.to_string()
+                        } else {
+                            "Произошла ошибка при изменении подписки".to_string()
+                        };
                         bot.answer_callback_query(q.id)
-                            .text("Ссылка на оплату отправлена")
+                            .text(text)
                             .await?;
                     }
                 }
-            } else if data.starts_with("complete_") {
-                // Обработка нажатия кнопки "Выполнено" (только для администраторов)
-                if let Ok(order_id) = data[9..].parse::<i64>() {
-                    if let Some(message) = &q.message {
-                        if let Err(e) = bot_instance.notifier.handle_order_completion(order_id, message.id()).await {
-                            eprintln!("Ошибка завершения заказа: {:?}", e);
-                            bot.answer_callback_query(q.id)
-                                .text("Произошла ошибка при завершении заказа")
-                                .await?;
-                        } else {
-                            bot.answer_callback_query(q.id)
-                                .text("Заказ отмечен как выполненный")
-                                .await?;
-                        }
-                    }
+            }
+        }
+        Ok(())
+    }
+
+    // Telegram ждет ответа на pre_checkout_query до списания денег (не дольше 10 секунд)
+    async fn pre_checkout_handler(
+        bot: Bot,
+        query: PreCheckoutQuery,
+        bot_instance: Arc<Self>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match bot_instance.payments.validate_pre_checkout(&query).await {
+            Ok(()) => {
+                bot.answer_pre_checkout_query(query.id, true).await?;
+            }
+            Err(e) => {
+                let text = if e.is_user_error() {
+                    e.to_string()
+                } else {
+                    eprintln!("Ошибка проверки платежа: {:?}", e);
+                    "Не удалось проверить заказ, попробуйте позже".to_string()
+                };
+                bot.answer_pre_checkout_query(query.id, false)
+                    .error_message(text)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn successful_payment_handler(
+        bot: Bot,
+        msg: Message,
+        bot_instance: Arc<Self>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (Some(payment), Some(user)) = (msg.successful_payment(), msg.from.as_ref()) else {
+            return Ok(());
+        };
+
+        // Платеж записывается и зачитывается вместе. Просьбу о новой доплате и карточку заказа
+        // администраторам отправит очередь уведомлений
+        let settled = bot_instance.payments
+            .complete_invoice(user.id.0 as i64, payment, user.username.as_deref())
+            .await?;
+        if let Some((order_id, Settlement::Covered { .. })) = settled {
+            let text = bot_instance.notifier.templates.render(
+                user.language_code.as_deref(),
+                &Notification::PaymentReceived { order_id },
+            )?;
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        Ok(())
+    }
+
+    // Inline-режим: @бот <запрос> в любом чате ищет товары и отправляет карточку с кнопкой "Купить"
+    async fn inline_query_handler(
+        bot: Bot,
+        q: InlineQuery,
+        bot_instance: Arc<Self>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let products = catalog::search(&bot_instance.notifier.db_pool, &q.query, INLINE_RESULTS_LIMIT).await?;
+
+        let results: Vec<InlineQueryResult> = products
+            .into_iter()
+            .map(|product| {
+                let image = Url::parse(&product.image_url).ok();
+
+                let mut text = format!("*{}*\n💰 {:.2} TON", escape_markdown(&product.name), product.price);
+                if !product.description.is_empty() {
+                    let description: String = product.description.chars().take(300).collect();
+                    text.push_str("\n\n");
+                    text.push_str(&escape_markdown(&description));
+                }
+
+                // Фото товара показывается превью ссылки над текстом
+                let mut content = InputMessageContentText::new(text).parse_mode(ParseMode::Markdown);
+                if let Some(image) = &image {
+                    content = content.link_preview_options(LinkPreviewOptions {
+                        is_disabled: false,
+                        url: Some(image.to_string()),
+                        prefer_small_media: false,
+                        prefer_large_media: true,
+                        show_above_text: true,
+                    });
+                }
+
+                let mut article = InlineQueryResultArticle::new(
+                    product.id.to_string(),
+                    product.name.clone(),
+                    InputMessageContent::Text(content),
+                )
+                    .description(format!("{:.2} TON", product.price));
+                if let Some(image) = image {
+                    article = article.thumbnail_url(image);
                 }
+
+                // WebApp открывается сразу на товаре: startapp=product_<id>
+                let link = bot_instance.webapp_link.as_deref().and_then(|link| {
+                    Url::parse(&format!("{}?startapp=product_{}", link, product.id)).ok()
+                });
+                if let Some(link) = link {
+                    article = article.reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                        InlineKeyboardButton::url("Купить", link),
+                    ]]));
+                }
+
+                InlineQueryResult::Article(article)
+            })
+            .collect();
+
+        bot.answer_inline_query(q.id, results)
+            .cache_time(60)
+            .await?;
+        Ok(())
+    }
+
+    // Список категорий каталога
+    async fn send_catalog_categories(
+        bot: &Bot,
+        pool: &SqlitePool,
+        chat_id: ChatId,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let categories = catalog::categories(pool).await?;
+        if categories.is_empty() {
+            bot.send_message(chat_id, "Каталог пока пуст.").await?;
+            return Ok(());
+        }
+
+        let mut rows: Vec<Vec<InlineKeyboardButton>> = categories
+            .iter()
+            .map(|category| vec![InlineKeyboardButton::callback(
+                format!("{} ({})", category.name, category.products),
+                format!("catalog_{}_0", category.id),
+            )])
+            .collect();
+        rows.push(vec![InlineKeyboardButton::callback("🛒 Корзина", "cart_show")]);
+
+        bot.send_message(chat_id, "📂 Выберите категорию:")
+            .reply_markup(InlineKeyboardMarkup::new(rows))
+            .await?;
+        Ok(())
+    }
+
+    // Карточка товара: фото (если есть ссылка), цена, остаток и кнопки листания
+    async fn send_catalog_product(
+        bot: &Bot,
+        pool: &SqlitePool,
+        chat_id: ChatId,
+        category_id: i64,
+        index: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some((product, total)) = catalog::product_at(pool, category_id, index).await? else {
+            bot.send_message(chat_id, "В этой категории пока нет товаров.")
+                .reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                    InlineKeyboardButton::callback("📂 Категории", "catalog"),
+                ]]))
+                .await?;
+            return Ok(());
+        };
+        let index = index.clamp(0, total - 1);
+
+        let mut text = format!("*{}*\n💰 {:.2} TON", escape_markdown(&product.name), product.price);
+        if product.stock > 0 {
+            text.push_str(&format!("\n📦 В наличии: {} шт.", product.stock));
+        } else {
+            text.push_str("\n❌ Нет в наличии");
+        }
+        if !product.description.is_empty() {
+            // Подпись к фото ограничена 1024 символами
+            let description: String = product.description.chars().take(600).collect();
+            text.push_str("\n\n");
+            text.push_str(&escape_markdown(&description));
+        }
+
+        let mut rows = Vec::new();
+        if product.stock > 0 {
+            rows.push(vec![InlineKeyboardButton::callback("➕ В корзину", format!("cart_add_{}", product.id))]);
+        }
+        let mut navigation = Vec::new();
+        if index > 0 {
+            navigation.push(InlineKeyboardButton::callback("◀️", format!("catalog_{}_{}", category_id, index - 1)));
+        }
+        navigation.push(InlineKeyboardButton::callback(
+            format!("{}/{}", index + 1, total),
+            format!("catalog_{}_{}", category_id, index),
+        ));
+        if index + 1 < total {
+            navigation.push(InlineKeyboardButton::callback("▶️", format!("catalog_{}_{}", category_id, index + 1)));
+        }
+        rows.push(navigation);
+        rows.push(vec![
+            InlineKeyboardButton::callback("📂 Категории", "catalog"),
+            InlineKeyboardButton::callback("🛒 Корзина", "cart_show"),
+        ]);
+        let keyboard = InlineKeyboardMarkup::new(rows);
+
+        // Фото отправляем только по абсолютной ссылке; если Telegram не смог его загрузить - показываем текст
+        if let Ok(url) = Url::parse(&product.image_url) {
+            let sent = bot.send_photo(chat_id, InputFile::url(url))
+                .caption(text.clone())
+                .parse_mode(ParseMode::Markdown)
+                .reply_markup(keyboard.clone())
+                .await;
+            match sent {
+                Ok(_) => return Ok(()),
+                Err(e) => eprintln!("Ошибка отправки фото товара {}: {:?}", product.id, e),
             }
         }
+
+        bot.send_message(chat_id, text)
+            .parse_mode(ParseMode::Markdown)
+            .reply_markup(keyboard)
+            .await?;
         Ok(())
     }
 
+    // Содержимое корзины пользователя (той же, что в WebApp)
+    async fn send_cart_summary(
+        bot: &Bot,
+        pool: &SqlitePool,
+        chat_id: ChatId,
+        user_id: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let cart_id = cart::user_cart_id(pool, user_id).await?;
+        let validation = cart::validate_cart(pool, cart_id, false).await?;
+
+        let text = if validation.items.is_empty() {
+            "🛒 Корзина пуста.".to_string()
+        } else {
+            let mut text = "🛒 *Корзина*:\n".to_string();
+            for item in &validation.items {
+                text.push_str(&format!(
+                    "- {} (×{}) — {:.2} TON\n",
+                    escape_markdown(item.name.as_deref().unwrap_or("Товар удален")),
+                    item.capped_quantity,
+                    item.current_price.unwrap_or(0.0) * item.capped_quantity as f64
+                ));
+            }
+            text.push_str(&format!(
+                "💰 *Итого*: {:.2} TON\n\nОформить заказ можно в WebApp — корзина общая.",
+                validation.total_amount
+            ));
+            text
+        };
+
+        bot.send_message(chat_id, text)
+            .parse_mode(ParseMode::Markdown)
+            .reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::callback("📂 Каталог", "catalog"),
+            ]]))
+            .await?;
+        Ok(())
+    }
+
+    // Действие из карточки заказа в чате администраторов. Возвращает текст ответа на нажатие.
+    async fn handle_admin_action(
+        bot: &Bot,
+        bot_instance: &Arc<Self>,
+        card: &Message,
+        admin_id: i64,
+        action: AdminAction,
+        order_id: i64,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let notifier = &bot_instance.notifier;
+        let pool = &notifier.db_pool;
+
+        let answer = match action {
+            AdminAction::Ready => {
+                // Заказ на самовывоз собран: покупатель получает код и QR
+                match pickup::mark_ready(pool, notifier, order_id).await {
+                    Ok(()) => {
+                        notifier.refresh_admin_card(card.id, order_id).await;
+                        "Код выдачи отправлен покупателю".to_string()
+                    }
+                    Err(e) if e.is_user_error() => format!("⚠️ {}", e),
+                    Err(e) => {
+                        eprintln!("Ошибка подготовки самовывоза: {:?}", e);
+                        "Произошла ошибка при подготовке самовывоза".to_string()
+                    }
+                }
+            }
+            AdminAction::Pack | AdminAction::Ship | AdminAction::Complete | AdminAction::Cancel => {
+                let status = match admin_actions::apply_transition(pool, &notifier.loyalty, order_id, action).await {
+                    Ok(status) => status,
+                    Err(e) if e.is_user_error() => return Ok(format!("⚠️ {}", e)),
+                    Err(e) => return Err(e.into()),
+                };
+                println!("🛠 Администратор {} перевел заказ {} в статус {}", admin_id, order_id, status);
+                match action {
+                    AdminAction::Complete => {
+                        notifier.handle_order_completion(order_id, Some(card.id)).await?;
+                        "Заказ отмечен как выполненный".to_string()
+                    }
+                    AdminAction::Cancel => {
+                        // Заказ оплачен: деньги возвращаются сразу после фиксации отмены
+                        let answer = match bot_instance.payments.refund(order_id).await {
+                            Ok(refund) if refund.pending > 0 => format!(
+                                "Заказ №{} отменен, платежей к ручному возврату: {}",
+                                order_id, refund.pending
+                            ),
+                            Ok(_) => format!("Заказ №{} отменен, оплата возвращена", order_id),
+                            Err(e) => {
+                                eprintln!("Ошибка возврата оплаты заказа {}: {:?}", order_id, e);
+                                format!("Заказ №{} отменен, оплату нужно вернуть вручную", order_id)
+                            }
+                        };
+                        notifier.handle_order_cancellation(order_id, Some(card.id)).await?;
+                        answer
+                    }
+                    _ => {
+                        // Покупатель получит уведомление о статусе из очереди
+                        notifier.refresh_admin_card(card.id, order_id).await;
+                        format!("Заказ №{}: статус \"{}\"", order_id, status)
+                    }
+                }
+            }
+            AdminAction::Note | AdminAction::Message => {
+                admin_actions::set_pending_input(pool, admin_id, action, order_id).await?;
+                let prompt = if action == AdminAction::Note {
+                    format!("📝 Отправьте следующим сообщением заметку к заказу №{}", order_id)
+                } else {
+                    format!("✉️ Отправьте следующим сообщением текст для покупателя по заказу №{}", order_id)
+                };
+                let mut request = bot.send_message(card.chat.id, prompt);
+                if let Some(topic) = card.thread_id.filter(|_| card.is_topic_message) {
+                    request = request.message_thread_id(topic);
+                }
+                request.await?;
+                "Жду сообщение".to_string()
+            }
+        };
+        Ok(answer)
+    }
+
+    // Ввод администратора после кнопки "Заметка" или "Написать покупателю".
+    // Возвращает ответ для чата администраторов или None, если ввода не ожидалось.
+    async fn handle_pending_input(
+        notifier: &TelegramNotifier,
+        msg: &Message,
+        admin_id: i64,
+        text: Option<&str>,
+        media: Option<&SupportMedia>,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        if !notifier.admin_callbacks.is_admin(admin_id) {
+            return Ok(None);
+        }
+        let Some((action, order_id)) = admin_actions::take_pending_input(&notifier.db_pool, admin_id).await? else {
+            return Ok(None);
+        };
+
+        let answer = match action {
+            AdminAction::Note => match text.map(str::trim).filter(|text| !text.is_empty()) {
+                Some(text) => {
+                    admin_actions::add_note(&notifier.db_pool, order_id, admin_id, text).await?;
+                    format!("📝 Заметка к заказу №{} сохранена", order_id)
+                }
+                None => "⚠️ Заметка должна содержать текст".to_string(),
+            },
+            AdminAction::Message => {
+                let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+                    .bind(order_id)
+                    .fetch_one(&notifier.db_pool)
+                    .await?;
+                let thread = support::open_thread(&notifier.db_pool, user_id, Some(order_id)).await?;
+                notifier.forward_admin_comment_to_user(&thread, msg.id, admin_id, text, media).await?;
+                format!("✉️ Сообщение по заказу №{} отправлено покупателю", order_id)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(answer))
+    }
+
     // Метод для обработки подтверждения оплаты TON (вызывается извне при получении платежа)
     pub async fn handle_payment_confirmation(&self, order_id: i64, username: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
         // Уведомляем администраторов о новом заказе
@@ -148,12 +862,55 @@ impl TelegramBot {
         Ok(())
     }
 
-    // Метод для обработки комментариев администраторов (вызывается при получении комментария в канале)
-    pub async fn handle_admin_comment(&self, order_id: i64, comment_text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-        if let Err(e) = self.notifier.forward_admin_comment_to_user(order_id, comment_text).await {
-            eprintln!("Ошибка пересылки комментария пользователю: {:?}", e);
+    // Сообщение администратора в чате администраторов (вызывается ботом и вебхуком).
+    // Обращение определяется по теме форума, иначе по сообщению, на которое ответили.
+    // /track привязывает трек-номер к заказу, /close закрывает обращение,
+    // остальное пересылается покупателю. Возвращает ответ для чата администраторов.
+    pub async fn handle_admin_comment(
+        notifier: &TelegramNotifier,
+        shipments: &ShipmentService,
+        comment: &AdminComment<'_>,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let AdminComment { admin_message_id, topic_id, reply_to_message_id, admin_id, text: comment_text, media } = *comment;
+
+        // Отвечать покупателям и выполнять команды могут только администраторы
+        if !notifier.admin_callbacks.is_admin(admin_id) {
+            return Ok(None);
         }
-        Ok(())
+        let thread = match (topic_id, reply_to_message_id) {
+            (Some(topic_id), _) => support::thread_by_admin_topic(&notifier.db_pool, topic_id).await?,
+            (None, Some(reply_to)) => support::thread_by_admin_message(&notifier.db_pool, reply_to.0).await?,
+            (None, None) => None,
+        };
+        let Some(thread) = thread else {
+            return Ok(None);
+        };
+
+        // Команды действуют только для текстовых ответов
+        if let Some(input) = comment_text.filter(|_| media.is_none()).and_then(shipping::parse_track_command) {
+            let Some(order_id) = thread.order_id else {
+                return Ok(Some("⚠️ Обращение не связано с заказом".to_string()));
+            };
+            let answer = match shipments.attach(order_id, &input).await {
+                Ok(shipment) => format!(
+                    "✅ Трек-номер {} привязан к заказу №{}, покупатель уведомлен",
+                    shipment.tracking_number, order_id
+                ),
+                Err(e) => format!("⚠️ Не удалось привязать трек-номер: {}", e),
+            };
+            return Ok(Some(answer));
+        }
+
+        if media.is_none() && comment_text.map(str::trim) == Some("/close") {
+            notifier.close_support_thread(&thread).await?;
+            return Ok(Some(format!("✅ {} закрыто", thread.title())));
+        }
+
+        if thread.status != support::ThreadStatus::Open {
+            return Ok(Some(format!("⚠️ {} закрыто, сообщение не отправлено", thread.title())));
+        }
+        notifier.forward_admin_comment_to_user(&thread, admin_message_id, admin_id, comment_text, media).await?;
+        Ok(None)
     }
 }
 
@@ -164,4 +921,16 @@ enum Command {
     Start,
     #[command(description = "Показать справку")]
     Help,
+    #[command(description = "Каталог товаров")]
+    Catalog,
+    #[command(description = "Баланс бонусных баллов")]
+    Points,
+    #[command(description = "Мои подписки")]
+    Subscriptions,
+    #[command(description = "Обращения в поддержку")]
+    Support,
+    #[command(description = "Адреса доставки")]
+    Address,
+    #[command(description = "Выдать заказ по коду самовывоза")]
+    Pickup(String),
 }
\ No newline at end of file
@@ -1,9 +1,23 @@
 use crate::models::{Order, Payment};
+use crate::admin_actions::{self, AdminCallbacks};
+use crate::loyalty::LoyaltyProgram;
+use crate::bundles::BundleComponent;
+use crate::delivery::DeliveryKind;
+use crate::notification_templates::{
+    self, DeliveryLine, Label, Notification, NotificationTemplates, OrderLine, TemplateError,
+};
+use crate::outbox::OutboxMessage;
+use crate::payments::PAYMENT_TOLERANCE;
+use crate::shipping::ShipmentStatus;
+use crate::support::{self, MessageDirection, NewSupportMessage, SupportMedia, SupportThread};
+use crate::subscriptions::{Subscription, SubscriptionAction, SubscriptionStatus};
 use sqlx::SqlitePool;
 use teloxide::prelude::*;
-use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, MessageId};
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode, MessageId, ReplyParameters, ThreadId};
 use thiserror::Error;
 use reqwest::Url;
+use std::collections::HashSet;
+use std::sync::Arc;
 
 #[derive(Error, Debug)]
 pub enum NotificationError {
@@ -13,6 +27,20 @@ pub enum NotificationError {
     DbError(#[from] sqlx::Error),
     #[error("Parse error: {0}")]
     ParseError(String),
+    #[error("Template error: {0}")]
+    TemplateError(#[from] TemplateError),
+}
+
+// Экранирование пользовательского текста для ParseMode::Markdown
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '_' | '*' | '`' | '[') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
 
 #[derive(Debug)]
@@ -21,102 +49,114 @@ pub struct CartItemData {
     pub quantity: i32,
     pub name: String,
     pub price: f64,
+    pub components: Vec<BundleComponent>,
 }
 
 pub struct TelegramNotifier {
     bot: Bot,
     admin_chat_id: i64,
     pub db_pool: SqlitePool,
-    payment_wallet: String,
+    pub loyalty: Arc<LoyaltyProgram>,
+    // Способы оплаты (code, title): кнопка на каждый под подтверждением заказа
+    payment_methods: Vec<(String, String)>,
+    // Чат администраторов - форум: отдельная тема на каждое обращение
+    forum_topics: bool,
+    pub admin_callbacks: AdminCallbacks,
+    pub templates: Arc<NotificationTemplates>,
 }
 
 impl TelegramNotifier {
-    pub fn new(bot_token: String, admin_chat_id: i64, db_pool: SqlitePool) -> Self {
+    pub fn new(
+        bot_token: String,
+        admin_chat_id: i64,
+        db_pool: SqlitePool,
+        loyalty: Arc<LoyaltyProgram>,
+        templates: Arc<NotificationTemplates>,
+    ) -> Self {
+        let admin_callbacks = AdminCallbacks::new(&bot_token);
         Self {
             bot: Bot::new(bot_token),
             admin_chat_id,
             db_pool,
-            payment_wallet: "UQCbShhQNTKUd3GvKJsBxeiwLHuJghq9r7FQrkC5mSOfLXgy".to_string(),
+            loyalty,
+            payment_methods: vec![("ton".to_string(), "Оплатить".to_string())],
+            forum_topics: false,
+            admin_callbacks,
+            templates,
         }
     }
 
-    // 1. Отправка подтверждения заказа пользователю с кнопкой оплаты
-    pub async fn send_order_confirmation(
-        &self,
-        order_id: i64,
-        user_id: i64,
-        cart_items: &[CartItemData],
-        delivery_address: &str,
-        total_amount: f64,
-        _username: Option<&str>,
-    ) -> Result<(), NotificationError> {
-        let mut order_text = "🛒 *Ваш заказ*:\n".to_string();
-        
-        for item in cart_items {
-            order_text.push_str(&format!("- {} (×{})\n", item.name, item.quantity));
-        }
-        
-        order_text.push_str(&format!(
-            "📦 *Адрес доставки*: {}\n💰 *Сумма к оплате*: {:.2} TON",
-            delivery_address, total_amount
-        ));
-
-        let keyboard = InlineKeyboardMarkup::new(vec![vec![
-            InlineKeyboardButton::callback("Оплатить", format!("pay_{}", order_id))
-        ]]);
+    pub fn with_forum_topics(mut self, enabled: bool) -> Self {
+        self.forum_topics = enabled;
+        self
+    }
 
-        let message = self.bot
-            .send_message(ChatId(user_id), order_text)
-            .parse_mode(ParseMode::Markdown)
-            .reply_markup(keyboard)
-            .send()
-            .await?;
+    pub fn with_payment_methods(mut self, payment_methods: Vec<(String, String)>) -> Self {
+        self.payment_methods = payment_methods;
+        self
+    }
 
-        // Сохраняем message_id для дальнейшего использования
-        sqlx::query!(
-            "UPDATE orders SET telegram_message_id = ?, dialog_active = TRUE WHERE id = ?",
-            message.id.0,
-            order_id
-        )
-        .execute(&self.db_pool)
-        .await?;
+    // Администраторы, которым доступны кнопки карточки заказа
+    pub fn with_admin_ids(mut self, admin_ids: HashSet<i64>) -> Self {
+        self.admin_callbacks = self.admin_callbacks.with_admin_ids(admin_ids);
+        self
+    }
 
-        Ok(())
+    // Язык пользователя для шаблонов; без сохраненного - язык по умолчанию
+    async fn language(&self, user_id: i64) -> Option<String> {
+        match notification_templates::user_language(&self.db_pool, user_id).await {
+            Ok(language) => language,
+            Err(e) => {
+                eprintln!("Ошибка получения языка пользователя {}: {:?}", user_id, e);
+                None
+            }
+        }
     }
 
-    // 2. Обработка нажатия кнопки "Оплатить" - создание TON платежа
-    pub async fn handle_payment_request(&self, order_id: i64, user_id: i64) -> Result<(), NotificationError> {
-        // Получаем данные заказа
-        let order = sqlx::query!(
-            "SELECT total_amount FROM orders WHERE id = ? AND user_id = ?",
-            order_id,
-            user_id
-        )
-        .fetch_one(&self.db_pool)
-        .await?;
+    // 1. Отправка подтверждения заказа пользователю с кнопкой оплаты
+    async fn deliver_order_confirmation(&self, order_id: i64, user_id: i64, order_text: &str) -> Result<(), NotificationError> {
+        // Заказ, целиком оплаченный с баланса, уже оплачен: кнопки оплаты не нужны
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(&self.db_pool)
+            .await?;
 
-        // Создаем TON платеж (здесь должна быть интеграция с TON)
-        let payment_url = format!(
-            "ton://transfer/{}?amount={}&text=Order_{}",
-            self.payment_wallet,
-            (order.total_amount * 1_000_000_000.0) as i64, // Convert to nanotons
-            order_id
-        );
+        let mut request = self.bot
+            .send_message(ChatId(user_id), order_text)
+            .parse_mode(ParseMode::Markdown);
+        if status == "pending" {
+            request = request.reply_markup(InlineKeyboardMarkup::new(self.payment_methods.iter().map(|(code, title)| {
+                vec![InlineKeyboardButton::callback(title.clone(), format!("pay_{}_{}", code, order_id))]
+            })));
+        }
+        let message = request.send().await?;
 
-        let keyboard = InlineKeyboardMarkup::new(vec![vec![
-            InlineKeyboardButton::url("Оплатить в TON", Url::parse(&payment_url).unwrap())
-        ]]);
+        // Сообщение уже отправлено: ошибка записи не должна приводить к повторной отправке из очереди
+        if let Err(e) = self.record_order_confirmation(order_id, user_id, message.id).await {
+            eprintln!("Ошибка сохранения подтверждения заказа {}: {:?}", order_id, e);
+        }
+        Ok(())
+    }
 
-        self.bot
-            .send_message(
-                ChatId(user_id),
-                format!("💳 *Оплата заказа №{}*\n\nСумма: {:.2} TON\n\nНажмите кнопку ниже для оплаты:", order_id, order.total_amount)
-            )
-            .parse_mode(ParseMode::Markdown)
-            .reply_markup(keyboard)
-            .send()
+    async fn record_order_confirmation(&self, order_id: i64, user_id: i64, message_id: MessageId) -> Result<(), NotificationError> {
+        // Сохраняем message_id для дальнейшего использования
+        sqlx::query("UPDATE orders SET telegram_message_id = ? WHERE id = ?")
+            .bind(message_id.0)
+            .bind(order_id)
+            .execute(&self.db_pool)
             .await?;
 
+        // Открываем обращение по заказу: ответы на это сообщение попадут в него
+        let thread = support::open_thread(&self.db_pool, user_id, Some(order_id)).await?;
+        support::record_message(&self.db_pool, thread.id, &NewSupportMessage {
+            direction: MessageDirection::System,
+            sender_id: None,
+            text: Some("Подтверждение заказа"),
+            user_message_id: Some(message_id.0),
+            admin_message_id: None,
+            media: None,
+        }).await?;
+
         Ok(())
     }
 
@@ -131,84 +171,123 @@ impl TelegramNotifier {
         .fetch_one(&self.db_pool)
         .await?;
 
-        // Получаем товары заказа
-        let order_items = sqlx::query!(
-            "SELECT oi.quantity, p.name 
-             FROM order_items oi 
-             JOIN products p ON oi.product_id = p.id 
-             WHERE oi.order_id = ?",
-            order_id
+        // Получаем товары заказа (состав наборов идет сразу после строки набора)
+        let order_items = sqlx::query_as::<_, (Option<i64>, i32, String)>(
+            "SELECT oi.parent_item_id, oi.quantity, p.name
+             FROM order_items oi
+             JOIN products p ON oi.product_id = p.id
+             WHERE oi.order_id = ?
+             ORDER BY COALESCE(oi.parent_item_id, oi.id), oi.parent_item_id IS NOT NULL, oi.id"
         )
+        .bind(order_id)
         .fetch_all(&self.db_pool)
         .await?;
 
-        let mut message_text = format!(
-            "🚀 *Новый заказ* (ID: {})\n👤 *Покупатель*: {}\n📦 *Адрес*: {}\n🛒 *Состав заказа*:\n",
-            order.id,
-            username.map(|u| format!("@{}", u)).unwrap_or_else(|| format!("ID: {}", order.user_id)),
-            order.delivery_address.unwrap_or_else(|| "Не указан".to_string())
-        );
+        // Способ и стоимость доставки
+        let delivery = sqlx::query_as::<_, (String, f64, DeliveryKind)>(
+            "SELECT dm.name, o.delivery_cost, dm.kind
+             FROM orders o
+             JOIN delivery_methods dm ON dm.id = o.delivery_method_id
+             WHERE o.id = ?"
+        )
+        .bind(order_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
 
-        for item in order_items {
-            message_text.push_str(&format!("- {} (×{})\n", item.name, item.quantity));
+        let lines = order_items
+            .into_iter()
+            .map(|(parent_item_id, quantity, name)| OrderLine { name, quantity, component: parent_item_id.is_some() })
+            .collect::<Vec<_>>();
+        let delivery_line = delivery.as_ref().map(|(name, cost, _)| DeliveryLine {
+            name: name.clone(),
+            cost: *cost,
+            free: *cost <= 0.0,
+        });
+        let message_text = self.templates.render(None, &Notification::AdminNewOrder {
+            order_id: order.id,
+            user_id: order.user_id,
+            username,
+            address: order.delivery_address.as_deref(),
+            delivery: delivery_line.as_ref(),
+            lines: &lines,
+            total: order.total_amount,
+        })?;
+
+        // Заказ на самовывоз выдается по коду, вместо отправки - кнопка "Готов к выдаче"
+        let self_pickup = matches!(delivery, Some((_, _, DeliveryKind::SelfPickup)));
+        let keyboard = self.admin_callbacks.order_keyboard(order_id, "paid", self_pickup);
+
+        let thread = support::open_thread(&self.db_pool, order.user_id, Some(order_id)).await?;
+        let mut request = self.bot
+            .send_message(ChatId(self.admin_chat_id), message_text)
+            .parse_mode(ParseMode::Markdown)
+            .reply_markup(keyboard);
+        if let Some(topic) = self.admin_topic(&thread).await? {
+            request = request.message_thread_id(topic);
         }
+        let message = request.send().await?;
 
-        message_text.push_str(&format!("💰 *Оплачено*: {:.2} TON", order.total_amount));
-
-        let keyboard = InlineKeyboardMarkup::new(vec![vec![
-            InlineKeyboardButton::callback("Выполнено", format!("complete_{}", order_id))
-        ]]);
+        // Карточка уже отправлена: ошибка записи не должна приводить к повторной отправке из очереди
+        if let Err(e) = self.record_admin_card(order_id, thread.id, message.id).await {
+            eprintln!("Ошибка сохранения карточки заказа {}: {:?}", order_id, e);
+        }
+        Ok(message.id)
+    }
 
-        let message = self.bot
-            .send_message(ChatId(self.admin_chat_id), message_text)
-            .parse_mode(ParseMode::Markdown)
-            .reply_markup(keyboard)
-            .send()
+    async fn record_admin_card(&self, order_id: i64, thread_id: i64, message_id: MessageId) -> Result<(), NotificationError> {
+        // Заказ переводится в paid при зачете оплаты; здесь - только для старых вызовов.
+        // Уведомление из очереди может прийти позже, когда заказ уже собран: статус не откатываем
+        sqlx::query("UPDATE orders SET status = 'paid' WHERE id = ? AND status = 'pending'")
+            .bind(order_id)
+            .execute(&self.db_pool)
             .await?;
 
         // Сохраняем ID сообщения администраторов для связи с комментариями
-        sqlx::query!(
-            "UPDATE orders SET status = 'paid' WHERE id = ?",
-            order_id
-        )
-        .execute(&self.db_pool)
-        .await?;
+        support::record_message(&self.db_pool, thread_id, &NewSupportMessage {
+            direction: MessageDirection::System,
+            sender_id: None,
+            text: Some("Уведомление о новом заказе"),
+            user_message_id: None,
+            admin_message_id: Some(message_id.0),
+            media: None,
+        }).await?;
 
-        Ok(message.id)
+        Ok(())
     }
 
-    // 4. Обработка нажатия кнопки "Выполнено"
-    pub async fn handle_order_completion(&self, order_id: i64, message_id: MessageId) -> Result<(), NotificationError> {
-        // Получаем информацию о заказе
-        let order = sqlx::query!(
-            "SELECT user_id, dialog_active FROM orders WHERE id = ?",
-            order_id
-        )
-        .fetch_one(&self.db_pool)
-        .await?;
+    // 4. Заказ выполнен: кнопка "Выполнено" или выдача на стойке (message_id - карточка заказа в чате администраторов).
+    // Статус уже переведен в completed вызывающим
+    pub async fn handle_order_completion(&self, order_id: i64, message_id: Option<MessageId>) -> Result<(), NotificationError> {
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(&self.db_pool)
+            .await?;
 
-        // Обновляем статус заказа и закрываем диалог
-        sqlx::query!(
-            "UPDATE orders SET status = 'completed', dialog_active = FALSE WHERE id = ?",
-            order_id
-        )
-        .execute(&self.db_pool)
-        .await?;
+        // Закрываем обращения по заказу
+        self.close_order_threads(order_id).await?;
 
-        // Редактируем сообщение в канале администраторов - убираем кнопку
-        if let Err(e) = self.bot
-            .edit_message_reply_markup(ChatId(self.admin_chat_id), message_id)
-            .reply_markup(InlineKeyboardMarkup::new(Vec::<Vec<InlineKeyboardButton>>::new()))
-            .send()
-            .await {
-            eprintln!("Ошибка редактирования сообщения: {:?}", e);
+        // Обновляем кнопки карточки заказа в канале администраторов
+        if let Some(message_id) = message_id {
+            self.refresh_admin_card(message_id, order_id).await;
         }
 
+        // Начисляем бонусные баллы за заказ
+        let points = match self.loyalty.accrue_for_order(order_id).await {
+            Ok(points) => points,
+            Err(e) => {
+                eprintln!("Ошибка начисления баллов за заказ {}: {:?}", order_id, e);
+                0
+            }
+        };
+
+        let language = self.language(user_id).await;
+        let completion_text = self.templates.render(language.as_deref(), &Notification::OrderCompleted { order_id, points })?;
+
         // Отправляем уведомление пользователю
         self.bot
             .send_message(
-                ChatId(order.user_id),
-                "✅ *Ваш заказ выполнен!*\nСпасибо за покупку! Диалог закрыт."
+                ChatId(user_id),
+                completion_text
             )
             .parse_mode(ParseMode::Markdown)
             .send()
@@ -217,65 +296,372 @@ impl TelegramNotifier {
         Ok(())
     }
 
-    // 5. Пересылка комментария администратора пользователю
-    pub async fn forward_admin_comment_to_user(&self, order_id: i64, comment_text: &str) -> Result<(), NotificationError> {
-        let order = sqlx::query!(
-            "SELECT user_id, dialog_active FROM orders WHERE id = ?",
-            order_id
-        )
-        .fetch_one(&self.db_pool)
-        .await?;
+    // 5. Пересылка ответа администратора пользователю (текст или вложение)
+    pub async fn forward_admin_comment_to_user(
+        &self,
+        thread: &SupportThread,
+        admin_message_id: MessageId,
+        admin_id: i64,
+        comment_text: Option<&str>,
+        media: Option<&SupportMedia>,
+    ) -> Result<(), NotificationError> {
+
+        let language = self.language(thread.user_id).await;
+        let header = self.templates.render(language.as_deref(), &Notification::SupportReply { order_id: thread.order_id })?;
+
+        let user_message_id = match media {
+            Some(_) => {
+                let (header_id, copy_id) = self
+                    .copy_with_header(ChatId(thread.user_id), ChatId(self.admin_chat_id), admin_message_id, header, None)
+                    .await?;
+                support::record_message(&self.db_pool, thread.id, &NewSupportMessage {
+                    direction: MessageDirection::System,
+                    sender_id: None,
+                    text: None,
+                    user_message_id: Some(header_id.0),
+                    admin_message_id: None,
+                    media: None,
+                }).await?;
+                copy_id
+            }
+            None => {
+                self.bot
+                    .send_message(
+                        ChatId(thread.user_id),
+                        format!("{}:\n{}", header, escape_markdown(comment_text.unwrap_or_default()))
+                    )
+                    .parse_mode(ParseMode::Markdown)
+                    .send()
+                    .await?
+                    .id
+            }
+        };
+
+        support::record_message(&self.db_pool, thread.id, &NewSupportMessage {
+            direction: MessageDirection::Admin,
+            sender_id: Some(admin_id),
+            text: comment_text,
+            user_message_id: Some(user_message_id.0),
+            admin_message_id: Some(admin_message_id.0),
+            media,
+        }).await?;
+
+        Ok(())
+    }
 
-        // Проверяем, активен ли диалог
-        if !order.dialog_active.unwrap_or(false) {
-            return Ok(()); // Диалог закрыт, не пересылаем
+    // 6. Пересылка сообщения пользователя (текст или вложение) в чат администраторов
+    pub async fn forward_user_message_to_admin(
+        &self,
+        user_id: i64,
+        user_message_id: MessageId,
+        reply_to_message_id: Option<MessageId>,
+        message_text: Option<&str>,
+        media: Option<&SupportMedia>,
+    ) -> Result<SupportThread, NotificationError> {
+        let thread = support::resolve_user_thread(
+            &self.db_pool,
+            user_id,
+            reply_to_message_id.map(|id| id.0),
+        ).await?;
+
+        let header = self.templates.render(None, &Notification::AdminUserMessage {
+            user_id,
+            thread_id: thread.id,
+            order_id: thread.order_id,
+        })?;
+        let topic = self.admin_topic(&thread).await?;
+
+        let admin_message_id = match media {
+            Some(_) => {
+                let (header_id, copy_id) = self
+                    .copy_with_header(ChatId(self.admin_chat_id), ChatId(user_id), user_message_id, header, topic)
+                    .await?;
+                // Ответ администратора на заголовок тоже попадет в это обращение
+                support::record_message(&self.db_pool, thread.id, &NewSupportMessage {
+                    direction: MessageDirection::System,
+                    sender_id: None,
+                    text: None,
+                    user_message_id: None,
+                    admin_message_id: Some(header_id.0),
+                    media: None,
+                }).await?;
+                copy_id
+            }
+            None => {
+                let mut request = self.bot
+                    .send_message(
+                        ChatId(self.admin_chat_id),
+                        format!("{}:\n{}", header, escape_markdown(message_text.unwrap_or_default()))
+                    )
+                    .parse_mode(ParseMode::Markdown);
+                if let Some(topic) = topic {
+                    request = request.message_thread_id(topic);
+                }
+                request.send().await?.id
+            }
+        };
+
+        support::record_message(&self.db_pool, thread.id, &NewSupportMessage {
+            direction: MessageDirection::User,
+            sender_id: Some(user_id),
+            text: message_text,
+            user_message_id: Some(user_message_id.0),
+            admin_message_id: Some(admin_message_id.0),
+            media,
+        }).await?;
+
+        Ok(thread)
+    }
+
+    // 7. Копия вложения в другой чат: сначала заголовок, затем само сообщение ответом на него.
+    // Подпись вложения сохраняется. Возвращает ID заголовка и копии.
+    async fn copy_with_header(
+        &self,
+        to: ChatId,
+        from: ChatId,
+        message_id: MessageId,
+        header: String,
+        topic: Option<ThreadId>,
+    ) -> Result<(MessageId, MessageId), NotificationError> {
+        let mut request = self.bot
+            .send_message(to, header)
+            .parse_mode(ParseMode::Markdown);
+        if let Some(topic) = topic {
+            request = request.message_thread_id(topic);
         }
+        let header = request.send().await?;
+
+        let copy = self.bot
+            .copy_message(to, from, message_id)
+            .reply_parameters(ReplyParameters::new(header.id))
+            .send()
+            .await?;
+
+        Ok((header.id, copy))
+    }
+
+    // Тема форума для обращения в чате администраторов (если темы включены).
+    // Создается при первом сообщении; повторно открытое обращение возвращается в прежнюю тему.
+    async fn admin_topic(&self, thread: &SupportThread) -> Result<Option<ThreadId>, NotificationError> {
+        if !self.forum_topics {
+            return Ok(None);
+        }
+        if let Some(topic_id) = thread.admin_topic_id {
+            return Ok(Some(ThreadId(MessageId(topic_id))));
+        }
+
+        let topic = match support::previous_admin_topic(&self.db_pool, thread.user_id, thread.order_id).await? {
+            Some(topic_id) => {
+                let topic = ThreadId(MessageId(topic_id));
+                if let Err(e) = self.bot.reopen_forum_topic(ChatId(self.admin_chat_id), topic).send().await {
+                    eprintln!("Ошибка повторного открытия темы {}: {:?}", topic_id, e);
+                }
+                topic
+            }
+            None => {
+                let name = self.templates.render(None, &Notification::AdminTopic {
+                    user_id: thread.user_id,
+                    order_id: thread.order_id,
+                })?;
+                self.bot
+                    .create_forum_topic(ChatId(self.admin_chat_id), name)
+                    .send()
+                    .await?
+                    .thread_id
+            }
+        };
+
+        support::set_admin_topic(&self.db_pool, thread.id, topic.0 .0).await?;
+        Ok(Some(topic))
+    }
+
+    // Закрытие обращения вместе с его темой в чате администраторов
+    pub async fn close_support_thread(&self, thread: &SupportThread) -> Result<(), NotificationError> {
+        support::close_thread(&self.db_pool, thread.id).await?;
+        self.close_admin_topic(thread).await;
+        Ok(())
+    }
+
+    pub async fn close_order_threads(&self, order_id: i64) -> Result<(), NotificationError> {
+        for thread in support::close_order_threads(&self.db_pool, order_id).await? {
+            self.close_admin_topic(&thread).await;
+        }
+        Ok(())
+    }
+
+    async fn close_admin_topic(&self, thread: &SupportThread) {
+        let Some(topic_id) = thread.admin_topic_id else {
+            return;
+        };
+        if let Err(e) = self.bot
+            .close_forum_topic(ChatId(self.admin_chat_id), ThreadId(MessageId(topic_id)))
+            .send()
+            .await {
+            eprintln!("Ошибка закрытия темы {}: {:?}", topic_id, e);
+        }
+    }
+
+    // 9. Сообщение с управлением подпиской (пауза, пропуск, отмена)
+    async fn subscription_message(
+        &self,
+        subscription: &Subscription,
+        notice: Option<&str>,
+    ) -> Result<(String, InlineKeyboardMarkup), NotificationError> {
+        let language = self.language(subscription.user_id).await;
+        let language = language.as_deref();
+        let text = self.templates.render(language, &Notification::Subscription {
+            id: subscription.id,
+            interval_days: subscription.interval_days,
+            status: subscription.status,
+            next_run_at: subscription.next_run_at.and_utc().timestamp(),
+            notice,
+        })?;
+
+        let button = |label: Label, action: SubscriptionAction| -> Result<InlineKeyboardButton, TemplateError> {
+            Ok(InlineKeyboardButton::callback(
+                self.templates.label(language, label)?,
+                action.callback_data(subscription.id),
+            ))
+        };
+        let rows = match subscription.status {
+            SubscriptionStatus::Active => vec![
+                vec![
+                    button(Label::SubscriptionSkip, SubscriptionAction::Skip)?,
+                    button(Label::SubscriptionPause, SubscriptionAction::Pause)?,
+                ],
+                vec![button(Label::SubscriptionCancel, SubscriptionAction::Cancel)?],
+            ],
+            SubscriptionStatus::Paused => vec![
+                vec![button(Label::SubscriptionResume, SubscriptionAction::Resume)?],
+                vec![button(Label::SubscriptionCancel, SubscriptionAction::Cancel)?],
+            ],
+            SubscriptionStatus::Cancelled => Vec::new(),
+        };
+
+        Ok((text, InlineKeyboardMarkup::new(rows)))
+    }
+
+    pub async fn send_subscription_controls(&self, subscription: &Subscription, notice: Option<&str>) -> Result<(), NotificationError> {
+        let (text, keyboard) = self.subscription_message(subscription, notice).await?;
 
         self.bot
-            .send_message(
-                ChatId(order.user_id),
-                format!("📢 *Ответ от поддержки*:\n{}", comment_text)
-            )
+            .send_message(ChatId(subscription.user_id), text)
             .parse_mode(ParseMode::Markdown)
+            .reply_markup(keyboard)
             .send()
             .await?;
 
         Ok(())
     }
 
-    // 6. Пересылка сообщения пользователя как комментарий в канал
-    pub async fn forward_user_message_to_admin(&self, user_id: i64, message_text: &str) -> Result<(), NotificationError> {
-        // Находим активный заказ пользователя
-        let order = sqlx::query!(
-            "SELECT id FROM orders WHERE user_id = ? AND dialog_active = TRUE ORDER BY created_at DESC LIMIT 1",
-            user_id
-        )
-        .fetch_optional(&self.db_pool)
-        .await?;
+    // Обновляет сообщение с кнопками после действия пользователя
+    pub async fn update_subscription_controls(&self, chat_id: ChatId, message_id: MessageId, subscription: &Subscription) -> Result<(), NotificationError> {
+        let (text, keyboard) = self.subscription_message(subscription, None).await?;
+
+        self.bot
+            .edit_message_text(chat_id, message_id, text)
+            .parse_mode(ParseMode::Markdown)
+            .reply_markup(keyboard)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    // 10. Трек-номер отправления с кнопкой отслеживания
+    pub async fn send_tracking_info(
+        &self,
+        user_id: i64,
+        order_id: i64,
+        carrier_name: &str,
+        tracking_number: &str,
+        tracking_url: &str,
+    ) -> Result<(), NotificationError> {
+        let language = self.language(user_id).await;
+        let text = self.templates.render(language.as_deref(), &Notification::TrackingInfo {
+            order_id,
+            carrier: carrier_name,
+            tracking_number,
+        })?;
+
+        let mut request = self.bot
+            .send_message(ChatId(user_id), text)
+            .parse_mode(ParseMode::Markdown);
+
+        if let Ok(url) = Url::parse(tracking_url) {
+            request = request.reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::url(self.templates.label(language.as_deref(), Label::TrackParcel)?, url)
+            ]]));
+        }
+
+        request.send().await?;
+        Ok(())
+    }
 
-        if let Some(order) = order {
-            // Отправляем сообщение как комментарий в канал администраторов
-            self.bot
-                .send_message(
-                    ChatId(self.admin_chat_id),
-                    format!("💬 *Сообщение от пользователя* (Заказ #{}):\n{}", order.id, message_text)
-                )
-                .parse_mode(ParseMode::Markdown)
-                .send()
-                .await?;
+    // 11. Изменение статуса отправления
+    pub async fn send_shipment_status(
+        &self,
+        user_id: i64,
+        order_id: i64,
+        status: ShipmentStatus,
+        description: Option<&str>,
+        tracking_url: &str,
+    ) -> Result<(), NotificationError> {
+        let language = self.language(user_id).await;
+        let text = self.templates.render(language.as_deref(), &Notification::ShipmentStatus {
+            order_id,
+            status,
+            description,
+        })?;
+
+        let mut request = self.bot
+            .send_message(ChatId(user_id), text)
+            .parse_mode(ParseMode::Markdown);
+
+        if let Ok(url) = Url::parse(tracking_url) {
+            request = request.reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::url(self.templates.label(language.as_deref(), Label::TrackParcel)?, url)
+            ]]));
         }
 
+        request.send().await?;
+        Ok(())
+    }
+
+    // 12. Код и QR для самовывоза
+    pub async fn send_pickup_code(&self, user_id: i64, order_id: i64, code: &str, qr_png: Vec<u8>) -> Result<(), NotificationError> {
+        let language = self.language(user_id).await;
+        let caption = self.templates.render(language.as_deref(), &Notification::PickupCode { order_id, code })?;
+
+        self.bot
+            .send_photo(ChatId(user_id), InputFile::memory(qr_png).file_name("pickup.png"))
+            .caption(caption)
+            .parse_mode(ParseMode::Markdown)
+            .send()
+            .await?;
+
         Ok(())
     }
 
-    // 8. Инициализация диалога с пользователем (для случаев ChatNotFound)
-    pub async fn initialize_user_dialog(&self, user_id: i64) -> Result<(), NotificationError> {
-        // Отправляем приветственное сообщение для инициализации диалога
+    // 13. Отмена заказа: закрытие обращений, уведомление покупателя.
+    // Остатки и баллы возвращаются в транзакции отмены
+    pub async fn handle_order_cancellation(&self, order_id: i64, message_id: Option<MessageId>) -> Result<(), NotificationError> {
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        self.close_order_threads(order_id).await?;
+
+        if let Some(message_id) = message_id {
+            self.refresh_admin_card(message_id, order_id).await;
+        }
+
+        let language = self.language(user_id).await;
+        let text = self.templates.render(language.as_deref(), &Notification::OrderCancelled { order_id })?;
+
         self.bot
-            .send_message(
-                ChatId(user_id),
-                "👋 Добро пожаловать в SportShop!\n\nВаш заказ обрабатывается..."
-            )
+            .send_message(ChatId(user_id), text)
             .parse_mode(ParseMode::Markdown)
             .send()
             .await?;
@@ -283,16 +669,116 @@ impl TelegramNotifier {
         Ok(())
     }
 
-    // 7. Проверка активности диалога
-    pub async fn is_dialog_active(&self, user_id: i64) -> Result<bool, NotificationError> {
-        let result = sqlx::query!(
-            "SELECT COUNT(*) as count FROM orders WHERE user_id = ? AND dialog_active = TRUE",
-            user_id
+    // Доставка уведомления из очереди
+    pub async fn deliver(&self, message: &OutboxMessage) -> Result<(), NotificationError> {
+        match message {
+            OutboxMessage::OrderConfirmation { order_id, user_id, text } => {
+                self.deliver_order_confirmation(*order_id, *user_id, text).await
+            }
+            OutboxMessage::AdminNewOrder { order_id, username } => {
+                self.notify_admin_new_order(*order_id, username.as_deref()).await.map(|_| ())
+            }
+            OutboxMessage::OrderStatus { order_id, status } => self.send_order_status_update(*order_id, status).await,
+            OutboxMessage::PaymentRequest { order_id, user_id, provider } => {
+                self.send_payment_request(*order_id, *user_id, provider.as_deref()).await
+            }
+        }
+    }
+
+    // Просьба доплатить остаток с кнопкой того же способа оплаты (если он еще доступен - иначе всех способов).
+    // Сумма перечитывается при отправке: заказ могли доплатить или отменить, пока сообщение ждало в очереди
+    async fn send_payment_request(&self, order_id: i64, user_id: i64, provider: Option<&str>) -> Result<(), NotificationError> {
+        let order = sqlx::query_as::<_, (f64, String, f64)>(
+            r#"
+            SELECT o.total_amount, o.status, COALESCE((
+                SELECT SUM(p.credited_amount) FROM payments p
+                WHERE p.order_id = CAST(o.id AS TEXT) AND p.status = 'confirmed'
+            ), 0.0)
+            FROM orders o WHERE o.id = ?
+            "#
         )
+        .bind(order_id)
         .fetch_one(&self.db_pool)
         .await?;
+        let (total, status, paid) = order;
+        let remaining = total - paid;
+        if status != "pending" || remaining <= PAYMENT_TOLERANCE {
+            return Ok(());
+        }
+
+        let language = self.language(user_id).await;
+        let text = self.templates.render(language.as_deref(), &Notification::PaymentTopUp {
+            order_id,
+            paid,
+            total,
+            remaining,
+        })?;
+
+        let same_method = self.payment_methods.iter().any(|(code, _)| Some(code.as_str()) == provider);
+        let keyboard = InlineKeyboardMarkup::new(
+            self.payment_methods
+                .iter()
+                .filter(|(code, _)| !same_method || Some(code.as_str()) == provider)
+                .map(|(code, title)| {
+                    vec![InlineKeyboardButton::callback(title.clone(), format!("pay_{}_{}", code, order_id))]
+                }),
+        );
+
+        self.bot
+            .send_message(ChatId(user_id), text)
+            .parse_mode(ParseMode::Markdown)
+            .reply_markup(keyboard)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    // 14. Изменение статуса заказа (собран, отправлен)
+    pub async fn send_order_status_update(&self, order_id: i64, status: &str) -> Result<(), NotificationError> {
+        let notification = match status {
+            "packed" => Notification::OrderPacked { order_id },
+            "shipped" => Notification::OrderShipped { order_id },
+            _ => return Ok(()),
+        };
+
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(&self.db_pool)
+            .await?;
+        let language = self.language(user_id).await;
+        let text = self.templates.render(language.as_deref(), &notification)?;
+
+        self.bot
+            .send_message(ChatId(user_id), text)
+            .parse_mode(ParseMode::Markdown)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    // Кнопки карточки заказа в соответствии с текущим статусом
+    pub async fn refresh_admin_card(&self, message_id: MessageId, order_id: i64) {
+        let keyboard = match admin_actions::order_state(&self.db_pool, order_id).await {
+            Ok((status, self_pickup)) => self.admin_callbacks.order_keyboard(order_id, &status, self_pickup),
+            Err(e) => {
+                eprintln!("Ошибка получения статуса заказа {}: {:?}", order_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.bot
+            .edit_message_reply_markup(ChatId(self.admin_chat_id), message_id)
+            .reply_markup(keyboard)
+            .send()
+            .await {
+            eprintln!("Ошибка редактирования сообщения: {:?}", e);
+        }
+    }
 
-        Ok(result.count > 0)
+    pub fn admin_chat_id(&self) -> ChatId {
+        ChatId(self.admin_chat_id)
     }
 
     // Остальные методы для совместимости...
@@ -0,0 +1,327 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+// Минимальная поддержка Bag of Cells: разбор тел входящих сообщений, хэш ячеек StateInit
+// и сборка комментария
+
+const BOC_MAGIC: [u8; 4] = [0xb5, 0xee, 0x9c, 0x72];
+
+#[derive(Debug, Clone)]
+struct RawCell {
+    // Данные вместе с битами дополнения, как в сериализации
+    data: Vec<u8>,
+    bit_len: usize,
+    refs: Vec<usize>,
+    // Обычная ячейка: не exotic и нулевой уровень
+    ordinary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Boc {
+    cells: Vec<RawCell>,
+    root: usize,
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(slice)
+    }
+
+    fn uint(&mut self, len: usize) -> Option<usize> {
+        Some(self.take(len)?.iter().fold(0, |acc, byte| (acc << 8) | *byte as usize))
+    }
+}
+
+impl Boc {
+    pub fn from_base64(encoded: &str) -> Option<Self> {
+        Self::parse(&STANDARD.decode(encoded).ok()?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut reader = ByteReader { bytes, pos: 0 };
+        if reader.take(4)? != BOC_MAGIC {
+            return None;
+        }
+
+        let flags = reader.uint(1)?;
+        let has_index = flags & 0x80 != 0;
+        let ref_size = flags & 0x07;
+        let offset_size = reader.uint(1)?;
+        let cell_count = reader.uint(ref_size)?;
+        let root_count = reader.uint(ref_size)?;
+        let _absent = reader.uint(ref_size)?;
+        let _total_size = reader.uint(offset_size)?;
+        if root_count == 0 {
+            return None;
+        }
+        let root = reader.uint(ref_size)?;
+        reader.take((root_count - 1) * ref_size)?;
+        if has_index {
+            reader.take(cell_count * offset_size)?;
+        }
+
+        let mut cells = Vec::with_capacity(cell_count);
+        for _ in 0..cell_count {
+            let d1 = reader.uint(1)?;
+            let d2 = reader.uint(1)?;
+            let data = reader.take(d2.div_ceil(2))?.to_vec();
+            // Нечетный d2: последний байт неполный, биты дополнены единицей и нулями
+            let bit_len = if d2 % 2 == 0 {
+                data.len() * 8
+            } else {
+                let last = *data.last()?;
+                if last == 0 {
+                    return None;
+                }
+                data.len() * 8 - last.trailing_zeros() as usize - 1
+            };
+            let refs = (0..d1 & 0x07)
+                .map(|_| reader.uint(ref_size))
+                .collect::<Option<Vec<_>>>()?;
+            if refs.iter().any(|index| *index >= cell_count) {
+                return None;
+            }
+            cells.push(RawCell { data, bit_len, refs, ordinary: d1 & 0xe8 == 0 });
+        }
+
+        if root >= cells.len() {
+            return None;
+        }
+        Some(Self { cells, root })
+    }
+
+    pub fn root(&self) -> CellSlice<'_> {
+        CellSlice::new(self, self.root)
+    }
+
+    // Хэш представления ячейки и ее глубина. Поддерживаются только обычные ячейки,
+    // ссылки должны вести к ячейкам с большим индексом (как в любой корректной сериализации)
+    fn cell_hash(&self, index: usize) -> Option<([u8; 32], u16)> {
+        let cell = &self.cells[index];
+        if !cell.ordinary || cell.refs.iter().any(|child| *child <= index) {
+            return None;
+        }
+        let children = cell.refs
+            .iter()
+            .map(|child| self.cell_hash(*child))
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut repr = Vec::with_capacity(2 + cell.data.len() + children.len() * 34);
+        repr.push(cell.refs.len() as u8);
+        repr.push((cell.bit_len / 8 + cell.bit_len.div_ceil(8)) as u8);
+        repr.extend_from_slice(&cell.data);
+        for (_, depth) in &children {
+            repr.extend_from_slice(&depth.to_be_bytes());
+        }
+        for (hash, _) in &children {
+            repr.extend_from_slice(hash);
+        }
+
+        let depth = children.iter().map(|(_, depth)| depth + 1).max().unwrap_or(0);
+        Some((Sha256::digest(&repr).into(), depth))
+    }
+}
+
+// Последовательное чтение битов ячейки
+#[derive(Debug, Clone, Copy)]
+pub struct CellSlice<'a> {
+    boc: &'a Boc,
+    cell: usize,
+    bit_pos: usize,
+    ref_pos: usize,
+}
+
+impl<'a> CellSlice<'a> {
+    fn new(boc: &'a Boc, cell: usize) -> Self {
+        Self { boc, cell, bit_pos: 0, ref_pos: 0 }
+    }
+
+    fn raw(&self) -> &'a RawCell {
+        &self.boc.cells[self.cell]
+    }
+
+    // Хэш всей ячейки (независимо от прочитанной части) - для StateInit совпадает с адресом контракта
+    pub fn hash(&self) -> Option<[u8; 32]> {
+        self.boc.cell_hash(self.cell).map(|(hash, _)| hash)
+    }
+
+    pub fn remaining_bits(&self) -> usize {
+        self.raw().bit_len - self.bit_pos
+    }
+
+    pub fn load_bit(&mut self) -> Option<bool> {
+        if self.remaining_bits() == 0 {
+            return None;
+        }
+        let byte = self.raw().data[self.bit_pos / 8];
+        let bit = byte & (0x80 >> (self.bit_pos % 8)) != 0;
+        self.bit_pos += 1;
+        Some(bit)
+    }
+
+    pub fn load_uint(&mut self, bits: usize) -> Option<u128> {
+        if bits > 128 || self.remaining_bits() < bits {
+            return None;
+        }
+        let mut value = 0u128;
+        for _ in 0..bits {
+            value = (value << 1) | self.load_bit()? as u128;
+        }
+        Some(value)
+    }
+
+    pub fn skip_bits(&mut self, bits: usize) -> Option<()> {
+        if self.remaining_bits() < bits {
+            return None;
+        }
+        self.bit_pos += bits;
+        Some(())
+    }
+
+    pub fn load_ref(&mut self) -> Option<CellSlice<'a>> {
+        let index = *self.raw().refs.get(self.ref_pos)?;
+        self.ref_pos += 1;
+        Some(CellSlice::new(self.boc, index))
+    }
+
+    // Coins (VarUInteger 16): длина в байтах и само число
+    pub fn load_coins(&mut self) -> Option<u128> {
+        let len = self.load_uint(4)? as usize;
+        self.load_uint(len * 8)
+    }
+
+    // MsgAddress: Some((workchain, hash)) для обычного адреса, None для addr_none
+    pub fn load_address(&mut self) -> Option<Option<(i32, [u8; 32])>> {
+        match self.load_uint(2)? {
+            0b00 => Some(None),
+            0b10 => {
+                // anycast давно не используется, но пропускаем его корректно
+                if self.load_bit()? {
+                    let depth = self.load_uint(5)? as usize;
+                    self.skip_bits(depth)?;
+                }
+                let workchain = self.load_uint(8)? as u8 as i8 as i32;
+                let mut hash = [0u8; 32];
+                for byte in hash.iter_mut() {
+                    *byte = self.load_uint(8)? as u8;
+                }
+                Some(Some((workchain, hash)))
+            }
+            _ => None,
+        }
+    }
+
+    // Either X ^X: данные в этой же ячейке или по ссылке
+    pub fn load_either_ref(&mut self) -> Option<CellSlice<'a>> {
+        if self.load_bit()? {
+            self.load_ref()
+        } else {
+            Some(*self)
+        }
+    }
+
+    // Текстовый комментарий: op-код 0 и UTF-8 текст, продолжение - в цепочке ссылок
+    pub fn load_comment(mut self) -> Option<String> {
+        if self.load_uint(32)? != 0 {
+            return None;
+        }
+        let mut bytes = Vec::new();
+        let mut slice = self;
+        loop {
+            while slice.remaining_bits() >= 8 {
+                bytes.push(slice.load_uint(8)? as u8);
+            }
+            match slice.load_ref() {
+                Some(next) => slice = next,
+                None => break,
+            }
+        }
+        String::from_utf8(bytes).ok()
+    }
+}
+
+// BoC из одной ячейки: op-код 0 (текстовый комментарий) и текст. Комментарий не длиннее 123 байт
+pub fn comment_payload(comment: &str) -> String {
+    let mut data = vec![0u8; 4];
+    data.extend_from_slice(comment.as_bytes());
+    debug_assert!(data.len() <= 127, "comment does not fit into a single cell");
+
+    let mut boc = BOC_MAGIC.to_vec();
+    boc.extend_from_slice(&[
+        0x01,                   // без индекса и crc, ссылки на ячейки - 1 байт
+        0x01,                   // смещения - 1 байт
+        0x01,                   // ячеек
+        0x01,                   // корней
+        0x00,                   // отсутствующих ячеек
+        (data.len() + 2) as u8, // размер всех ячеек
+        0x00,                   // индекс корня
+        0x00,                   // d1: без ссылок, обычная ячейка
+        (data.len() * 2) as u8, // d2: данные кратны байту
+    ]);
+    boc.extend(data);
+    STANDARD.encode(boc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BoC посчитаны отдельно (Python, сериализация и хэш ячеек по спецификации TON)
+
+    #[test]
+    fn builds_comment_payload() {
+        assert_eq!(comment_payload("Order_42"), "te6ccgEBAQEADgAAGAAAAABPcmRlcl80Mg==");
+    }
+
+    #[test]
+    fn reads_comment() {
+        let boc = Boc::from_base64("te6ccgEBAQEADgAAGAAAAABPcmRlcl80Mg==").unwrap();
+        assert_eq!(boc.root().load_comment().as_deref(), Some("Order_42"));
+    }
+
+    #[test]
+    fn reads_comment_continued_in_refs() {
+        let boc = Boc::from_base64(
+            "te6ccgEBAgEALQABHAAAAADQl9Cw0LrQsNC3AQA0INC4INC/0YDQvtC00L7Qu9C20LXQvdC40LU="
+        ).unwrap();
+        assert_eq!(boc.root().load_comment().as_deref(), Some("Заказ и продолжение"));
+    }
+
+    #[test]
+    fn reads_cell_with_incomplete_last_byte() {
+        // transfer_notification: 32 + 64 + 36 + 267 + 1 + 96 = 496 бит, данные дополнены до байта
+        let boc = Boc::from_base64(
+            "te6ccgEBAQEAQAAAfHNi0JwAAAAAAAAAB0AX14QIAAACBAYICgwOEBIUFhgaHB4gIiQmKCosLjAyNDY4Ojw+AAAAAE9yZGVyXzQy"
+        ).unwrap();
+        let root = boc.root();
+        assert_eq!(root.remaining_bits(), 32 + 64 + 36 + 267 + 1 + 96);
+        assert_eq!(
+            root.hash().map(hex::encode).as_deref(),
+            Some("5729a616486fbef6389c3dbcf7e932a13932cc13c732185cfb57e4bc8cd9c603")
+        );
+    }
+
+    #[test]
+    fn hashes_empty_cell() {
+        let boc = Boc::parse(&[0xb5, 0xee, 0x9c, 0x72, 0x01, 0x01, 0x01, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(
+            boc.root().hash().map(hex::encode).as_deref(),
+            Some("96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_boc() {
+        assert!(Boc::from_base64("AAAAAA==").is_none());
+        // Обрезанные данные ячейки
+        assert!(Boc::parse(&[0xb5, 0xee, 0x9c, 0x72, 0x01, 0x01, 0x01, 0x01, 0x00, 0x04, 0x00, 0x00, 0x08]).is_none());
+    }
+}
@@ -0,0 +1,518 @@
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+use thiserror::Error;
+use crate::payments::{self, order_comment, NewPaymentRecord, PaymentError, PaymentService, Settlement};
+use crate::ton_boc::{comment_payload, Boc, CellSlice};
+use crate::toncenter::{ToncenterClient, ToncenterError};
+
+// Payload для ton_proof действует 15 минут
+const PAYLOAD_TTL_MINUTES: i64 = 15;
+// Подпись кошелька старше 15 минут не принимаем
+const PROOF_MAX_AGE_SECS: i64 = 15 * 60;
+// Сколько WebApp ждет подтверждения перевода в кошельке
+const TRANSFER_TTL_SECS: i64 = 10 * 60;
+// Сколько последних транзакций кошелька магазина просматривать при поиске оплаты
+const TRANSACTIONS_LIMIT: u32 = 50;
+
+const TON_PROOF_PREFIX: &[u8] = b"ton-proof-item-v2/";
+const TON_CONNECT_PREFIX: &[u8] = b"ton-connect";
+
+#[derive(Error, Debug)]
+pub enum TonConnectError {
+    #[error("Database error: {0}")]
+    DbError(#[from] sqlx::Error),
+    #[error("{0}")]
+    ApiError(#[from] ToncenterError),
+    #[error("Некорректный адрес кошелька")]
+    InvalidAddress,
+    #[error("Payload устарел или уже использован, подключите кошелек заново")]
+    PayloadExpired,
+    #[error("Подпись кошелька не прошла проверку: {0}")]
+    InvalidProof(&'static str),
+    #[error("Кошелек не подключен")]
+    WalletNotLinked,
+    #[error("Заказ не найден")]
+    OrderNotFound,
+    #[error("Заказ уже оплачен или отменен")]
+    OrderNotPayable,
+    #[error("Payment error: {0}")]
+    PaymentError(#[from] PaymentError),
+}
+
+impl TonConnectError {
+    // Ошибки, которые можно показать пользователю как есть
+    pub fn is_user_error(&self) -> bool {
+        !matches!(
+            self,
+            TonConnectError::DbError(_) | TonConnectError::ApiError(_) | TonConnectError::PaymentError(_)
+        )
+    }
+}
+
+// Подтверждение владения кошельком из TON Connect (account + connectItems.tonProof.proof)
+#[derive(Debug, Deserialize)]
+pub struct TonProofRequest {
+    pub address: String,
+    pub public_key: String,
+    pub proof: TonProof,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TonProof {
+    pub timestamp: i64,
+    pub domain: TonProofDomain,
+    pub signature: String,
+    pub payload: String,
+    // wallet.account.walletStateInit: по нему проверяется ключ кошелька, еще не развернутого в сети
+    #[serde(default)]
+    pub state_init: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TonProofDomain {
+    pub length_bytes: u32,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct LinkedWallet {
+    pub address: String,
+    pub public_key: String,
+}
+
+// Запрос для tonConnectUI.sendTransaction
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TonConnectTransaction {
+    pub valid_until: i64,
+    pub from: String,
+    pub messages: Vec<TonConnectMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TonConnectMessage {
+    pub address: String,
+    // Сумма в нанотонах строкой, как требует TON Connect
+    pub amount: String,
+    // BoC ячейки с текстовым комментарием в base64
+    pub payload: String,
+}
+
+// Адрес TON: raw (0:abcd...) или user-friendly (EQ.../UQ...), приводим к (workchain, hash)
+pub fn parse_address(address: &str) -> Option<(i32, [u8; 32])> {
+    if let Some((workchain, hash)) = address.split_once(':') {
+        let workchain = workchain.parse::<i32>().ok()?;
+        let hash: [u8; 32] = hex::decode(hash).ok()?.try_into().ok()?;
+        return Some((workchain, hash));
+    }
+
+    let trimmed = address.trim_end_matches('=');
+    let bytes = URL_SAFE_NO_PAD
+        .decode(trimmed)
+        .or_else(|_| STANDARD_NO_PAD.decode(trimmed))
+        .ok()?;
+    // tag (1) + workchain (1) + hash (32) + crc16 (2)
+    if bytes.len() != 36 || crc16(&bytes[..34]).to_be_bytes() != bytes[34..] {
+        return None;
+    }
+    Some((bytes[1] as i8 as i32, bytes[2..34].try_into().ok()?))
+}
+
+pub fn raw_address(workchain: i32, hash: &[u8; 32]) -> String {
+    format!("{}:{}", workchain, hex::encode(hash))
+}
+
+// CRC16-XMODEM для контрольной суммы user-friendly адреса
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Публичный ключ из начальных данных стандартного кошелька. StateInit должен давать адрес кошелька,
+// иначе ключ мог бы взяться из чужого контракта. Ok(None) - данные неизвестного кошелька
+fn state_init_public_key(state_init: &str, hash: &[u8; 32]) -> Result<Option<[u8; 32]>, TonConnectError> {
+    let boc = Boc::from_base64(state_init).ok_or(TonConnectError::InvalidProof("некорректный state_init"))?;
+    let root = boc.root();
+    if root.hash().as_ref() != Some(hash) {
+        return Err(TonConnectError::InvalidProof("state_init не соответствует адресу"));
+    }
+
+    let Some(mut data) = state_init_data(root) else {
+        return Ok(None);
+    };
+
+    // Смещение ключа по размеру данных: v2 - seqno; v3 - seqno, subwallet;
+    // v4 - то же и словарь плагинов; v5 - флаг подписи, seqno, wallet_id и словарь расширений
+    let offset = match data.remaining_bits() {
+        288 => 32,
+        320 | 321 => 64,
+        322 => 65,
+        _ => return Ok(None),
+    };
+    let key = data.skip_bits(offset).and_then(|_| {
+        let high = data.load_uint(128)?.to_be_bytes();
+        let low = data.load_uint(128)?.to_be_bytes();
+        let mut key = [0u8; 32];
+        key[..16].copy_from_slice(&high);
+        key[16..].copy_from_slice(&low);
+        Some(key)
+    });
+    Ok(key)
+}
+
+// StateInit: split_depth:(Maybe ## 5) special:(Maybe TickTock) code:(Maybe ^Cell) data:(Maybe ^Cell) ...
+fn state_init_data(mut state_init: CellSlice<'_>) -> Option<CellSlice<'_>> {
+    if state_init.load_bit()? {
+        state_init.skip_bits(5)?;
+    }
+    if state_init.load_bit()? {
+        state_init.skip_bits(2)?;
+    }
+    if state_init.load_bit()? {
+        state_init.load_ref()?;
+    }
+    if state_init.load_bit()? {
+        state_init.load_ref()
+    } else {
+        None
+    }
+}
+
+// Сообщение, которое подписывает кошелек (ton_proof, версия 2)
+fn proof_message(workchain: i32, hash: &[u8; 32], proof: &TonProof) -> [u8; 32] {
+    let mut message = Vec::new();
+    message.extend_from_slice(TON_PROOF_PREFIX);
+    message.extend_from_slice(&workchain.to_be_bytes());
+    message.extend_from_slice(hash);
+    message.extend_from_slice(&proof.domain.length_bytes.to_le_bytes());
+    message.extend_from_slice(proof.domain.value.as_bytes());
+    message.extend_from_slice(&(proof.timestamp as u64).to_le_bytes());
+    message.extend_from_slice(proof.payload.as_bytes());
+
+    let mut full = vec![0xff, 0xff];
+    full.extend_from_slice(TON_CONNECT_PREFIX);
+    full.extend_from_slice(&Sha256::digest(&message));
+    Sha256::digest(&full).into()
+}
+
+pub struct TonConnectConfig {
+    // Домен WebApp, для которого кошелек подписывает ton_proof
+    pub domain: String,
+    pub merchant_wallet: String,
+}
+
+impl TonConnectConfig {
+    pub fn from_env(merchant_wallet: String) -> Self {
+        Self {
+            domain: std::env::var("TON_CONNECT_DOMAIN").unwrap_or_else(|_| "yourdomain.com".to_string()),
+            merchant_wallet,
+        }
+    }
+}
+
+pub struct TonConnectService {
+    db_pool: SqlitePool,
+    toncenter: ToncenterClient,
+    config: TonConnectConfig,
+}
+
+impl TonConnectService {
+    pub fn new(db_pool: SqlitePool, toncenter: ToncenterClient, config: TonConnectConfig) -> Self {
+        Self {
+            db_pool,
+            toncenter,
+            config,
+        }
+    }
+
+    // Одноразовый payload, который кошелек включит в подпись ton_proof
+    pub async fn issue_payload(&self, user_id: i64) -> Result<String, TonConnectError> {
+        sqlx::query("DELETE FROM ton_proof_payloads WHERE expires_at <= datetime('now')")
+            .execute(&self.db_pool)
+            .await?;
+
+        let payload = uuid::Uuid::new_v4().simple().to_string();
+        sqlx::query(&format!(
+            "INSERT INTO ton_proof_payloads (payload, user_id, expires_at) VALUES (?, ?, datetime('now', '+{} minutes'))",
+            PAYLOAD_TTL_MINUTES
+        ))
+            .bind(&payload)
+            .bind(user_id)
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(payload)
+    }
+
+    // Проверяет ton_proof и привязывает кошелек к пользователю Telegram
+    pub async fn verify_proof(&self, user_id: i64, request: &TonProofRequest) -> Result<LinkedWallet, TonConnectError> {
+        let (workchain, hash) = parse_address(&request.address).ok_or(TonConnectError::InvalidAddress)?;
+        let proof = &request.proof;
+
+        if proof.domain.value != self.config.domain || proof.domain.length_bytes as usize != proof.domain.value.len() {
+            return Err(TonConnectError::InvalidProof("неверный домен"));
+        }
+        if (chrono::Utc::now().timestamp() - proof.timestamp).abs() > PROOF_MAX_AGE_SECS {
+            return Err(TonConnectError::InvalidProof("подпись устарела"));
+        }
+
+        let public_key: [u8; 32] = hex::decode(&request.public_key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(TonConnectError::InvalidProof("некорректный публичный ключ"))?;
+        let signature: [u8; 64] = STANDARD
+            .decode(&proof.signature)
+            .ok()
+            .and_then(|signature| signature.try_into().ok())
+            .ok_or(TonConnectError::InvalidProof("некорректная подпись"))?;
+
+        // Payload используется один раз, даже если проверка дальше не пройдет
+        let taken = sqlx::query_scalar::<_, String>(
+            "DELETE FROM ton_proof_payloads WHERE payload = ? AND user_id = ? AND expires_at > datetime('now') RETURNING payload"
+        )
+            .bind(&proof.payload)
+            .bind(user_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        if taken.is_none() {
+            return Err(TonConnectError::PayloadExpired);
+        }
+
+        let verifying_key = VerifyingKey::from_bytes(&public_key)
+            .map_err(|_| TonConnectError::InvalidProof("некорректный публичный ключ"))?;
+        verifying_key
+            .verify(&proof_message(workchain, &hash, proof), &Signature::from_bytes(&signature))
+            .map_err(|_| TonConnectError::InvalidProof("подпись не совпадает"))?;
+
+        // Ключ должен принадлежать именно этому кошельку, а не любому, подписавшему payload.
+        // Для неразвернутого кошелька ключ берется из state_init, иначе - из get_public_key
+        let address = raw_address(workchain, &hash);
+        let wallet_key = match proof.state_init.as_deref() {
+            Some(state_init) => state_init_public_key(state_init, &hash)?,
+            None => None,
+        };
+        let wallet_key = match wallet_key {
+            Some(key) => key,
+            None => self.wallet_public_key(&address).await?,
+        };
+        if wallet_key != public_key {
+            return Err(TonConnectError::InvalidProof("ключ не принадлежит кошельку"));
+        }
+
+        let wallet = LinkedWallet {
+            address,
+            public_key: request.public_key.to_lowercase(),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO user_wallets (user_id, address, public_key) VALUES (?, ?, ?)
+            ON CONFLICT(user_id) DO UPDATE SET
+                address = excluded.address,
+                public_key = excluded.public_key,
+                linked_at = CURRENT_TIMESTAMP
+            "#
+        )
+            .bind(user_id)
+            .bind(&wallet.address)
+            .bind(&wallet.public_key)
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(wallet)
+    }
+
+    pub async fn linked_wallet(&self, user_id: i64) -> Result<Option<LinkedWallet>, TonConnectError> {
+        Ok(sqlx::query_as::<_, LinkedWallet>("SELECT address, public_key FROM user_wallets WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.db_pool)
+            .await?)
+    }
+
+    // Остаток к оплате по заказу, который еще ждет оплаты
+    async fn pending_order(&self, order_id: i64, user_id: i64) -> Result<f64, TonConnectError> {
+        let order = sqlx::query_as::<_, (f64, String)>(
+            "SELECT total_amount, status FROM orders WHERE id = ? AND user_id = ?"
+        )
+            .bind(order_id)
+            .bind(user_id)
+            .fetch_optional(&self.db_pool)
+            .await?
+            .ok_or(TonConnectError::OrderNotFound)?;
+
+        if order.1 != "pending" {
+            return Err(TonConnectError::OrderNotPayable);
+        }
+        let paid = payments::paid_amount(&self.db_pool, order_id).await?;
+        Ok((order.0 - paid).max(0.0))
+    }
+
+    // Перевод на кошелек магазина с комментарием заказа - WebApp отправляет его через TON Connect
+    pub async fn transfer(&self, order_id: i64, user_id: i64) -> Result<TonConnectTransaction, TonConnectError> {
+        let wallet = self.linked_wallet(user_id).await?.ok_or(TonConnectError::WalletNotLinked)?;
+        let amount_due = self.pending_order(order_id, user_id).await?;
+
+        Ok(TonConnectTransaction {
+            valid_until: chrono::Utc::now().timestamp() + TRANSFER_TTL_SECS,
+            from: wallet.address,
+            messages: vec![TonConnectMessage {
+                address: self.config.merchant_wallet.clone(),
+                amount: to_nanotons(amount_due).to_string(),
+                payload: comment_payload(&order_comment(order_id)),
+            }],
+        })
+    }
+
+    // Ищет переводы по заказу среди входящих транзакций магазина, записывает и зачитывает их, в том числе частичные.
+    // None - новых платежей нет
+    pub async fn check_payment(
+        &self,
+        payments: &PaymentService,
+        order_id: i64,
+        user_id: i64,
+        username: Option<&str>,
+    ) -> Result<Option<Settlement>, TonConnectError> {
+        let wallet = self.linked_wallet(user_id).await?.ok_or(TonConnectError::WalletNotLinked)?;
+        self.pending_order(order_id, user_id).await?;
+        let sender = parse_address(&wallet.address);
+        let comment = order_comment(order_id);
+
+        let mut settled = None;
+        for transaction in self.toncenter.transactions(&self.config.merchant_wallet, TRANSACTIONS_LIMIT).await? {
+            let Some(message) = &transaction.in_msg else {
+                continue;
+            };
+            let nanotons = message.value.parse::<i64>().unwrap_or(0);
+            if message.message != comment || parse_address(&message.source) != sender || nanotons <= 0 {
+                continue;
+            }
+
+            let amount = nanotons as f64 / 1_000_000_000.0;
+            let settlement = payments.apply_payment(&NewPaymentRecord {
+                order_id,
+                user_id,
+                provider: "ton",
+                amount,
+                currency: "TON",
+                wallet_address: &wallet.address,
+                transaction_hash: Some(&transaction.transaction_id.hash),
+                provider_payment_id: None,
+                credited_amount: amount,
+            }, username).await?;
+            settled = settlement.or(settled);
+        }
+
+        Ok(settled)
+    }
+
+    async fn wallet_public_key(&self, address: &str) -> Result<[u8; 32], TonConnectError> {
+        let result = self.toncenter.run_get_method(address, "get_public_key").await?;
+        // Ненулевой код выхода: кошелек еще не развернут в сети
+        if result.exit_code != 0 {
+            return Err(TonConnectError::InvalidProof("кошелек не активирован, отправьте с него любую транзакцию"));
+        }
+
+        let key = result.stack_num(0)
+            .and_then(|value| hex::decode(format!("{:0>64}", value)).ok())
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| ToncenterError::ResponseError("unexpected get_public_key result".to_string()))?;
+        Ok(key)
+    }
+}
+
+fn to_nanotons(amount: f64) -> i64 {
+    (amount * 1_000_000_000.0).round() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Векторы посчитаны отдельно (Python: hashlib и cryptography для Ed25519) для кошелька v4
+    // с ключом из seed 01..20, адрес - хэш его StateInit
+    const PUBLIC_KEY: &str = "79b5562e8fe654f94078b112e8a98ba7901f853ae695bed7e0e3910bad049664";
+    const ADDRESS_HASH: &str = "732bf2fd474d187614df497dc0ac9fa27ee2aa0b313fe0e1c01842e2230a27f1";
+    const FRIENDLY_ADDRESS: &str = "EQBzK_L9R00YdhTfSX3ArJ-ifuKqCzE_4OHAGELiIwon8fsc";
+    const STATE_INIT_V4: &str =
+        "te6ccgEBAwEAPAACATQBAgAU/wAg3SCCAUyXugBRAAAAACmpoxd5tVYuj+ZU+UB4sRLoqYunkB+FOuaVvtfg45ELrQSWZEA=";
+    const STATE_INIT_V3: &str =
+        "te6ccgEBAwEAOwACATQBAgAU/wAg3SCCAUyXugBQAAAAACmpoxd5tVYuj+ZU+UB4sRLoqYunkB+FOuaVvtfg45ELrQSWZA==";
+    const ADDRESS_HASH_V3: &str = "f17e179babe6bb6367aff8d8bec39a8e5a628ada1e4c8e1642b7f6284185164b";
+    const SIGNATURE: &str =
+        "JEnb0Cxv+cMpr8st/sw7PnN6XoBtYp/liYMXu1gVJZtR7lfj5xu3Q5mBw2hAc4XOv1PEw0muQ2y1Mel20B26DQ==";
+
+    fn bytes32(value: &str) -> [u8; 32] {
+        hex::decode(value).unwrap().try_into().unwrap()
+    }
+
+    fn proof() -> TonProof {
+        TonProof {
+            timestamp: 1754000000,
+            domain: TonProofDomain { length_bytes: 16, value: "shop.example.com".to_string() },
+            signature: SIGNATURE.to_string(),
+            payload: "a1b2c3d4e5f6".to_string(),
+            state_init: Some(STATE_INIT_V4.to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_raw_and_friendly_addresses() {
+        let expected = Some((0, bytes32(ADDRESS_HASH)));
+        assert_eq!(parse_address(FRIENDLY_ADDRESS), expected);
+        assert_eq!(parse_address(&format!("0:{}", ADDRESS_HASH)), expected);
+        // Испорченная контрольная сумма
+        assert_eq!(parse_address("EQBzK_L9R00YdhTfSX3ArJ-ifuKqCzE_4OHAGELiIwon8fsd"), None);
+    }
+
+    #[test]
+    fn builds_ton_proof_message() {
+        let message = proof_message(0, &bytes32(ADDRESS_HASH), &proof());
+        assert_eq!(hex::encode(message), "4da0de41cb58717c5383733c6a9bd789fa7778008ca1c515417abc6da8bf9141");
+    }
+
+    #[test]
+    fn wallet_signature_matches_proof_message() {
+        let key = VerifyingKey::from_bytes(&bytes32(PUBLIC_KEY)).unwrap();
+        let signature: [u8; 64] = STANDARD.decode(SIGNATURE).unwrap().try_into().unwrap();
+        let message = proof_message(0, &bytes32(ADDRESS_HASH), &proof());
+
+        assert!(key.verify(&message, &Signature::from_bytes(&signature)).is_ok());
+        // Подпись привязана к адресу: для другого кошелька сообщение другое
+        let other = proof_message(0, &bytes32(ADDRESS_HASH_V3), &proof());
+        assert!(key.verify(&other, &Signature::from_bytes(&signature)).is_err());
+    }
+
+    #[test]
+    fn derives_public_key_from_state_init() {
+        assert_eq!(
+            state_init_public_key(STATE_INIT_V4, &bytes32(ADDRESS_HASH)).unwrap(),
+            Some(bytes32(PUBLIC_KEY))
+        );
+        assert_eq!(
+            state_init_public_key(STATE_INIT_V3, &bytes32(ADDRESS_HASH_V3)).unwrap(),
+            Some(bytes32(PUBLIC_KEY))
+        );
+    }
+
+    #[test]
+    fn rejects_state_init_of_other_address() {
+        assert!(matches!(
+            state_init_public_key(STATE_INIT_V3, &bytes32(ADDRESS_HASH)),
+            Err(TonConnectError::InvalidProof(_))
+        ));
+        assert!(matches!(
+            state_init_public_key("not a boc", &bytes32(ADDRESS_HASH)),
+            Err(TonConnectError::InvalidProof(_))
+        ));
+    }
+}
@@ -1,9 +1,20 @@
 use serde::{Deserialize, Serialize};
-use sqlx::{SqlitePool, FromRow};
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
 use chrono::NaiveDateTime;
 use reqwest::{Client, header};
 use thiserror::Error;
-use uuid::Uuid;
+use tokio::sync::OnceCell;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::payments::{self, parse_order_comment, NewPaymentRecord, PaymentError, PaymentService, Settlement};
+use crate::ton_boc::Boc;
+use crate::ton_connect::{parse_address, raw_address};
+use crate::toncenter::{ToncenterClient, ToncenterError};
+
+// op-код transfer_notification (TEP-74): кошелек жетонов уведомляет владельца о входящем переводе
+const TRANSFER_NOTIFICATION_OP: u128 = 0x7362d09c;
+// Сколько последних транзакций кошелька магазина просматривать при поиске оплаты жетонами
+const JETTON_TRANSACTIONS_LIMIT: u32 = 50;
 
 #[derive(Error, Debug)]
 pub enum TonPaymentError {
@@ -19,6 +30,134 @@ pub enum TonPaymentError {
     EnvError(String),
     #[error("JSON parsing error: {0}")]
     JsonError(String),
+    #[error("{0}")]
+    ToncenterError(#[from] ToncenterError),
+    #[error("Unknown payment: {0}")]
+    UnknownPayment(String),
+    #[error("Jetton wallet {0} does not belong to the merchant or the configured jetton master")]
+    JettonWalletMismatch(String),
+    #[error("Payment error: {0}")]
+    PaymentError(#[from] PaymentError),
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Заголовок с подписью уведомления платежного сервиса: hex(HMAC-SHA256(PAYMENT_CALLBACK_SECRET, тело запроса))
+pub const CALLBACK_SIGNATURE_HEADER: &str = "X-Payment-Signature";
+
+// Уведомление платежного сервиса об изменении статуса платежа
+#[derive(Debug, Deserialize)]
+pub struct PaymentCallback {
+    pub payment_id: String,
+    pub status: CallbackStatus,
+    pub amount: f64,
+    pub transaction_hash: Option<String>,
+    // Номер заказа из комментария перевода: по нему оплата записывается, если инвойс создан не магазином
+    #[serde(default)]
+    pub order_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CallbackStatus {
+    Pending,
+    Paid,
+    Failed,
+    Expired,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallbackOutcome {
+    // Платеж подтвержден и зачтен в заказ
+    Paid(Settlement),
+    // Платеж отклонен или истек
+    Closed,
+    // Повтор или устаревшее уведомление: состояние не изменилось
+    Ignored,
+}
+
+// Жетон, которым можно оплатить заказ (USDT на TON)
+#[derive(Debug, Clone)]
+pub struct JettonConfig {
+    pub code: String,
+    pub symbol: String,
+    pub master_address: String,
+    // Кошелек жетонов, принадлежащий кошельку магазина
+    pub merchant_jetton_wallet: String,
+    pub decimals: u32,
+    // Сколько жетонов стоит 1 TON: суммы заказов хранятся в TON
+    pub ton_rate: f64,
+}
+
+impl JettonConfig {
+    // USDT. None, если не задан кошелек жетонов магазина или курс
+    pub fn from_env() -> Option<Self> {
+        let merchant_jetton_wallet = std::env::var("USDT_MERCHANT_JETTON_WALLET").ok().filter(|wallet| !wallet.is_empty())?;
+        let ton_rate = match std::env::var("USDT_TON_RATE").ok().and_then(|rate| rate.parse::<f64>().ok()) {
+            Some(rate) if rate > 0.0 => rate,
+            _ => {
+                eprintln!("⚠️ USDT_TON_RATE не задан: оплата в USDT отключена");
+                return None;
+            }
+        };
+
+        Some(Self {
+            code: "usdt".to_string(),
+            symbol: "USDT".to_string(),
+            master_address: std::env::var("USDT_JETTON_MASTER")
+                .unwrap_or_else(|_| "EQCxE6mUtQJKFnGfaROTKOt1lZbDiiX1kCixRv7Nw2Id_sDs".to_string()),
+            merchant_jetton_wallet,
+            decimals: std::env::var("USDT_DECIMALS").ok().and_then(|decimals| decimals.parse().ok()).unwrap_or(6),
+            ton_rate,
+        })
+    }
+
+    // Сумма заказа в минимальных единицах жетона, с округлением вверх
+    pub fn amount_units(&self, total_amount: f64) -> u128 {
+        (total_amount * self.ton_rate * 10f64.powi(self.decimals as i32)).ceil() as u128
+    }
+
+    pub fn amount_from_units(&self, units: u128) -> f64 {
+        units as f64 / 10f64.powi(self.decimals as i32)
+    }
+
+    // Сумма в жетонах, пересчитанная в TON по курсу
+    pub fn ton_amount(&self, amount: f64) -> f64 {
+        amount / self.ton_rate
+    }
+}
+
+// Подпись уведомления: HMAC-SHA256 тела запроса в hex
+fn callback_signature_valid(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(expected) = hex::decode(signature.trim()) else {
+        return false;
+    };
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+pub struct TransferNotification {
+    pub amount: u128,
+    pub sender: Option<(i32, [u8; 32])>,
+    pub comment: Option<String>,
+}
+
+// transfer_notification#7362d09c query_id:uint64 amount:Coins sender:MsgAddress forward_payload:(Either Cell ^Cell)
+pub fn parse_transfer_notification(body: &str) -> Option<TransferNotification> {
+    let boc = Boc::from_base64(body)?;
+    let mut slice = boc.root();
+    if slice.load_uint(32)? != TRANSFER_NOTIFICATION_OP {
+        return None;
+    }
+    slice.load_uint(64)?;
+    let amount = slice.load_coins()?;
+    let sender = slice.load_address()?;
+    let comment = slice.load_either_ref().and_then(|payload| payload.load_comment());
+
+    Some(TransferNotification { amount, sender, comment })
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
@@ -59,6 +198,10 @@ pub struct TonProcessor {
     merchant_wallet: String,
     callback_url: String,
     db_pool: SqlitePool,
+    // Общий секрет для подписи уведомлений на CALLBACK_URL
+    callback_secret: Option<String>,
+    jetton: Option<(ToncenterClient, JettonConfig)>,
+    jetton_wallet_verified: OnceCell<()>,
 }
 
 impl TonProcessor {
@@ -76,25 +219,248 @@ impl TonProcessor {
             callback_url: std::env::var("CALLBACK_URL")
                 .unwrap_or_else(|_| "https://yourdomain.com/api/payment-callback".to_string()),
             db_pool,
+            callback_secret: std::env::var("PAYMENT_CALLBACK_SECRET").ok().filter(|secret| !secret.is_empty()),
+            jetton: None,
+            jetton_wallet_verified: OnceCell::new(),
         })
     }
 
+    // Без PAYMENT_CALLBACK_SECRET уведомления не принимаются вовсе
+    pub fn verify_callback_signature(&self, body: &[u8], signature: &str) -> bool {
+        let Some(secret) = &self.callback_secret else {
+            eprintln!("⚠️ PAYMENT_CALLBACK_SECRET не задан: уведомление платежного сервиса отклонено");
+            return false;
+        };
+        callback_signature_valid(secret, body, signature)
+    }
+
+    // Применяет уведомление к платежу и зачитывает оплату в заказ в одной транзакции.
+    // Платеж ищется по инвойсу магазина, затем по хешу транзакции; если его нет, оплата записывается
+    // по номеру заказа из уведомления. Статус платежа меняется только вперед: повторы и опоздавшие
+    // уведомления ничего не меняют и ничего не отправляют
+    pub async fn handle_callback(
+        &self,
+        payments: &PaymentService,
+        callback: &PaymentCallback,
+    ) -> Result<CallbackOutcome, TonPaymentError> {
+        let mut tx = self.db_pool.begin().await?;
+
+        let payment = sqlx::query_as::<_, (i64, String, String)>(
+            r#"
+            SELECT id, order_id, status FROM payments
+            WHERE ton_payment_id = ? OR (? IS NOT NULL AND transaction_hash = ?)
+            ORDER BY ton_payment_id = ? DESC
+            LIMIT 1
+            "#
+        )
+            .bind(&callback.payment_id)
+            .bind(&callback.transaction_hash)
+            .bind(&callback.transaction_hash)
+            .bind(&callback.payment_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some((payment_id, order_id, status)) = payment else {
+            let outcome = self.record_callback_payment(&mut tx, payments, callback).await?;
+            tx.commit().await?;
+            return Ok(outcome);
+        };
+        let order_id = order_id
+            .parse::<i64>()
+            .map_err(|_| TonPaymentError::UnknownPayment(callback.payment_id.clone()))?;
+
+        let outcome = match callback.status {
+            CallbackStatus::Pending => CallbackOutcome::Ignored,
+            // Деньги пришли: подтверждаем даже истекший или отклоненный ранее платеж.
+            // Зачитывается фактически полученная сумма, недоплату покупатель доплачивает отдельно
+            CallbackStatus::Paid if matches!(status.as_str(), "pending" | "failed" | "expired") => {
+                if callback.amount <= 0.0 {
+                    return Err(TonPaymentError::InvalidAmount);
+                }
+
+                sqlx::query(
+                    r#"
+                    UPDATE payments
+                    SET status = 'confirmed', amount = ?, credited_amount = ?, transaction_hash = COALESCE(?, transaction_hash)
+                    WHERE id = ? AND status = ?
+                    "#
+                )
+                    .bind(callback.amount)
+                    .bind(callback.amount)
+                    .bind(&callback.transaction_hash)
+                    .bind(payment_id)
+                    .bind(&status)
+                    .execute(&mut *tx)
+                    .await?;
+
+                CallbackOutcome::Paid(payments.settle_in(&mut tx, order_id, None).await?)
+            }
+            CallbackStatus::Failed | CallbackStatus::Expired if status == "pending" => {
+                let new_status = if callback.status == CallbackStatus::Failed { "failed" } else { "expired" };
+                sqlx::query("UPDATE payments SET status = ? WHERE id = ? AND status = 'pending'")
+                    .bind(new_status)
+                    .bind(payment_id)
+                    .execute(&mut *tx)
+                    .await?;
+                CallbackOutcome::Closed
+            }
+            _ => CallbackOutcome::Ignored,
+        };
+
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    // Оплата, о которой магазин еще не знает: записывается по номеру заказа и хешу транзакции,
+    // повтор уведомления не запишет ее второй раз
+    async fn record_callback_payment(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        payments: &PaymentService,
+        callback: &PaymentCallback,
+    ) -> Result<CallbackOutcome, TonPaymentError> {
+        let unknown = || TonPaymentError::UnknownPayment(callback.payment_id.clone());
+        let (Some(order_id), Some(transaction_hash)) = (callback.order_id, callback.transaction_hash.as_deref()) else {
+            return Err(unknown());
+        };
+        if callback.status != CallbackStatus::Paid {
+            return Ok(CallbackOutcome::Ignored);
+        }
+        if callback.amount <= 0.0 {
+            return Err(TonPaymentError::InvalidAmount);
+        }
+
+        let user_id = sqlx::query_scalar::<_, i64>("SELECT user_id FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(unknown)?;
+
+        let recorded = payments::record_payment(tx, &NewPaymentRecord {
+            order_id,
+            user_id,
+            provider: "ton",
+            amount: callback.amount,
+            currency: "TON",
+            wallet_address: "",
+            transaction_hash: Some(transaction_hash),
+            provider_payment_id: Some(&callback.payment_id),
+            credited_amount: callback.amount,
+        }).await?;
+        if !recorded {
+            return Ok(CallbackOutcome::Ignored);
+        }
+
+        Ok(CallbackOutcome::Paid(payments.settle_in(tx, order_id, None).await?))
+    }
+
+    // Прием жетонов на кошелек жетонов магазина
+    pub fn with_jetton(mut self, toncenter: ToncenterClient, config: JettonConfig) -> Self {
+        self.jetton = Some((toncenter, config));
+        self
+    }
+
+    // Кошелек жетонов из настроек должен принадлежать магазину и выпускаться нужным мастер-контрактом,
+    // иначе уведомления от него ничего не доказывают
+    async fn verify_jetton_wallet(&self, toncenter: &ToncenterClient, config: &JettonConfig) -> Result<(), TonPaymentError> {
+        self.jetton_wallet_verified.get_or_try_init(|| async {
+            let result = toncenter.run_get_method(&config.merchant_jetton_wallet, "get_wallet_data").await?;
+            let owner = result.stack_cell(1).and_then(|boc| boc.root().load_address().flatten());
+            let master = result.stack_cell(2).and_then(|boc| boc.root().load_address().flatten());
+
+            if result.exit_code != 0
+                || owner.is_none()
+                || owner != parse_address(&self.merchant_wallet)
+                || master != parse_address(&config.master_address)
+            {
+                return Err(TonPaymentError::JettonWalletMismatch(config.merchant_jetton_wallet.clone()));
+            }
+            Ok(())
+        }).await?;
+        Ok(())
+    }
+
+    // Ищет входящие переводы жетонов с комментарием заказа, записывает и зачитывает их, в том числе частичные.
+    // Возвращает итог зачета по каждому новому платежу
+    pub async fn poll_jetton_payments(&self, payments: &PaymentService) -> Result<Vec<(i64, Settlement)>, TonPaymentError> {
+        let Some((toncenter, config)) = &self.jetton else {
+            return Ok(Vec::new());
+        };
+        self.verify_jetton_wallet(toncenter, config).await?;
+
+        let jetton_wallet = parse_address(&config.merchant_jetton_wallet);
+        let mut paid = Vec::new();
+        for transaction in toncenter.transactions(&self.merchant_wallet, JETTON_TRANSACTIONS_LIMIT).await? {
+            let Some(message) = &transaction.in_msg else {
+                continue;
+            };
+            // Уведомление принимаем только от своего кошелька жетонов: его отправителя подделать нельзя
+            if parse_address(&message.source) != jetton_wallet {
+                continue;
+            }
+            let notification = message.msg_data
+                .as_ref()
+                .and_then(|data| data.body.as_deref())
+                .and_then(parse_transfer_notification);
+            let Some(notification) = notification else {
+                continue;
+            };
+            let Some(order_id) = notification.comment.as_deref().and_then(parse_order_comment) else {
+                continue;
+            };
+
+            let order = sqlx::query_as::<_, (i64, String)>(
+                "SELECT user_id, status FROM orders WHERE id = ?"
+            )
+                .bind(order_id)
+                .fetch_optional(&self.db_pool)
+                .await?;
+            let Some((user_id, status)) = order else {
+                continue;
+            };
+            if status != "pending" {
+                continue;
+            }
+
+            let sender = notification.sender
+                .map(|(workchain, hash)| raw_address(workchain, &hash))
+                .unwrap_or_default();
+            let settlement = payments.apply_payment(&NewPaymentRecord {
+                order_id,
+                user_id,
+                provider: &config.code,
+                amount: config.amount_from_units(notification.amount),
+                currency: &config.symbol,
+                wallet_address: &sender,
+                transaction_hash: Some(&transaction.transaction_id.hash),
+                provider_payment_id: None,
+                credited_amount: config.ton_amount(config.amount_from_units(notification.amount)),
+            }, None).await?;
+            if let Some(settlement) = settlement {
+                paid.push((order_id, settlement));
+            }
+        }
+
+        Ok(paid)
+    }
+
+    // Инвойс платежного сервиса по заказу. Результат оплаты приходит на CALLBACK_URL
     pub async fn create_payment(
         &self,
+        order_id: i64,
         user_id: i64,
         amount: f64,
-    ) -> Result<(String, String), TonPaymentError> {
+    ) -> Result<String, TonPaymentError> {
         if amount <= 0.0 {
             return Err(TonPaymentError::InvalidAmount);
         }
 
-        let order_id = Uuid::new_v4().to_string();
+        let order_id = order_id.to_string();
 
         // Сохраняем платеж в БД
         let payment_id = sqlx::query_scalar::<_, i32>(
             r#"
-            INSERT INTO payments (order_id, user_id, amount, wallet_address, status)
-            VALUES (?, ?, ?, ?, 'pending')
+            INSERT INTO payments (order_id, user_id, amount, wallet_address, status, credited_amount)
+            VALUES (?, ?, ?, ?, 'pending', ?)
             RETURNING id
             "#
         )
@@ -102,6 +468,7 @@ impl TonProcessor {
             .bind(user_id)
             .bind(amount)
             .bind(&self.merchant_wallet)
+            .bind(amount)
             .fetch_one(&self.db_pool)
             .await?;
 
@@ -135,7 +502,7 @@ impl TonProcessor {
             .execute(&self.db_pool)
             .await?;
 
-        Ok((order_id, response.payment_url))
+        Ok(response.payment_url)
     }
 
     pub async fn verify_payment(
@@ -185,3 +552,148 @@ impl TonProcessor {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Тела transfer_notification посчитаны отдельно (Python, сериализация ячеек по спецификации TON):
+    // 0.025 USDT (25_000_000 единиц) от 0:0001..1f, комментарий в самой ячейке или по ссылке
+    const SENDER: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn parses_transfer_notification_with_inline_comment() {
+        let notification = parse_transfer_notification(
+            "te6ccgEBAQEAQAAAfHNi0JwAAAAAAAAAB0AX14QIAAACBAYICgwOEBIUFhgaHB4gIiQmKCosLjAyNDY4Ojw+AAAAAE9yZGVyXzQy"
+        ).unwrap();
+
+        assert_eq!(notification.amount, 25_000_000);
+        assert_eq!(notification.sender.map(|(workchain, hash)| (workchain, hex::encode(hash))), Some((0, SENDER.to_string())));
+        assert_eq!(notification.comment.as_deref(), Some("Order_42"));
+    }
+
+    #[test]
+    fn parses_transfer_notification_with_comment_in_ref() {
+        let notification = parse_transfer_notification(
+            "te6ccgEBAgEAQwABZHNi0JwAAAAAAAAAB0AX14QIAAACBAYICgwOEBIUFhgaHB4gIiQmKCosLjAyNDY4Ojw/AQAYAAAAAE9yZGVyXzQz"
+        ).unwrap();
+
+        assert_eq!(notification.amount, 25_000_000);
+        assert_eq!(notification.comment.as_deref(), Some("Order_43"));
+    }
+
+    #[test]
+    fn ignores_other_jetton_messages() {
+        // transfer (0x0f8a7ea5) и другие op-коды - не уведомление о входящем переводе
+        assert!(parse_transfer_notification("te6ccgEBAQEADgAAGA+KfqUAAAAAAAAABw==").is_none());
+        assert!(parse_transfer_notification("not a boc").is_none());
+    }
+
+    // Подпись посчитана отдельно (Python, hmac + hashlib)
+    const CALLBACK_BODY: &[u8] = br#"{"payment_id":"pay_1","status":"paid","amount":10.5,"transaction_hash":null}"#;
+    const CALLBACK_SIGNATURE: &str = "bb7de54d0ca5d9fce32ce7e94c221b7d54a453033b300eed321a872d2428932b";
+
+    #[test]
+    fn accepts_signed_callback() {
+        assert!(callback_signature_valid("callback-secret", CALLBACK_BODY, CALLBACK_SIGNATURE));
+        assert!(callback_signature_valid("callback-secret", CALLBACK_BODY, &format!(" {} ", CALLBACK_SIGNATURE.to_uppercase())));
+    }
+
+    #[test]
+    fn rejects_tampered_callback() {
+        let tampered = br#"{"payment_id":"pay_1","status":"paid","amount":100.5,"transaction_hash":null}"#;
+        assert!(!callback_signature_valid("callback-secret", tampered, CALLBACK_SIGNATURE));
+        assert!(!callback_signature_valid("other-secret", CALLBACK_BODY, CALLBACK_SIGNATURE));
+    }
+
+    #[test]
+    fn rejects_malformed_signature() {
+        assert!(!callback_signature_valid("callback-secret", CALLBACK_BODY, ""));
+        assert!(!callback_signature_valid("callback-secret", CALLBACK_BODY, "not hex"));
+        // Усеченная подпись не принимается
+        assert!(!callback_signature_valid("callback-secret", CALLBACK_BODY, &CALLBACK_SIGNATURE[..16]));
+    }
+
+    // База в памяти живет, пока открыто единственное соединение
+    async fn processor() -> (TonProcessor, PaymentService) {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::database::run_migrations(&pool).await.unwrap();
+        std::env::set_var("TON_API_URL", "http://127.0.0.1:9");
+        std::env::set_var("TON_API_KEY", "test");
+        (TonProcessor::new(pool.clone()).unwrap(), PaymentService::new(pool))
+    }
+
+    async fn order(pool: &SqlitePool, total_amount: f64) -> i64 {
+        sqlx::query_scalar::<_, i64>(
+            "INSERT INTO orders (user_id, total_amount, status, delivery_address) VALUES (7, ?, 'pending', '') RETURNING id"
+        )
+            .bind(total_amount)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    fn paid(payment_id: &str, amount: f64, order_id: Option<i64>) -> PaymentCallback {
+        PaymentCallback {
+            payment_id: payment_id.to_string(),
+            status: CallbackStatus::Paid,
+            amount,
+            transaction_hash: Some(format!("hash_{}", payment_id)),
+            order_id,
+        }
+    }
+
+    #[tokio::test]
+    async fn callback_confirms_invoice_and_settles_order() {
+        let (processor, payments) = processor().await;
+        let order_id = order(&processor.db_pool, 10.0).await;
+        sqlx::query(
+            "INSERT INTO payments (order_id, user_id, amount, wallet_address, status, ton_payment_id) VALUES (?, 7, 10.0, '', 'pending', 'inv_1')"
+        )
+            .bind(order_id.to_string())
+            .execute(&processor.db_pool)
+            .await
+            .unwrap();
+
+        let outcome = processor.handle_callback(&payments, &paid("inv_1", 10.0, None)).await.unwrap();
+        assert_eq!(outcome, CallbackOutcome::Paid(Settlement::Covered { overpaid: 0.0 }));
+        let status: String = sqlx::query_scalar("SELECT status FROM orders WHERE id = ?")
+            .bind(order_id)
+            .fetch_one(&processor.db_pool)
+            .await
+            .unwrap();
+        assert_eq!(status, "paid");
+    }
+
+    #[tokio::test]
+    async fn callback_records_payment_by_order_once() {
+        let (processor, payments) = processor().await;
+        let order_id = order(&processor.db_pool, 10.0).await;
+
+        let outcome = processor.handle_callback(&payments, &paid("inv_2", 9.5, Some(order_id))).await.unwrap();
+        assert_eq!(outcome, CallbackOutcome::Paid(Settlement::Partial { paid: 9.5, remaining: 0.5 }));
+
+        // Повтор не записывает платеж и не просит доплату второй раз
+        let outcome = processor.handle_callback(&payments, &paid("inv_2", 9.5, Some(order_id))).await.unwrap();
+        assert_eq!(outcome, CallbackOutcome::Ignored);
+        let (payments_count, queued): (i64, i64) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM payments), (SELECT COUNT(*) FROM notification_outbox WHERE kind = 'payment_request')"
+        )
+            .fetch_one(&processor.db_pool)
+            .await
+            .unwrap();
+        assert_eq!((payments_count, queued), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn callback_without_known_payment_or_order_is_rejected() {
+        let (processor, payments) = processor().await;
+
+        let result = processor.handle_callback(&payments, &paid("inv_3", 1.0, None)).await;
+        assert!(matches!(result, Err(TonPaymentError::UnknownPayment(_))));
+    }
+}
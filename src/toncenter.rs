@@ -0,0 +1,148 @@
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use crate::ton_boc::Boc;
+
+// Клиент toncenter API v2: get-методы контрактов и транзакции кошельков
+
+#[derive(Error, Debug)]
+pub enum ToncenterError {
+    #[error("TON API request failed: {0}")]
+    ApiError(#[from] reqwest::Error),
+    #[error("TON API response error: {0}")]
+    ResponseError(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct ToncenterResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunGetMethodResult {
+    pub exit_code: i64,
+    pub stack: Vec<(String, serde_json::Value)>,
+}
+
+impl RunGetMethodResult {
+    // Число из стека: toncenter отдает его строкой "0x..."
+    pub fn stack_num(&self, index: usize) -> Option<&str> {
+        let (_, value) = self.stack.get(index)?;
+        Some(value.as_str()?.trim_start_matches("0x"))
+    }
+
+    // Ячейка или срез из стека: {"bytes": "<BoC в base64>", ...}
+    pub fn stack_cell(&self, index: usize) -> Option<Boc> {
+        let (_, value) = self.stack.get(index)?;
+        Boc::from_base64(value.get("bytes")?.as_str()?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Transaction {
+    // Время транзакции, unix time
+    #[serde(default)]
+    pub utime: i64,
+    pub transaction_id: TransactionId,
+    pub in_msg: Option<TransactionMessage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransactionId {
+    // Логическое время транзакции: вместе с hash задает место в истории кошелька
+    #[serde(default)]
+    pub lt: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransactionMessage {
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub value: String,
+    // Комментарий, если toncenter смог его разобрать
+    #[serde(default)]
+    pub message: String,
+    pub msg_data: Option<MessageData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageData {
+    pub body: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ToncenterClient {
+    client: Client,
+    api_url: String,
+    api_key: Option<String>,
+}
+
+impl ToncenterClient {
+    pub fn from_env() -> Self {
+        Self {
+            client: Client::new(),
+            api_url: std::env::var("TONCENTER_API_URL").unwrap_or_else(|_| "https://toncenter.com/api/v2".to_string()),
+            api_key: std::env::var("TONCENTER_API_KEY").ok().filter(|key| !key.is_empty()),
+        }
+    }
+
+    fn request(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => builder.header("X-API-Key", api_key),
+            None => builder,
+        }
+    }
+
+    pub async fn run_get_method(&self, address: &str, method: &str) -> Result<RunGetMethodResult, ToncenterError> {
+        let response: ToncenterResponse<RunGetMethodResult> = self
+            .request(self.client.post(format!("{}/runGetMethod", self.api_url)))
+            .json(&json!({ "address": address, "method": method, "stack": [] }))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        response_result(response)
+    }
+
+    // Последние транзакции кошелька, новые первыми
+    pub async fn transactions(&self, address: &str, limit: u32) -> Result<Vec<Transaction>, ToncenterError> {
+        self.transactions_from(address, limit, None).await
+    }
+
+    // Транзакции кошелька, новые первыми, начиная с from (она тоже входит в ответ) - страница истории
+    pub async fn transactions_from(
+        &self,
+        address: &str,
+        limit: u32,
+        from: Option<&TransactionId>,
+    ) -> Result<Vec<Transaction>, ToncenterError> {
+        let limit = limit.to_string();
+        let mut query = vec![("address", address), ("limit", limit.as_str())];
+        if let Some(from) = from {
+            query.push(("lt", from.lt.as_str()));
+            query.push(("hash", from.hash.as_str()));
+        }
+        let response: ToncenterResponse<Vec<Transaction>> = self
+            .request(self.client.get(format!("{}/getTransactions", self.api_url)))
+            .query(&query)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        response_result(response)
+    }
+}
+
+fn response_result<T>(response: ToncenterResponse<T>) -> Result<T, ToncenterError> {
+    match response.result {
+        Some(result) if response.ok => Ok(result),
+        _ => Err(ToncenterError::ResponseError(response.error.unwrap_or_else(|| "empty result".to_string()))),
+    }
+}